@@ -0,0 +1,4 @@
+// Rebuild when a migration is added, the migrations are embedded to check for pending ones
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,7 @@
+-- Replay protection state for COSE messages received from a device
+CREATE TABLE device_replay_window (
+    device INT PRIMARY KEY,
+    highest_sequence_number BIGINT NOT NULL,
+    window_bitmap BIGINT NOT NULL,
+    FOREIGN KEY (device) REFERENCES device(id) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+ALTER TABLE lightweight_key_details DROP COLUMN kek_id;
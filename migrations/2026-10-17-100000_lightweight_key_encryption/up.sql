@@ -0,0 +1,2 @@
+-- Id of the key encryption key the key is wrapped with, NULL for plaintext keys
+ALTER TABLE lightweight_key_details ADD COLUMN kek_id BYTEA;
@@ -0,0 +1,2 @@
+ALTER TABLE device_key DROP COLUMN acknowledged_at;
+ALTER TABLE device_key DROP COLUMN delivered_at;
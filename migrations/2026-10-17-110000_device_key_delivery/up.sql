@@ -0,0 +1,3 @@
+-- Over-the-air delivery state of NEXT keys
+ALTER TABLE device_key ADD COLUMN delivered_at TIMESTAMP;
+ALTER TABLE device_key ADD COLUMN acknowledged_at TIMESTAMP;
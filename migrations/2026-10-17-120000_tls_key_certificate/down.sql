@@ -0,0 +1,4 @@
+ALTER TABLE tls_key_details DROP CONSTRAINT tls_key_details_serial_unique;
+ALTER TABLE tls_key_details DROP COLUMN revoked_at;
+ALTER TABLE tls_key_details DROP COLUMN certificate;
+ALTER TABLE tls_key_details DROP COLUMN serial;
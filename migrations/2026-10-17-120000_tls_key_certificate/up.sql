@@ -0,0 +1,5 @@
+-- Certificates issued by the internal CA, TLS keys could not be created before
+ALTER TABLE tls_key_details ADD COLUMN serial TEXT NOT NULL;
+ALTER TABLE tls_key_details ADD COLUMN certificate TEXT NOT NULL;
+ALTER TABLE tls_key_details ADD COLUMN revoked_at TIMESTAMP;
+ALTER TABLE tls_key_details ADD CONSTRAINT tls_key_details_serial_unique UNIQUE (serial);
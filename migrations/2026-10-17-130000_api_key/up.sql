@@ -0,0 +1,13 @@
+CREATE TYPE api_key_role AS ENUM ('READ_ONLY', 'OPERATOR', 'ADMIN');
+
+CREATE TABLE api_key (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(100) NOT NULL UNIQUE,
+    -- SHA-256 of the key, the key itself is only returned on creation
+    key_hash BYTEA NOT NULL UNIQUE,
+    role api_key_role NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    expires_at TIMESTAMP,
+    last_used_at TIMESTAMP,
+    revoked_at TIMESTAMP
+);
@@ -0,0 +1,27 @@
+CREATE TABLE audit_event (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    -- API key name, "jwt:<subject>", "cert:<subject>" or "device:<id>"
+    actor TEXT NOT NULL,
+    source_ip VARCHAR(45),
+    action VARCHAR(50) NOT NULL,
+    entity_type VARCHAR(50) NOT NULL,
+    -- No foreign key, events outlive the entities they describe
+    entity_id INTEGER NOT NULL,
+    before JSONB,
+    after JSONB
+);
+
+CREATE INDEX audit_event_entity_idx ON audit_event (entity_type, entity_id);
+CREATE INDEX audit_event_actor_idx ON audit_event (actor);
+CREATE INDEX audit_event_created_at_idx ON audit_event (created_at);
+
+CREATE FUNCTION audit_event_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_event is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_event_append_only
+    BEFORE UPDATE OR DELETE OR TRUNCATE ON audit_event
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_event_append_only();
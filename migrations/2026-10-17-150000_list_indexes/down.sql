@@ -0,0 +1,8 @@
+DROP INDEX firmware_version_prefix_idx;
+DROP INDEX firmware_name_prefix_idx;
+DROP INDEX device_type_name_prefix_idx;
+DROP INDEX device_name_prefix_idx;
+DROP INDEX device_desired_firmware_filter_idx;
+DROP INDEX device_firmware_filter_idx;
+DROP INDEX device_status_filter_idx;
+DROP INDEX device_type_filter_idx;
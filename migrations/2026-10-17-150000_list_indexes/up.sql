@@ -0,0 +1,11 @@
+-- Filters of the list endpoints
+CREATE INDEX device_type_filter_idx ON device (type);
+CREATE INDEX device_status_filter_idx ON device (status);
+CREATE INDEX device_firmware_filter_idx ON device (firmware);
+CREATE INDEX device_desired_firmware_filter_idx ON device (desired_firmware);
+
+-- Prefix searches, text_pattern_ops keeps LIKE 'x%' indexable regardless of the collation
+CREATE INDEX device_name_prefix_idx ON device (name text_pattern_ops);
+CREATE INDEX device_type_name_prefix_idx ON device_type (name text_pattern_ops);
+CREATE INDEX firmware_name_prefix_idx ON firmware (name text_pattern_ops);
+CREATE INDEX firmware_version_prefix_idx ON firmware (version text_pattern_ops);
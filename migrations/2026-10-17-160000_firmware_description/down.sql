@@ -0,0 +1 @@
+ALTER TABLE firmware DROP COLUMN description;
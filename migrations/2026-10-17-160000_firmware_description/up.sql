@@ -0,0 +1 @@
+ALTER TABLE firmware ADD COLUMN description VARCHAR(1000);
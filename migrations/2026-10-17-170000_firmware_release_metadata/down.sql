@@ -0,0 +1,10 @@
+DROP INDEX firmware_channel_version_idx;
+
+ALTER TABLE firmware
+    DROP COLUMN version_sort_key,
+    DROP COLUMN channel,
+    DROP COLUMN git_commit,
+    DROP COLUMN build_timestamp,
+    DROP COLUMN release_notes;
+
+DROP TYPE release_channel;
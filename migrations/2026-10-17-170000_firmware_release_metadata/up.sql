@@ -0,0 +1,15 @@
+CREATE TYPE release_channel AS ENUM ('DEV', 'BETA', 'STABLE');
+
+ALTER TABLE firmware
+    ADD COLUMN release_notes TEXT,
+    ADD COLUMN build_timestamp TIMESTAMP,
+    ADD COLUMN git_commit VARCHAR(64),
+    -- Firmware uploaded before channels existed is considered released
+    ADD COLUMN channel release_channel NOT NULL DEFAULT 'STABLE',
+    -- Byte-wise comparable semver precedence, NULL if the version is no semantic version.
+    -- Filled by the server, existing rows on its next start.
+    ADD COLUMN version_sort_key TEXT COLLATE "C";
+
+ALTER TABLE firmware ALTER COLUMN channel SET DEFAULT 'DEV';
+
+CREATE INDEX firmware_channel_version_idx ON firmware (channel, version_sort_key);
@@ -0,0 +1,4 @@
+DROP TABLE rollout_campaign_device;
+DROP TABLE rollout_campaign;
+DROP TYPE campaign_device_status;
+DROP TYPE campaign_status;
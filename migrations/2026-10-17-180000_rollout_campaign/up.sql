@@ -0,0 +1,47 @@
+CREATE TYPE campaign_status AS ENUM ('RUNNING', 'PAUSED', 'ABORTED', 'COMPLETED');
+CREATE TYPE campaign_device_status AS ENUM (
+    'PENDING', 'ASSIGNED', 'SUCCEEDED', 'FAILED', 'ROLLED_BACK', 'CANCELLED'
+);
+
+CREATE TABLE rollout_campaign (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(100) NOT NULL UNIQUE,
+    target_firmware INT NOT NULL REFERENCES firmware(id) ON DELETE RESTRICT,
+    -- Device list filter the targeted devices were selected with
+    device_filter JSONB NOT NULL,
+    -- Cumulative number of devices assigned once each wave has started
+    waves INT[] NOT NULL,
+    wave_interval_seconds INT NOT NULL,
+    device_timeout_seconds INT NOT NULL,
+    max_failure_percent INT NOT NULL,
+    status campaign_status NOT NULL DEFAULT 'RUNNING',
+    status_reason TEXT,
+    -- Number of waves started
+    current_wave INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    wave_started_at TIMESTAMP,
+    finished_at TIMESTAMP
+);
+
+CREATE INDEX rollout_campaign_status_idx ON rollout_campaign (status);
+
+CREATE TABLE rollout_campaign_device (
+    id SERIAL PRIMARY KEY,
+    campaign INT NOT NULL REFERENCES rollout_campaign(id) ON DELETE CASCADE,
+    device INT NOT NULL REFERENCES device(id) ON DELETE CASCADE,
+    status campaign_device_status NOT NULL DEFAULT 'PENDING',
+    -- Index of the wave the device was assigned in
+    wave INT,
+    -- Restored if the campaign is aborted before the device updated
+    previous_desired_firmware INT REFERENCES firmware(id) ON DELETE SET NULL,
+    assigned_at TIMESTAMP,
+    -- Last block of the target firmware served to the device
+    downloaded_at TIMESTAMP,
+    finished_at TIMESTAMP,
+    CONSTRAINT rollout_campaign_device_unique_pair UNIQUE (campaign, device)
+);
+
+-- Devices take part in at most one unfinished campaign at a time
+CREATE UNIQUE INDEX rollout_campaign_device_active_idx ON rollout_campaign_device (device)
+    WHERE status IN ('PENDING', 'ASSIGNED');
+CREATE INDEX rollout_campaign_device_status_idx ON rollout_campaign_device (campaign, status);
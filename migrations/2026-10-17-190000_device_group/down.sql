@@ -0,0 +1,3 @@
+DROP TABLE device_tag;
+DROP TABLE device_group_device;
+DROP TABLE device_group;
@@ -0,0 +1,23 @@
+CREATE TABLE device_group (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(100) NOT NULL UNIQUE,
+    description VARCHAR(1000)
+);
+
+CREATE TABLE device_group_device (
+    id SERIAL PRIMARY KEY,
+    device_group INT NOT NULL REFERENCES device_group(id) ON DELETE CASCADE,
+    device INT NOT NULL REFERENCES device(id) ON DELETE CASCADE,
+    CONSTRAINT device_group_device_unique_pair UNIQUE (device_group, device)
+);
+
+CREATE INDEX device_group_device_device_idx ON device_group_device (device);
+
+CREATE TABLE device_tag (
+    id SERIAL PRIMARY KEY,
+    device INT NOT NULL REFERENCES device(id) ON DELETE CASCADE,
+    tag VARCHAR(100) NOT NULL,
+    CONSTRAINT device_tag_unique_pair UNIQUE (device, tag)
+);
+
+CREATE INDEX device_tag_tag_idx ON device_tag (tag);
@@ -0,0 +1,36 @@
+CREATE TYPE webhook_event_type AS ENUM (
+    'DEVICE_FIRMWARE_REPORTED', 'DEVICE_KEY_CREATED', 'DEVICE_KEY_DELETED', 'FIRMWARE_UPLOADED'
+);
+CREATE TYPE webhook_delivery_status AS ENUM ('PENDING', 'DELIVERED', 'FAILED');
+
+CREATE TABLE webhook (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(100) NOT NULL UNIQUE,
+    url VARCHAR(2000) NOT NULL,
+    -- HMAC-SHA256 key of the signature, needed in plain to sign
+    secret VARCHAR(128) NOT NULL,
+    event_types webhook_event_type[] NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE TABLE webhook_delivery (
+    id BIGSERIAL PRIMARY KEY,
+    webhook INT NOT NULL REFERENCES webhook(id) ON DELETE CASCADE,
+    event_type webhook_event_type NOT NULL,
+    -- Event data, the sent body wraps it with the delivery id, event type and creation time
+    payload JSONB NOT NULL,
+    status webhook_delivery_status NOT NULL DEFAULT 'PENDING',
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT now(),
+    last_attempt_at TIMESTAMP,
+    -- HTTP status of the last attempt, none if no response was received
+    last_response_status INT,
+    last_error TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMP
+);
+
+CREATE INDEX webhook_delivery_due_idx ON webhook_delivery (next_attempt_at)
+    WHERE status = 'PENDING';
+CREATE INDEX webhook_delivery_webhook_idx ON webhook_delivery (webhook, id);
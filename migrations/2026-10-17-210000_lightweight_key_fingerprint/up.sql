@@ -0,0 +1,6 @@
+-- Hex encoded start of the SHA-256 of the plaintext key, shown without unwrapping the key.
+-- Plaintext keys are filled in here, wrapped keys when the keys are wrapped at startup.
+ALTER TABLE lightweight_key_details ADD COLUMN fingerprint VARCHAR(16);
+UPDATE lightweight_key_details
+SET fingerprint = encode(substring(sha256(key) FROM 1 FOR 8), 'hex')
+WHERE kek_id IS NULL;
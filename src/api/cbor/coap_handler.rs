@@ -0,0 +1,655 @@
+use crate::api::cbor;
+use crate::api::cbor::codec::coap::{
+    self, BlockOption, CoapMessage, Code, MessageType, OptionNumber,
+};
+use crate::api::cbor::codec::operation;
+use crate::api::cbor::codec::oscore;
+use crate::api::cbor::cose_handler;
+use crate::api::cbor::operation_handler;
+use crate::api::cbor::replay_window;
+use crate::db::models::KeyStatus;
+use diesel::QueryDsl;
+use diesel_async::RunQueryDsl;
+use log::{debug, error, warn};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// EXCHANGE_LIFETIME from RFC 7252 section 4.8.2 with the default transmission parameters
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+
+/// Exchanges remembered for deduplication, the oldest is evicted beyond this
+const MAX_EXCHANGES: usize = 8192;
+
+/// Largest Block2 size whose GetFirmwareResponse still fits into one encoded operation
+const MAX_BLOCK_SZX: u8 = 5; // 512 bytes
+
+const RESOURCE_OPERATION: &str = "op";
+const RESOURCE_FIRMWARE: &str = "fw";
+
+enum Exchange {
+    InProgress(Instant),
+    Done(Instant, Vec<u8>),
+}
+
+impl Exchange {
+    fn started(&self) -> Instant {
+        match self {
+            Exchange::InProgress(t) | Exchange::Done(t, _) => *t,
+        }
+    }
+}
+
+type ExchangeKey = (SocketAddr, u16);
+
+/// Exchanges in the order they started, bounded by `MAX_EXCHANGES` and `EXCHANGE_LIFETIME`
+#[derive(Default)]
+struct ExchangeCache {
+    exchanges: HashMap<ExchangeKey, Exchange>,
+    order: VecDeque<(Instant, ExchangeKey)>,
+}
+
+impl ExchangeCache {
+    fn get(&self, key: &ExchangeKey, now: Instant) -> Option<&Exchange> {
+        self.exchanges
+            .get(key)
+            .filter(|e| now.duration_since(e.started()) < EXCHANGE_LIFETIME)
+    }
+
+    /// Start a new exchange, expiring and evicting the oldest ones first
+    fn begin(&mut self, key: ExchangeKey, now: Instant) {
+        while let Some((started, _)) = self.order.front() {
+            if now.duration_since(*started) < EXCHANGE_LIFETIME && self.order.len() < MAX_EXCHANGES
+            {
+                break;
+            }
+            self.pop_oldest();
+        }
+        self.exchanges.insert(key, Exchange::InProgress(now));
+        self.order.push_back((now, key));
+    }
+
+    fn finish(&mut self, key: &ExchangeKey, response: Vec<u8>) {
+        if let Some(exchange) = self.exchanges.get_mut(key) {
+            *exchange = Exchange::Done(exchange.started(), response);
+        }
+    }
+
+    fn remove(&mut self, key: &ExchangeKey) {
+        self.exchanges.remove(key);
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((started, key)) = self.order.pop_front()
+            // The key may have been removed and started again since
+            && self.exchanges.get(&key).is_some_and(|e| e.started() == started)
+        {
+            self.exchanges.remove(&key);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CoapHandler {
+    config: cbor::CborApiConfig,
+    exchanges: Arc<Mutex<ExchangeCache>>,
+    next_message_id: Arc<AtomicU16>,
+}
+
+impl CoapHandler {
+    pub fn new(config: cbor::CborApiConfig) -> Self {
+        let mut seed = [0u8; 2];
+        let _ = getrandom::fill(&mut seed);
+        CoapHandler {
+            config,
+            exchanges: Arc::new(Mutex::new(ExchangeCache::default())),
+            next_message_id: Arc::new(AtomicU16::new(u16::from_be_bytes(seed))),
+        }
+    }
+
+    pub async fn handle_datagram(&self, socket: Arc<UdpSocket>, addr: SocketAddr, msg: Vec<u8>) {
+        let request = match coap::decode_msg(&msg[..]) {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("Failed to decode CoAP message from {addr}: {e:?}");
+                // Reject malformed confirmable messages when the header is still readable
+                if msg.len() >= 4 && MessageType::from(msg[0] >> 4) == MessageType::Confirmable {
+                    let message_id = u16::from_be_bytes([msg[2], msg[3]]);
+                    self.send(&socket, addr, &reset(message_id)).await;
+                }
+                return;
+            }
+        };
+
+        match request.message_type {
+            MessageType::Acknowledgement | MessageType::Reset => {
+                // We only ever send piggybacked or non-confirmable responses
+                return;
+            }
+            MessageType::Confirmable if request.code == Code::Empty as u8 => {
+                // CoAP ping
+                self.send(&socket, addr, &reset(request.message_id)).await;
+                return;
+            }
+            _ => {}
+        }
+
+        let exchange_key = (addr, request.message_id);
+        let cached_response = {
+            let mut exchanges = self.exchanges.lock().expect("exchange cache poisoned");
+            let now = Instant::now();
+            match exchanges.get(&exchange_key, now) {
+                Some(Exchange::Done(_, response)) => Some(response.clone()),
+                Some(Exchange::InProgress(_)) => {
+                    debug!(
+                        "Duplicate CoAP message {} from {addr} still in progress; ignoring",
+                        request.message_id
+                    );
+                    return;
+                }
+                None => {
+                    exchanges.begin(exchange_key, now);
+                    None
+                }
+            }
+        };
+        if let Some(response) = cached_response {
+            debug!(
+                "Duplicate CoAP message {} from {addr}; resending response",
+                request.message_id
+            );
+            self.send_raw(&socket, addr, &response).await;
+            return;
+        }
+
+        let response = self.handle_request(&request, addr).await;
+        let response_buf = match coap::encode_msg(&response) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Failed to encode CoAP response: {e:?}");
+                self.exchanges
+                    .lock()
+                    .expect("exchange cache poisoned")
+                    .remove(&exchange_key);
+                return;
+            }
+        };
+        self.exchanges
+            .lock()
+            .expect("exchange cache poisoned")
+            .finish(&exchange_key, response_buf.clone());
+        self.send_raw(&socket, addr, &response_buf).await;
+    }
+
+    async fn handle_request(&self, request: &CoapMessage, addr: SocketAddr) -> CoapMessage {
+        let mut response = self.response_for(request);
+
+        if request.code != Code::Post as u8 {
+            response.code = Code::MethodNotAllowed as u8;
+            return response;
+        }
+
+        // Unknown critical (odd numbered) options must be rejected. Uri-Host and Uri-Port are
+        // ignored, there is a single origin (RFC 7252 section 5.10.1)
+        if request.options.iter().any(|(number, _)| {
+            number % 2 == 1
+                && *number != OptionNumber::UriHost as u16
+                && *number != OptionNumber::UriPort as u16
+                && *number != OptionNumber::Oscore as u16
+                && *number != OptionNumber::UriPath as u16
+                && *number != OptionNumber::Block2 as u16
+        }) {
+            response.code = Code::BadOption as u8;
+            return response;
+        }
+
+        if request.option(OptionNumber::Oscore).is_some() {
+            return self.handle_oscore_request(request, addr, response).await;
+        }
+
+        let path = request.uri_path();
+        let block2 = match path.as_str() {
+            RESOURCE_OPERATION => None,
+            RESOURCE_FIRMWARE => {
+                let Some(value) = request.option(OptionNumber::Block2) else {
+                    return diagnostic(response, Code::BadRequest, "Block2 option required");
+                };
+                match BlockOption::decode(value) {
+                    Ok(b) if b.szx <= MAX_BLOCK_SZX => Some(b),
+                    Ok(_) => {
+                        return diagnostic(response, Code::BadRequest, "block size too large");
+                    }
+                    Err(_) => return diagnostic(response, Code::BadOption, "invalid Block2"),
+                }
+            }
+            _ => {
+                response.code = Code::NotFound as u8;
+                return response;
+            }
+        };
+
+        let mut cose_handler = cose_handler::CoseHandler::new(
+            self.config.shared_pool.clone(),
+            self.config.key_encryption_key.clone(),
+        );
+        let operation_handler = operation_handler::OperationHandler::new(self.config.clone(), addr);
+        let mut opcode: u16 = 0;
+        let mut device_id: u32 = 0;
+
+        let (opcode_response, operation_response) = match cose_handler
+            .decode_msg(&mut device_id, &mut opcode, &request.payload[..])
+            .await
+        {
+            Ok(operation_bytes) => {
+                let operation_bytes = match block2 {
+                    Some(block) => match block_request(opcode, &operation_bytes[..], block) {
+                        Ok(b) => b,
+                        Err(msg) => {
+                            warn!("Invalid blockwise firmware request from {addr}: {msg}");
+                            return diagnostic(response, Code::BadRequest, msg);
+                        }
+                    },
+                    None => operation_bytes,
+                };
+                operation_handler
+                    .handle_operation(device_id, opcode, &operation_bytes[..])
+                    .await
+            }
+            Err(cose_handler::CoseHandlerError::ReplayDetected) => {
+                warn!("Replayed message from device {device_id} at {addr}");
+                operation_handler.handle_replay(device_id, opcode)
+            }
+            Err(cose_handler::CoseHandlerError::DecodingError(e)) => {
+                error!("Failed to decode message from {addr}: {e:?}");
+                self.config
+                    .metrics
+                    .cbor_decode_failures
+                    .with_label_values(&[format!("{:?}", e)])
+                    .inc();
+                response.code = Code::Unauthorized as u8;
+                return response;
+            }
+            Err(_e) => {
+                error!("Failed to decode message from {addr}");
+                response.code = Code::Unauthorized as u8;
+                return response;
+            }
+        };
+
+        if let Some(block) = block2 {
+            let more = match operation::OperationType::from(opcode_response) {
+                operation::OperationType::GetFirmwareResponse => {
+                    match operation::firmware::decode_get_firmware_response(&operation_response[..])
+                    {
+                        Ok(r) => match self.firmware_size(r.firmware).await {
+                            Some(size) => more_blocks(r.offset, r.length, size),
+                            None => false,
+                        },
+                        Err(_) => false,
+                    }
+                }
+                _ => false,
+            };
+            response.add_option(
+                OptionNumber::Block2,
+                BlockOption {
+                    num: block.num,
+                    more,
+                    szx: block.szx,
+                }
+                .encode(),
+            );
+        }
+
+        let response_buf = match cose_handler
+            .encode_msg(opcode_response, &operation_response[..])
+            .await
+        {
+            Ok(b) => b,
+            Err(_e) => {
+                error!("Failed to encode COSE response");
+                response.code = Code::InternalServerError as u8;
+                return response;
+            }
+        };
+        debug!(
+            "Sending CoAP response with opcode {opcode_response} to device {device_id} at {addr}"
+        );
+
+        response.code = Code::Changed as u8;
+        response.add_option(
+            OptionNumber::ContentFormat,
+            coap::encode_uint(coap::CONTENT_FORMAT_COSE_ENCRYPT0 as u32),
+        );
+        response.payload = response_buf;
+        response
+    }
+
+    async fn handle_oscore_request(
+        &self,
+        request: &CoapMessage,
+        addr: SocketAddr,
+        mut response: CoapMessage,
+    ) -> CoapMessage {
+        let mut key_provider = cose_handler::DbKeyProvider::new(
+            self.config.shared_pool.clone(),
+            self.config.key_encryption_key.clone(),
+        );
+        let mut next_key = None;
+        let oscore_request = match oscore::decode_request(&mut key_provider, request).await {
+            Err(
+                e @ (oscore::OscoreError::DecryptionError
+                | oscore::OscoreError::UnknownSecurityContext),
+            ) => {
+                // During rotation the device may already use the NEXT key
+                let mut key_provider = cose_handler::DbKeyProvider::new(
+                    self.config.shared_pool.clone(),
+                    self.config.key_encryption_key.clone(),
+                )
+                .with_status(KeyStatus::Next);
+                match oscore::decode_request(&mut key_provider, request).await {
+                    Ok(r) => {
+                        next_key = key_provider.key_id();
+                        Ok(r)
+                    }
+                    Err(_) => Err(e),
+                }
+            }
+            res => res,
+        };
+        let oscore_request = match oscore_request {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Failed to decode OSCORE request from {addr}: {e:?}");
+                // Error responses to unprotected requests are sent unprotected (RFC 8613 8.2)
+                return match e {
+                    oscore::OscoreError::InvalidOption => {
+                        diagnostic(response, Code::BadOption, "Invalid OSCORE option")
+                    }
+                    oscore::OscoreError::UnknownSecurityContext => {
+                        diagnostic(response, Code::Unauthorized, "Security context not found")
+                    }
+                    _ => diagnostic(response, Code::BadRequest, "Decryption failed"),
+                };
+            }
+        };
+        let device_id = oscore_request.device_id;
+
+        match replay_window::check_and_store(
+            &self.config.shared_pool,
+            device_id,
+            oscore_request.sequence_number,
+            next_key,
+        )
+        .await
+        {
+            Ok(()) => {}
+            Err(replay_window::ReplayCheckError::Replayed) => {
+                warn!("Replayed OSCORE message from device {device_id} at {addr}");
+                return diagnostic(response, Code::Unauthorized, "Replay detected");
+            }
+            Err(replay_window::ReplayCheckError::DbError) => {
+                response.code = Code::InternalServerError as u8;
+                return response;
+            }
+        }
+
+        let (inner_code, inner_payload) = self.handle_oscore_inner(&oscore_request, addr).await;
+        match oscore::encode_response(&oscore_request, inner_code, &[], &inner_payload[..]) {
+            Ok((option, payload)) => {
+                debug!("Sending OSCORE response to device {device_id} at {addr}");
+                response.code = Code::Changed as u8;
+                response.add_option(OptionNumber::Oscore, option);
+                response.payload = payload;
+            }
+            Err(e) => {
+                error!("Failed to protect OSCORE response: {e:?}");
+                response.code = Code::InternalServerError as u8;
+            }
+        }
+        response
+    }
+
+    /// The inner request is a POST to `/op` whose payload is the CBOR sequence `opcode, operation`
+    async fn handle_oscore_inner(
+        &self,
+        request: &oscore::OscoreRequest,
+        addr: SocketAddr,
+    ) -> (u8, Vec<u8>) {
+        if request.code != Code::Post as u8 {
+            return (Code::MethodNotAllowed as u8, Vec::new());
+        }
+        if coap::uri_path(&request.options) != RESOURCE_OPERATION {
+            return (Code::NotFound as u8, Vec::new());
+        }
+
+        let mut decoder = minicbor::Decoder::new(&request.payload[..]);
+        let opcode = match decoder.u16() {
+            Ok(o) => o,
+            Err(e) => {
+                error!("Failed to decode OSCORE operation from {addr}: {e}");
+                return (Code::BadRequest as u8, Vec::new());
+            }
+        };
+        let operation = &request.payload[decoder.position()..];
+
+        let operation_handler = operation_handler::OperationHandler::new(self.config.clone(), addr);
+        let (opcode_response, operation_response) = operation_handler
+            .handle_operation(request.device_id, opcode, operation)
+            .await;
+
+        let mut buf = Vec::with_capacity(8 + operation_response.len());
+        // Encoding cannot fail as we are writing to a Vec
+        let _ = minicbor::Encoder::new(&mut buf).u16(opcode_response);
+        buf.extend_from_slice(&operation_response);
+        (Code::Changed as u8, buf)
+    }
+
+    async fn firmware_size(&self, firmware_id: u32) -> Option<i64> {
+        use crate::db::schema::firmware::dsl::*;
+
+        let mut conn = match self.config.shared_pool.get().await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to get DB connection: {e}");
+                return None;
+            }
+        };
+        match firmware
+            .find(firmware_id as i32)
+            .select(size)
+            .first::<i64>(&mut conn)
+            .await
+        {
+            Ok(s) => Some(s),
+            Err(e) => {
+                error!("Failed to query size of firmware {firmware_id}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Piggybacked acknowledgement for confirmable requests, a new non-confirmable message otherwise
+    fn response_for(&self, request: &CoapMessage) -> CoapMessage {
+        let (message_type, message_id) = match request.message_type {
+            MessageType::Confirmable => (MessageType::Acknowledgement, request.message_id),
+            _ => (
+                MessageType::NonConfirmable,
+                self.next_message_id.fetch_add(1, Ordering::Relaxed),
+            ),
+        };
+        CoapMessage {
+            message_type,
+            code: Code::InternalServerError as u8,
+            message_id,
+            token: request.token.clone(),
+            options: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    async fn send(&self, socket: &UdpSocket, addr: SocketAddr, msg: &CoapMessage) {
+        match coap::encode_msg(msg) {
+            Ok(buf) => self.send_raw(socket, addr, &buf).await,
+            Err(e) => error!("Failed to encode CoAP message: {e:?}"),
+        }
+    }
+
+    async fn send_raw(&self, socket: &UdpSocket, addr: SocketAddr, buf: &[u8]) {
+        if let Err(e) = socket.send_to(buf, addr).await {
+            error!("Failed to send to {addr}: {e}");
+        }
+    }
+}
+
+/// Firmware request for the block of the Block2 option.
+///
+/// Every block is a new authenticated `GetFirmwareRequest` for the same firmware, so a
+/// blockwise client can repeat the request with a new sequence number. Offset and length of
+/// the request are ignored, the block is selected by the Block2 option alone.
+fn block_request(
+    opcode: u16,
+    operation: &[u8],
+    block: BlockOption,
+) -> Result<Vec<u8>, &'static str> {
+    match operation::OperationType::from(opcode) {
+        operation::OperationType::GetFirmwareRequest => {}
+        _ => return Err("only firmware requests can be transferred blockwise"),
+    }
+    let req = operation::firmware::decode_get_firmware_request(operation)
+        .map_err(|_| "malformed firmware request")?;
+    operation::firmware::encode_get_firmware_request(&operation::firmware::GetFirmwareRequest {
+        firmware: req.firmware,
+        offset: block.offset(),
+        length: block.size(),
+    })
+    .map_err(|_| "malformed firmware request")
+}
+
+/// Whether the firmware continues after the block at `offset` with `length` bytes
+fn more_blocks(offset: u32, length: u32, firmware_size: i64) -> bool {
+    (offset as i64 + length as i64) < firmware_size
+}
+
+fn diagnostic(mut response: CoapMessage, code: Code, msg: &str) -> CoapMessage {
+    response.code = code as u8;
+    response.payload = msg.as_bytes().to_vec();
+    response
+}
+
+fn reset(message_id: u16) -> CoapMessage {
+    CoapMessage {
+        message_type: MessageType::Reset,
+        code: Code::Empty as u8,
+        message_id,
+        token: Vec::new(),
+        options: Vec::new(),
+        payload: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(message_id: u16) -> ExchangeKey {
+        ("192.0.2.1:5683".parse().unwrap(), message_id)
+    }
+
+    #[test]
+    fn more_blocks_until_end_of_firmware() {
+        let block = BlockOption {
+            num: 0,
+            more: false,
+            szx: 2,
+        };
+        assert!(more_blocks(0, block.size(), 100));
+        assert!(!more_blocks(64, 36, 100));
+        assert!(!more_blocks(0, 0, 0));
+    }
+
+    #[test]
+    fn no_more_blocks_for_exact_multiple_of_block_size() {
+        let block = BlockOption {
+            num: 3,
+            more: false,
+            szx: 2,
+        };
+        let size = 4 * block.size() as i64;
+        assert!(more_blocks(2 * block.size(), block.size(), size));
+        assert!(!more_blocks(block.offset(), block.size(), size));
+    }
+
+    #[test]
+    fn block_request_takes_offset_and_length_from_block2() {
+        let request = operation::firmware::encode_get_firmware_request(
+            &operation::firmware::GetFirmwareRequest {
+                firmware: 7,
+                offset: 0,
+                length: 16,
+            },
+        )
+        .unwrap();
+        let block = BlockOption {
+            num: 5,
+            more: false,
+            szx: 5,
+        };
+        let opcode = operation::OperationType::GetFirmwareRequest as u16;
+        let rewritten = block_request(opcode, &request, block).unwrap();
+        let rewritten = operation::firmware::decode_get_firmware_request(&rewritten).unwrap();
+        assert_eq!(rewritten.firmware, 7);
+        assert_eq!(rewritten.offset, 5 * 512);
+        assert_eq!(rewritten.length, 512);
+
+        // The same payload is valid for every block
+        let next = BlockOption { num: 6, ..block };
+        assert!(block_request(opcode, &request, next).is_ok());
+        assert!(block_request(opcode, &[0x80], block).is_err());
+    }
+
+    #[test]
+    fn exchange_cache_evicts_oldest_beyond_capacity() {
+        let mut cache = ExchangeCache::default();
+        let now = Instant::now();
+        for message_id in 0..(MAX_EXCHANGES as u16 + 10) {
+            cache.begin(key(message_id), now);
+        }
+        assert_eq!(cache.exchanges.len(), MAX_EXCHANGES);
+        assert_eq!(cache.order.len(), MAX_EXCHANGES);
+        assert!(cache.get(&key(9), now).is_none());
+        assert!(cache.get(&key(10), now).is_some());
+    }
+
+    #[test]
+    fn exchange_cache_expires_after_lifetime() {
+        let mut cache = ExchangeCache::default();
+        let start = Instant::now();
+        cache.begin(key(1), start);
+        cache.finish(&key(1), vec![1]);
+        assert!(matches!(cache.get(&key(1), start), Some(Exchange::Done(_, r)) if r == &[1]));
+
+        let later = start + EXCHANGE_LIFETIME;
+        assert!(cache.get(&key(1), later).is_none());
+        cache.begin(key(2), later);
+        assert!(!cache.exchanges.contains_key(&key(1)));
+        assert_eq!(cache.order.len(), 1);
+    }
+
+    #[test]
+    fn exchange_cache_keeps_restarted_key() {
+        let mut cache = ExchangeCache::default();
+        let start = Instant::now();
+        cache.begin(key(1), start);
+        cache.remove(&key(1));
+        let restarted = start + Duration::from_secs(1);
+        cache.begin(key(1), restarted);
+
+        // Expiring the first start must not drop the second one
+        cache.begin(key(2), start + EXCHANGE_LIFETIME);
+        assert!(cache.get(&key(1), start + EXCHANGE_LIFETIME).is_some());
+    }
+}
@@ -0,0 +1,287 @@
+pub const COAP_VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xFF;
+const MAX_TOKEN_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum CoapCodecError {
+    UnsupportedVersion,
+    InvalidTokenLength,
+    InvalidOption,
+    InvalidMessage,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl From<u8> for MessageType {
+    fn from(value: u8) -> Self {
+        match value & 0x03 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        }
+    }
+}
+
+/// Message codes as `class << 5 | detail`
+pub enum Code {
+    Empty = 0x00,
+    Post = 0x02,
+    Changed = 0x44,
+    BadRequest = 0x80,
+    Unauthorized = 0x81,
+    BadOption = 0x82,
+    NotFound = 0x84,
+    MethodNotAllowed = 0x85,
+    InternalServerError = 0xA0,
+}
+
+pub enum OptionNumber {
+    UriHost = 3,
+    UriPort = 7,
+    Oscore = 9,
+    UriPath = 11,
+    ContentFormat = 12,
+    Block2 = 23,
+}
+
+/// Content-Format for `application/cose; cose-type="cose-encrypt0"`
+pub const CONTENT_FORMAT_COSE_ENCRYPT0: u16 = 16;
+
+/// Option number and raw option value
+pub type CoapOption = (u16, Vec<u8>);
+
+/// CoAP (RFC 7252) message, Block2 (RFC 7959) is handled with `BlockOption`
+pub struct CoapMessage {
+    pub message_type: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    pub options: Vec<CoapOption>,
+    pub payload: Vec<u8>,
+}
+
+impl CoapMessage {
+    pub fn option(&self, number: OptionNumber) -> Option<&[u8]> {
+        let number = number as u16;
+        self.options
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, v)| &v[..])
+    }
+
+    pub fn uri_path(&self) -> String {
+        uri_path(&self.options)
+    }
+
+    pub fn add_option(&mut self, number: OptionNumber, value: Vec<u8>) {
+        self.options.push((number as u16, value));
+    }
+}
+
+/// Block2 option value: block number, more flag and size exponent (block size is 2^(szx + 4))
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockOption {
+    pub num: u32,
+    pub more: bool,
+    pub szx: u8,
+}
+
+impl BlockOption {
+    pub fn size(&self) -> u32 {
+        1u32 << (self.szx as u32 + 4)
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.num * self.size()
+    }
+
+    pub fn decode(value: &[u8]) -> Result<Self, CoapCodecError> {
+        if value.len() > 3 {
+            return Err(CoapCodecError::InvalidOption);
+        }
+        let raw = decode_uint(value);
+        let szx = (raw & 0x07) as u8;
+        if szx == 7 {
+            // Reserved (BERT is only defined for reliable transports)
+            return Err(CoapCodecError::InvalidOption);
+        }
+        Ok(BlockOption {
+            num: raw >> 4,
+            more: raw & 0x08 != 0,
+            szx,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let raw = (self.num << 4) | ((self.more as u32) << 3) | (self.szx as u32 & 0x07);
+        encode_uint(raw)
+    }
+}
+
+/// Join all Uri-Path options into a path without leading slash
+pub fn uri_path(options: &[CoapOption]) -> String {
+    let number = OptionNumber::UriPath as u16;
+    options
+        .iter()
+        .filter(|(n, _)| *n == number)
+        .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+pub fn decode_uint(value: &[u8]) -> u32 {
+    value.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32)
+}
+
+/// Encode an unsigned integer option value with the minimum number of bytes
+pub fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+pub fn decode_msg(buf: &[u8]) -> Result<CoapMessage, CoapCodecError> {
+    if buf.len() < 4 {
+        return Err(CoapCodecError::InvalidMessage);
+    }
+    if buf[0] >> 6 != COAP_VERSION {
+        return Err(CoapCodecError::UnsupportedVersion);
+    }
+    let message_type = MessageType::from(buf[0] >> 4);
+    let token_len = (buf[0] & 0x0F) as usize;
+    if token_len > MAX_TOKEN_LEN {
+        return Err(CoapCodecError::InvalidTokenLength);
+    }
+    let code = buf[1];
+    let message_id = u16::from_be_bytes([buf[2], buf[3]]);
+    let mut pos = 4;
+    if buf.len() < pos + token_len {
+        return Err(CoapCodecError::InvalidMessage);
+    }
+    let token = buf[pos..pos + token_len].to_vec();
+    pos += token_len;
+
+    let (options, payload) = decode_options_and_payload(&buf[pos..])?;
+
+    Ok(CoapMessage {
+        message_type,
+        code,
+        message_id,
+        token,
+        options,
+        payload,
+    })
+}
+
+pub fn encode_msg(msg: &CoapMessage) -> Result<Vec<u8>, CoapCodecError> {
+    if msg.token.len() > MAX_TOKEN_LEN {
+        return Err(CoapCodecError::InvalidTokenLength);
+    }
+    let mut buf = Vec::with_capacity(64 + msg.payload.len());
+    buf.push((COAP_VERSION << 6) | ((msg.message_type as u8) << 4) | msg.token.len() as u8);
+    buf.push(msg.code);
+    buf.extend_from_slice(&msg.message_id.to_be_bytes());
+    buf.extend_from_slice(&msg.token);
+
+    buf.extend_from_slice(&encode_options_and_payload(&msg.options, &msg.payload)?);
+    Ok(buf)
+}
+
+/// Decode the option list and payload following the header and token of a message
+pub fn decode_options_and_payload(
+    buf: &[u8],
+) -> Result<(Vec<CoapOption>, Vec<u8>), CoapCodecError> {
+    let mut options = Vec::new();
+    let mut option_number: u16 = 0;
+    let mut payload = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let byte = buf[pos];
+        pos += 1;
+        if byte == PAYLOAD_MARKER {
+            if pos == buf.len() {
+                // A payload marker followed by an empty payload is a format error
+                return Err(CoapCodecError::InvalidMessage);
+            }
+            payload = buf[pos..].to_vec();
+            break;
+        }
+        let delta = decode_option_nibble(byte >> 4, buf, &mut pos)?;
+        let length = decode_option_nibble(byte & 0x0F, buf, &mut pos)? as usize;
+        option_number = option_number
+            .checked_add(delta)
+            .ok_or(CoapCodecError::InvalidOption)?;
+        if buf.len() < pos + length {
+            return Err(CoapCodecError::InvalidOption);
+        }
+        options.push((option_number, buf[pos..pos + length].to_vec()));
+        pos += length;
+    }
+    Ok((options, payload))
+}
+
+pub fn encode_options_and_payload(
+    options: &[CoapOption],
+    payload: &[u8],
+) -> Result<Vec<u8>, CoapCodecError> {
+    let mut buf = Vec::with_capacity(32 + payload.len());
+
+    // Options have to be written in ascending order as they are delta encoded
+    let mut options: Vec<&CoapOption> = options.iter().collect();
+    options.sort_by_key(|(number, _)| *number);
+    let mut last_number: u16 = 0;
+    for (number, value) in options {
+        if value.len() > u16::MAX as usize {
+            return Err(CoapCodecError::InvalidOption);
+        }
+        let (delta_nibble, delta_ext) = encode_option_nibble(number - last_number);
+        let (length_nibble, length_ext) = encode_option_nibble(value.len() as u16);
+        buf.push((delta_nibble << 4) | length_nibble);
+        buf.extend_from_slice(&delta_ext);
+        buf.extend_from_slice(&length_ext);
+        buf.extend_from_slice(value);
+        last_number = *number;
+    }
+
+    if !payload.is_empty() {
+        buf.push(PAYLOAD_MARKER);
+        buf.extend_from_slice(payload);
+    }
+    Ok(buf)
+}
+
+fn decode_option_nibble(nibble: u8, buf: &[u8], pos: &mut usize) -> Result<u16, CoapCodecError> {
+    match nibble {
+        0..=12 => Ok(nibble as u16),
+        13 => {
+            let ext = *buf.get(*pos).ok_or(CoapCodecError::InvalidOption)?;
+            *pos += 1;
+            Ok(ext as u16 + 13)
+        }
+        14 => {
+            let ext = buf
+                .get(*pos..*pos + 2)
+                .ok_or(CoapCodecError::InvalidOption)?;
+            *pos += 2;
+            (u16::from_be_bytes([ext[0], ext[1]]))
+                .checked_add(269)
+                .ok_or(CoapCodecError::InvalidOption)
+        }
+        _ => Err(CoapCodecError::InvalidOption),
+    }
+}
+
+fn encode_option_nibble(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
@@ -8,6 +8,7 @@ pub enum KeyProviderError {
     KeyMismatch,
     KeyNotFound,
     DbError,
+    KeyUnwrapError,
 }
 
 pub trait KeyProvider: Send + Sync {
@@ -18,6 +19,7 @@ pub trait KeyProvider: Send + Sync {
     ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, KeyProviderError>> + Send + 'a>>;
 }
 
+#[derive(Debug)]
 pub enum CoseCodecError {
     MissingHeaderField,
     UnknownHeaderKey,
@@ -41,6 +43,7 @@ enum ProtectedHeaderKey {
     EncryptionNonce = 5,
     DeviceId = 8608,
     Opcode = 8633,
+    SequenceNumber = 8634,
     Unknown = 65535,
 }
 
@@ -52,13 +55,14 @@ impl From<u16> for ProtectedHeaderKey {
             5 => ProtectedHeaderKey::EncryptionNonce,
             8608 => ProtectedHeaderKey::DeviceId,
             8633 => ProtectedHeaderKey::Opcode,
+            8634 => ProtectedHeaderKey::SequenceNumber,
             _ => ProtectedHeaderKey::Unknown,
         }
     }
 }
 
 #[derive(Clone, Copy)]
-enum CoseAlgorithmIdentifier {
+pub enum CoseAlgorithmIdentifier {
     AesGcm128 = 1,
     AsconAead128 = 35,
     Unknown,
@@ -77,6 +81,7 @@ impl From<u16> for CoseAlgorithmIdentifier {
 struct ProtectedHeaderDecode {
     device_id: Option<u32>,
     opcode: Option<u16>,
+    sequence_number: Option<u32>,
     encryption_algorithm: Option<CoseAlgorithmIdentifier>,
     nonce: Option<Vec<u8>>,
 }
@@ -84,6 +89,7 @@ struct ProtectedHeaderDecode {
 struct ProtectedHeader {
     device_id: u32,
     opcode: u16,
+    sequence_number: u32,
     encryption_algorithm: CoseAlgorithmIdentifier,
     nonce: Vec<u8>,
 }
@@ -94,6 +100,7 @@ impl TryFrom<ProtectedHeaderDecode> for ProtectedHeader {
         let ProtectedHeaderDecode {
             device_id: Some(device_id),
             opcode: Some(opcode),
+            sequence_number: Some(sequence_number),
             encryption_algorithm: Some(encryption_algorithm),
             nonce: Some(nonce),
         } = src
@@ -103,6 +110,7 @@ impl TryFrom<ProtectedHeaderDecode> for ProtectedHeader {
         Ok(Self {
             device_id,
             opcode,
+            sequence_number,
             encryption_algorithm,
             nonce,
         })
@@ -140,6 +148,7 @@ pub async fn decode_msg(
     key_type: &mut KeyType,
     device_id: &mut u32,
     opcode: &mut u16,
+    sequence_number: &mut u32,
     msg: &[u8],
 ) -> Result<Vec<u8>, CoseCodecError> {
     let mut decoder = Decoder::new(msg);
@@ -201,6 +210,7 @@ pub async fn decode_msg(
     *key_type = crypto_key_type;
     *device_id = protected_header.device_id;
     *opcode = protected_header.opcode;
+    *sequence_number = protected_header.sequence_number;
     debug!(
         "Decrypted operation with opcode: {}",
         protected_header.opcode
@@ -213,6 +223,7 @@ pub async fn encode_msg(
     key_type: KeyType,
     device_id: u32,
     operation_id: u16,
+    sequence_number: u32,
     operation: &[u8],
 ) -> Result<Vec<u8>, CoseCodecError> {
     let mut buf = Vec::with_capacity(256);
@@ -228,6 +239,7 @@ pub async fn encode_msg(
     let protected_header = ProtectedHeader {
         device_id,
         opcode: operation_id,
+        sequence_number,
         encryption_algorithm: crypto_alg.alg_id().into(),
         nonce: nonce.to_vec(),
     };
@@ -262,19 +274,22 @@ fn encode_protected_header(protected_header: ProtectedHeader) -> Vec<u8> {
     let mut enc = Encoder::new(&mut buf);
 
     // Encoding cannot fail as we are writing to a Vec
-    let _ = enc.map(5);
+    let _ = enc.map(6);
     let _ = enc.u16(ProtectedHeaderKey::EncryptionAlgorithm as u16);
     let _ = enc.u16(protected_header.encryption_algorithm as u16);
     let _ = enc.u16(ProtectedHeaderKey::DeviceId as u16);
     let _ = enc.u32(protected_header.device_id);
     let _ = enc.u16(ProtectedHeaderKey::Opcode as u16);
     let _ = enc.u16(protected_header.opcode);
+    let _ = enc.u16(ProtectedHeaderKey::SequenceNumber as u16);
+    let _ = enc.u32(protected_header.sequence_number);
     let _ = enc.u16(ProtectedHeaderKey::EncryptionNonce as u16);
     let _ = enc.bytes(&protected_header.nonce[..]);
     let _ = enc.u16(ProtectedHeaderKey::CriticalHeaderList as u16);
-    let _ = enc.array(2);
+    let _ = enc.array(3);
     let _ = enc.u16(ProtectedHeaderKey::DeviceId as u16);
     let _ = enc.u16(ProtectedHeaderKey::Opcode as u16);
+    let _ = enc.u16(ProtectedHeaderKey::SequenceNumber as u16);
 
     buf
 }
@@ -289,6 +304,7 @@ fn decode_protected_header(
     let mut header = ProtectedHeaderDecode {
         device_id: None,
         opcode: None,
+        sequence_number: None,
         encryption_algorithm: None,
         nonce: None,
     };
@@ -308,6 +324,7 @@ fn decode_protected_header(
         match ProtectedHeaderKey::from(header_key) {
             ProtectedHeaderKey::DeviceId => header.device_id = Some(decoder.u32()?),
             ProtectedHeaderKey::Opcode => header.opcode = Some(decoder.u16()?),
+            ProtectedHeaderKey::SequenceNumber => header.sequence_number = Some(decoder.u32()?),
             ProtectedHeaderKey::EncryptionAlgorithm => {
                 let alg = decoder.u16()?;
                 header.encryption_algorithm = match CoseAlgorithmIdentifier::from(alg) {
@@ -338,7 +355,9 @@ fn decode_protected_header(
 
                     let header_id = decoder.u16()?;
                     match ProtectedHeaderKey::from(header_id) {
-                        ProtectedHeaderKey::DeviceId | ProtectedHeaderKey::Opcode => {}
+                        ProtectedHeaderKey::DeviceId
+                        | ProtectedHeaderKey::Opcode
+                        | ProtectedHeaderKey::SequenceNumber => {}
                         _ => {
                             return Err(CoseCodecError::UnknownCriticalHeader);
                         }
@@ -1,3 +1,5 @@
+pub mod coap;
 pub mod cose;
 mod crypto;
 pub mod operation;
+pub mod oscore;
@@ -60,6 +60,28 @@ pub fn decode_get_firmware_request(
     firmware_request.try_into()
 }
 
+pub fn decode_get_firmware_response(
+    operation: &[u8],
+) -> Result<GetFirmwareResponse, minicbor::decode::Error> {
+    let mut decoder = minicbor::Decoder::new(operation);
+    if decoder.array()? != Some(4) {
+        return Err(minicbor::decode::Error::message(
+            "Expected firmware response array of length 4",
+        ));
+    }
+    let firmware = decoder.u32()?;
+    let offset = decoder.u32()?;
+    let length = decoder.u32()?;
+    let data = decoder.bytes()?.to_vec();
+
+    Ok(GetFirmwareResponse {
+        firmware,
+        offset,
+        length,
+        data,
+    })
+}
+
 pub fn encode_get_firmware_response(
     firmware_response: &GetFirmwareResponse,
 ) -> Result<Vec<u8>, minicbor::decode::Error> {
@@ -77,3 +99,20 @@ pub fn encode_get_firmware_response(
 
     Ok(inner[..pos].to_vec())
 }
+
+pub fn encode_get_firmware_request(
+    firmware_request: &GetFirmwareRequest,
+) -> Result<Vec<u8>, minicbor::decode::Error> {
+    let mut cursor: minicbor::encode::write::Cursor<[u8; 32]> =
+        minicbor::encode::write::Cursor::new([0u8; 32]);
+    let mut enc = minicbor::Encoder::new(&mut cursor);
+    let _ = enc.array(3);
+    let _ = enc.u32(firmware_request.firmware);
+    let _ = enc.u32(firmware_request.offset);
+    let _ = enc.u32(firmware_request.length);
+
+    let pos = cursor.position();
+    let inner = cursor.into_inner();
+
+    Ok(inner[..pos].to_vec())
+}
@@ -0,0 +1,88 @@
+use log::debug;
+use zeroize::Zeroize;
+
+pub struct GetNextKeyResponse {
+    pub key_id: u32,
+    pub algorithm: u16,
+    pub key: Vec<u8>,
+}
+
+impl Drop for GetNextKeyResponse {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+pub struct AckNextKeyRequestDecode {
+    pub key_id: Option<u32>,
+}
+
+pub struct AckNextKeyRequest {
+    pub key_id: u32,
+}
+
+impl TryFrom<AckNextKeyRequestDecode> for AckNextKeyRequest {
+    type Error = minicbor::decode::Error;
+
+    fn try_from(src: AckNextKeyRequestDecode) -> Result<Self, Self::Error> {
+        let Some(id) = src.key_id else {
+            return Err(minicbor::decode::Error::message("Missing key_id"));
+        };
+        Ok(AckNextKeyRequest { key_id: id })
+    }
+}
+
+pub struct AckNextKeyResponse {
+    pub key_id: u32,
+}
+
+pub fn decode_get_next_key_request(operation: &[u8]) -> Result<(), minicbor::decode::Error> {
+    let mut decoder = minicbor::Decoder::new(operation);
+    debug!("Starting operation decoding");
+    if decoder.array()? != Some(0) {
+        return Err(minicbor::decode::Error::message(
+            "Expected next key request array of length 0",
+        ));
+    }
+    Ok(())
+}
+
+pub fn encode_get_next_key_response(
+    next_key_response: &GetNextKeyResponse,
+) -> Result<Vec<u8>, minicbor::decode::Error> {
+    let mut buf = Vec::with_capacity(64);
+    let mut enc = minicbor::Encoder::new(&mut buf);
+    let _ = enc.array(3);
+    let _ = enc.u32(next_key_response.key_id);
+    let _ = enc.u16(next_key_response.algorithm);
+    let _ = enc.bytes(&next_key_response.key);
+
+    Ok(buf)
+}
+
+pub fn decode_ack_next_key_request(
+    operation: &[u8],
+) -> Result<AckNextKeyRequest, minicbor::decode::Error> {
+    let mut decoder = minicbor::Decoder::new(operation);
+    let mut ack_request = AckNextKeyRequestDecode { key_id: None };
+    debug!("Starting operation decoding");
+    if decoder.array()? != Some(1) {
+        return Err(minicbor::decode::Error::message(
+            "Expected next key acknowledge array of length 1",
+        ));
+    }
+    ack_request.key_id = Some(decoder.u32()?);
+
+    ack_request.try_into()
+}
+
+pub fn encode_ack_next_key_response(
+    ack_response: &AckNextKeyResponse,
+) -> Result<Vec<u8>, minicbor::decode::Error> {
+    let mut buf = Vec::with_capacity(16);
+    let mut enc = minicbor::Encoder::new(&mut buf);
+    let _ = enc.array(1);
+    let _ = enc.u32(ack_response.key_id);
+
+    Ok(buf)
+}
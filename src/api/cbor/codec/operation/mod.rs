@@ -1,9 +1,11 @@
 pub mod device_info;
 pub mod firmware;
+pub mod key;
 pub mod operation_error;
 // ToDo: re-enable parameter module when implementing
 //pub mod parameter;
 
+#[derive(Debug, Clone, Copy)]
 pub enum OperationError {
     InvalidOperation = 0,
     DecodingError = 1,
@@ -12,6 +14,8 @@ pub enum OperationError {
     DeviceNotFound = 4,
     FirmwareNotFound = 5,
     InternalError = 6,
+    ReplayDetected = 7,
+    KeyNotFound = 8,
 }
 
 impl From<u16> for OperationError {
@@ -24,11 +28,14 @@ impl From<u16> for OperationError {
             4 => OperationError::DeviceNotFound,
             5 => OperationError::FirmwareNotFound,
             6 => OperationError::InternalError,
+            7 => OperationError::ReplayDetected,
+            8 => OperationError::KeyNotFound,
             _ => OperationError::InvalidOperation,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy)]
 pub enum OperationType {
     Invalid = 0,
     Error = 1,
@@ -42,6 +49,10 @@ pub enum OperationType {
     SetDeviceInfoResponse = 9,
     GetFirmwareRequest = 10,
     GetFirmwareResponse = 11,
+    GetNextKeyRequest = 12,
+    GetNextKeyResponse = 13,
+    AckNextKeyRequest = 14,
+    AckNextKeyResponse = 15,
 }
 
 impl From<u16> for OperationType {
@@ -58,6 +69,10 @@ impl From<u16> for OperationType {
             9 => OperationType::SetDeviceInfoResponse,
             10 => OperationType::GetFirmwareRequest,
             11 => OperationType::GetFirmwareResponse,
+            12 => OperationType::GetNextKeyRequest,
+            13 => OperationType::GetNextKeyResponse,
+            14 => OperationType::AckNextKeyRequest,
+            15 => OperationType::AckNextKeyResponse,
             _ => OperationType::Invalid,
         }
     }
@@ -0,0 +1,332 @@
+use crate::api::cbor::codec::coap::{self, CoapMessage};
+use crate::api::cbor::codec::cose::{
+    CoseAlgorithmIdentifier, KeyProvider, KeyProviderError, KeyType,
+};
+use crate::api::cbor::codec::crypto;
+use hkdf::Hkdf;
+use log::debug;
+use minicbor::Encoder;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+const OSCORE_VERSION: u8 = 1;
+const SERVER_SENDER_ID: &[u8] = &[];
+const MAX_PARTIAL_IV_LEN: usize = 5;
+
+const FLAG_KID: u8 = 0x08;
+const FLAG_KID_CONTEXT: u8 = 0x10;
+const FLAGS_RESERVED: u8 = 0xE0;
+const FLAGS_PARTIAL_IV_LEN: u8 = 0x07;
+
+#[derive(Debug)]
+pub enum OscoreError {
+    InvalidOption,
+    UnknownSecurityContext,
+    DecryptionError,
+    EncryptionError,
+    InvalidMessage,
+}
+
+/// Derived with the device key as master secret, without master salt and ID context
+struct SecurityContext {
+    algorithm: CoseAlgorithmIdentifier,
+    crypto_alg: Box<dyn crypto::CryptoAead>,
+    sender_key: Vec<u8>,
+    recipient_key: Vec<u8>,
+    common_iv: Vec<u8>,
+}
+
+impl Drop for SecurityContext {
+    fn drop(&mut self) {
+        self.sender_key.zeroize();
+        self.recipient_key.zeroize();
+        self.common_iv.zeroize();
+    }
+}
+
+/// Decrypted OSCORE request with everything needed to protect the matching response
+pub struct OscoreRequest {
+    pub device_id: u32,
+    pub sequence_number: u32,
+    pub code: u8,
+    pub options: Vec<coap::CoapOption>,
+    pub payload: Vec<u8>,
+    kid: Vec<u8>,
+    partial_iv: Vec<u8>,
+    context: SecurityContext,
+}
+
+/// The device id as 4 byte big endian, the server uses the empty Sender ID
+pub fn device_sender_id(device_id: u32) -> Vec<u8> {
+    device_id.to_be_bytes().to_vec()
+}
+
+pub async fn decode_request(
+    key_provider: &mut dyn KeyProvider,
+    msg: &CoapMessage,
+) -> Result<OscoreRequest, OscoreError> {
+    let option = msg
+        .option(coap::OptionNumber::Oscore)
+        .ok_or(OscoreError::InvalidOption)?;
+    let (partial_iv, kid) = decode_option(option)?;
+    let Some(kid) = kid else {
+        debug!("OSCORE request without kid");
+        return Err(OscoreError::InvalidOption);
+    };
+    if partial_iv.is_empty() || kid.len() != 4 {
+        return Err(OscoreError::InvalidOption);
+    }
+    let device_id = u32::from_be_bytes([kid[0], kid[1], kid[2], kid[3]]);
+    let sequence_number = partial_iv
+        .iter()
+        .fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    // Sequence numbers beyond 32 bit are not supported by the replay window
+    let sequence_number = u32::try_from(sequence_number).map_err(|_| OscoreError::InvalidOption)?;
+
+    let context = security_context(key_provider, device_id).await?;
+
+    let nonce = create_nonce(&kid, &partial_iv, &context.common_iv);
+    let aad = create_aad(context.algorithm as u16, &kid, &partial_iv);
+    let plaintext = context
+        .crypto_alg
+        .decrypt(&context.recipient_key, &nonce, &aad, &msg.payload)
+        .map_err(|_| OscoreError::DecryptionError)?;
+
+    let Some((&code, rest)) = plaintext.split_first() else {
+        return Err(OscoreError::InvalidMessage);
+    };
+    let (options, payload) =
+        coap::decode_options_and_payload(rest).map_err(|_| OscoreError::InvalidMessage)?;
+
+    Ok(OscoreRequest {
+        device_id,
+        sequence_number,
+        code,
+        options,
+        payload,
+        kid,
+        partial_iv,
+        context,
+    })
+}
+
+/// Returns the OSCORE option value and the protected payload of the response
+pub fn encode_response(
+    request: &OscoreRequest,
+    code: u8,
+    options: &[coap::CoapOption],
+    payload: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), OscoreError> {
+    let mut plaintext = vec![code];
+    plaintext.extend_from_slice(
+        &coap::encode_options_and_payload(options, payload)
+            .map_err(|_| OscoreError::EncryptionError)?,
+    );
+
+    let context = &request.context;
+    // Responses reuse the request nonce
+    let nonce = create_nonce(&request.kid, &request.partial_iv, &context.common_iv);
+    let aad = create_aad(context.algorithm as u16, &request.kid, &request.partial_iv);
+    let ciphertext = context
+        .crypto_alg
+        .encrypt(&context.sender_key, &nonce, &aad, &plaintext)
+        .map_err(|_| OscoreError::EncryptionError)?;
+    plaintext.zeroize();
+
+    // No Partial IV, no kid: the option value is empty
+    Ok((Vec::new(), ciphertext))
+}
+
+async fn security_context(
+    key_provider: &mut dyn KeyProvider,
+    device_id: u32,
+) -> Result<SecurityContext, OscoreError> {
+    // OSCORE messages do not carry the algorithm, it is part of the stored key
+    for key_type in [KeyType::AesGcm128, KeyType::AsconAead128] {
+        let mut master_secret = match key_provider.key_for_device(device_id, key_type).await {
+            Ok(k) => k,
+            Err(KeyProviderError::KeyMismatch) => continue,
+            Err(_) => return Err(OscoreError::UnknownSecurityContext),
+        };
+        let crypto_alg: Box<dyn crypto::CryptoAead> = match key_type {
+            KeyType::AesGcm128 => Box::new(crypto::crypto_aes::CryptoAes128Gcm),
+            KeyType::AsconAead128 => Box::new(crypto::crypto_ascon::CryptoAsconAead128),
+        };
+        let algorithm = CoseAlgorithmIdentifier::from(crypto_alg.alg_id());
+        let key_len = master_secret.len();
+        let nonce_len = crypto_alg.nonce_len();
+        let device_id_bytes = device_sender_id(device_id);
+
+        let alg = algorithm as u16;
+        let sender_key = derive(&master_secret, SERVER_SENDER_ID, alg, "Key", key_len);
+        let recipient_key = derive(&master_secret, &device_id_bytes, alg, "Key", key_len);
+        let common_iv = derive(&master_secret, &[], alg, "IV", nonce_len);
+        master_secret.zeroize();
+
+        return Ok(SecurityContext {
+            algorithm,
+            crypto_alg,
+            sender_key: sender_key?,
+            recipient_key: recipient_key?,
+            common_iv: common_iv?,
+        });
+    }
+    Err(OscoreError::UnknownSecurityContext)
+}
+
+fn derive(
+    master_secret: &[u8],
+    id: &[u8],
+    algorithm: u16,
+    kind: &str,
+    len: usize,
+) -> Result<Vec<u8>, OscoreError> {
+    let mut info = Vec::with_capacity(32);
+    let mut enc = Encoder::new(&mut info);
+
+    // Encoding cannot fail as we are writing to a Vec
+    let _ = enc.array(5);
+    let _ = enc.bytes(id);
+    let _ = enc.null();
+    let _ = enc.u16(algorithm);
+    let _ = enc.str(kind);
+    let _ = enc.u64(len as u64);
+
+    let mut okm = vec![0u8; len];
+    Hkdf::<Sha256>::new(Some(&[]), master_secret)
+        .expand(&info, &mut okm)
+        .map_err(|_| OscoreError::UnknownSecurityContext)?;
+    Ok(okm)
+}
+
+fn decode_option(option: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), OscoreError> {
+    let Some((&flags, mut rest)) = option.split_first() else {
+        return Ok((Vec::new(), None));
+    };
+    if flags & FLAGS_RESERVED != 0 {
+        return Err(OscoreError::InvalidOption);
+    }
+    let partial_iv_len = (flags & FLAGS_PARTIAL_IV_LEN) as usize;
+    if partial_iv_len > MAX_PARTIAL_IV_LEN || rest.len() < partial_iv_len {
+        return Err(OscoreError::InvalidOption);
+    }
+    let partial_iv = rest[..partial_iv_len].to_vec();
+    rest = &rest[partial_iv_len..];
+    if flags & FLAG_KID_CONTEXT != 0 {
+        // ID contexts are not used, but skip them to reach the kid
+        let Some((&len, tail)) = rest.split_first() else {
+            return Err(OscoreError::InvalidOption);
+        };
+        if tail.len() < len as usize {
+            return Err(OscoreError::InvalidOption);
+        }
+        rest = &tail[len as usize..];
+    }
+    let kid = if flags & FLAG_KID != 0 {
+        Some(rest.to_vec())
+    } else {
+        None
+    };
+    Ok((partial_iv, kid))
+}
+
+fn create_nonce(id_piv: &[u8], partial_iv: &[u8], common_iv: &[u8]) -> Vec<u8> {
+    let nonce_len = common_iv.len();
+    let mut nonce = vec![0u8; nonce_len];
+    nonce[0] = id_piv.len() as u8;
+    let id_end = nonce_len - MAX_PARTIAL_IV_LEN;
+    nonce[id_end - id_piv.len()..id_end].copy_from_slice(id_piv);
+    nonce[nonce_len - partial_iv.len()..].copy_from_slice(partial_iv);
+    for (n, iv) in nonce.iter_mut().zip(common_iv) {
+        *n ^= iv;
+    }
+    nonce
+}
+
+fn create_aad(algorithm: u16, request_kid: &[u8], request_piv: &[u8]) -> Vec<u8> {
+    let mut external_aad = Vec::with_capacity(32);
+    let mut enc = Encoder::new(&mut external_aad);
+
+    // Encoding cannot fail as we are writing to a Vec
+    let _ = enc.array(5);
+    let _ = enc.u8(OSCORE_VERSION);
+    let _ = enc.array(1);
+    let _ = enc.u16(algorithm);
+    let _ = enc.bytes(request_kid);
+    let _ = enc.bytes(request_piv);
+    let _ = enc.bytes(&[][..]);
+
+    let mut buf = Vec::with_capacity(64);
+    let mut enc = Encoder::new(&mut buf);
+    let _ = enc.array(3);
+    let _ = enc.str("Encrypt0");
+    let _ = enc.bytes(&[][..]);
+    let _ = enc.bytes(&external_aad);
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 8613 Appendix C.2, no Master Salt and no ID Context like the contexts used here
+    const MASTER_SECRET: [u8; 16] = [
+        0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+        0x10,
+    ];
+    const AES_CCM_16_64_128: u16 = 10;
+    const CLIENT_ID: &[u8] = &[0x00];
+    const SERVER_ID: &[u8] = &[0x01];
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn derives_rfc8613_c2_context() {
+        let client_key = derive(&MASTER_SECRET, CLIENT_ID, AES_CCM_16_64_128, "Key", 16).unwrap();
+        let server_key = derive(&MASTER_SECRET, SERVER_ID, AES_CCM_16_64_128, "Key", 16).unwrap();
+        let common_iv = derive(&MASTER_SECRET, &[], AES_CCM_16_64_128, "IV", 13).unwrap();
+        assert_eq!(client_key, hex("321b26943253c7ffb6003b0b64d74041"));
+        assert_eq!(server_key, hex("e57b5635815177cd679ab4bcec9d7dda"));
+        assert_eq!(common_iv, hex("be35ae297d2dace910c52e99f9"));
+    }
+
+    #[test]
+    fn creates_rfc8613_c2_nonces() {
+        let common_iv = hex("be35ae297d2dace910c52e99f9");
+        assert_eq!(
+            create_nonce(CLIENT_ID, &[0x00], &common_iv),
+            hex("bf35ae297d2dace910c52e99f9")
+        );
+        assert_eq!(
+            create_nonce(SERVER_ID, &[0x00], &common_iv),
+            hex("bf35ae297d2dace810c52e99f9")
+        );
+    }
+
+    #[test]
+    fn protects_rfc8613_c5_request() {
+        // Request nonce and AAD, the response without Partial IV reuses both
+        let common_iv = hex("be35ae297d2dace910c52e99f9");
+        assert_eq!(
+            create_nonce(CLIENT_ID, &[0x14], &common_iv),
+            hex("bf35ae297d2dace910c52e99ed")
+        );
+        assert_eq!(
+            create_aad(AES_CCM_16_64_128, CLIENT_ID, &[0x14]),
+            hex("8368456e63727970743040498501810a4100411440")
+        );
+    }
+
+    #[test]
+    fn decodes_rfc8613_c5_option() {
+        let (partial_iv, kid) = decode_option(&hex("091400")).unwrap();
+        assert_eq!(partial_iv, vec![0x14]);
+        assert_eq!(kid, Some(CLIENT_ID.to_vec()));
+    }
+}
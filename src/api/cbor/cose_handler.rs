@@ -1,4 +1,6 @@
 use super::codec::cose;
+use super::replay_window;
+use crate::db::key_encryption::KeyEncryptionKey;
 use crate::db::models::{DeviceKey, KeyStatus, LightweightKeyDetails};
 use diesel::ExpressionMethods;
 use diesel::QueryDsl;
@@ -10,16 +12,44 @@ use std::{future::Future, pin::Pin};
 use zeroize::Zeroize;
 
 pub enum CoseHandlerError {
-    DecodingError,
+    DecodingError(cose::CoseCodecError),
     EncodingError,
+    ReplayDetected,
+    InternalError,
 }
 
 #[derive(Clone)]
-struct DbKeyProvider {
+pub struct DbKeyProvider {
     shared_pool: Arc<crate::DbPool>,
+    key_encryption_key: Arc<KeyEncryptionKey>,
+    status: KeyStatus,
+    key_id: Option<i32>,
     key_bytes: Option<Vec<u8>>,
 }
 
+impl DbKeyProvider {
+    pub fn new(shared_pool: Arc<crate::DbPool>, key_encryption_key: Arc<KeyEncryptionKey>) -> Self {
+        DbKeyProvider {
+            shared_pool,
+            key_encryption_key,
+            status: KeyStatus::Active,
+            key_id: None,
+            key_bytes: None,
+        }
+    }
+
+    /// Look up the key with `status` instead of the ACTIVE key
+    pub fn with_status(mut self, status: KeyStatus) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Id of the last key handed out
+    pub fn key_id(&self) -> Option<i32> {
+        self.key_id
+    }
+}
+
 impl cose::KeyProvider for DbKeyProvider {
     fn key_for_device<'a>(
         &'a mut self,
@@ -35,11 +65,11 @@ impl cose::KeyProvider for DbKeyProvider {
                 .await
                 .map_err(|_| cose::KeyProviderError::DbError)?;
 
-            let (active_key, details): (DeviceKey, LightweightKeyDetails) =
+            let (device_key, details): (DeviceKey, LightweightKeyDetails) =
                 device_key_dsl::device_key
                     .inner_join(details_dsl::lightweight_key_details)
                     .filter(device_key_dsl::device.eq(device_id as i32))
-                    .filter(device_key_dsl::status.eq(KeyStatus::Active))
+                    .filter(device_key_dsl::status.eq(self.status))
                     .select((DeviceKey::as_select(), LightweightKeyDetails::as_select()))
                     .first(&mut conn)
                     .await
@@ -53,15 +83,20 @@ impl cose::KeyProvider for DbKeyProvider {
                             cose::KeyProviderError::DbError
                         }
                     })?;
-            if active_key.key_type != crate::db::models::KeyType::Lightweight {
+            self.key_id = Some(device_key.id);
+            if device_key.key_type != crate::db::models::KeyType::Lightweight {
                 warn!("Key type mismatch for device {}", device_id);
                 return Err(cose::KeyProviderError::KeyMismatch);
             }
+            let key = self.key_encryption_key.unwrap(&details).map_err(|e| {
+                warn!("Failed to unwrap key for device {}: {}", device_id, e);
+                cose::KeyProviderError::KeyUnwrapError
+            })?;
             match details.algorithm {
                 crate::db::models::CryptoAlgorithm::AesGcm128 => match key_type {
                     cose::KeyType::AesGcm128 => {
-                        self.key_bytes = details.key.clone().into();
-                        Ok(details.key)
+                        self.key_bytes = key.clone().into();
+                        Ok(key)
                     }
                     _ => {
                         warn!("Key algorithm mismatch for device {}", device_id);
@@ -70,8 +105,8 @@ impl cose::KeyProvider for DbKeyProvider {
                 },
                 crate::db::models::CryptoAlgorithm::AsconAead128 => match key_type {
                     cose::KeyType::AsconAead128 => {
-                        self.key_bytes = details.key.clone().into();
-                        Ok(details.key)
+                        self.key_bytes = key.clone().into();
+                        Ok(key)
                     }
                     _ => {
                         warn!("Key algorithm mismatch for device {}", device_id);
@@ -124,7 +159,9 @@ impl Drop for StaticKeyProvider {
 
 pub struct CoseHandler {
     shared_pool: Arc<crate::DbPool>,
+    key_encryption_key: Arc<KeyEncryptionKey>,
     device_id: Option<u32>,
+    sequence_number: Option<u32>,
     key_bytes: Option<Vec<u8>>,
     key_type: Option<cose::KeyType>,
 }
@@ -138,10 +175,12 @@ impl Drop for CoseHandler {
 }
 
 impl CoseHandler {
-    pub fn new(shared_pool: Arc<crate::DbPool>) -> Self {
+    pub fn new(shared_pool: Arc<crate::DbPool>, key_encryption_key: Arc<KeyEncryptionKey>) -> Self {
         CoseHandler {
             shared_pool,
+            key_encryption_key,
             device_id: None,
+            sequence_number: None,
             key_bytes: None,
             key_type: None,
         }
@@ -153,20 +192,61 @@ impl CoseHandler {
         opcode: &mut u16,
         msg: &[u8],
     ) -> Result<Vec<u8>, CoseHandlerError> {
-        let mut key_provider = Box::new(DbKeyProvider {
-            shared_pool: Arc::clone(&self.shared_pool),
-            key_bytes: None,
-        });
+        let mut key_provider = Box::new(DbKeyProvider::new(
+            Arc::clone(&self.shared_pool),
+            Arc::clone(&self.key_encryption_key),
+        ));
         let mut key_type: cose::KeyType = cose::KeyType::AesGcm128; // Default, will be set by decode_msg
-        let res = cose::decode_msg(key_provider.as_mut(), &mut key_type, device_id, opcode, msg)
-            .await
-            .map_err(|_| CoseHandlerError::DecodingError)?;
+        let mut sequence_number: u32 = 0;
+        let mut next_key = None;
+        let res = match cose::decode_msg(
+            key_provider.as_mut(),
+            &mut key_type,
+            device_id,
+            opcode,
+            &mut sequence_number,
+            msg,
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(cose::CoseCodecError::DecryptionError) => {
+                // During rotation the device may already use the NEXT key
+                *key_provider = DbKeyProvider::new(
+                    Arc::clone(&self.shared_pool),
+                    Arc::clone(&self.key_encryption_key),
+                )
+                .with_status(KeyStatus::Next);
+                let res = cose::decode_msg(
+                    key_provider.as_mut(),
+                    &mut key_type,
+                    device_id,
+                    opcode,
+                    &mut sequence_number,
+                    msg,
+                )
+                .await
+                .map_err(CoseHandlerError::DecodingError)?;
+                next_key = key_provider.key_id();
+                res
+            }
+            Err(e) => return Err(CoseHandlerError::DecodingError(e)),
+        };
         self.device_id = Some(*device_id);
+        self.sequence_number = Some(sequence_number);
         self.key_bytes = match key_provider.key_bytes.clone() {
             Some(k) => Some(k),
-            _ => return Err(CoseHandlerError::DecodingError),
+            _ => return Err(CoseHandlerError::InternalError),
         };
         self.key_type = Some(key_type);
+
+        // Only authenticated messages may advance the replay window
+        replay_window::check_and_store(&self.shared_pool, *device_id, sequence_number, next_key)
+            .await
+            .map_err(|e| match e {
+                replay_window::ReplayCheckError::Replayed => CoseHandlerError::ReplayDetected,
+                replay_window::ReplayCheckError::DbError => CoseHandlerError::InternalError,
+            })?;
         Ok(res)
     }
 
@@ -184,6 +264,9 @@ impl CoseHandler {
         let Some(key_type) = self.key_type else {
             return Err(CoseHandlerError::EncodingError);
         };
+        let Some(sequence_number) = self.sequence_number else {
+            return Err(CoseHandlerError::EncodingError);
+        };
 
         let mut key_provider = Box::new(StaticKeyProvider {
             device_id,
@@ -196,6 +279,7 @@ impl CoseHandler {
             key_type,
             device_id,
             operation_id,
+            sequence_number,
             operation,
         )
         .await
@@ -1,64 +1,171 @@
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::net::UdpSocket;
 use tokio::select;
+use tokio::sync::Semaphore;
 use tokio_util::sync::CancellationToken;
 
+mod coap_handler;
 mod codec;
 mod cose_handler;
 mod operation_handler;
+mod replay_window;
+
+/// Upper bound of `max_concurrent_requests`, which are all acquired at once on shutdown
+pub const MAX_CONCURRENT_REQUESTS: usize = 65536;
 
 #[derive(Clone)]
 pub struct CborApiConfig {
     pub listen_address: SocketAddr,
+    pub coap_listen_address: SocketAddr,
     pub shared_pool: Arc<crate::DbPool>,
+    pub key_encryption_key: Arc<crate::db::key_encryption::KeyEncryptionKey>,
     pub data_storage_location: PathBuf,
+    pub max_concurrent_requests: usize,
+    pub events: crate::api::events::EventBus,
+    pub metrics: crate::api::metrics::Metrics,
 }
 
 pub struct CborApi {
     config: CborApiConfig,
-    joiner: Option<tokio::task::JoinHandle<()>>,
+    joiners: Vec<tokio::task::JoinHandle<()>>,
     cancel: CancellationToken,
+    health: CborApiHealth,
+}
+
+/// Tells whether the UDP loops of `CborApi::start` are still running, used for readiness
+#[derive(Clone, Default)]
+pub struct CborApiHealth {
+    started: Arc<AtomicUsize>,
+    running: Arc<AtomicUsize>,
+}
+
+impl CborApiHealth {
+    pub fn is_running(&self) -> bool {
+        let started = self.started.load(Ordering::SeqCst);
+        started > 0 && self.running.load(Ordering::SeqCst) == started
+    }
+
+    /// Counts a loop as running until the guard is dropped, also if the loop panics
+    fn track(&self) -> RunningGuard {
+        self.started.fetch_add(1, Ordering::SeqCst);
+        self.running.fetch_add(1, Ordering::SeqCst);
+        RunningGuard(self.running.clone())
+    }
+}
+
+struct RunningGuard(Arc<AtomicUsize>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone)]
+enum Transport {
+    Raw,
+    Coap(coap_handler::CoapHandler),
+}
+
+impl Transport {
+    fn name(&self) -> &'static str {
+        match self {
+            Transport::Raw => "raw",
+            Transport::Coap(_) => "coap",
+        }
+    }
 }
 
 impl CborApi {
     pub fn new(config: CborApiConfig) -> Self {
         CborApi {
             config,
-            joiner: None,
+            joiners: Vec::new(),
             cancel: CancellationToken::new(),
+            health: CborApiHealth::default(),
         }
     }
+
+    pub fn health(&self) -> CborApiHealth {
+        self.health.clone()
+    }
+
     pub async fn start(&mut self) {
         let socket = UdpSocket::bind(self.config.listen_address)
             .await
             .expect("Failed to bind UDP socket");
         let cancel = self.cancel.clone();
         let config = self.config.clone();
-        self.joiner = Some(tokio::spawn(async move {
-            udp_loop(socket, config, cancel).await
+        let running = self.health.track();
+        self.joiners.push(tokio::spawn(async move {
+            let _running = running;
+            udp_loop(socket, config, Transport::Raw, cancel).await
         }));
         info!(
             "CBOR listening on {}:{}/UDP",
             self.config.listen_address.ip(),
             self.config.listen_address.port()
         );
+
+        let coap_socket = UdpSocket::bind(self.config.coap_listen_address)
+            .await
+            .expect("Failed to bind CoAP UDP socket");
+        let cancel = self.cancel.clone();
+        let config = self.config.clone();
+        let transport = Transport::Coap(coap_handler::CoapHandler::new(config.clone()));
+        let running = self.health.track();
+        self.joiners.push(tokio::spawn(async move {
+            let _running = running;
+            udp_loop(coap_socket, config, transport, cancel).await
+        }));
+        info!(
+            "CoAP listening on {}:{}/UDP",
+            self.config.coap_listen_address.ip(),
+            self.config.coap_listen_address.port()
+        );
     }
 
     pub async fn shutdown(&mut self) {
         self.cancel.cancel();
-        if self.joiner.is_some() {
-            let handle = self.joiner.take().expect("Failed to take join handle");
+        for handle in self.joiners.drain(..) {
             let _ = handle.await;
         }
     }
 }
 
-async fn udp_loop(socket: UdpSocket, config: CborApiConfig, cancellation_token: CancellationToken) {
+async fn udp_loop(
+    socket: UdpSocket,
+    config: CborApiConfig,
+    transport: Transport,
+    cancellation_token: CancellationToken,
+) {
+    let socket = Arc::new(socket);
+    let max_concurrent_requests = config
+        .max_concurrent_requests
+        .clamp(1, MAX_CONCURRENT_REQUESTS);
+    let limiter = Arc::new(Semaphore::new(max_concurrent_requests));
     let mut buf = [0u8; 2048];
+    let datagrams = config
+        .metrics
+        .cbor_datagrams
+        .with_label_values(&[transport.name()]);
     loop {
+        // Wait for a free slot before reading the next datagram. While all slots are
+        // busy, incoming datagrams queue up in the socket receive buffer (backpressure).
+        let permit = select! {
+            permit = limiter.clone().acquire_owned() => match permit {
+                Ok(p) => p,
+                Err(_) => break,
+            },
+            _ = cancellation_token.cancelled() => {
+                debug!("UDP loop received shutdown; exiting");
+                break;
+            }
+        };
         select! {
             res = socket.recv_from(&mut buf[..]) => {
                 let (len, addr) = match res {
@@ -68,36 +175,18 @@ async fn udp_loop(socket: UdpSocket, config: CborApiConfig, cancellation_token:
                         continue;
                     }
                 };
-                let mut cose_handler = cose_handler::CoseHandler::new(
-                    config.shared_pool.clone(),
-                );
-                let operation_handler = operation_handler::OperationHandler::new(config.clone(), addr);
-                let mut opcode: u16 = 0;
-                let mut device_id: u32 = 0;
-
-                let operation_bytes =
-                    match cose_handler.decode_msg(&mut device_id, &mut opcode, &buf[..len]).await {
-                        Ok(op) => op,
-                        Err(_e) => {
-                            error!("Failed to decode message from {addr}");//: {e}");
-                            continue;
-                        }
-                    };
-
-                let (opcode_response, operation_response) = operation_handler.handle_operation(device_id, opcode, &operation_bytes[..]).await;
-
-                let response_buf = match cose_handler.encode_msg(opcode_response, &operation_response[..]).await {
-                    Ok(b) => b,
-                    Err(_e) => {
-                        error!("Failed to encode COSE response");//: {e}");
-                        continue;
+                datagrams.inc();
+                let msg = buf[..len].to_vec();
+                let socket = Arc::clone(&socket);
+                let config = config.clone();
+                let transport = transport.clone();
+                tokio::spawn(async move {
+                    match transport {
+                        Transport::Raw => handle_datagram(socket, config, addr, msg).await,
+                        Transport::Coap(handler) => handler.handle_datagram(socket, addr, msg).await,
                     }
-                };
-                if let Err(e) = socket.send_to(&response_buf[..], addr).await {
-                    error!("Failed to send to {addr}: {e}");
-                } else {
-                    debug!("Sent response with opcode {opcode_response} to device {device_id} at {addr}");
-                }
+                    drop(permit);
+                });
             }
             _ = cancellation_token.cancelled() => {
                 debug!("UDP loop received shutdown; exiting");
@@ -105,4 +194,66 @@ async fn udp_loop(socket: UdpSocket, config: CborApiConfig, cancellation_token:
             }
         }
     }
+
+    // Wait for in-flight requests to finish before returning
+    let _ = limiter.acquire_many(max_concurrent_requests as u32).await;
+}
+
+async fn handle_datagram(
+    socket: Arc<UdpSocket>,
+    config: CborApiConfig,
+    addr: SocketAddr,
+    msg: Vec<u8>,
+) {
+    let mut cose_handler = cose_handler::CoseHandler::new(
+        config.shared_pool.clone(),
+        config.key_encryption_key.clone(),
+    );
+    let operation_handler = operation_handler::OperationHandler::new(config.clone(), addr);
+    let mut opcode: u16 = 0;
+    let mut device_id: u32 = 0;
+
+    let (opcode_response, operation_response) = match cose_handler
+        .decode_msg(&mut device_id, &mut opcode, &msg[..])
+        .await
+    {
+        Ok(operation_bytes) => {
+            operation_handler
+                .handle_operation(device_id, opcode, &operation_bytes[..])
+                .await
+        }
+        Err(cose_handler::CoseHandlerError::ReplayDetected) => {
+            warn!("Replayed message from device {device_id} at {addr}");
+            operation_handler.handle_replay(device_id, opcode)
+        }
+        Err(cose_handler::CoseHandlerError::DecodingError(e)) => {
+            error!("Failed to decode message from {addr}: {e:?}");
+            config
+                .metrics
+                .cbor_decode_failures
+                .with_label_values(&[format!("{:?}", e)])
+                .inc();
+            return;
+        }
+        Err(_e) => {
+            error!("Failed to decode message from {addr}"); //: {e}");
+            return;
+        }
+    };
+
+    let response_buf = match cose_handler
+        .encode_msg(opcode_response, &operation_response[..])
+        .await
+    {
+        Ok(b) => b,
+        Err(_e) => {
+            error!("Failed to encode COSE response"); //: {e}");
+            return;
+        }
+    };
+    if let Err(e) = socket.send_to(&response_buf[..], addr).await {
+        error!("Failed to send to {addr}: {e}");
+    } else {
+        debug!("Sent response with opcode {opcode_response} to device {device_id} at {addr}");
+    }
 }
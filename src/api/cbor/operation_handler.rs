@@ -1,11 +1,16 @@
 use crate::api::cbor;
+use crate::api::cbor::codec::cose::CoseAlgorithmIdentifier;
 use crate::api::cbor::codec::operation;
-use crate::db::models::{Device, DeviceStatus, Firmware, UpdateDevice};
+use crate::api::events::FleetEventKind;
+use crate::db::models::{
+    CryptoAlgorithm, Device, DeviceKey, DeviceStatus, Firmware, KeyStatus, LightweightKeyDetails,
+    UpdateDevice, WebhookEventType,
+};
 use diesel::ExpressionMethods;
 use diesel::SelectableHelper;
 use diesel::query_dsl::methods::{FilterDsl, FindDsl, SelectDsl};
 use diesel::result::DatabaseErrorKind;
-use diesel_async::RunQueryDsl;
+use diesel_async::{AsyncConnection, RunQueryDsl};
 use log::{error, info, warn};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::{fs, io};
@@ -35,11 +40,62 @@ impl OperationHandler {
         OperationHandler { config, addr }
     }
 
+    /// Handle an authenticated operation, publish it to the live events and record its metrics
     pub async fn handle_operation(
         &self,
         device_id: u32,
         opcode: u16,
         operation: &[u8],
+    ) -> (u16, Vec<u8>) {
+        let opcode_type = operation::OperationType::from(opcode);
+        self.config.events.publish(
+            device_id,
+            FleetEventKind::DeviceContact {
+                operation: format!("{:?}", opcode_type),
+                address: self.addr.to_string(),
+            },
+        );
+        let timer = self
+            .config
+            .metrics
+            .cbor_operation_duration
+            .with_label_values(&[format!("{:?}", opcode_type)])
+            .start_timer();
+        let response = self.dispatch_operation(device_id, opcode, operation).await;
+        timer.observe_duration();
+        if response.0 == operation::OperationType::Error as u16
+            && let Ok(error) = operation::operation_error::decode_operation_error(&response.1)
+        {
+            self.publish_error(device_id, opcode, error);
+        }
+        response
+    }
+
+    /// Error response to a replayed operation
+    pub fn handle_replay(&self, device_id: u32, opcode: u16) -> (u16, Vec<u8>) {
+        self.publish_error(device_id, opcode, operation::OperationError::ReplayDetected);
+        self.handle_error_operation(operation::OperationError::ReplayDetected)
+    }
+
+    fn publish_error(&self, device_id: u32, opcode: u16, error: operation::OperationError) {
+        let operation = format!("{:?}", operation::OperationType::from(opcode));
+        let error = format!("{:?}", error);
+        self.config
+            .metrics
+            .cbor_operation_errors
+            .with_label_values(&[&operation, &error])
+            .inc();
+        self.config.events.publish(
+            device_id,
+            FleetEventKind::OperationError { operation, error },
+        );
+    }
+
+    async fn dispatch_operation(
+        &self,
+        device_id: u32,
+        opcode: u16,
+        operation: &[u8],
     ) -> (u16, Vec<u8>) {
         let opcode_type = operation::OperationType::from(opcode);
         let response_buf: (u16, Vec<u8>);
@@ -169,17 +225,45 @@ impl OperationHandler {
                     name: None,
                     type_: None,
                 };
+                let reported = req.firmware as i32;
 
-                // Perform the insert and return the created row
-                let result: Result<Device, (u16, Vec<u8>)> = match diesel::update(
-                    device.find(device_id as i32),
-                )
-                .set(&payload)
-                .returning(Device::as_returning())
-                .get_result(&mut conn)
-                .await
-                {
-                    Ok(d) => Ok(d),
+                // The report is committed together with its rollout campaign progress and webhook
+                // deliveries. The lock keeps concurrent reports from announcing the same change.
+                let transaction = conn
+                    .transaction::<_, diesel::result::Error, _>(|conn| {
+                        Box::pin(async move {
+                            let previous: (Option<i32>, DeviceStatus) =
+                                diesel::QueryDsl::for_update(
+                                    device.find(device_id as i32).select((firmware, status)),
+                                )
+                                .first(conn)
+                                .await?;
+                            let updated = diesel::update(device.find(device_id as i32))
+                                .set(&payload)
+                                .returning(Device::as_returning())
+                                .get_result(conn)
+                                .await?;
+                            crate::db::rollout::record_report(conn, device_id as i32, reported)
+                                .await?;
+                            // Only a change of the reported firmware is announced to webhooks
+                            if previous.0 != Some(reported) {
+                                let data = serde_json::json!({
+                                    "device": updated,
+                                    "previous_firmware": previous.0,
+                                });
+                                crate::db::webhook::enqueue(
+                                    conn,
+                                    WebhookEventType::DeviceFirmwareReported,
+                                    &data,
+                                )
+                                .await?;
+                            }
+                            Ok((updated, previous))
+                        })
+                    })
+                    .await;
+                let result = match transaction {
+                    Ok(r) => Ok(r),
                     Err(diesel::result::Error::DatabaseError(
                         DatabaseErrorKind::ForeignKeyViolation,
                         info,
@@ -240,7 +324,7 @@ impl OperationHandler {
                         Err(self.handle_error_operation(operation::OperationError::InternalError))
                     }
                 };
-                let result = match result {
+                let (result, (previous_firmware, previous_status)) = match result {
                     Ok(r) => r,
                     Err(b) => return b,
                 };
@@ -254,6 +338,15 @@ impl OperationHandler {
                     "Device {} set its firmware to {} and its status to {:?}",
                     device_id, req.firmware, ds
                 );
+                self.config.events.publish(
+                    device_id,
+                    FleetEventKind::DeviceInfo {
+                        firmware: fw,
+                        previous_firmware,
+                        status: result.status,
+                        previous_status: Some(previous_status),
+                    },
+                );
                 let response = operation::device_info::SetDeviceInfoResponse {
                     firmware: fw as u32,
                     desired_firmware: result.desired_firmware as u32,
@@ -352,12 +445,46 @@ impl OperationHandler {
                     }
                 };
                 buf.truncate(read);
+                self.config
+                    .metrics
+                    .firmware_bytes_served
+                    .with_label_values(&["cbor"])
+                    .inc_by(read as u64);
+                self.config.events.publish(
+                    device_id,
+                    FleetEventKind::DownloadProgress {
+                        firmware: result.id,
+                        offset: req.offset,
+                        length: read as u32,
+                        size: result.size,
+                    },
+                );
 
-                if (read as u32) < req.length {
+                // Reaching the end with a full block must count as well, as a blockwise
+                // client does not ask beyond the last block
+                let end = req.offset as i64 + read as i64;
+                if (read > 0 || req.offset == 0) && end >= result.size {
+                    if let Err(e) =
+                        crate::db::rollout::record_download(&mut conn, device_id as i32, result.id)
+                            .await
+                    {
+                        error!(
+                            "Failed to record firmware download of device {} for rollout campaigns: {}",
+                            device_id, e
+                        );
+                        return self
+                            .handle_error_operation(operation::OperationError::InternalError);
+                    }
                     info!(
                         "Device {} finished downloading firmware {}",
                         device_id, req.firmware
                     );
+                    self.config.events.publish(
+                        device_id,
+                        FleetEventKind::DownloadFinished {
+                            firmware: result.id,
+                        },
+                    );
                 }
 
                 let response = operation::firmware::GetFirmwareResponse {
@@ -376,6 +503,157 @@ impl OperationHandler {
                     }
                 }
             }
+            operation::OperationType::GetNextKeyRequest => {
+                use crate::db::schema::device_key::dsl as key_dsl;
+                use crate::db::schema::lightweight_key_details::dsl as lw_dsl;
+
+                if let Err(e) = operation::key::decode_get_next_key_request(operation) {
+                    error!("Failed to decode operation from {}: {}", self.addr, e);
+                    return self.handle_error_operation(operation::OperationError::DecodingError);
+                }
+
+                let mut conn = match self.config.shared_pool.clone().get_owned().await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("Failed to get DB connection: {}", e);
+                        return self
+                            .handle_error_operation(operation::OperationError::InternalError);
+                    }
+                };
+                let (next_key, details) = match diesel::QueryDsl::inner_join(
+                    key_dsl::device_key,
+                    lw_dsl::lightweight_key_details,
+                )
+                .filter(key_dsl::device.eq(device_id as i32))
+                .filter(key_dsl::status.eq(KeyStatus::Next))
+                .select((DeviceKey::as_select(), LightweightKeyDetails::as_select()))
+                .first::<(DeviceKey, LightweightKeyDetails)>(&mut conn)
+                .await
+                {
+                    Ok(r) => r,
+                    Err(diesel::result::Error::NotFound) => {
+                        warn!("No NEXT key pending for device {}", device_id);
+                        return self.handle_error_operation(operation::OperationError::KeyNotFound);
+                    }
+                    Err(e) => {
+                        error!("Failed to query device key: {}", e);
+                        return self
+                            .handle_error_operation(operation::OperationError::InternalError);
+                    }
+                };
+                let key = match self.config.key_encryption_key.unwrap(&details) {
+                    Ok(k) => k,
+                    Err(e) => {
+                        error!("Failed to unwrap key: {}", e);
+                        return self
+                            .handle_error_operation(operation::OperationError::InternalError);
+                    }
+                };
+
+                if let Err(e) = diesel::update(
+                    key_dsl::device_key
+                        .find(next_key.id)
+                        .filter(key_dsl::delivered_at.is_null()),
+                )
+                .set(key_dsl::delivered_at.eq(Some(chrono::Utc::now().naive_utc())))
+                .execute(&mut conn)
+                .await
+                {
+                    error!("Failed to store key delivery: {}", e);
+                    return self.handle_error_operation(operation::OperationError::InternalError);
+                }
+
+                info!(
+                    "Delivering NEXT key {} to device {}",
+                    next_key.id, device_id
+                );
+                self.config
+                    .events
+                    .publish(device_id, FleetEventKind::KeyDelivered { key: next_key.id });
+                let algorithm = match details.algorithm {
+                    CryptoAlgorithm::AesGcm128 => CoseAlgorithmIdentifier::AesGcm128,
+                    CryptoAlgorithm::AsconAead128 => CoseAlgorithmIdentifier::AsconAead128,
+                };
+                let response = operation::key::GetNextKeyResponse {
+                    key_id: next_key.id as u32,
+                    algorithm: algorithm as u16,
+                    key,
+                };
+
+                response_buf = match operation::key::encode_get_next_key_response(&response) {
+                    Ok(b) => (operation::OperationType::GetNextKeyResponse as u16, b),
+                    Err(e) => {
+                        error!("Failed to encode operation: {e}");
+                        return self
+                            .handle_error_operation(operation::OperationError::EncodingError);
+                    }
+                }
+            }
+            operation::OperationType::AckNextKeyRequest => {
+                use crate::db::schema::device_key::dsl as key_dsl;
+
+                let req = match operation::key::decode_ack_next_key_request(operation) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error!("Failed to decode operation from {}: {}", self.addr, e);
+                        return self
+                            .handle_error_operation(operation::OperationError::DecodingError);
+                    }
+                };
+
+                let mut conn = match self.config.shared_pool.clone().get_owned().await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("Failed to get DB connection: {}", e);
+                        return self
+                            .handle_error_operation(operation::OperationError::InternalError);
+                    }
+                };
+                // A retransmitted acknowledge may arrive after the key was already promoted
+                let updated = match diesel::update(
+                    key_dsl::device_key
+                        .find(req.key_id as i32)
+                        .filter(key_dsl::device.eq(device_id as i32))
+                        .filter(key_dsl::status.eq_any([KeyStatus::Next, KeyStatus::Active]))
+                        .filter(key_dsl::delivered_at.is_not_null()),
+                )
+                .set(key_dsl::acknowledged_at.eq(Some(chrono::Utc::now().naive_utc())))
+                .execute(&mut conn)
+                .await
+                {
+                    Ok(n) => n,
+                    Err(e) => {
+                        error!("Failed to store key acknowledge: {}", e);
+                        return self
+                            .handle_error_operation(operation::OperationError::InternalError);
+                    }
+                };
+                if updated == 0 {
+                    warn!(
+                        "Device {} acknowledged unknown key {}",
+                        device_id, req.key_id
+                    );
+                    return self.handle_error_operation(operation::OperationError::KeyNotFound);
+                }
+
+                info!("Device {} installed key {}", device_id, req.key_id);
+                self.config.events.publish(
+                    device_id,
+                    FleetEventKind::KeyInstalled {
+                        key: req.key_id as i32,
+                    },
+                );
+                let response = operation::key::AckNextKeyResponse { key_id: req.key_id };
+
+                response_buf = match operation::key::encode_ack_next_key_response(&response) {
+                    Ok(b) => (operation::OperationType::AckNextKeyResponse as u16, b),
+                    Err(e) => {
+                        error!("Failed to encode operation: {e}");
+                        return self
+                            .handle_error_operation(operation::OperationError::EncodingError);
+                    }
+                }
+            }
             _ => {
                 error!("Unsupported opcode {} from {}", opcode, self.addr);
                 return self.handle_error_operation(operation::OperationError::InvalidOperation);
@@ -384,7 +662,7 @@ impl OperationHandler {
         response_buf
     }
 
-    fn handle_error_operation(&self, error: operation::OperationError) -> (u16, Vec<u8>) {
+    pub fn handle_error_operation(&self, error: operation::OperationError) -> (u16, Vec<u8>) {
         (
             operation::OperationType::Error as u16,
             operation::operation_error::encode_operation_error(error),
@@ -0,0 +1,248 @@
+use crate::db::audit;
+use crate::db::key_rotation;
+use crate::db::models::{DeviceReplayWindow, NewAuditEvent};
+use diesel::OptionalExtension;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use log::{debug, info, warn};
+
+/// Number of sequence numbers below the highest one that are still accepted.
+pub const REPLAY_WINDOW_SIZE: u32 = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    Duplicate,
+    TooOld,
+}
+
+/// Sliding replay window (RFC 4303 style) over the sequence numbers of one device.
+///
+/// Bit `n` of `bitmap` is set when `highest - n` has already been received.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayWindow {
+    pub highest: u32,
+    pub bitmap: u64,
+}
+
+impl ReplayWindow {
+    /// Window for a device that has not sent any message yet.
+    pub fn first(sequence_number: u32) -> Self {
+        ReplayWindow {
+            highest: sequence_number,
+            bitmap: 1,
+        }
+    }
+
+    pub fn check_and_update(&mut self, sequence_number: u32) -> Result<(), ReplayError> {
+        if sequence_number > self.highest {
+            let shift = sequence_number - self.highest;
+            self.bitmap = if shift >= REPLAY_WINDOW_SIZE {
+                1
+            } else {
+                (self.bitmap << shift) | 1
+            };
+            self.highest = sequence_number;
+            return Ok(());
+        }
+
+        let offset = self.highest - sequence_number;
+        if offset >= REPLAY_WINDOW_SIZE {
+            return Err(ReplayError::TooOld);
+        }
+        let mask = 1u64 << offset;
+        if self.bitmap & mask != 0 {
+            return Err(ReplayError::Duplicate);
+        }
+        self.bitmap |= mask;
+        Ok(())
+    }
+}
+
+pub enum ReplayCheckError {
+    Replayed,
+    DbError,
+}
+
+/// Check the sequence number of an authenticated message against the stored window of
+/// the device and persist the updated window.
+///
+/// `next_key` is the NEXT key the message was decrypted with. The key is promoted to ACTIVE
+/// only after the message passed the check, a key that was not promoted yet has no window.
+pub async fn check_and_store(
+    shared_pool: &crate::DbPool,
+    device_id: u32,
+    sequence_number: u32,
+    next_key: Option<i32>,
+) -> Result<(), ReplayCheckError> {
+    use crate::db::schema::device_replay_window::dsl as window_dsl;
+
+    let mut conn = shared_pool.get().await.map_err(|_| {
+        warn!("Failed to get DB connection for replay check");
+        ReplayCheckError::DbError
+    })?;
+
+    let tx_result: Result<Result<bool, ReplayError>, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                // Serialize concurrent messages of the same device, promote_key takes the
+                // same lock again
+                diesel::dsl::sql_query("SELECT pg_advisory_xact_lock($1)")
+                    .bind::<diesel::sql_types::BigInt, _>(device_id as i64)
+                    .execute(conn)
+                    .await?;
+
+                let promote = match next_key {
+                    Some(key_id) => {
+                        key_rotation::is_next_key(conn, device_id as i32, key_id).await?
+                    }
+                    None => false,
+                };
+                let stored: Option<DeviceReplayWindow> = if promote {
+                    None
+                } else {
+                    window_dsl::device_replay_window
+                        .find(device_id as i32)
+                        .select(DeviceReplayWindow::as_select())
+                        .first(conn)
+                        .await
+                        .optional()?
+                };
+
+                let window = match stored {
+                    Some(row) => {
+                        let mut window = ReplayWindow {
+                            highest: row.highest_sequence_number as u32,
+                            bitmap: row.window_bitmap as u64,
+                        };
+                        if let Err(e) = window.check_and_update(sequence_number) {
+                            return Ok(Err(e));
+                        }
+                        window
+                    }
+                    None => ReplayWindow::first(sequence_number),
+                };
+
+                if let Some(key_id) = next_key.filter(|_| promote) {
+                    key_rotation::promote_key(conn, device_id as i32, key_id).await?;
+                    let actor = format!("device:{}", device_id);
+                    audit::record(
+                        conn,
+                        NewAuditEvent::new(&actor, None, "promote", "device_key", key_id),
+                    )
+                    .await?;
+                }
+
+                let row = DeviceReplayWindow {
+                    device: device_id as i32,
+                    highest_sequence_number: window.highest as i64,
+                    window_bitmap: window.bitmap as i64,
+                };
+                diesel::insert_into(window_dsl::device_replay_window)
+                    .values(&row)
+                    .on_conflict(window_dsl::device)
+                    .do_update()
+                    .set(&row)
+                    .execute(conn)
+                    .await?;
+                Ok(Ok(promote))
+            })
+        })
+        .await;
+
+    match tx_result {
+        Ok(Ok(promoted)) => {
+            if let Some(key_id) = next_key {
+                if promoted {
+                    info!(
+                        "Device {} started using key {}, promoted to ACTIVE",
+                        device_id, key_id
+                    );
+                } else {
+                    debug!("Key {} of device {} already promoted", key_id, device_id);
+                }
+            }
+            Ok(())
+        }
+        Ok(Err(e)) => {
+            warn!(
+                "Rejected message {} from device {}: {:?}",
+                sequence_number, device_id, e
+            );
+            Err(ReplayCheckError::Replayed)
+        }
+        Err(e) => {
+            warn!(
+                "Database error during replay check for device {}: {}",
+                device_id, e
+            );
+            Err(ReplayCheckError::DbError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_in_order_numbers() {
+        let mut window = ReplayWindow::first(1);
+        for sequence_number in 2..200 {
+            assert_eq!(window.check_and_update(sequence_number), Ok(()));
+        }
+        assert_eq!(window.highest, 199);
+        assert_eq!(window.bitmap, u64::MAX);
+    }
+
+    #[test]
+    fn accepts_out_of_order_numbers_inside_window() {
+        let mut window = ReplayWindow::first(100);
+        assert_eq!(window.check_and_update(105), Ok(()));
+        assert_eq!(window.check_and_update(103), Ok(()));
+        assert_eq!(window.check_and_update(101), Ok(()));
+        assert_eq!(window.check_and_update(104), Ok(()));
+        assert_eq!(window.highest, 105);
+        assert_eq!(window.check_and_update(103), Err(ReplayError::Duplicate));
+    }
+
+    #[test]
+    fn rejects_duplicate() {
+        let mut window = ReplayWindow::first(7);
+        assert_eq!(window.check_and_update(7), Err(ReplayError::Duplicate));
+        assert_eq!(window.check_and_update(8), Ok(()));
+        assert_eq!(window.check_and_update(8), Err(ReplayError::Duplicate));
+        assert_eq!(window.check_and_update(7), Err(ReplayError::Duplicate));
+    }
+
+    #[test]
+    fn window_edge() {
+        let mut window = ReplayWindow::first(1000);
+        // The oldest accepted number is REPLAY_WINDOW_SIZE - 1 behind the highest
+        assert_eq!(
+            window.check_and_update(1000 - (REPLAY_WINDOW_SIZE - 1)),
+            Ok(())
+        );
+        assert_eq!(
+            window.check_and_update(1000 - REPLAY_WINDOW_SIZE),
+            Err(ReplayError::TooOld)
+        );
+    }
+
+    #[test]
+    fn jump_beyond_window_resets_bitmap() {
+        let mut window = ReplayWindow::first(10);
+        assert_eq!(window.check_and_update(11), Ok(()));
+        assert_eq!(window.check_and_update(11 + REPLAY_WINDOW_SIZE), Ok(()));
+        assert_eq!(window.bitmap, 1);
+        // Numbers between the old and the new highest are new to the reset window
+        assert_eq!(window.check_and_update(40), Ok(()));
+        assert_eq!(window.check_and_update(11), Err(ReplayError::TooOld));
+
+        let mut window = ReplayWindow::first(0);
+        assert_eq!(window.check_and_update(1000), Ok(()));
+        assert_eq!(window.bitmap, 1);
+        assert_eq!(window.check_and_update(999), Ok(()));
+        assert_eq!(window.check_and_update(1000), Err(ReplayError::Duplicate));
+    }
+}
@@ -0,0 +1,110 @@
+use crate::db::models::DeviceStatus;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// A subscriber falling further behind misses the oldest events
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FleetEvent {
+    pub time: NaiveDateTime,
+    pub device: i32,
+    #[serde(flatten)]
+    pub kind: FleetEventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum FleetEventKind {
+    /// Authenticated operation received from the device
+    DeviceContact {
+        operation: String,
+        address: String,
+    },
+    /// Firmware and status reported with `SetDeviceInfoRequest`
+    DeviceInfo {
+        firmware: i32,
+        previous_firmware: Option<i32>,
+        status: DeviceStatus,
+        previous_status: Option<DeviceStatus>,
+    },
+    /// Chunk of a firmware sent to the device
+    DownloadProgress {
+        firmware: i32,
+        offset: u32,
+        length: u32,
+        size: i64,
+    },
+    DownloadFinished {
+        firmware: i32,
+    },
+    KeyDelivered {
+        key: i32,
+    },
+    KeyInstalled {
+        key: i32,
+    },
+    /// Operation answered with an error
+    OperationError {
+        operation: String,
+        error: String,
+    },
+}
+
+impl FleetEventKind {
+    pub const NAMES: &[&str] = &[
+        "DEVICE_CONTACT",
+        "DEVICE_INFO",
+        "DOWNLOAD_PROGRESS",
+        "DOWNLOAD_FINISHED",
+        "KEY_DELIVERED",
+        "KEY_INSTALLED",
+        "OPERATION_ERROR",
+    ];
+
+    /// Value of `type`, used as SSE event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            FleetEventKind::DeviceContact { .. } => "DEVICE_CONTACT",
+            FleetEventKind::DeviceInfo { .. } => "DEVICE_INFO",
+            FleetEventKind::DownloadProgress { .. } => "DOWNLOAD_PROGRESS",
+            FleetEventKind::DownloadFinished { .. } => "DOWNLOAD_FINISHED",
+            FleetEventKind::KeyDelivered { .. } => "KEY_DELIVERED",
+            FleetEventKind::KeyInstalled { .. } => "KEY_INSTALLED",
+            FleetEventKind::OperationError { .. } => "OPERATION_ERROR",
+        }
+    }
+}
+
+/// Live device activity published by the CBOR API, events are not stored
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<FleetEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        EventBus { sender }
+    }
+
+    /// Dropped if nobody is subscribed
+    pub fn publish(&self, device: u32, kind: FleetEventKind) {
+        let _ = self.sender.send(FleetEvent {
+            time: chrono::Utc::now().naive_utc(),
+            device: device as i32,
+            kind,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<FleetEvent> {
+        self.sender.subscribe()
+    }
+}
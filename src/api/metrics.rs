@@ -0,0 +1,200 @@
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Counter, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::{Arc, Mutex};
+
+/// Prometheus metrics recorded by the CBOR and REST API
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Datagrams read from the CBOR sockets, by `transport`
+    pub cbor_datagrams: IntCounterVec,
+    /// COSE messages that could not be decoded, by `CoseCodecError` variant
+    pub cbor_decode_failures: IntCounterVec,
+    /// Authenticated operations, by `OperationType`
+    pub cbor_operation_duration: HistogramVec,
+    /// Operations answered with an error, by `OperationType` and `OperationError`
+    pub cbor_operation_errors: IntCounterVec,
+    /// Firmware sent to devices and downloaded through the REST API, by `api`
+    pub firmware_bytes_served: IntCounterVec,
+    /// REST requests, by `method`, matched `route` and `status`
+    pub http_request_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new(shared_pool: Arc<crate::DbPool>) -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("firmups".to_string()), None)?;
+        let metrics = Metrics {
+            cbor_datagrams: IntCounterVec::new(
+                Opts::new(
+                    "cbor_datagrams_received_total",
+                    "UDP datagrams received by the CBOR API",
+                ),
+                &["transport"],
+            )?,
+            cbor_decode_failures: IntCounterVec::new(
+                Opts::new(
+                    "cbor_decode_failures_total",
+                    "COSE messages that failed to decode or authenticate",
+                ),
+                &["error"],
+            )?,
+            cbor_operation_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "cbor_operation_duration_seconds",
+                    "Time to handle an authenticated operation",
+                ),
+                &["operation"],
+            )?,
+            cbor_operation_errors: IntCounterVec::new(
+                Opts::new(
+                    "cbor_operation_errors_total",
+                    "Operations answered with an error",
+                ),
+                &["operation", "error"],
+            )?,
+            firmware_bytes_served: IntCounterVec::new(
+                Opts::new("firmware_bytes_served_total", "Firmware bytes sent"),
+                &["api"],
+            )?,
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time until the response headers of a REST request are sent",
+                ),
+                &["method", "route", "status"],
+            )?,
+            registry,
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.cbor_datagrams.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.cbor_decode_failures.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.cbor_operation_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.cbor_operation_errors.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.firmware_bytes_served.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.http_request_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(PoolCollector::new(shared_pool)?))?;
+        Ok(metrics)
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        String::from_utf8(buf).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+/// Reads the state and statistics of the bb8 pool on every scrape
+struct PoolCollector {
+    shared_pool: Arc<crate::DbPool>,
+    /// Counters are set to the totals of the pool, which must not interleave between scrapes
+    lock: Mutex<()>,
+    connections: IntGaugeVec,
+    max_connections: IntGauge,
+    gets: IntCounterVec,
+    get_wait_seconds: Counter,
+    connections_closed: IntCounterVec,
+}
+
+impl PoolCollector {
+    fn new(shared_pool: Arc<crate::DbPool>) -> Result<Self, prometheus::Error> {
+        Ok(PoolCollector {
+            shared_pool,
+            lock: Mutex::new(()),
+            connections: IntGaugeVec::new(
+                Opts::new("db_pool_connections", "Open DB connections"),
+                &["state"],
+            )?,
+            max_connections: IntGauge::new(
+                "db_pool_max_connections",
+                "Maximum number of DB connections",
+            )?,
+            gets: IntCounterVec::new(
+                Opts::new(
+                    "db_pool_gets_total",
+                    "DB connections taken from the pool, by whether they had to wait or timed out",
+                ),
+                &["result"],
+            )?,
+            get_wait_seconds: Counter::new(
+                "db_pool_get_wait_seconds_total",
+                "Time spent waiting for a DB connection",
+            )?,
+            connections_closed: IntCounterVec::new(
+                Opts::new("db_pool_connections_closed_total", "Closed DB connections"),
+                &["reason"],
+            )?,
+        })
+    }
+}
+
+impl Collector for PoolCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = self.connections.desc();
+        descs.extend(self.max_connections.desc());
+        descs.extend(self.gets.desc());
+        descs.extend(self.get_wait_seconds.desc());
+        descs.extend(self.connections_closed.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let state = self.shared_pool.state();
+        let statistics = &state.statistics;
+        self.connections
+            .with_label_values(&["idle"])
+            .set(state.idle_connections as i64);
+        self.connections
+            .with_label_values(&["in_use"])
+            .set(state.connections.saturating_sub(state.idle_connections) as i64);
+        self.max_connections
+            .set(self.shared_pool.config().max_size as i64);
+        for (result, total) in [
+            ("direct", statistics.get_direct),
+            ("waited", statistics.get_waited),
+            ("timed_out", statistics.get_timed_out),
+        ] {
+            let counter = self.gets.with_label_values(&[result]);
+            counter.reset();
+            counter.inc_by(total);
+        }
+        self.get_wait_seconds.reset();
+        self.get_wait_seconds
+            .inc_by(statistics.get_wait_time.as_secs_f64());
+        for (reason, total) in [
+            ("broken", statistics.connections_closed_broken),
+            ("invalid", statistics.connections_closed_invalid),
+            ("max_lifetime", statistics.connections_closed_max_lifetime),
+            ("idle_timeout", statistics.connections_closed_idle_timeout),
+        ] {
+            let counter = self.connections_closed.with_label_values(&[reason]);
+            counter.reset();
+            counter.inc_by(total);
+        }
+
+        let mut families = self.connections.collect();
+        families.extend(self.max_connections.collect());
+        families.extend(self.gets.collect());
+        families.extend(self.get_wait_seconds.collect());
+        families.extend(self.connections_closed.collect());
+        families
+    }
+}
@@ -1,2 +1,4 @@
 pub mod cbor;
+pub mod events;
+pub mod metrics;
 pub mod rest;
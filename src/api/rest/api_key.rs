@@ -0,0 +1,320 @@
+use crate::api::rest;
+use crate::db::audit;
+use crate::db::models::{ApiKey, ApiKeyRole, NewApiKey};
+use crate::db::schema::api_key::dsl as key_dsl;
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::{Method, StatusCode};
+use chrono::NaiveDateTime;
+use diesel::BoolExpressionMethods;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel::result::DatabaseErrorKind;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const KEY_LEN: usize = 32;
+// Avoid a DB write on every request, usage is only tracked with this resolution
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewApiKeyPayload {
+    pub name: String,
+    pub role: ApiKeyRole,
+    #[serde(default)]
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyPayload {
+    pub id: i32,
+    pub name: String,
+    pub role: ApiKeyRole,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    /// Only present in the create response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl From<ApiKey> for ApiKeyPayload {
+    fn from(src: ApiKey) -> Self {
+        Self {
+            id: src.id,
+            name: src.name,
+            role: src.role,
+            created_at: src.created_at,
+            expires_at: src.expires_at,
+            last_used_at: src.last_used_at,
+            revoked_at: src.revoked_at,
+            key: None,
+        }
+    }
+}
+
+/// Caller authenticated by the API key middleware, available as request extension
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    pub role: ApiKeyRole,
+}
+
+/// Random alphanumeric key, uniformly distributed over the characters
+pub fn generate_key() -> String {
+    const CHARSET: &[u8; 62] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                              abcdefghijklmnopqrstuvwxyz\
+                              0123456789";
+    // Bytes from this value on would favour the first characters of the charset
+    const REJECT_FROM: u8 = (256 - 256 % CHARSET.len()) as u8;
+    let mut key = String::with_capacity(KEY_LEN);
+    let mut buf = [0u8; KEY_LEN];
+    while key.len() < KEY_LEN {
+        getrandom::fill(&mut buf).expect("Failed to get random bytes");
+        key.extend(
+            buf.iter()
+                .filter(|&&b| b < REJECT_FROM)
+                .take(KEY_LEN - key.len())
+                .map(|&b| CHARSET[b as usize % CHARSET.len()] as char),
+        );
+    }
+    key
+}
+
+fn hash_key(key: &str) -> Vec<u8> {
+    Sha256::digest(key.as_bytes()).to_vec()
+}
+
+/// Role needed to call `method` on the route `path`
+pub fn required_role(method: &Method, path: &str) -> ApiKeyRole {
+    // Webhooks hold signing secrets and make the server call arbitrary URLs, the audit log
+    // holds snapshots of keys and webhooks
+    if path.starts_with("/api_key") || path.starts_with("/webhook") || path.starts_with("/audit") {
+        return ApiKeyRole::Admin;
+    }
+    if method == Method::GET || method == Method::HEAD {
+        return ApiKeyRole::ReadOnly;
+    }
+    if path.starts_with("/device/{id}/key") || method == Method::DELETE {
+        return ApiKeyRole::Admin;
+    }
+    ApiKeyRole::Operator
+}
+
+/// Look up a valid (not revoked, not expired) key and record its usage
+pub async fn authenticate(
+    conn: &mut AsyncPgConnection,
+    key: &str,
+) -> Result<Option<Principal>, diesel::result::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let api_key: Option<ApiKey> = key_dsl::api_key
+        .filter(key_dsl::key_hash.eq(hash_key(key)))
+        .filter(key_dsl::revoked_at.is_null())
+        .filter(
+            key_dsl::expires_at
+                .is_null()
+                .or(key_dsl::expires_at.gt(now)),
+        )
+        .select(ApiKey::as_select())
+        .first(conn)
+        .await
+        .map(Some)
+        .or_else(|e| match e {
+            diesel::result::Error::NotFound => Ok(None),
+            e => Err(e),
+        })?;
+    let Some(api_key) = api_key else {
+        return Ok(None);
+    };
+
+    let stale = now - chrono::Duration::seconds(LAST_USED_RESOLUTION_SECS);
+    if api_key.last_used_at.is_none_or(|t| t < stale) {
+        diesel::update(key_dsl::api_key.find(api_key.id))
+            .set(key_dsl::last_used_at.eq(now))
+            .execute(conn)
+            .await?;
+    }
+    Ok(Some(Principal {
+        name: api_key.name,
+        role: api_key.role,
+    }))
+}
+
+#[axum::debug_handler]
+pub async fn create_api_key(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Json(payload): Json<NewApiKeyPayload>,
+) -> Result<(StatusCode, Json<ApiKeyPayload>), rest::error::ApiError> {
+    let name_trimmed = payload.name.trim();
+    if name_trimmed.is_empty() || name_trimmed.len() > 100 {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "name must be between 1 and 100 characters".to_string(),
+        ));
+    }
+    if let Some(expires_at) = payload.expires_at
+        && expires_at <= chrono::Utc::now().naive_utc()
+    {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "expires_at has to be in the future".to_string(),
+        ));
+    }
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let key = generate_key();
+    let new_row = NewApiKey {
+        name: name_trimmed.to_string(),
+        key_hash: hash_key(&key),
+        role: payload.role,
+        expires_at: payload.expires_at,
+    };
+    let result: Result<ApiKey, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let created: ApiKey = diesel::insert_into(key_dsl::api_key)
+                    .values(&new_row)
+                    .returning(ApiKey::as_returning())
+                    .get_result(conn)
+                    .await?;
+                // The payload without key, neither the key nor its hash belong in the audit log
+                audit::record(
+                    conn,
+                    actor
+                        .event("create", "api_key", created.id)
+                        .after(&ApiKeyPayload::from(created.clone())),
+                )
+                .await?;
+                Ok(created)
+            })
+        })
+        .await;
+
+    match result {
+        Ok(created) => {
+            info!(
+                "Created API key {} \"{}\" with role {:?}",
+                created.id, created.name, created.role
+            );
+            // The key is only returned here
+            let mut api_key_payload = ApiKeyPayload::from(created);
+            api_key_payload.key = Some(key);
+            Ok((StatusCode::CREATED, Json(api_key_payload)))
+        }
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Err(rest::error::client_error(
+                StatusCode::CONFLICT,
+                format!("API key '{}' already exists", name_trimmed),
+            ))
+        }
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn list_api_keys(
+    State(api_config): State<rest::RestApiConfig>,
+) -> Result<Json<Vec<ApiKeyPayload>>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    let keys: Vec<ApiKey> = key_dsl::api_key
+        .order(key_dsl::id)
+        .select(ApiKey::as_select())
+        .load(&mut conn)
+        .await
+        .map_err(rest::error::internal_error)?;
+    Ok(Json(keys.into_iter().map(ApiKeyPayload::from).collect()))
+}
+
+#[axum::debug_handler]
+pub async fn revoke_api_key(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+) -> Result<Json<ApiKeyPayload>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tx_result: Result<ApiKey, rest::error::TransactionError> = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            Box::pin(async move {
+                let api_key: ApiKey = key_dsl::api_key
+                    .find(path_id)
+                    .select(ApiKey::as_select())
+                    .for_update()
+                    .first(conn)
+                    .await?;
+                if api_key.revoked_at.is_some() {
+                    return Err(rest::error::TransactionError::from(
+                        rest::error::client_error(
+                            StatusCode::CONFLICT,
+                            format!("API key {} is already revoked", path_id),
+                        ),
+                    ));
+                }
+                let revoked: ApiKey = diesel::update(key_dsl::api_key.find(path_id))
+                    .set(key_dsl::revoked_at.eq(chrono::Utc::now().naive_utc()))
+                    .returning(ApiKey::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("revoke", "api_key", path_id)
+                        .before(&ApiKeyPayload::from(api_key))
+                        .after(&ApiKeyPayload::from(revoked.clone())),
+                )
+                .await?;
+                Ok(revoked)
+            })
+        })
+        .await;
+    match tx_result {
+        Ok(revoked) => {
+            info!("Revoked API key {} \"{}\"", revoked.id, revoked.name);
+            Ok(Json(revoked.into()))
+        }
+        Err(rest::error::TransactionError::Db(diesel::result::Error::NotFound)) => {
+            Err(rest::error::client_error(
+                StatusCode::NOT_FOUND,
+                format!("API key {} not found", path_id),
+            ))
+        }
+        Err(rest::error::TransactionError::Db(e)) => Err(rest::error::internal_error(e)),
+        Err(rest::error::TransactionError::Api(api)) => Err(api),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_keys_are_alphanumeric() {
+        for _ in 0..100 {
+            let key = generate_key();
+            assert_eq!(key.len(), KEY_LEN);
+            assert!(key.chars().all(|c| c.is_ascii_alphanumeric()));
+        }
+        assert_ne!(generate_key(), generate_key());
+    }
+}
@@ -0,0 +1,124 @@
+use crate::api::rest;
+use crate::api::rest::api_key::Principal;
+use crate::db::models::{AuditEvent, NewAuditEvent};
+use crate::db::schema;
+use axum::extract::{ConnectInfo, FromRequestParts, Query, State};
+use axum::http::request::Parts;
+use chrono::NaiveDateTime;
+use diesel::pg::Pg;
+use diesel::{ExpressionMethods, QueryDsl};
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+/// Authenticated caller of a request, recorded in the audit events of its mutations
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub name: String,
+    pub source_ip: Option<String>,
+}
+
+impl Actor {
+    pub fn event(&self, action: &str, entity_type: &str, entity_id: i32) -> NewAuditEvent {
+        NewAuditEvent::new(
+            &self.name,
+            self.source_ip.clone(),
+            action,
+            entity_type,
+            entity_id,
+        )
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Actor {
+    type Rejection = rest::error::ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Inserted by the API key middleware for every authenticated request
+        let principal = parts.extensions.get::<Principal>().ok_or_else(|| {
+            rest::error::internal_error(rest::error::FirmupsRestInternalError {
+                message: "Request without principal".to_string(),
+            })
+        })?;
+        let source_ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ci| ci.0.ip().to_string());
+        Ok(Actor {
+            name: principal.name.clone(),
+            source_ip,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub entity_type: Option<String>,
+    pub entity_id: Option<i32>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+}
+
+const AUDIT_SORT_FIELDS: &[&str] = &["id", "created_at", "actor", "action"];
+
+fn filtered_audit_events(filter: &AuditFilter) -> schema::audit_event::BoxedQuery<'static, Pg> {
+    use crate::db::schema::audit_event::dsl::*;
+
+    let mut query = diesel::QueryDsl::into_boxed(audit_event);
+    if let Some(filter_actor) = &filter.actor {
+        query = query.filter(actor.eq(filter_actor.clone()));
+    }
+    if let Some(filter_action) = &filter.action {
+        query = query.filter(action.eq(filter_action.clone()));
+    }
+    if let Some(filter_entity_type) = &filter.entity_type {
+        query = query.filter(entity_type.eq(filter_entity_type.clone()));
+    }
+    if let Some(filter_entity_id) = filter.entity_id {
+        query = query.filter(entity_id.eq(filter_entity_id));
+    }
+    if let Some(since) = filter.since {
+        query = query.filter(created_at.ge(since));
+    }
+    if let Some(until) = filter.until {
+        query = query.filter(created_at.lt(until));
+    }
+    query
+}
+
+#[axum::debug_handler]
+pub async fn list_audit_events(
+    State(api_config): State<rest::RestApiConfig>,
+    Query(mut page): Query<rest::pagination::PageQuery>,
+    Query(filter): Query<AuditFilter>,
+) -> Result<rest::pagination::Paged<AuditEvent>, rest::error::ApiError> {
+    use crate::db::schema::audit_event::dsl::*;
+
+    // Unlike the other lists the audit log is read newest first
+    page.sort.get_or_insert_with(|| "-id".to_string());
+    let page = page.validate(AUDIT_SORT_FIELDS)?;
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || filtered_audit_events(&filter),
+        |query| match (page.sort_field, page.descending) {
+            ("created_at", false) => query.order(created_at.asc()),
+            ("created_at", true) => query.order(created_at.desc()),
+            ("actor", false) => query.order(actor.asc()),
+            ("actor", true) => query.order(actor.desc()),
+            ("action", false) => query.order(action.asc()),
+            ("action", true) => query.order(action.desc()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await
+}
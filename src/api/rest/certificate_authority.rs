@@ -0,0 +1,235 @@
+use crate::api::rest;
+use crate::db::schema::tls_key_details::dsl as tls_dsl;
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel_async::RunQueryDsl;
+use rcgen::{
+    CertificateParams, CertificateRevocationListParams, CertificateSigningRequestParams, DnType,
+    ExtendedKeyUsagePurpose, IsCa, Issuer, KeyIdMethod, KeyPair, KeyUsagePurpose,
+    RevokedCertParams, SerialNumber,
+};
+use thiserror::Error;
+use time::OffsetDateTime;
+use x509_parser::extensions::ParsedExtension;
+
+const SERIAL_LEN: usize = 16;
+const CRL_VALIDITY_DAYS: i64 = 7;
+
+#[derive(Error, Debug)]
+pub enum CertificateAuthorityError {
+    #[error("failed to read {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("invalid certificate signing request: {0}")]
+    InvalidCsr(rcgen::Error),
+    #[error("invalid validity period")]
+    InvalidValidity,
+    #[error("invalid serial number {0}")]
+    InvalidSerial(String),
+    #[error("failed to generate serial number")]
+    Randomness,
+    #[error(transparent)]
+    Rcgen(#[from] rcgen::Error),
+}
+
+/// Internal CA issuing the TLS device certificates
+pub struct CertificateAuthority {
+    issuer: Issuer<'static, KeyPair>,
+    // Subject key identifier of the CA certificate, used as authority key identifier of CRLs
+    key_identifier_method: KeyIdMethod,
+}
+
+/// Certificate issued to a device, the private key is only set if it was generated
+pub struct IssuedCertificate {
+    pub serial: String,
+    pub certificate_pem: String,
+    pub private_key_pem: Option<String>,
+}
+
+impl CertificateAuthority {
+    /// Load the CA certificate and its private key from PEM files
+    pub fn load(cert_path: &str, key_path: &str) -> Result<Self, CertificateAuthorityError> {
+        let cert_pem = std::fs::read_to_string(cert_path)
+            .map_err(|e| CertificateAuthorityError::Io(cert_path.to_string(), e))?;
+        let key_pem = std::fs::read_to_string(key_path)
+            .map_err(|e| CertificateAuthorityError::Io(key_path.to_string(), e))?;
+        let key_pair = KeyPair::from_pem(&key_pem)?;
+        let key_identifier_method = ca_key_identifier(&cert_pem)?;
+        let issuer = Issuer::from_ca_cert_pem(&cert_pem, key_pair)?;
+        Ok(CertificateAuthority {
+            issuer,
+            key_identifier_method,
+        })
+    }
+
+    /// Issue a certificate for the public key of a device CSR.
+    ///
+    /// Only the key is taken from the CSR, subject and extensions are set by the CA.
+    pub fn sign_csr(
+        &self,
+        device_id: i32,
+        csr_pem: &str,
+        valid_from: chrono::NaiveDateTime,
+        valid_to: chrono::NaiveDateTime,
+    ) -> Result<IssuedCertificate, CertificateAuthorityError> {
+        let csr = CertificateSigningRequestParams::from_pem(csr_pem)
+            .map_err(CertificateAuthorityError::InvalidCsr)?;
+        let (params, serial) = device_params(device_id, valid_from, valid_to)?;
+        let cert = params.signed_by(&csr.public_key, &self.issuer)?;
+        Ok(IssuedCertificate {
+            serial,
+            certificate_pem: cert.pem(),
+            private_key_pem: None,
+        })
+    }
+
+    /// Generate a P-256 key pair for the device and issue a certificate for it
+    pub fn generate(
+        &self,
+        device_id: i32,
+        valid_from: chrono::NaiveDateTime,
+        valid_to: chrono::NaiveDateTime,
+    ) -> Result<IssuedCertificate, CertificateAuthorityError> {
+        let key_pair = KeyPair::generate()?;
+        let (params, serial) = device_params(device_id, valid_from, valid_to)?;
+        let cert = params.signed_by(&key_pair, &self.issuer)?;
+        Ok(IssuedCertificate {
+            serial,
+            certificate_pem: cert.pem(),
+            private_key_pem: Some(key_pair.serialize_pem()),
+        })
+    }
+
+    /// Create a CRL of the given `(serial, revocation time)` pairs
+    pub fn crl(
+        &self,
+        revoked: &[(String, chrono::NaiveDateTime)],
+    ) -> Result<String, CertificateAuthorityError> {
+        let now = OffsetDateTime::now_utc();
+        let revoked_certs = revoked
+            .iter()
+            .map(|(serial, revoked_at)| {
+                Ok(RevokedCertParams {
+                    serial_number: parse_serial(serial)?,
+                    revocation_time: to_offset_date_time(*revoked_at)?,
+                    reason_code: None,
+                    invalidity_date: None,
+                })
+            })
+            .collect::<Result<Vec<_>, CertificateAuthorityError>>()?;
+        let params = CertificateRevocationListParams {
+            this_update: now,
+            next_update: now + time::Duration::days(CRL_VALIDITY_DAYS),
+            crl_number: SerialNumber::from(now.unix_timestamp() as u64),
+            issuing_distribution_point: None,
+            revoked_certs,
+            key_identifier_method: self.key_identifier_method.clone(),
+        };
+        Ok(params.signed_by(&self.issuer)?.pem()?)
+    }
+}
+
+fn device_params(
+    device_id: i32,
+    valid_from: chrono::NaiveDateTime,
+    valid_to: chrono::NaiveDateTime,
+) -> Result<(CertificateParams, String), CertificateAuthorityError> {
+    if valid_to <= valid_from {
+        return Err(CertificateAuthorityError::InvalidValidity);
+    }
+    let mut serial = [0u8; SERIAL_LEN];
+    getrandom::fill(&mut serial).map_err(|_| CertificateAuthorityError::Randomness)?;
+    // Serial numbers have to be positive, a non zero first byte keeps the DER encoding minimal
+    serial[0] = (serial[0] & 0x7F).max(1);
+
+    let mut params = CertificateParams::default();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, format!("device-{}", device_id));
+    params.not_before = to_offset_date_time(valid_from)?;
+    params.not_after = to_offset_date_time(valid_to)?;
+    params.serial_number = Some(SerialNumber::from_slice(&serial));
+    params.is_ca = IsCa::ExplicitNoCa;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    params.use_authority_key_identifier_extension = true;
+
+    let serial = serial.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok((params, serial))
+}
+
+/// Key identifier method reproducing the subject key identifier of the CA certificate
+fn ca_key_identifier(cert_pem: &str) -> Result<KeyIdMethod, CertificateAuthorityError> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(cert_pem.as_bytes())
+        .map_err(|_| rcgen::Error::CouldNotParseCertificate)?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|_| rcgen::Error::CouldNotParseCertificate)?;
+    let ski = cert
+        .iter_extensions()
+        .find_map(|ext| match ext.parsed_extension() {
+            ParsedExtension::SubjectKeyIdentifier(key_id) => Some(key_id.0.to_vec()),
+            _ => None,
+        });
+    // rcgen falls back to SHA-256 as well if the CA certificate has no identifier
+    Ok(ski.map_or(KeyIdMethod::Sha256, KeyIdMethod::PreSpecified))
+}
+
+fn parse_serial(serial: &str) -> Result<SerialNumber, CertificateAuthorityError> {
+    let bytes = (0..serial.len())
+        .step_by(2)
+        .map(|i| {
+            serial
+                .get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| CertificateAuthorityError::InvalidSerial(serial.to_string()))?;
+    Ok(SerialNumber::from_slice(&bytes))
+}
+
+fn to_offset_date_time(
+    value: chrono::NaiveDateTime,
+) -> Result<OffsetDateTime, CertificateAuthorityError> {
+    OffsetDateTime::from_unix_timestamp(value.and_utc().timestamp())
+        .map_err(|_| CertificateAuthorityError::InvalidValidity)
+}
+
+/// Returns 503 if no CA is configured
+pub(crate) fn configured(
+    api_config: &rest::RestApiConfig,
+) -> Result<&CertificateAuthority, rest::error::ApiError> {
+    api_config.certificate_authority.as_deref().ok_or_else(|| {
+        rest::error::client_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "No certificate authority configured".to_string(),
+        )
+    })
+}
+
+#[axum::debug_handler]
+pub async fn get_certificate_revocation_list(
+    State(api_config): State<rest::RestApiConfig>,
+) -> Result<impl IntoResponse, rest::error::ApiError> {
+    let ca = configured(&api_config)?;
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    let revoked: Vec<(String, Option<chrono::NaiveDateTime>)> = tls_dsl::tls_key_details
+        .filter(tls_dsl::revoked_at.is_not_null())
+        .select((tls_dsl::serial, tls_dsl::revoked_at))
+        .load(&mut conn)
+        .await
+        .map_err(rest::error::internal_error)?;
+    let revoked: Vec<(String, chrono::NaiveDateTime)> = revoked
+        .into_iter()
+        .filter_map(|(serial, revoked_at)| revoked_at.map(|r| (serial, r)))
+        .collect();
+    let crl = ca.crl(&revoked).map_err(rest::error::internal_error)?;
+    Ok(([(header::CONTENT_TYPE, "application/x-pem-file")], crl))
+}
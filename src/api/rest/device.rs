@@ -1,38 +1,126 @@
 use crate::api::rest;
-use crate::db::models::{Device, NewDevice, UpdateDevice};
+use crate::db::audit;
+use crate::db::models::{Device, DeviceStatus, NewDevice, UpdateDevice};
+use crate::db::schema;
 use axum::Json;
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use diesel::ExpressionMethods;
 use diesel::SelectableHelper;
-use diesel::query_dsl::methods::{FilterDsl, FindDsl, SelectDsl};
+use diesel::TextExpressionMethods;
+use diesel::pg::Pg;
+use diesel::query_dsl::methods::{FilterDsl, FindDsl, OrderDsl, SelectDsl};
 use diesel::result::DatabaseErrorKind;
-use diesel_async::RunQueryDsl;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceFilter {
+    /// Prefix of the name
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub type_: Option<i32>,
+    pub status: Option<DeviceStatus>,
+    pub firmware: Option<i32>,
+    pub desired_firmware: Option<i32>,
+    /// ID of a device group the device is member of
+    pub group: Option<i32>,
+    pub tag: Option<String>,
+}
+
+const DEVICE_SORT_FIELDS: &[&str] = &[
+    "id",
+    "name",
+    "type",
+    "status",
+    "firmware",
+    "desired_firmware",
+];
+
+pub fn filtered_devices(filter: &DeviceFilter) -> schema::device::BoxedQuery<'static, Pg> {
+    use crate::db::schema::device::dsl::*;
+
+    let mut query = diesel::QueryDsl::into_boxed(device);
+    if let Some(prefix) = &filter.name {
+        query = query.filter(name.like(rest::pagination::like_prefix(prefix)));
+    }
+    if let Some(filter_type) = filter.type_ {
+        query = query.filter(type_.eq(filter_type));
+    }
+    if let Some(filter_status) = filter.status {
+        query = query.filter(status.eq(filter_status));
+    }
+    if let Some(filter_firmware) = filter.firmware {
+        query = query.filter(firmware.eq(filter_firmware));
+    }
+    if let Some(filter_desired_firmware) = filter.desired_firmware {
+        query = query.filter(desired_firmware.eq(filter_desired_firmware));
+    }
+    if let Some(filter_group) = filter.group {
+        use crate::db::schema::device_group_device::dsl as member_dsl;
+        query = query.filter(
+            id.eq_any(
+                member_dsl::device_group_device
+                    .filter(member_dsl::device_group.eq(filter_group))
+                    .select(member_dsl::device),
+            ),
+        );
+    }
+    if let Some(filter_tag) = &filter.tag {
+        use crate::db::schema::device_tag::dsl as tag_dsl;
+        query = query.filter(
+            id.eq_any(
+                tag_dsl::device_tag
+                    .filter(tag_dsl::tag.eq(filter_tag.clone()))
+                    .select(tag_dsl::device),
+            ),
+        );
+    }
+    query
+}
 
 #[axum::debug_handler]
 pub async fn list_devices(
     State(api_config): State<rest::RestApiConfig>,
-) -> Result<Json<Vec<Device>>, rest::error::ApiError> {
+    Query(page): Query<rest::pagination::PageQuery>,
+    Query(filter): Query<DeviceFilter>,
+) -> Result<rest::pagination::Paged<Device>, rest::error::ApiError> {
     use crate::db::schema::device::dsl::*;
 
+    let page = page.validate(DEVICE_SORT_FIELDS)?;
     let mut conn = api_config
         .shared_pool
         .clone()
         .get_owned()
         .await
         .map_err(rest::error::internal_error)?;
-    let result = device
-        .select(Device::as_select())
-        .load(&mut conn)
-        .await
-        .map_err(rest::error::internal_error)?;
-
-    Ok(Json(result))
+    rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || filtered_devices(&filter),
+        |query| match (page.sort_field, page.descending) {
+            ("name", false) => query.order(name.asc()),
+            ("name", true) => query.order(name.desc()),
+            ("type", false) => query.order(type_.asc()),
+            ("type", true) => query.order(type_.desc()),
+            ("status", false) => query.order(status.asc()),
+            ("status", true) => query.order(status.desc()),
+            ("firmware", false) => query.order(firmware.asc()),
+            ("firmware", true) => query.order(firmware.desc()),
+            ("desired_firmware", false) => query.order(desired_firmware.asc()),
+            ("desired_firmware", true) => query.order(desired_firmware.desc()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await
 }
 
 #[axum::debug_handler]
 pub async fn create_device(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Json(payload): Json<NewDevice>,
 ) -> Result<(StatusCode, Json<Device>), rest::error::ApiError> {
     use crate::db::schema::device::dsl as device_dsl;
@@ -67,60 +155,68 @@ pub async fn create_device(
     };
 
     // Perform the insert and return the created row
-    let result: Result<(StatusCode, Json<Device>), rest::error::ApiError> =
-        match diesel::insert_into(device_dsl::device)
-            .values(&new_row)
-            .returning(Device::as_returning())
-            .get_result(&mut conn)
-            .await
-        {
-            Ok(device) => Ok((StatusCode::CREATED, Json(device))),
-            Err(diesel::result::Error::DatabaseError(
-                DatabaseErrorKind::ForeignKeyViolation,
-                info,
-            )) => {
-                // Optional: check which constraint failed for more specific messages.
-                match info.constraint_name() {
-                    Some("fk_device_type") => Err(rest::error::client_error(
-                        StatusCode::BAD_REQUEST,
-                        "unknown device type".to_string(),
-                    )),
-                    Some("fk_firmware") => Err(rest::error::client_error(
-                        StatusCode::BAD_REQUEST,
-                        "unknown firmware".to_string(),
-                    )),
-                    Some("fk_desired_firmware") => Err(rest::error::client_error(
-                        StatusCode::BAD_REQUEST,
-                        "unknown desired firmware".to_string(),
-                    )),
-                    Some("fk_device_type_current") => Err(rest::error::client_error(
-                        StatusCode::BAD_REQUEST,
-                        "device type has no link to firmware".to_string(),
-                    )),
-                    Some("fk_device_type_desired") => Err(rest::error::client_error(
-                        StatusCode::BAD_REQUEST,
-                        "device type has no link to desired firmware".to_string(),
-                    )),
-                    _ => {
-                        let error = diesel::result::Error::DatabaseError(
-                            DatabaseErrorKind::ForeignKeyViolation,
-                            info,
-                        );
-                        Err(rest::error::internal_error(error))
-                    }
+    let result: Result<(StatusCode, Json<Device>), rest::error::ApiError> = match conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let created: Device = diesel::insert_into(device_dsl::device)
+                    .values(&new_row)
+                    .returning(Device::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor.event("create", "device", created.id).after(&created),
+                )
+                .await?;
+                Ok(created)
+            })
+        })
+        .await
+    {
+        Ok(device) => Ok((StatusCode::CREATED, Json(device))),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, info)) => {
+            // Optional: check which constraint failed for more specific messages.
+            match info.constraint_name() {
+                Some("fk_device_type") => Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "unknown device type".to_string(),
+                )),
+                Some("fk_firmware") => Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "unknown firmware".to_string(),
+                )),
+                Some("fk_desired_firmware") => Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "unknown desired firmware".to_string(),
+                )),
+                Some("fk_device_type_current") => Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "device type has no link to firmware".to_string(),
+                )),
+                Some("fk_device_type_desired") => Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "device type has no link to desired firmware".to_string(),
+                )),
+                _ => {
+                    let error = diesel::result::Error::DatabaseError(
+                        DatabaseErrorKind::ForeignKeyViolation,
+                        info,
+                    );
+                    Err(rest::error::internal_error(error))
                 }
             }
-            // If you also have uniqueness constraints etc., you can match them too:
-            Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)) => {
-                // e.g., duplicate device name
-                let _detail = info.message(); // or .details()
-                Err(rest::error::client_error(
-                    StatusCode::CONFLICT,
-                    "Device already exists".to_string(),
-                ))
-            }
-            Err(e) => Err(rest::error::internal_error(e)),
-        };
+        }
+        // If you also have uniqueness constraints etc., you can match them too:
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)) => {
+            // e.g., duplicate device name
+            let _detail = info.message(); // or .details()
+            Err(rest::error::client_error(
+                StatusCode::CONFLICT,
+                "Device already exists".to_string(),
+            ))
+        }
+        Err(e) => Err(rest::error::internal_error(e)),
+    };
     result
 }
 
@@ -161,6 +257,7 @@ pub async fn get_device(
 #[axum::debug_handler]
 pub async fn update_device(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Path(path_id): Path<i32>,
     Json(payload): Json<UpdateDevice>,
 ) -> Result<(StatusCode, Json<Device>), rest::error::ApiError> {
@@ -191,71 +288,88 @@ pub async fn update_device(
     };
 
     // Perform the insert and return the created row
-    let result: Result<(StatusCode, Json<Device>), rest::error::ApiError> =
-        match diesel::update(device_dsl::device.find(path_id))
-            .set(&payload)
-            .returning(Device::as_returning())
-            .get_result(&mut conn)
-            .await
-        {
-            Ok(device) => Ok((StatusCode::CREATED, Json(device))),
-            Err(diesel::result::Error::DatabaseError(
-                DatabaseErrorKind::ForeignKeyViolation,
-                info,
-            )) => {
-                // Optional: check which constraint failed for more specific messages.
-                match info.constraint_name() {
-                    Some("fk_device_type") => Err(rest::error::client_error(
-                        StatusCode::BAD_REQUEST,
-                        "unknown device type".to_string(),
-                    )),
-                    Some("fk_firmware") => Err(rest::error::client_error(
-                        StatusCode::BAD_REQUEST,
-                        "unknown firmware".to_string(),
-                    )),
-                    Some("fk_desired_firmware") => Err(rest::error::client_error(
-                        StatusCode::BAD_REQUEST,
-                        "unknown desired firmware".to_string(),
-                    )),
-                    Some("fk_device_type_current") => Err(rest::error::client_error(
-                        StatusCode::BAD_REQUEST,
-                        "device type has no link to firmware".to_string(),
-                    )),
-                    Some("fk_device_type_desired") => Err(rest::error::client_error(
-                        StatusCode::BAD_REQUEST,
-                        "device type has no link to desired firmware".to_string(),
-                    )),
-                    _ => {
-                        let error = diesel::result::Error::DatabaseError(
-                            DatabaseErrorKind::ForeignKeyViolation,
-                            info,
-                        );
-                        Err(rest::error::internal_error(error))
-                    }
+    let result: Result<(StatusCode, Json<Device>), rest::error::ApiError> = match conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let before: Device = diesel::QueryDsl::for_update(
+                    device_dsl::device.find(path_id).select(Device::as_select()),
+                )
+                .first(conn)
+                .await?;
+                let updated: Device = diesel::update(device_dsl::device.find(path_id))
+                    .set(&payload)
+                    .returning(Device::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("update", "device", path_id)
+                        .before(&before)
+                        .after(&updated),
+                )
+                .await?;
+                Ok(updated)
+            })
+        })
+        .await
+    {
+        Ok(device) => Ok((StatusCode::CREATED, Json(device))),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, info)) => {
+            // Optional: check which constraint failed for more specific messages.
+            match info.constraint_name() {
+                Some("fk_device_type") => Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "unknown device type".to_string(),
+                )),
+                Some("fk_firmware") => Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "unknown firmware".to_string(),
+                )),
+                Some("fk_desired_firmware") => Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "unknown desired firmware".to_string(),
+                )),
+                Some("fk_device_type_current") => Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "device type has no link to firmware".to_string(),
+                )),
+                Some("fk_device_type_desired") => Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "device type has no link to desired firmware".to_string(),
+                )),
+                _ => {
+                    let error = diesel::result::Error::DatabaseError(
+                        DatabaseErrorKind::ForeignKeyViolation,
+                        info,
+                    );
+                    Err(rest::error::internal_error(error))
                 }
             }
-            Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)) => {
-                // e.g., duplicate device name
-                let _detail = info.message(); // or .details()
-                Err(rest::error::client_error(
-                    StatusCode::CONFLICT,
-                    "Device already exists".to_string(),
-                ))
-            }
-            Err(diesel::result::Error::NotFound) => {
-                return Err(rest::error::client_error(
-                    axum::http::StatusCode::NOT_FOUND,
-                    format!("device {} not found", path_id),
-                ));
-            }
-            Err(e) => Err(rest::error::internal_error(e)),
-        };
+        }
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)) => {
+            // e.g., duplicate device name
+            let _detail = info.message(); // or .details()
+            Err(rest::error::client_error(
+                StatusCode::CONFLICT,
+                "Device already exists".to_string(),
+            ))
+        }
+        Err(diesel::result::Error::NotFound) => {
+            return Err(rest::error::client_error(
+                axum::http::StatusCode::NOT_FOUND,
+                format!("device {} not found", path_id),
+            ));
+        }
+        Err(e) => Err(rest::error::internal_error(e)),
+    };
     result
 }
 
 #[axum::debug_handler]
 pub async fn delete_device(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Path(path_id): Path<i32>,
 ) -> Result<Json<Device>, rest::error::ApiError> {
     use crate::db::schema::device::dsl::*;
@@ -267,11 +381,18 @@ pub async fn delete_device(
         .await
         .map_err(rest::error::internal_error)?;
 
-    let deleted: Result<Device, diesel::result::Error> =
-        diesel::delete(device.filter(id.eq(path_id)))
-            .returning(Device::as_returning())
-            .get_result(&mut conn)
-            .await;
+    let deleted: Result<Device, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let row: Device = diesel::delete(device.filter(id.eq(path_id)))
+                    .returning(Device::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(conn, actor.event("delete", "device", path_id).before(&row)).await?;
+                Ok(row)
+            })
+        })
+        .await;
 
     match deleted {
         Ok(row) => Ok(Json(row)),
@@ -0,0 +1,636 @@
+use crate::api::rest;
+use crate::api::rest::device::{DeviceFilter, filtered_devices};
+use crate::db::audit;
+use crate::db::models::{
+    Device, DeviceGroup, DeviceGroupDevice, DeviceStatus, NewDeviceGroup, UpdateDevice,
+    UpdateDeviceGroup,
+};
+use crate::db::schema;
+use crate::db::schema::device_group::dsl as group_dsl;
+use crate::db::schema::device_group_device::dsl as member_dsl;
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use diesel::ExpressionMethods;
+use diesel::SelectableHelper;
+use diesel::TextExpressionMethods;
+use diesel::pg::Pg;
+use diesel::query_dsl::methods::{FilterDsl, FindDsl, OrderDsl, SelectDsl};
+use diesel::result::DatabaseErrorKind;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+const DEVICE_GROUP_SORT_FIELDS: &[&str] = &["id", "name"];
+const MEMBER_SORT_FIELDS: &[&str] = &["id", "name"];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceGroupFilter {
+    /// Prefix of the name
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateDeviceGroupPayload {
+    pub name: Option<String>,
+    /// `null` removes the description
+    #[serde(default, deserialize_with = "rest::serde_helpers::double_option")]
+    pub description: Option<Option<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceGroupMembersPayload {
+    pub devices: Vec<i32>,
+}
+
+/// Fields set on every member of a group
+#[derive(Debug, Clone, Deserialize)]
+pub struct BulkDeviceUpdate {
+    pub desired_firmware: Option<i32>,
+    pub status: Option<DeviceStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkUpdateFailure {
+    pub device: i32,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkUpdateResult {
+    /// IDs of the updated devices
+    pub updated: Vec<i32>,
+    /// Members left unchanged
+    pub failed: Vec<BulkUpdateFailure>,
+}
+
+fn validate_name(name: &str) -> Result<(), rest::error::ApiError> {
+    if name.is_empty() || name.len() > 100 {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "name must be between 1 and 100 characters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Empty descriptions are stored as none
+fn normalize_description(
+    description: Option<String>,
+) -> Result<Option<String>, rest::error::ApiError> {
+    let Some(description) = description else {
+        return Ok(None);
+    };
+    let description = description.trim();
+    if description.len() > 1000 {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "description too long (max 1000)".to_string(),
+        ));
+    }
+    Ok(Some(description.to_string()).filter(|d| !d.is_empty()))
+}
+
+fn group_not_found(group_id: i32) -> rest::error::ApiError {
+    rest::error::client_error(
+        StatusCode::NOT_FOUND,
+        format!("device group {} not found", group_id),
+    )
+}
+
+async fn ensure_group_exists(
+    conn: &mut AsyncPgConnection,
+    group_id: i32,
+) -> Result<(), rest::error::TransactionError> {
+    let exists: bool = diesel::select(diesel::dsl::exists(group_dsl::device_group.find(group_id)))
+        .get_result(conn)
+        .await?;
+    if !exists {
+        return Err(group_not_found(group_id).into());
+    }
+    Ok(())
+}
+
+fn filtered_device_groups(
+    filter: &DeviceGroupFilter,
+) -> schema::device_group::BoxedQuery<'static, Pg> {
+    let mut query = diesel::QueryDsl::into_boxed(group_dsl::device_group);
+    if let Some(prefix) = &filter.name {
+        query = query.filter(group_dsl::name.like(rest::pagination::like_prefix(prefix)));
+    }
+    query
+}
+
+#[axum::debug_handler]
+pub async fn create_device_group(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Json(payload): Json<NewDeviceGroup>,
+) -> Result<(StatusCode, Json<DeviceGroup>), rest::error::ApiError> {
+    let name_trimmed = payload.name.trim().to_string();
+    validate_name(&name_trimmed)?;
+    let new_row = NewDeviceGroup {
+        name: name_trimmed.clone(),
+        description: normalize_description(payload.description)?,
+    };
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let result: Result<DeviceGroup, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let created: DeviceGroup = diesel::insert_into(group_dsl::device_group)
+                    .values(&new_row)
+                    .returning(DeviceGroup::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("create", "device_group", created.id)
+                        .after(&created),
+                )
+                .await?;
+                Ok(created)
+            })
+        })
+        .await;
+
+    match result {
+        Ok(created) => Ok((StatusCode::CREATED, Json(created))),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Err(rest::error::client_error(
+                StatusCode::CONFLICT,
+                format!("device group '{}' already exists", name_trimmed),
+            ))
+        }
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn list_device_groups(
+    State(api_config): State<rest::RestApiConfig>,
+    Query(page): Query<rest::pagination::PageQuery>,
+    Query(filter): Query<DeviceGroupFilter>,
+) -> Result<rest::pagination::Paged<DeviceGroup>, rest::error::ApiError> {
+    use crate::db::schema::device_group::dsl::*;
+
+    let page = page.validate(DEVICE_GROUP_SORT_FIELDS)?;
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || filtered_device_groups(&filter),
+        |query| match (page.sort_field, page.descending) {
+            ("name", false) => query.order(name.asc()),
+            ("name", true) => query.order(name.desc()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await
+}
+
+#[axum::debug_handler]
+pub async fn get_device_group(
+    State(api_config): State<rest::RestApiConfig>,
+    Path(path_id): Path<i32>,
+) -> Result<Json<DeviceGroup>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    match group_dsl::device_group
+        .find(path_id)
+        .select(DeviceGroup::as_select())
+        .first(&mut conn)
+        .await
+    {
+        Ok(group) => Ok(Json(group)),
+        Err(diesel::result::Error::NotFound) => Err(group_not_found(path_id)),
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn update_device_group(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+    Json(payload): Json<UpdateDeviceGroupPayload>,
+) -> Result<Json<DeviceGroup>, rest::error::ApiError> {
+    let name = payload.name.map(|n| n.trim().to_string());
+    if let Some(name) = &name {
+        validate_name(name)?;
+    }
+    let changes = UpdateDeviceGroup {
+        name,
+        description: payload.description.map(normalize_description).transpose()?,
+    };
+    if changes.name.is_none() && changes.description.is_none() {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "nothing to update".to_string(),
+        ));
+    }
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let result: Result<DeviceGroup, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let before: DeviceGroup = diesel::QueryDsl::for_update(
+                    group_dsl::device_group
+                        .find(path_id)
+                        .select(DeviceGroup::as_select()),
+                )
+                .first(conn)
+                .await?;
+                let updated: DeviceGroup = diesel::update(group_dsl::device_group.find(path_id))
+                    .set(&changes)
+                    .returning(DeviceGroup::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("update", "device_group", path_id)
+                        .before(&before)
+                        .after(&updated),
+                )
+                .await?;
+                Ok(updated)
+            })
+        })
+        .await;
+
+    match result {
+        Ok(updated) => Ok(Json(updated)),
+        Err(diesel::result::Error::NotFound) => Err(group_not_found(path_id)),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Err(rest::error::client_error(
+                StatusCode::CONFLICT,
+                "device group with this name already exists".to_string(),
+            ))
+        }
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn delete_device_group(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+) -> Result<Json<DeviceGroup>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let deleted: Result<DeviceGroup, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                // Memberships are removed by the cascade, the devices stay
+                let row: DeviceGroup = diesel::delete(group_dsl::device_group.find(path_id))
+                    .returning(DeviceGroup::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor.event("delete", "device_group", path_id).before(&row),
+                )
+                .await?;
+                Ok(row)
+            })
+        })
+        .await;
+
+    match deleted {
+        Ok(row) => Ok(Json(row)),
+        Err(diesel::result::Error::NotFound) => Err(group_not_found(path_id)),
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn list_device_group_devices(
+    State(api_config): State<rest::RestApiConfig>,
+    Path(path_id): Path<i32>,
+    Query(page): Query<rest::pagination::PageQuery>,
+) -> Result<rest::pagination::Paged<Device>, rest::error::ApiError> {
+    use crate::db::schema::device::dsl::*;
+
+    let page = page.validate(MEMBER_SORT_FIELDS)?;
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    ensure_group_exists(&mut conn, path_id)
+        .await
+        .map_err(|e| match e {
+            rest::error::TransactionError::Db(e) => rest::error::internal_error(e),
+            rest::error::TransactionError::Api(api) => api,
+        })?;
+
+    let filter = DeviceFilter {
+        group: Some(path_id),
+        ..Default::default()
+    };
+    rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || filtered_devices(&filter),
+        |query| match (page.sort_field, page.descending) {
+            ("name", false) => query.order(name.asc()),
+            ("name", true) => query.order(name.desc()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await
+}
+
+#[axum::debug_handler]
+pub async fn add_device_group_devices(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+    Json(payload): Json<DeviceGroupMembersPayload>,
+) -> Result<Json<Vec<DeviceGroupDevice>>, rest::error::ApiError> {
+    if payload.devices.is_empty() {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "devices cannot be empty".to_string(),
+        ));
+    }
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tx_result: Result<Vec<DeviceGroupDevice>, rest::error::TransactionError> = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            Box::pin(async move {
+                ensure_group_exists(conn, path_id).await?;
+                let rows: Vec<_> = payload
+                    .devices
+                    .iter()
+                    .map(|device_id| {
+                        (
+                            member_dsl::device_group.eq(path_id),
+                            member_dsl::device.eq(*device_id),
+                        )
+                    })
+                    .collect();
+                // Devices that already are members are skipped
+                let added: Vec<DeviceGroupDevice> =
+                    diesel::insert_into(member_dsl::device_group_device)
+                        .values(&rows)
+                        .on_conflict_do_nothing()
+                        .returning(DeviceGroupDevice::as_returning())
+                        .get_results(conn)
+                        .await?;
+                for member in &added {
+                    audit::record(
+                        conn,
+                        actor
+                            .event("add_device", "device_group", path_id)
+                            .after(member),
+                    )
+                    .await?;
+                }
+                Ok(added)
+            })
+        })
+        .await;
+
+    match tx_result {
+        Ok(added) => Ok(Json(added)),
+        Err(rest::error::TransactionError::Db(diesel::result::Error::DatabaseError(
+            DatabaseErrorKind::ForeignKeyViolation,
+            _,
+        ))) => Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "unknown device".to_string(),
+        )),
+        Err(rest::error::TransactionError::Db(e)) => Err(rest::error::internal_error(e)),
+        Err(rest::error::TransactionError::Api(api)) => Err(api),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn remove_device_group_device(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path((path_id, device_id)): Path<(i32, i32)>,
+) -> Result<Json<DeviceGroupDevice>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let deleted: Result<DeviceGroupDevice, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let row: DeviceGroupDevice = diesel::delete(
+                    member_dsl::device_group_device
+                        .filter(member_dsl::device_group.eq(path_id))
+                        .filter(member_dsl::device.eq(device_id)),
+                )
+                .returning(DeviceGroupDevice::as_returning())
+                .get_result(conn)
+                .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("remove_device", "device_group", path_id)
+                        .before(&row),
+                )
+                .await?;
+                Ok(row)
+            })
+        })
+        .await;
+
+    match deleted {
+        Ok(row) => Ok(Json(row)),
+        Err(diesel::result::Error::NotFound) => Err(rest::error::client_error(
+            StatusCode::NOT_FOUND,
+            format!(
+                "device {} is no member of device group {}",
+                device_id, path_id
+            ),
+        )),
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+/// Reason a member cannot be updated, `None` if the error is not caused by the member
+fn member_error(error: &diesel::result::Error) -> Option<String> {
+    let diesel::result::Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, info) = error
+    else {
+        return None;
+    };
+    match info.constraint_name() {
+        Some("fk_device_type_desired") => {
+            Some("device type has no link to desired firmware".to_string())
+        }
+        _ => None,
+    }
+}
+
+#[axum::debug_handler]
+pub async fn bulk_update_device_group(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+    Json(payload): Json<BulkDeviceUpdate>,
+) -> Result<Json<BulkUpdateResult>, rest::error::ApiError> {
+    use crate::db::schema::device::dsl as device_dsl;
+    use crate::db::schema::firmware::dsl as fw_dsl;
+
+    if payload.desired_firmware.is_none() && payload.status.is_none() {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "nothing to update".to_string(),
+        ));
+    }
+    let changes = UpdateDevice {
+        name: None,
+        type_: None,
+        firmware: None,
+        desired_firmware: payload.desired_firmware,
+        status: payload.status,
+    };
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tx_result: Result<BulkUpdateResult, rest::error::TransactionError> = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            Box::pin(async move {
+                ensure_group_exists(conn, path_id).await?;
+                if let Some(desired_firmware) = payload.desired_firmware {
+                    let exists: bool = diesel::select(diesel::dsl::exists(
+                        fw_dsl::firmware.find(desired_firmware),
+                    ))
+                    .get_result(conn)
+                    .await?;
+                    if !exists {
+                        return Err(rest::error::client_error(
+                            StatusCode::BAD_REQUEST,
+                            "unknown desired firmware".to_string(),
+                        )
+                        .into());
+                    }
+                }
+
+                let members: Vec<Device> = diesel::QueryDsl::for_update(
+                    device_dsl::device
+                        .filter(
+                            device_dsl::id.eq_any(
+                                member_dsl::device_group_device
+                                    .filter(member_dsl::device_group.eq(path_id))
+                                    .select(member_dsl::device),
+                            ),
+                        )
+                        .order(device_dsl::id.asc())
+                        .select(Device::as_select()),
+                )
+                .load(conn)
+                .await?;
+
+                let mut result = BulkUpdateResult {
+                    updated: Vec::new(),
+                    failed: Vec::new(),
+                };
+                for before in members {
+                    // A savepoint per member, so that a violated constraint only skips it
+                    let device_id = before.id;
+                    let changes = changes.clone();
+                    let actor = actor.clone();
+                    let member_result = conn
+                        .transaction::<_, diesel::result::Error, _>(|conn| {
+                            Box::pin(async move {
+                                let updated: Device =
+                                    diesel::update(device_dsl::device.find(device_id))
+                                        .set(&changes)
+                                        .returning(Device::as_returning())
+                                        .get_result(conn)
+                                        .await?;
+                                audit::record(
+                                    conn,
+                                    actor
+                                        .event("update", "device", device_id)
+                                        .before(&before)
+                                        .after(&updated),
+                                )
+                                .await?;
+                                Ok(updated.id)
+                            })
+                        })
+                        .await;
+                    match member_result {
+                        Ok(device_id) => result.updated.push(device_id),
+                        Err(e) => match member_error(&e) {
+                            Some(error) => result.failed.push(BulkUpdateFailure {
+                                device: device_id,
+                                error,
+                            }),
+                            None => return Err(e.into()),
+                        },
+                    }
+                }
+                Ok(result)
+            })
+        })
+        .await;
+
+    match tx_result {
+        Ok(result) => {
+            info!(
+                "Updated {} devices of device group {}, {} failed",
+                result.updated.len(),
+                path_id,
+                result.failed.len()
+            );
+            Ok(Json(result))
+        }
+        Err(rest::error::TransactionError::Db(e)) => Err(rest::error::internal_error(e)),
+        Err(rest::error::TransactionError::Api(api)) => Err(api),
+    }
+}
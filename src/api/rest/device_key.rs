@@ -1,15 +1,20 @@
 use crate::api::rest;
+use crate::api::rest::certificate_authority::{self, CertificateAuthorityError, IssuedCertificate};
+use crate::db::audit;
+use crate::db::key_encryption::{self, KeyEncryptionKey};
+use crate::db::key_rotation;
 use crate::db::models::{
     CryptoAlgorithm, DeviceKey, KeyStatus, KeyType, LightweightKeyDetails,
-    NewLightweightKeyDetails, TlsKeyDetails,
+    NewLightweightKeyDetails, NewTlsKeyDetails, TlsKeyDetails, WebhookEventType,
 };
 use crate::db::schema::device_key::dsl as key_dsl;
 use crate::db::schema::lightweight_key_details::dsl as lw_dsl;
 use crate::db::schema::tls_key_details::dsl as tls_dsl;
 use crate::db::schema::{device_key as dk, lightweight_key_details as lw, tls_key_details as tls};
 use axum::Json;
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
 use diesel::BoolExpressionMethods;
 use diesel::ExpressionMethods;
 use diesel::JoinOnDsl;
@@ -17,8 +22,10 @@ use diesel::NullableExpressionMethods;
 use diesel::QueryDsl;
 use diesel::SelectableHelper;
 use diesel_async::{AsyncConnection, RunQueryDsl};
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use zeroize::Zeroize;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NewDeviceKeyPayload {
@@ -30,6 +37,10 @@ pub struct NewDeviceKeyPayload {
 pub struct DeviceKeyPayload {
     pub id: i32,
     pub status: KeyStatus,
+    /// Time the device fetched the key over the air
+    pub delivered_at: Option<chrono::NaiveDateTime>,
+    /// Time the device confirmed installing the key
+    pub acknowledged_at: Option<chrono::NaiveDateTime>,
     #[serde(flatten)]
     pub kind: DeviceKeyKind,
 }
@@ -39,32 +50,103 @@ pub struct DeviceKeyPayload {
 pub enum NewDeviceKeyKind {
     #[serde(rename = "LIGHTWEIGHT")]
     Lightweight {
-        details: LightweightKeyDetailsPayload,
+        details: NewLightweightKeyDetailsPayload,
     },
     #[serde(rename = "TLS")]
-    Tls { details: TlsKeyDetailsPayload },
+    Tls { details: NewTlsKeyDetailsPayload },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewLightweightKeyDetailsPayload {
+    pub algorithm: CryptoAlgorithm,
+    /// Let the server generate the key instead of supplying it
+    #[serde(default)]
+    pub generate: bool,
+    #[serde(
+        default,
+        serialize_with = "rest::serde_helpers::as_base64_opt",
+        deserialize_with = "rest::serde_helpers::from_base64_opt"
+    )]
+    pub key: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LightweightKeyDetailsPayload {
     pub algorithm: CryptoAlgorithm,
+    /// Hex encoded start of the SHA-256 of the key
+    pub fingerprint: String,
+    /// Only present in the create and export responses
     #[serde(
-        serialize_with = "rest::serde_helpers::as_base64",
-        deserialize_with = "rest::serde_helpers::from_base64"
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "rest::serde_helpers::as_base64_opt",
+        deserialize_with = "rest::serde_helpers::from_base64_opt"
     )]
-    pub key: Vec<u8>,
+    pub key: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewTlsKeyDetailsPayload {
+    pub valid_from: chrono::NaiveDateTime,
+    pub valid_to: chrono::NaiveDateTime,
+    /// Let the server generate the key pair instead of supplying a CSR
+    #[serde(default)]
+    pub generate: bool,
+    /// PEM encoded certificate signing request of the device
+    #[serde(default)]
+    pub csr: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TlsKeyDetailsPayload {
     pub valid_from: chrono::NaiveDateTime,
     pub valid_to: chrono::NaiveDateTime,
+    /// Hex encoded serial number of the certificate
+    pub serial: String,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+    /// Only present in the create response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<String>,
+    /// Only present in the create response if the key pair was generated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+}
+
+impl LightweightKeyDetailsPayload {
+    fn with_key(algorithm: CryptoAlgorithm, key: Vec<u8>) -> Self {
+        Self {
+            algorithm,
+            fingerprint: key_encryption::fingerprint(&key),
+            key: Some(key),
+        }
+    }
+
+    fn unwrap_from(
+        kek: &KeyEncryptionKey,
+        src: &LightweightKeyDetails,
+    ) -> Result<Self, rest::error::ApiError> {
+        let key = kek.unwrap(src).map_err(rest::error::internal_error)?;
+        Ok(Self::with_key(src.algorithm, key))
+    }
+
+    fn redacted(mut self) -> Self {
+        if let Some(key) = &mut self.key {
+            key.zeroize();
+        }
+        self.key = None;
+        self
+    }
 }
 
 impl From<LightweightKeyDetails> for LightweightKeyDetailsPayload {
+    /// Without the key, which is only unwrapped for the export
     fn from(src: LightweightKeyDetails) -> Self {
-        let LightweightKeyDetails { algorithm, key, .. } = src;
-        Self { algorithm, key }
+        Self {
+            algorithm: src.algorithm,
+            // Set when the keys are wrapped at startup for keys stored before it was introduced
+            fingerprint: src.fingerprint.unwrap_or_default(),
+            key: None,
+        }
     }
 }
 
@@ -73,11 +155,40 @@ impl From<TlsKeyDetails> for TlsKeyDetailsPayload {
         let TlsKeyDetails {
             valid_from,
             valid_to,
+            serial,
+            revoked_at,
             ..
         } = src;
         Self {
             valid_from,
             valid_to,
+            serial,
+            revoked_at,
+            certificate: None,
+            private_key: None,
+        }
+    }
+}
+
+impl DeviceKeyPayload {
+    /// Copy without key material and certificate, as recorded in the audit log
+    fn redacted(&self) -> Self {
+        let kind = match self.kind.clone() {
+            DeviceKeyKind::Lightweight { details } => DeviceKeyKind::Lightweight {
+                details: details.redacted(),
+            },
+            DeviceKeyKind::Tls { mut details } => {
+                details.certificate = None;
+                details.private_key = None;
+                DeviceKeyKind::Tls { details }
+            }
+        };
+        Self {
+            id: self.id,
+            status: self.status,
+            delivered_at: self.delivered_at,
+            acknowledged_at: self.acknowledged_at,
+            kind,
         }
     }
 }
@@ -96,6 +207,7 @@ pub enum DeviceKeyKind {
 #[axum::debug_handler]
 pub async fn create_device_key(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Path(device_id): Path<i32>,
     Json(payload): Json<NewDeviceKeyPayload>,
 ) -> Result<(StatusCode, Json<DeviceKeyPayload>), rest::error::ApiError> {
@@ -107,6 +219,11 @@ pub async fn create_device_key(
         }
     };
 
+    let kek = api_config.key_encryption_key.clone();
+    let ca = match payload.kind {
+        NewDeviceKeyKind::Tls { .. } => Some(certificate_authority::configured(&api_config)?),
+        NewDeviceKeyKind::Lightweight { .. } => None,
+    };
     let tx_result: Result<DeviceKeyPayload, rest::error::TransactionError> = conn
         .transaction::<_, rest::error::TransactionError, _>(|mut conn| {
             Box::pin(async move {
@@ -120,40 +237,102 @@ pub async fn create_device_key(
                             .execute(&mut conn)
                             .await?;
 
+                let mut lightweight_key: Option<Vec<u8>> = None;
+                let mut tls_certificate: Option<IssuedCertificate> = None;
                 match payload.kind.clone() {
                     NewDeviceKeyKind::Lightweight { details: det } => {
                         key_type = KeyType::Lightweight;
-                        match det.algorithm {
-                            CryptoAlgorithm::AsconAead128  => {
-                                if det.key.len() != 16 /* ToDo: Replace magic number */ {
-                                    return Err(rest::error::TransactionError::from(
-                                        rest::error::client_error(
-                                            StatusCode::BAD_REQUEST,
-                                            format!(
-                                                "Invalid key length {} for ascon aead128 should be 16",
-                                                det.key.len()
-                                            ),
+                        let key_len = det.algorithm.key_len();
+                        let key = match (det.generate, det.key) {
+                            (true, None) => {
+                                let mut key = vec![0u8; key_len];
+                                getrandom::fill(&mut key[..]).map_err(|e| {
+                                    rest::error::TransactionError::from(
+                                        rest::error::internal_error(
+                                            rest::error::FirmupsRestInternalError {
+                                                message: format!("Failed to generate key: {}", e),
+                                            },
                                         ),
-                                    ));
-                                };
+                                    )
+                                })?;
+                                key
                             }
-                            CryptoAlgorithm::AesGcm128 => {
-                                if det.key.len() != 12 /* ToDo: Replace magic number */ {
-                                    return Err(rest::error::TransactionError::from(
-                                        rest::error::client_error(
-                                            StatusCode::BAD_REQUEST,
-                                            format!(
-                                                "Invalid key length {} for aes gcm128 should be 12",
-                                                det.key.len()
-                                            ),
-                                        ),
-                                    ));
-                                };
+                            (false, Some(key)) => key,
+                            (true, Some(_)) => {
+                                return Err(rest::error::TransactionError::from(
+                                    rest::error::client_error(
+                                        StatusCode::BAD_REQUEST,
+                                        "key must not be supplied when generate is set".to_string(),
+                                    ),
+                                ));
                             }
-                        }
+                            (false, None) => {
+                                return Err(rest::error::TransactionError::from(
+                                    rest::error::client_error(
+                                        StatusCode::BAD_REQUEST,
+                                        "key required unless generate is set".to_string(),
+                                    ),
+                                ));
+                            }
+                        };
+                        if key.len() != key_len {
+                            return Err(rest::error::TransactionError::from(
+                                rest::error::client_error(
+                                    StatusCode::BAD_REQUEST,
+                                    format!(
+                                        "Invalid key length {} for {:?} should be {}",
+                                        key.len(),
+                                        det.algorithm,
+                                        key_len
+                                    ),
+                                ),
+                            ));
+                        };
+                        lightweight_key = Some(key);
                     }
-                    NewDeviceKeyKind::Tls { details: _ } => {
+                    NewDeviceKeyKind::Tls { details: det } => {
                         key_type = KeyType::Tls;
+                        let Some(ca) = ca else {
+                            return Err(rest::error::TransactionError::from(
+                                rest::error::internal_error(
+                                    rest::error::FirmupsRestInternalError {
+                                        message: "No certificate authority configured".to_string(),
+                                    },
+                                ),
+                            ));
+                        };
+                        let issued = match (det.generate, det.csr) {
+                            (true, None) => ca.generate(device_id, det.valid_from, det.valid_to),
+                            (false, Some(csr)) => {
+                                ca.sign_csr(device_id, &csr, det.valid_from, det.valid_to)
+                            }
+                            (true, Some(_)) => {
+                                return Err(rest::error::TransactionError::from(
+                                    rest::error::client_error(
+                                        StatusCode::BAD_REQUEST,
+                                        "csr must not be supplied when generate is set".to_string(),
+                                    ),
+                                ));
+                            }
+                            (false, None) => {
+                                return Err(rest::error::TransactionError::from(
+                                    rest::error::client_error(
+                                        StatusCode::BAD_REQUEST,
+                                        "csr required unless generate is set".to_string(),
+                                    ),
+                                ));
+                            }
+                        };
+                        tls_certificate = Some(issued.map_err(|e| match e {
+                            CertificateAuthorityError::InvalidCsr(_)
+                            | CertificateAuthorityError::InvalidValidity => {
+                                rest::error::TransactionError::from(rest::error::client_error(
+                                    StatusCode::BAD_REQUEST,
+                                    e.to_string(),
+                                ))
+                            }
+                            _ => rest::error::TransactionError::from(rest::error::internal_error(e)),
+                        })?);
                     }
                 }
 
@@ -201,38 +380,83 @@ pub async fn create_device_key(
 
                 match payload.kind.clone() {
                     NewDeviceKeyKind::Lightweight { details } => {
+                        let key = lightweight_key.take().unwrap_or_default();
+                        let wrapped = kek.wrap(device_key.id, &key).map_err(|e| {
+                            rest::error::TransactionError::from(rest::error::internal_error(e))
+                        })?;
                         let to_insert = NewLightweightKeyDetails {
                             device_key: device_key.id,
                             algorithm: details.algorithm,
-                            key: details.key,
+                            key: wrapped,
+                            kek_id: Some(kek.id().to_vec()),
+                            fingerprint: Some(key_encryption::fingerprint(&key)),
                         };
                         let insert = diesel::insert_into(lw_dsl::lightweight_key_details)
                             .values(&to_insert)
                             .returning(LightweightKeyDetails::as_returning())
                             .get_result(&mut conn)
                             .await?;
+                        // The plaintext key is only returned in the create response
                         kind = DeviceKeyKind::Lightweight {
-                            details: LightweightKeyDetailsPayload {
-                                algorithm: insert.algorithm,
-                                key: insert.key,
-                            },
+                            details: LightweightKeyDetailsPayload::with_key(insert.algorithm, key),
                         };
                     }
-                    NewDeviceKeyKind::Tls { details: _ } => {
-                        return Err(rest::error::TransactionError::from(
-                            rest::error::client_error(
-                                StatusCode::CONFLICT,
-                                "TLS key functionality not yet implemented".to_string(),
-                            ),
-                        ));
+                    NewDeviceKeyKind::Tls { details } => {
+                        let Some(issued) = tls_certificate.take() else {
+                            return Err(rest::error::TransactionError::from(
+                                rest::error::internal_error(rest::error::FirmupsRestInternalError {
+                                    message: "No certificate issued for TLS key".to_string(),
+                                }),
+                            ));
+                        };
+                        let to_insert = NewTlsKeyDetails {
+                            device_key: device_key.id,
+                            valid_from: details.valid_from,
+                            valid_to: details.valid_to,
+                            serial: issued.serial,
+                            certificate: issued.certificate_pem,
+                        };
+                        let insert = diesel::insert_into(tls_dsl::tls_key_details)
+                            .values(&to_insert)
+                            .returning(TlsKeyDetails::as_returning())
+                            .get_result(&mut conn)
+                            .await?;
+                        info!(
+                            "Issued certificate {} for key {} of device {}",
+                            insert.serial, device_key.id, device_id
+                        );
+                        // The certificate and a generated private key are only returned here
+                        let certificate = insert.certificate.clone();
+                        let mut tls_details: TlsKeyDetailsPayload = insert.into();
+                        tls_details.certificate = Some(certificate);
+                        tls_details.private_key = issued.private_key_pem;
+                        kind = DeviceKeyKind::Tls {
+                            details: tls_details,
+                        };
                     }
                 }
 
-                Ok(DeviceKeyPayload {
+                let created = DeviceKeyPayload {
                     id: device_key.id,
                     status: device_key.status,
+                    delivered_at: device_key.delivered_at,
+                    acknowledged_at: device_key.acknowledged_at,
                     kind,
-                })
+                };
+                audit::record(
+                    conn,
+                    actor
+                        .event("create", "device_key", created.id)
+                        .after(&created.redacted()),
+                )
+                .await?;
+                crate::db::webhook::enqueue(
+                    conn,
+                    WebhookEventType::DeviceKeyCreated,
+                    &serde_json::json!({ "device": device_id, "key": created.redacted() }),
+                )
+                .await?;
+                Ok(created)
             })
         })
         .await;
@@ -329,6 +553,8 @@ pub async fn list_device_keys(
         res.push(DeviceKeyPayload {
             id: key.id,
             status: key.status,
+            delivered_at: key.delivered_at,
+            acknowledged_at: key.acknowledged_at,
             kind,
         });
     }
@@ -395,6 +621,8 @@ pub async fn get_device_key(
             Ok(Json(DeviceKeyPayload {
                 id: key.id,
                 status: key.status,
+                delivered_at: key.delivered_at,
+                acknowledged_at: key.acknowledged_at,
                 kind,
             }))
         }
@@ -409,6 +637,7 @@ pub async fn get_device_key(
 #[axum::debug_handler]
 pub async fn delete_device_key(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Path((device_id, path_id)): Path<(i32, i32)>,
 ) -> Result<Json<DeviceKeyPayload>, rest::error::ApiError> {
     let mut conn = api_config
@@ -467,6 +696,18 @@ pub async fn delete_device_key(
                         details: lw_details.into(),
                     };
                 } else if let Some(tls_details) = tls_opt {
+                    // Dropping the row would also drop the certificate from the CRL
+                    if tls_details.valid_to > chrono::Utc::now().naive_utc() {
+                        return Err(rest::error::TransactionError::from(
+                            rest::error::client_error(
+                                StatusCode::CONFLICT,
+                                format!(
+                                    "Certificate of key {} is valid until {}, it cannot be deleted before",
+                                    path_id, tls_details.valid_to
+                                ),
+                            ),
+                        ));
+                    }
                     kind = DeviceKeyKind::Tls {
                         details: tls_details.into(),
                     };
@@ -484,11 +725,25 @@ pub async fn delete_device_key(
                         .get_result(&mut conn)
                         .await?;
 
-                Ok(DeviceKeyPayload {
+                let deleted = DeviceKeyPayload {
                     id: key.id,
                     status: key.status,
+                    delivered_at: key.delivered_at,
+                    acknowledged_at: key.acknowledged_at,
                     kind,
-                })
+                };
+                audit::record(
+                    conn,
+                    actor.event("delete", "device_key", path_id).before(&deleted),
+                )
+                .await?;
+                crate::db::webhook::enqueue(
+                    conn,
+                    WebhookEventType::DeviceKeyDeleted,
+                    &serde_json::json!({ "device": device_id, "key": deleted }),
+                )
+                .await?;
+                Ok(deleted)
             })
         })
         .await;
@@ -504,3 +759,378 @@ pub async fn delete_device_key(
         Err(rest::error::TransactionError::Api(api)) => Err(api),
     }
 }
+
+#[axum::debug_handler]
+pub async fn export_device_key(
+    State(api_config): State<rest::RestApiConfig>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    actor: rest::audit::Actor,
+    Path((device_id, path_id)): Path<(i32, i32)>,
+) -> Result<impl IntoResponse, rest::error::ApiError> {
+    if !api_config.key_export_enabled {
+        return Err(rest::error::client_error(
+            StatusCode::FORBIDDEN,
+            "Key export is disabled".to_string(),
+        ));
+    }
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    let result: Result<(DeviceKey, LightweightKeyDetails), diesel::result::Error> =
+        key_dsl::device_key
+            .filter(key_dsl::id.eq(path_id))
+            .filter(key_dsl::device.eq(device_id))
+            .inner_join(lw::table)
+            .select((DeviceKey::as_select(), LightweightKeyDetails::as_select()))
+            .first(&mut conn)
+            .await;
+    match result {
+        Ok((key, lw_details)) => {
+            let details = LightweightKeyDetailsPayload::unwrap_from(
+                &api_config.key_encryption_key,
+                &lw_details,
+            )?;
+            // The key is only handed out once the export is on record
+            audit::record(&mut conn, actor.event("export", "device_key", key.id))
+                .await
+                .map_err(rest::error::internal_error)?;
+            warn!(
+                "Exported key {} of device {} to \"{:?}\"",
+                key.id, device_id, peer
+            );
+            Ok((
+                [(header::CACHE_CONTROL, "no-store")],
+                Json(DeviceKeyPayload {
+                    id: key.id,
+                    status: key.status,
+                    delivered_at: key.delivered_at,
+                    acknowledged_at: key.acknowledged_at,
+                    kind: DeviceKeyKind::Lightweight { details },
+                }),
+            ))
+        }
+        Err(diesel::result::Error::NotFound) => Err(rest::error::client_error(
+            StatusCode::NOT_FOUND,
+            format!(
+                "device {} or lightweight device key {} not found",
+                device_id, path_id
+            ),
+        )),
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+async fn load_device_key(
+    conn: &mut diesel_async::AsyncPgConnection,
+    device_id: i32,
+    key_id: i32,
+) -> Result<DeviceKeyPayload, rest::error::TransactionError> {
+    let (key, lw_opt, tls_opt): (
+        DeviceKey,
+        Option<LightweightKeyDetails>,
+        Option<TlsKeyDetails>,
+    ) = key_dsl::device_key
+        .filter(key_dsl::id.eq(key_id))
+        .filter(key_dsl::device.eq(device_id))
+        .left_outer_join(
+            lw::table.on(lw_dsl::device_key
+                .eq(key_dsl::id)
+                .and(key_dsl::key_type.eq(KeyType::Lightweight))),
+        )
+        .left_outer_join(
+            tls::table.on(tls_dsl::device_key
+                .eq(key_dsl::id)
+                .and(key_dsl::key_type.eq(KeyType::Tls))),
+        )
+        .select((
+            dk::all_columns,
+            lw::all_columns.nullable(),
+            tls::all_columns.nullable(),
+        ))
+        .first(conn)
+        .await?;
+    let kind = if let Some(lw_details) = lw_opt {
+        DeviceKeyKind::Lightweight {
+            details: lw_details.into(),
+        }
+    } else if let Some(tls_details) = tls_opt {
+        DeviceKeyKind::Tls {
+            details: tls_details.into(),
+        }
+    } else {
+        return Err(rest::error::TransactionError::from(
+            rest::error::internal_error(rest::error::FirmupsRestInternalError {
+                message: format!("No details found for device key {}", key_id),
+            }),
+        ));
+    };
+    Ok(DeviceKeyPayload {
+        id: key.id,
+        status: key.status,
+        delivered_at: key.delivered_at,
+        acknowledged_at: key.acknowledged_at,
+        kind,
+    })
+}
+
+#[axum::debug_handler]
+pub async fn promote_device_key(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path((device_id, path_id)): Path<(i32, i32)>,
+) -> Result<Json<DeviceKeyPayload>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tx_result: Result<DeviceKeyPayload, rest::error::TransactionError> = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            Box::pin(async move {
+                // promote_key takes the same lock again, the state read here stays current
+                diesel::dsl::sql_query("SELECT pg_advisory_xact_lock($1)")
+                    .bind::<diesel::sql_types::BigInt, _>(device_id as i64)
+                    .execute(conn)
+                    .await?;
+                let before = load_device_key(conn, device_id, path_id).await?;
+                if !key_rotation::promote_key(conn, device_id, path_id).await? {
+                    let key: DeviceKey = key_dsl::device_key
+                        .filter(key_dsl::id.eq(path_id))
+                        .filter(key_dsl::device.eq(device_id))
+                        .select(DeviceKey::as_select())
+                        .first(conn)
+                        .await?;
+                    return Err(rest::error::TransactionError::from(
+                        rest::error::client_error(
+                            StatusCode::CONFLICT,
+                            format!(
+                                "Only NEXT keys can be promoted, key {} is {:?}",
+                                key.id, key.status
+                            ),
+                        ),
+                    ));
+                }
+                info!("Promoted key {} of device {} to ACTIVE", path_id, device_id);
+                let after = load_device_key(conn, device_id, path_id).await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("promote", "device_key", path_id)
+                        .before(&before)
+                        .after(&after),
+                )
+                .await?;
+                Ok(after)
+            })
+        })
+        .await;
+    match tx_result {
+        Ok(device_key_payload) => Ok(Json(device_key_payload)),
+        Err(rest::error::TransactionError::Db(diesel::result::Error::NotFound)) => {
+            Err(rest::error::client_error(
+                StatusCode::NOT_FOUND,
+                format!("device {} or device key {} not found", device_id, path_id),
+            ))
+        }
+        Err(rest::error::TransactionError::Db(e)) => Err(rest::error::internal_error(e)),
+        Err(rest::error::TransactionError::Api(api)) => Err(api),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn expire_device_key(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path((device_id, path_id)): Path<(i32, i32)>,
+) -> Result<Json<DeviceKeyPayload>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tx_result: Result<DeviceKeyPayload, rest::error::TransactionError> = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            Box::pin(async move {
+                diesel::dsl::sql_query("SELECT pg_advisory_xact_lock($1)")
+                    .bind::<diesel::sql_types::BigInt, _>(device_id as i64)
+                    .execute(conn)
+                    .await?;
+
+                let key: DeviceKey = key_dsl::device_key
+                    .filter(key_dsl::id.eq(path_id))
+                    .filter(key_dsl::device.eq(device_id))
+                    .select(DeviceKey::as_select())
+                    .first(conn)
+                    .await?;
+                if key.status == KeyStatus::Expired {
+                    return Err(rest::error::TransactionError::from(
+                        rest::error::client_error(
+                            StatusCode::CONFLICT,
+                            format!("Key {} is already expired", key.id),
+                        ),
+                    ));
+                }
+                if key.status == KeyStatus::Active {
+                    warn!(
+                        "Expiring ACTIVE key {} of device {}, device can no longer communicate until a key is promoted",
+                        path_id, device_id
+                    );
+                }
+
+                let before = load_device_key(conn, device_id, path_id).await?;
+                diesel::update(key_dsl::device_key.find(path_id))
+                    .set(key_dsl::status.eq(KeyStatus::Expired))
+                    .execute(conn)
+                    .await?;
+                let after = load_device_key(conn, device_id, path_id).await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("expire", "device_key", path_id)
+                        .before(&before)
+                        .after(&after),
+                )
+                .await?;
+                Ok(after)
+            })
+        })
+        .await;
+    match tx_result {
+        Ok(device_key_payload) => Ok(Json(device_key_payload)),
+        Err(rest::error::TransactionError::Db(diesel::result::Error::NotFound)) => {
+            Err(rest::error::client_error(
+                StatusCode::NOT_FOUND,
+                format!("device {} or device key {} not found", device_id, path_id),
+            ))
+        }
+        Err(rest::error::TransactionError::Db(e)) => Err(rest::error::internal_error(e)),
+        Err(rest::error::TransactionError::Api(api)) => Err(api),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn get_device_key_certificate(
+    State(api_config): State<rest::RestApiConfig>,
+    Path((device_id, path_id)): Path<(i32, i32)>,
+) -> Result<impl IntoResponse, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    let result: Result<String, diesel::result::Error> = key_dsl::device_key
+        .filter(key_dsl::id.eq(path_id))
+        .filter(key_dsl::device.eq(device_id))
+        .inner_join(tls::table)
+        .select(tls_dsl::certificate)
+        .first(&mut conn)
+        .await;
+    match result {
+        Ok(certificate) => Ok((
+            [(header::CONTENT_TYPE, "application/x-pem-file")],
+            certificate,
+        )),
+        Err(diesel::result::Error::NotFound) => Err(rest::error::client_error(
+            StatusCode::NOT_FOUND,
+            format!(
+                "device {} or TLS device key {} not found",
+                device_id, path_id
+            ),
+        )),
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn revoke_device_key(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path((device_id, path_id)): Path<(i32, i32)>,
+) -> Result<Json<DeviceKeyPayload>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tx_result: Result<DeviceKeyPayload, rest::error::TransactionError> = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            Box::pin(async move {
+                diesel::dsl::sql_query("SELECT pg_advisory_xact_lock($1)")
+                    .bind::<diesel::sql_types::BigInt, _>(device_id as i64)
+                    .execute(conn)
+                    .await?;
+
+                let (key, tls_details): (DeviceKey, TlsKeyDetails) = key_dsl::device_key
+                    .filter(key_dsl::id.eq(path_id))
+                    .filter(key_dsl::device.eq(device_id))
+                    .inner_join(tls::table)
+                    .select((DeviceKey::as_select(), TlsKeyDetails::as_select()))
+                    .first(conn)
+                    .await?;
+                if tls_details.revoked_at.is_some() {
+                    return Err(rest::error::TransactionError::from(
+                        rest::error::client_error(
+                            StatusCode::CONFLICT,
+                            format!("Certificate of key {} is already revoked", key.id),
+                        ),
+                    ));
+                }
+                if key.status == KeyStatus::Active {
+                    warn!(
+                        "Revoking ACTIVE key {} of device {}, device can no longer communicate until a key is promoted",
+                        path_id, device_id
+                    );
+                }
+
+                let before = load_device_key(conn, device_id, path_id).await?;
+                diesel::update(tls_dsl::tls_key_details.find(tls_details.id))
+                    .set(tls_dsl::revoked_at.eq(chrono::Utc::now().naive_utc()))
+                    .execute(conn)
+                    .await?;
+                diesel::update(key_dsl::device_key.find(path_id))
+                    .set(key_dsl::status.eq(KeyStatus::Expired))
+                    .execute(conn)
+                    .await?;
+                info!(
+                    "Revoked certificate {} of key {} of device {}",
+                    tls_details.serial, path_id, device_id
+                );
+                let after = load_device_key(conn, device_id, path_id).await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("revoke", "device_key", path_id)
+                        .before(&before)
+                        .after(&after),
+                )
+                .await?;
+                Ok(after)
+            })
+        })
+        .await;
+    match tx_result {
+        Ok(device_key_payload) => Ok(Json(device_key_payload)),
+        Err(rest::error::TransactionError::Db(diesel::result::Error::NotFound)) => {
+            Err(rest::error::client_error(
+                StatusCode::NOT_FOUND,
+                format!(
+                    "device {} or TLS device key {} not found",
+                    device_id, path_id
+                ),
+            ))
+        }
+        Err(rest::error::TransactionError::Db(e)) => Err(rest::error::internal_error(e)),
+        Err(rest::error::TransactionError::Api(api)) => Err(api),
+    }
+}
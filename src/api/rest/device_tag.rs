@@ -0,0 +1,227 @@
+use crate::api::rest;
+use crate::db::audit;
+use crate::db::models::{DeviceTag, NewDeviceTag};
+use crate::db::schema::device_tag::dsl as tag_dsl;
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use diesel::AggregateExpressionMethods;
+use diesel::ExpressionMethods;
+use diesel::SelectableHelper;
+use diesel::TextExpressionMethods;
+use diesel::query_dsl::methods::{FilterDsl, LimitDsl, OffsetDsl, OrderDsl, SelectDsl};
+use diesel::result::DatabaseErrorKind;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+
+const TAG_SORT_FIELDS: &[&str] = &["tag", "devices"];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewDeviceTagPayload {
+    pub tag: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TagFilter {
+    /// Prefix of the tag
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TagSummary {
+    pub tag: String,
+    /// Number of devices with the tag
+    pub devices: i64,
+}
+
+fn device_not_found(device_id: i32) -> rest::error::ApiError {
+    rest::error::client_error(
+        StatusCode::NOT_FOUND,
+        format!("device {} not found", device_id),
+    )
+}
+
+#[axum::debug_handler]
+pub async fn list_device_tags(
+    State(api_config): State<rest::RestApiConfig>,
+    Path(device_id): Path<i32>,
+) -> Result<Json<Vec<DeviceTag>>, rest::error::ApiError> {
+    use crate::db::schema::device::dsl as device_dsl;
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    let exists: bool = diesel::select(diesel::dsl::exists(
+        device_dsl::device.filter(device_dsl::id.eq(device_id)),
+    ))
+    .get_result(&mut conn)
+    .await
+    .map_err(rest::error::internal_error)?;
+    if !exists {
+        return Err(device_not_found(device_id));
+    }
+    let tags: Vec<DeviceTag> = tag_dsl::device_tag
+        .filter(tag_dsl::device.eq(device_id))
+        .order(tag_dsl::tag.asc())
+        .select(DeviceTag::as_select())
+        .load(&mut conn)
+        .await
+        .map_err(rest::error::internal_error)?;
+    Ok(Json(tags))
+}
+
+#[axum::debug_handler]
+pub async fn create_device_tag(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(device_id): Path<i32>,
+    Json(payload): Json<NewDeviceTagPayload>,
+) -> Result<(StatusCode, Json<DeviceTag>), rest::error::ApiError> {
+    let tag_trimmed = payload.tag.trim().to_string();
+    if tag_trimmed.is_empty() || tag_trimmed.len() > 100 {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "tag must be between 1 and 100 characters".to_string(),
+        ));
+    }
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let new_row = NewDeviceTag {
+        device: device_id,
+        tag: tag_trimmed.clone(),
+    };
+    let result: Result<DeviceTag, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let created: DeviceTag = diesel::insert_into(tag_dsl::device_tag)
+                    .values(&new_row)
+                    .returning(DeviceTag::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor.event("add_tag", "device", device_id).after(&created),
+                )
+                .await?;
+                Ok(created)
+            })
+        })
+        .await;
+
+    match result {
+        Ok(created) => Ok((StatusCode::CREATED, Json(created))),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+            Err(device_not_found(device_id))
+        }
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Err(rest::error::client_error(
+                StatusCode::CONFLICT,
+                format!("device {} already has tag '{}'", device_id, tag_trimmed),
+            ))
+        }
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn delete_device_tag(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path((device_id, tag)): Path<(i32, String)>,
+) -> Result<Json<DeviceTag>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tag_filter = tag.clone();
+    let deleted: Result<DeviceTag, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let row: DeviceTag = diesel::delete(
+                    tag_dsl::device_tag
+                        .filter(tag_dsl::device.eq(device_id))
+                        .filter(tag_dsl::tag.eq(tag_filter)),
+                )
+                .returning(DeviceTag::as_returning())
+                .get_result(conn)
+                .await?;
+                audit::record(
+                    conn,
+                    actor.event("remove_tag", "device", device_id).before(&row),
+                )
+                .await?;
+                Ok(row)
+            })
+        })
+        .await;
+
+    match deleted {
+        Ok(row) => Ok(Json(row)),
+        Err(diesel::result::Error::NotFound) => Err(rest::error::client_error(
+            StatusCode::NOT_FOUND,
+            format!("device {} has no tag '{}'", device_id, tag),
+        )),
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+/// Distinct tags with the number of devices carrying them
+#[axum::debug_handler]
+pub async fn list_tags(
+    State(api_config): State<rest::RestApiConfig>,
+    Query(page): Query<rest::pagination::PageQuery>,
+    Query(filter): Query<TagFilter>,
+) -> Result<rest::pagination::Paged<TagSummary>, rest::error::ApiError> {
+    use crate::db::schema::device_tag::dsl::*;
+
+    let page = page.validate(TAG_SORT_FIELDS)?;
+    let pattern = rest::pagination::like_prefix(filter.tag.as_deref().unwrap_or(""));
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    let total: i64 = device_tag
+        .filter(tag.like(pattern.clone()))
+        .select(diesel::dsl::count(tag).aggregate_distinct())
+        .get_result(&mut conn)
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let query = diesel::QueryDsl::group_by(device_tag.filter(tag.like(pattern)), tag)
+        .select((tag, diesel::dsl::count_star()));
+    let query = diesel::QueryDsl::into_boxed(query);
+    let query = match (page.sort_field, page.descending) {
+        ("devices", false) => query.order((diesel::dsl::count_star().asc(), tag.asc())),
+        ("devices", true) => query.order((diesel::dsl::count_star().desc(), tag.asc())),
+        (_, false) => query.order(tag.asc()),
+        (_, true) => query.order(tag.desc()),
+    };
+    let rows: Vec<(String, i64)> = query
+        .offset(page.offset)
+        .limit(page.limit)
+        .load(&mut conn)
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    Ok(rest::pagination::Paged {
+        total,
+        items: rows
+            .into_iter()
+            .map(|(name, devices)| TagSummary { tag: name, devices })
+            .collect(),
+    })
+}
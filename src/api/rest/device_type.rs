@@ -1,17 +1,23 @@
 use crate::api::rest;
+use crate::db::audit;
 use crate::db::models::{DeviceType, NewDeviceType, UpdateDeviceType};
+use crate::db::schema;
 use axum::Json;
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use diesel::ExpressionMethods;
 use diesel::SelectableHelper;
-use diesel::query_dsl::methods::{FilterDsl, FindDsl, SelectDsl};
+use diesel::TextExpressionMethods;
+use diesel::pg::Pg;
+use diesel::query_dsl::methods::{FilterDsl, FindDsl, OrderDsl, SelectDsl};
 use diesel::result::DatabaseErrorKind;
-use diesel_async::RunQueryDsl;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use serde::Deserialize;
 
 #[axum::debug_handler]
 pub async fn create_device_type(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Json(payload): Json<NewDeviceType>,
 ) -> Result<(StatusCode, Json<DeviceType>), rest::error::ApiError> {
     use crate::db::schema::device_type::dsl::*;
@@ -38,10 +44,24 @@ pub async fn create_device_type(
     };
 
     // Perform the insert and return the created row
-    let result: Result<DeviceType, diesel::result::Error> = diesel::insert_into(device_type)
-        .values(&new_row)
-        .returning(DeviceType::as_returning())
-        .get_result(&mut conn)
+    let result: Result<DeviceType, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let created: DeviceType = diesel::insert_into(device_type)
+                    .values(&new_row)
+                    .returning(DeviceType::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("create", "device_type", created.id)
+                        .after(&created),
+                )
+                .await?;
+                Ok(created)
+            })
+        })
         .await;
 
     match result {
@@ -62,25 +82,54 @@ pub async fn create_device_type(
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceTypeFilter {
+    /// Prefix of the name
+    pub name: Option<String>,
+}
+
+const DEVICE_TYPE_SORT_FIELDS: &[&str] = &["id", "name"];
+
+fn filtered_device_types(
+    filter: &DeviceTypeFilter,
+) -> schema::device_type::BoxedQuery<'static, Pg> {
+    use crate::db::schema::device_type::dsl::*;
+
+    let mut query = diesel::QueryDsl::into_boxed(device_type);
+    if let Some(prefix) = &filter.name {
+        query = query.filter(name.like(rest::pagination::like_prefix(prefix)));
+    }
+    query
+}
+
 #[axum::debug_handler]
 pub async fn list_device_types(
     State(api_config): State<rest::RestApiConfig>,
-) -> Result<Json<Vec<DeviceType>>, rest::error::ApiError> {
+    Query(page): Query<rest::pagination::PageQuery>,
+    Query(filter): Query<DeviceTypeFilter>,
+) -> Result<rest::pagination::Paged<DeviceType>, rest::error::ApiError> {
     use crate::db::schema::device_type::dsl::*;
 
+    let page = page.validate(DEVICE_TYPE_SORT_FIELDS)?;
     let mut conn = api_config
         .shared_pool
         .clone()
         .get_owned()
         .await
         .map_err(rest::error::internal_error)?;
-    let result = device_type
-        .select(DeviceType::as_select())
-        .load(&mut conn)
-        .await
-        .map_err(rest::error::internal_error)?;
-
-    Ok(Json(result))
+    rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || filtered_device_types(&filter),
+        |query| match (page.sort_field, page.descending) {
+            ("name", false) => query.order(name.asc()),
+            ("name", true) => query.order(name.desc()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await
 }
 
 #[axum::debug_handler]
@@ -120,6 +169,7 @@ pub async fn get_device_type(
 #[axum::debug_handler]
 pub async fn update_device_type(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Path(path_id): Path<i32>,
     Json(payload): Json<UpdateDeviceType>,
 ) -> Result<(StatusCode, Json<DeviceType>), rest::error::ApiError> {
@@ -150,12 +200,31 @@ pub async fn update_device_type(
         }
     };
 
-    let result: Result<DeviceType, diesel::result::Error> =
-        diesel::update(device_type.find(path_id))
-            .set(&payload)
-            .returning(DeviceType::as_returning())
-            .get_result(&mut conn)
-            .await;
+    let result: Result<DeviceType, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let before: DeviceType = diesel::QueryDsl::for_update(
+                    device_type.find(path_id).select(DeviceType::as_select()),
+                )
+                .first(conn)
+                .await?;
+                let updated: DeviceType = diesel::update(device_type.find(path_id))
+                    .set(&payload)
+                    .returning(DeviceType::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("update", "device_type", path_id)
+                        .before(&before)
+                        .after(&updated),
+                )
+                .await?;
+                Ok(updated)
+            })
+        })
+        .await;
     match result {
         Ok(created) => Ok((StatusCode::CREATED, Json(created))),
         Err(diesel::result::Error::NotFound) => Err(rest::error::client_error(
@@ -181,6 +250,7 @@ pub async fn update_device_type(
 #[axum::debug_handler]
 pub async fn delete_device_type(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Path(path_id): Path<i32>,
 ) -> Result<Json<DeviceType>, rest::error::ApiError> {
     use crate::db::schema::device_type::dsl::*;
@@ -192,11 +262,22 @@ pub async fn delete_device_type(
         .await
         .map_err(rest::error::internal_error)?;
 
-    let deleted: Result<DeviceType, diesel::result::Error> =
-        diesel::delete(device_type.filter(id.eq(path_id)))
-            .returning(DeviceType::as_returning())
-            .get_result(&mut conn)
-            .await;
+    let deleted: Result<DeviceType, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let row: DeviceType = diesel::delete(device_type.filter(id.eq(path_id)))
+                    .returning(DeviceType::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor.event("delete", "device_type", path_id).before(&row),
+                )
+                .await?;
+                Ok(row)
+            })
+        })
+        .await;
 
     match deleted {
         Ok(row) => Ok(Json(row)),
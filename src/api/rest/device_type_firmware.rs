@@ -1,18 +1,23 @@
 use crate::api::rest;
+use crate::db::audit;
 use crate::db::models::{DeviceTypeFirmware, NewDeviceTypeFirmware};
+use crate::db::schema;
 use axum::Json;
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use diesel::ExpressionMethods;
 use diesel::SelectableHelper;
-use diesel::query_dsl::methods::{FilterDsl, SelectDsl};
+use diesel::pg::Pg;
+use diesel::query_dsl::methods::{FilterDsl, OrderDsl, SelectDsl};
 use diesel::result::DatabaseErrorKind;
-use diesel_async::RunQueryDsl;
+use diesel_async::{AsyncConnection, RunQueryDsl};
 use log::debug;
+use serde::Deserialize;
 
 #[axum::debug_handler]
 pub async fn create_device_type_firmware(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Json(payload): Json<NewDeviceTypeFirmware>,
 ) -> Result<(StatusCode, Json<DeviceTypeFirmware>), rest::error::ApiError> {
     use crate::db::schema::device_type_firmware::dsl::*;
@@ -23,12 +28,25 @@ pub async fn create_device_type_firmware(
         }
     };
 
-    let result: Result<DeviceTypeFirmware, diesel::result::Error> =
-        diesel::insert_into(device_type_firmware)
-            .values(&payload)
-            .returning(DeviceTypeFirmware::as_returning())
-            .get_result(&mut conn)
-            .await;
+    let result: Result<DeviceTypeFirmware, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let created: DeviceTypeFirmware = diesel::insert_into(device_type_firmware)
+                    .values(&payload)
+                    .returning(DeviceTypeFirmware::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("create", "device_type_firmware", created.id)
+                        .after(&created),
+                )
+                .await?;
+                Ok(created)
+            })
+        })
+        .await;
     match result {
         Ok(created) => Ok((StatusCode::CREATED, Json(created))),
         Err(diesel::result::Error::DatabaseError(kind, info)) => {
@@ -52,25 +70,59 @@ pub async fn create_device_type_firmware(
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceTypeFirmwareFilter {
+    pub device_type: Option<i32>,
+    pub firmware: Option<i32>,
+}
+
+const DEVICE_TYPE_FIRMWARE_SORT_FIELDS: &[&str] = &["id", "device_type", "firmware"];
+
+fn filtered_device_type_firmwares(
+    filter: &DeviceTypeFirmwareFilter,
+) -> schema::device_type_firmware::BoxedQuery<'static, Pg> {
+    use crate::db::schema::device_type_firmware::dsl::*;
+
+    let mut query = diesel::QueryDsl::into_boxed(device_type_firmware);
+    if let Some(filter_device_type) = filter.device_type {
+        query = query.filter(device_type.eq(filter_device_type));
+    }
+    if let Some(filter_firmware) = filter.firmware {
+        query = query.filter(firmware.eq(filter_firmware));
+    }
+    query
+}
+
 #[axum::debug_handler]
 pub async fn list_device_type_firmwares(
     State(api_config): State<rest::RestApiConfig>,
-) -> Result<Json<Vec<DeviceTypeFirmware>>, rest::error::ApiError> {
+    Query(page): Query<rest::pagination::PageQuery>,
+    Query(filter): Query<DeviceTypeFirmwareFilter>,
+) -> Result<rest::pagination::Paged<DeviceTypeFirmware>, rest::error::ApiError> {
     use crate::db::schema::device_type_firmware::dsl::*;
 
+    let page = page.validate(DEVICE_TYPE_FIRMWARE_SORT_FIELDS)?;
     let mut conn = api_config
         .shared_pool
         .clone()
         .get_owned()
         .await
         .map_err(rest::error::internal_error)?;
-    let result = device_type_firmware
-        .select(DeviceTypeFirmware::as_select())
-        .load(&mut conn)
-        .await
-        .map_err(rest::error::internal_error)?;
-
-    Ok(Json(result))
+    rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || filtered_device_type_firmwares(&filter),
+        |query| match (page.sort_field, page.descending) {
+            ("device_type", false) => query.order(device_type.asc()),
+            ("device_type", true) => query.order(device_type.desc()),
+            ("firmware", false) => query.order(firmware.asc()),
+            ("firmware", true) => query.order(firmware.desc()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await
 }
 
 #[axum::debug_handler]
@@ -111,6 +163,7 @@ pub async fn get_device_type_firmware(
 #[axum::debug_handler]
 pub async fn delete_device_type_firmware(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Path(path_id): Path<i32>,
 ) -> Result<Json<DeviceTypeFirmware>, rest::error::ApiError> {
     use crate::db::schema::device_type_firmware::dsl::*;
@@ -123,11 +176,25 @@ pub async fn delete_device_type_firmware(
         .await
         .map_err(rest::error::internal_error)?;
 
-    let deleted: Result<DeviceTypeFirmware, diesel::result::Error> =
-        diesel::delete(device_type_firmware.filter(id.eq(path_id)))
-            .returning(DeviceTypeFirmware::as_returning())
-            .get_result(&mut conn)
-            .await;
+    let deleted: Result<DeviceTypeFirmware, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let row: DeviceTypeFirmware =
+                    diesel::delete(device_type_firmware.filter(id.eq(path_id)))
+                        .returning(DeviceTypeFirmware::as_returning())
+                        .get_result(conn)
+                        .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("delete", "device_type_firmware", path_id)
+                        .before(&row),
+                )
+                .await?;
+                Ok(row)
+            })
+        })
+        .await;
 
     match deleted {
         Ok(row) => Ok(Json(row)),
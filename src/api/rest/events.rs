@@ -0,0 +1,100 @@
+use crate::api::events::{FleetEvent, FleetEventKind};
+use crate::api::rest;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use serde::Deserialize;
+use std::convert::Infallible;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Events buffered per subscriber while the connection is busy
+const SUBSCRIBER_BUFFER: usize = 64;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventFilter {
+    pub device: Option<i32>,
+    /// Comma separated event types
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+}
+
+fn event_types(types: Option<&str>) -> Result<Option<Vec<&'static str>>, rest::error::ApiError> {
+    let Some(types) = types else {
+        return Ok(None);
+    };
+    types
+        .split(',')
+        .map(|t| {
+            let t = t.trim().to_uppercase();
+            FleetEventKind::NAMES
+                .iter()
+                .find(|name| **name == t)
+                .copied()
+                .ok_or_else(|| {
+                    rest::error::client_error(
+                        StatusCode::BAD_REQUEST,
+                        format!(
+                            "unknown event type '{}', expected one of {}",
+                            t,
+                            FleetEventKind::NAMES.join(", ")
+                        ),
+                    )
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+fn to_sse(event: &FleetEvent) -> Event {
+    Event::default()
+        .event(event.kind.name())
+        .json_data(event)
+        .unwrap_or_else(|_| Event::default().event(event.kind.name()))
+}
+
+/// Forward the matching events as Server-Sent Events until the client disconnects or the server
+/// shuts down
+#[axum::debug_handler]
+pub async fn stream_events(
+    State(api_config): State<rest::RestApiConfig>,
+    Query(filter): Query<EventFilter>,
+) -> Result<impl IntoResponse, rest::error::ApiError> {
+    let types = event_types(filter.type_.as_deref())?;
+    let mut events = api_config.events.subscribe();
+    let shutdown = api_config.shutdown.clone();
+    let (sender, receiver) = mpsc::channel::<Result<Event, Infallible>>(SUBSCRIBER_BUFFER);
+
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                received = events.recv() => received,
+                _ = shutdown.cancelled() => break,
+                _ = sender.closed() => break,
+            };
+            let sse = match event {
+                Ok(event) => {
+                    if filter.device.is_some_and(|d| d != event.device)
+                        || types
+                            .as_ref()
+                            .is_some_and(|t| !t.contains(&event.kind.name()))
+                    {
+                        continue;
+                    }
+                    to_sse(&event)
+                }
+                // Tell the client to catch up by other means, e.g. by listing the devices
+                Err(broadcast::error::RecvError::Lagged(missed)) => Event::default()
+                    .event("LAGGED")
+                    .data(format!("{{\"missed\":{}}}", missed)),
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if sender.send(Ok(sse)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(receiver)).keep_alive(KeepAlive::default()))
+}
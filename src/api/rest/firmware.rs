@@ -1,107 +1,429 @@
 use crate::api::rest;
-use crate::db::models::{Firmware, NewFirmware};
+use crate::api::rest::range::{self, RangeRequest};
+use crate::db::audit;
+use crate::db::firmware_version;
+use crate::db::models::{
+    CampaignStatus, Firmware, NewFirmware, ReleaseChannel, UpdateFirmware, WebhookEventType,
+};
+use crate::db::schema;
 use axum::Json;
 use axum::body::Body;
 use axum::extract::Multipart;
-use axum::extract::{Path, State};
-use axum::http::{HeaderMap, HeaderValue, StatusCode};
-use axum::response::IntoResponse;
+use axum::extract::multipart::{Field, MultipartError};
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, NaiveDateTime};
+use diesel::BoolExpressionMethods;
 use diesel::ExpressionMethods;
+use diesel::PgSortExpressionMethods;
 use diesel::SelectableHelper;
-use diesel::query_dsl::methods::{FilterDsl, SelectDsl};
+use diesel::TextExpressionMethods;
+use diesel::pg::Pg;
+use diesel::query_dsl::methods::{FilterDsl, FindDsl, OrderDsl, SelectDsl};
 use diesel::result::DatabaseErrorKind;
-use diesel_async::RunQueryDsl;
-use log::warn;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use log::{info, warn};
+use serde::Deserialize;
 use sha2::{Digest, Sha256};
+use std::io::{Cursor, SeekFrom};
+use std::path::PathBuf;
+use std::pin::Pin;
 use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_stream::StreamExt;
 use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
+const PARTIAL_UPLOAD_SUFFIX: &str = ".part";
+const MAX_DESCRIPTION_LENGTH: usize = 1000;
+const MAX_RELEASE_NOTES_LENGTH: usize = 65536;
+/// Bytes of a text field in the multipart form, the longest field in UTF-8
+const MAX_TEXT_FIELD_SIZE: usize = 4 * MAX_RELEASE_NOTES_LENGTH;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FirmwareFilter {
+    /// Prefix of the name
+    pub name: Option<String>,
+    /// Prefix of the version
+    pub version: Option<String>,
+    pub channel: Option<ReleaseChannel>,
+    /// Only firmware linked to this device type
+    pub device_type: Option<i32>,
+    /// Prefix of the git commit
+    pub git_commit: Option<String>,
+    pub built_since: Option<NaiveDateTime>,
+    pub built_until: Option<NaiveDateTime>,
+}
+
+/// `version` sorts by semver precedence, other versions before all semantic versions
+const FIRMWARE_SORT_FIELDS: &[&str] = &["id", "name", "version", "size", "build_timestamp"];
+
+fn filtered_firmwares(filter: &FirmwareFilter) -> schema::firmware::BoxedQuery<'static, Pg> {
+    use crate::db::schema::firmware::dsl::*;
+
+    let mut query = diesel::QueryDsl::into_boxed(firmware);
+    if let Some(prefix) = &filter.name {
+        query = query.filter(name.like(rest::pagination::like_prefix(prefix)));
+    }
+    if let Some(prefix) = &filter.version {
+        query = query.filter(version.like(rest::pagination::like_prefix(prefix)));
+    }
+    if let Some(filter_channel) = filter.channel {
+        query = query.filter(channel.eq(filter_channel));
+    }
+    if let Some(filter_device_type) = filter.device_type {
+        query = query.filter(
+            id.eq_any(
+                schema::device_type_firmware::table
+                    .filter(schema::device_type_firmware::device_type.eq(filter_device_type))
+                    .select(schema::device_type_firmware::firmware),
+            ),
+        );
+    }
+    if let Some(prefix) = &filter.git_commit {
+        query = query
+            .filter(git_commit.like(rest::pagination::like_prefix(&prefix.to_ascii_lowercase())));
+    }
+    if let Some(since) = filter.built_since {
+        query = query.filter(build_timestamp.ge(since));
+    }
+    if let Some(until) = filter.built_until {
+        query = query.filter(build_timestamp.lt(until));
+    }
+    query
+}
+
 #[axum::debug_handler]
 pub async fn list_firmwares(
     State(api_config): State<rest::RestApiConfig>,
-) -> Result<Json<Vec<Firmware>>, rest::error::ApiError> {
+    Query(page): Query<rest::pagination::PageQuery>,
+    Query(filter): Query<FirmwareFilter>,
+) -> Result<rest::pagination::Paged<Firmware>, rest::error::ApiError> {
     use crate::db::schema::firmware::dsl::*;
 
+    let page = page.validate(FIRMWARE_SORT_FIELDS)?;
     let mut conn = api_config
         .shared_pool
         .clone()
         .get_owned()
         .await
         .map_err(rest::error::internal_error)?;
-    let result = firmware
-        .select(Firmware::as_select())
-        .load(&mut conn)
-        .await
-        .map_err(rest::error::internal_error)?;
-
-    Ok(Json(result))
+    rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || filtered_firmwares(&filter),
+        |query| match (page.sort_field, page.descending) {
+            ("name", false) => query.order(name.asc()),
+            ("name", true) => query.order(name.desc()),
+            ("version", false) => {
+                query.order((version_sort_key.asc().nulls_first(), version.asc()))
+            }
+            ("version", true) => {
+                query.order((version_sort_key.desc().nulls_last(), version.desc()))
+            }
+            ("size", false) => query.order(size.asc()),
+            ("size", true) => query.order(size.desc()),
+            ("build_timestamp", false) => query.order(build_timestamp.asc().nulls_last()),
+            ("build_timestamp", true) => query.order(build_timestamp.desc().nulls_last()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await
 }
 
-#[axum::debug_handler]
-pub async fn create_firmware(
-    State(api_config): State<rest::RestApiConfig>,
-    mut multipart: Multipart,
-) -> Result<(StatusCode, Json<Firmware>), rest::error::ApiError> {
-    use crate::db::schema::firmware::dsl::*;
+/// Upload in progress, the file is removed when dropped unless it was persisted
+struct PartialUpload {
+    path: PathBuf,
+    persisted: bool,
+}
 
-    let mut in_name: Option<String> = None;
-    let mut in_version: Option<String> = None;
-    let mut in_file_bytes: Option<Vec<u8>> = None;
+impl PartialUpload {
+    async fn persist(mut self, destination: &std::path::Path) -> std::io::Result<()> {
+        fs::rename(&self.path, destination).await?;
+        self.persisted = true;
+        Ok(())
+    }
+}
 
-    while let Some(field) = multipart.next_field().await.unwrap_or(None) {
-        let field_name = field.name().unwrap_or("").to_string();
-        match field_name.as_str() {
-            "name" => {
-                in_name = field.text().await.ok();
-            }
-            "version" => {
-                in_version = field.text().await.ok();
-            }
-            "file" => {
-                in_file_bytes = field.bytes().await.ok().map(|b| b.to_vec());
-            }
-            _ => {}
+impl Drop for PartialUpload {
+    fn drop(&mut self) {
+        // Also runs if the client aborts the upload and the handler is cancelled
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
         }
     }
+}
+
+fn multipart_error(e: MultipartError, max_firmware_size: usize) -> rest::error::ApiError {
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return rest::error::client_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("upload larger than {} bytes", max_firmware_size),
+        );
+    }
+    rest::error::client_error(e.status(), e.body_text())
+}
 
-    // Basic validation
-    let in_name = match in_name {
-        Some(v) if !v.is_empty() => v,
-        _ => {
+/// Stream `field` to a partial upload in `directory`, returns it with size and SHA-256
+async fn receive_file(
+    mut field: Field<'_>,
+    directory: &std::path::Path,
+    max_firmware_size: usize,
+) -> Result<(PartialUpload, i64, String), rest::error::ApiError> {
+    let upload = PartialUpload {
+        path: directory.join(format!("{}{}", Uuid::new_v4(), PARTIAL_UPLOAD_SUFFIX)),
+        persisted: false,
+    };
+    let mut file = fs::File::create(&upload.path)
+        .await
+        .map_err(rest::error::internal_error)?;
+    let mut hasher = Sha256::new();
+    let mut size: i64 = 0;
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|e| multipart_error(e, max_firmware_size))?
+    {
+        hasher.update(&chunk);
+        size += chunk.len() as i64;
+        file.write_all(&chunk)
+            .await
+            .map_err(rest::error::internal_error)?;
+    }
+    file.sync_all().await.map_err(rest::error::internal_error)?;
+    Ok((upload, size, format!("{:x}", hasher.finalize())))
+}
+
+/// Read a text field of at most `MAX_TEXT_FIELD_SIZE` bytes
+async fn receive_text(
+    mut field: Field<'_>,
+    name: &str,
+    max_firmware_size: usize,
+) -> Result<String, rest::error::ApiError> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|e| multipart_error(e, max_firmware_size))?
+    {
+        if buf.len() + chunk.len() > MAX_TEXT_FIELD_SIZE {
             return Err(rest::error::client_error(
-                StatusCode::BAD_REQUEST,
-                "name cannot be empty".to_string(),
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("{} larger than {} bytes", name, MAX_TEXT_FIELD_SIZE),
             ));
         }
-    };
+        buf.extend_from_slice(&chunk);
+    }
+    String::from_utf8(buf).map_err(|_| {
+        rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            format!("{} must be valid UTF-8", name),
+        )
+    })
+}
+
+/// Fields of the multipart form of `create_firmware` and `update_firmware`
+struct FirmwareForm {
+    name: Option<String>,
+    version: Option<String>,
+    description: Option<String>,
+    release_notes: Option<String>,
+    build_timestamp: Option<String>,
+    git_commit: Option<String>,
+    channel: Option<String>,
+    /// Received file with its size and SHA-256
+    file: Option<(PartialUpload, i64, String)>,
+}
+
+impl FirmwareForm {
+    async fn read(
+        multipart: &mut Multipart,
+        base_path: &std::path::Path,
+        max_firmware_size: usize,
+    ) -> Result<Self, rest::error::ApiError> {
+        let mut form = FirmwareForm {
+            name: None,
+            version: None,
+            description: None,
+            release_notes: None,
+            build_timestamp: None,
+            git_commit: None,
+            channel: None,
+            file: None,
+        };
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| multipart_error(e, max_firmware_size))?
+        {
+            let field_name = field.name().unwrap_or("").to_string();
+            let text = match field_name.as_str() {
+                "name" => &mut form.name,
+                "version" => &mut form.version,
+                "description" => &mut form.description,
+                "release_notes" => &mut form.release_notes,
+                "build_timestamp" => &mut form.build_timestamp,
+                "git_commit" => &mut form.git_commit,
+                "channel" => &mut form.channel,
+                "file" => {
+                    // The file is written while it is received, never held in memory
+                    form.file = Some(receive_file(field, base_path, max_firmware_size).await?);
+                    continue;
+                }
+                _ => continue,
+            };
+            *text = Some(receive_text(field, &field_name, max_firmware_size).await?);
+        }
+        Ok(form)
+    }
+
+    /// Validate the optional fields, taking them out of the form
+    fn metadata(&mut self) -> Result<FirmwareMetadata, rest::error::ApiError> {
+        Ok(FirmwareMetadata {
+            description: optional(self.description.take(), |d| {
+                validate_length("description", d, MAX_DESCRIPTION_LENGTH)
+            })?,
+            release_notes: optional(self.release_notes.take(), |n| {
+                validate_length("release_notes", n, MAX_RELEASE_NOTES_LENGTH)
+            })?,
+            build_timestamp: optional(self.build_timestamp.take(), |t| {
+                DateTime::parse_from_rfc3339(t.trim())
+                    .map(|t| t.naive_utc())
+                    .map_err(|_| {
+                        rest::error::client_error(
+                            StatusCode::BAD_REQUEST,
+                            "build_timestamp must be an RFC 3339 timestamp".to_string(),
+                        )
+                    })
+            })?,
+            git_commit: optional(self.git_commit.take(), |c| {
+                let c = c.trim().to_ascii_lowercase();
+                if !(7..=64).contains(&c.len()) || !c.chars().all(|ch| ch.is_ascii_hexdigit()) {
+                    return Err(rest::error::client_error(
+                        StatusCode::BAD_REQUEST,
+                        "git_commit must be 7 to 64 hex digits".to_string(),
+                    ));
+                }
+                Ok(c)
+            })?,
+            channel: match self.channel.take() {
+                Some(c) => Some(parse_channel(&c)?),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Optional fields of the form, `Some(None)` if sent empty
+struct FirmwareMetadata {
+    description: Option<Option<String>>,
+    release_notes: Option<Option<String>>,
+    build_timestamp: Option<Option<NaiveDateTime>>,
+    git_commit: Option<Option<String>>,
+    channel: Option<ReleaseChannel>,
+}
+
+fn optional<T>(
+    value: Option<String>,
+    parse: impl FnOnce(String) -> Result<T, rest::error::ApiError>,
+) -> Result<Option<Option<T>>, rest::error::ApiError> {
+    match value {
+        None => Ok(None),
+        Some(v) if v.is_empty() => Ok(Some(None)),
+        Some(v) => Ok(Some(Some(parse(v)?))),
+    }
+}
+
+fn parse_channel(value: &str) -> Result<ReleaseChannel, rest::error::ApiError> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "dev" => Ok(ReleaseChannel::Dev),
+        "beta" => Ok(ReleaseChannel::Beta),
+        "stable" => Ok(ReleaseChannel::Stable),
+        _ => Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "channel must be one of DEV, BETA, STABLE".to_string(),
+        )),
+    }
+}
 
-    if in_name.len() > 100 {
+fn validate_label(field: &str, value: &str) -> Result<(), rest::error::ApiError> {
+    if value.is_empty() {
         return Err(rest::error::client_error(
             StatusCode::BAD_REQUEST,
-            "name too long (max 100)".to_string(),
+            format!("{} cannot be empty", field),
         ));
     }
+    if value.len() > 100 {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            format!("{} too long (max 100)", field),
+        ));
+    }
+    Ok(())
+}
 
-    let in_version = match in_version {
-        Some(v) if !v.is_empty() => v,
-        _ => {
-            return Err(rest::error::client_error(
-                StatusCode::BAD_REQUEST,
-                "version cannot be empty".to_string(),
-            ));
-        }
-    };
-
-    if in_version.len() > 100 {
+fn validate_length(
+    field: &str,
+    value: String,
+    max_length: usize,
+) -> Result<String, rest::error::ApiError> {
+    if value.chars().count() > max_length {
         return Err(rest::error::client_error(
             StatusCode::BAD_REQUEST,
-            "version too long (max 100)".to_string(),
+            format!("{} too long (max {})", field, max_length),
         ));
     }
+    Ok(value)
+}
+
+/// Remove uploads left behind by a crash
+pub async fn remove_partial_uploads(data_storage_location: &std::path::Path) {
+    let Ok(mut entries) = fs::read_dir(data_storage_location.join("firmware")).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(PARTIAL_UPLOAD_SUFFIX)
+        {
+            match fs::remove_file(entry.path()).await {
+                Ok(()) => info!("Removed partial upload {:?}", entry.path()),
+                Err(e) => warn!("Failed to remove partial upload {:?}: {}", entry.path(), e),
+            }
+        }
+    }
+}
+
+#[axum::debug_handler]
+pub async fn create_firmware(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<Firmware>), rest::error::ApiError> {
+    use crate::db::schema::firmware::dsl::*;
+
+    let mut base_path = api_config.data_storage_location;
+    base_path.push("firmware");
+    fs::create_dir_all(&base_path)
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let mut form =
+        FirmwareForm::read(&mut multipart, &base_path, api_config.max_firmware_size).await?;
 
-    let file = match in_file_bytes {
-        Some(f) if !f.is_empty() => f,
+    // Basic validation
+    let metadata = form.metadata()?;
+    let in_name = form.name.unwrap_or_default();
+    validate_label("name", &in_name)?;
+    let in_version = form.version.unwrap_or_default();
+    validate_label("version", &in_version)?;
+
+    let (upload, in_size, in_sha256) = match form.file {
+        Some(f) if f.1 > 0 => f,
         _ => {
             return Err(rest::error::client_error(
                 StatusCode::BAD_REQUEST,
@@ -110,50 +432,63 @@ pub async fn create_firmware(
         }
     };
 
-    // Compute metadata
-    let in_size = file.len() as i64;
-    let in_sha256 = {
-        let mut hasher = Sha256::new();
-        hasher.update(&file);
-        format!("{:x}", hasher.finalize())
-    };
-
     let new_firmware = NewFirmware {
         name: in_name,
+        version_sort_key: firmware_version::sort_key(&in_version),
         version: in_version,
         file_id: Uuid::new_v4().to_string(),
         size: in_size,
         sha256: in_sha256,
+        description: metadata.description.flatten(),
+        release_notes: metadata.release_notes.flatten(),
+        build_timestamp: metadata.build_timestamp.flatten(),
+        git_commit: metadata.git_commit.flatten(),
+        channel: metadata.channel.unwrap_or(ReleaseChannel::Dev),
     };
 
     let safe_name = format!("{}.bin", new_firmware.file_id);
-    let mut path = api_config.data_storage_location;
-    path.push("firmware");
-    fs::create_dir_all(&path)
-        .await
-        .map_err(rest::error::internal_error)?;
-    path.push(&safe_name);
-    fs::write(&path, &file)
-        .await
-        .map_err(rest::error::internal_error)?;
+    let path = base_path.join(&safe_name);
     let mut conn = match api_config.shared_pool.get().await {
         Ok(c) => c,
         Err(e) => {
-            let _ = fs::remove_file(&path).await;
             return Err(rest::error::internal_error(e));
         }
     };
 
-    let inserted: Result<Firmware, diesel::result::Error> = diesel::insert_into(firmware)
-        .values(&new_firmware)
-        .returning(Firmware::as_returning())
-        .get_result(&mut conn)
+    let inserted: Result<Firmware, rest::error::TransactionError> = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            let new_firmware = &new_firmware;
+            let path = &path;
+            Box::pin(async move {
+                let created: Firmware = diesel::insert_into(firmware)
+                    .values(new_firmware)
+                    .returning(Firmware::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("create", "firmware", created.id)
+                        .after(&created),
+                )
+                .await?;
+                crate::db::webhook::enqueue(conn, WebhookEventType::FirmwareUploaded, &created)
+                    .await?;
+                // Last step, a failed rename rolls back the insert
+                upload.persist(path).await.map_err(|e| {
+                    rest::error::TransactionError::from(rest::error::internal_error(e))
+                })?;
+                Ok(created)
+            })
+        })
         .await;
     match inserted {
         Ok(record) => Ok((StatusCode::CREATED, axum::Json(record))),
-        Err(diesel::result::Error::DatabaseError(kind, info)) => {
+        Err(rest::error::TransactionError::Db(diesel::result::Error::DatabaseError(
+            kind,
+            info,
+        ))) => {
             if kind == DatabaseErrorKind::UniqueViolation {
-                let _ = fs::remove_file(&path).await;
                 Err(rest::error::client_error(
                     StatusCode::CONFLICT,
                     format!(
@@ -162,194 +497,225 @@ pub async fn create_firmware(
                     ),
                 ))
             } else {
+                // The commit may have failed after the file was moved into place
                 let _ = fs::remove_file(&path).await;
                 let error = diesel::result::Error::DatabaseError(kind, info);
                 Err(rest::error::internal_error(error))
             }
         }
-        Err(err) => {
+        Err(rest::error::TransactionError::Db(err)) => {
             let _ = fs::remove_file(&path).await;
             Err(rest::error::internal_error(err))
         }
+        Err(rest::error::TransactionError::Api(api)) => Err(api),
     }
 }
 
-// #[axum::debug_handler]
-// pub async fn update_firmware(
-//     State(api_config): State<rest::RestApiConfig>,
-//     Path(path_id): Path<i32>,
-//     mut multipart: Multipart,
-// ) -> Result<(StatusCode, Json<Firmware>), rest::error::ApiError> {
-//     use crate::db::schema::firmware::dsl::*;
-
-//     let mut in_name: Option<String> = None;
-//     let mut in_version: Option<String> = None;
-//     let mut in_file_bytes: Option<Vec<u8>> = None;
-
-//     while let Some(field) = multipart.next_field().await.unwrap_or(None) {
-//         let field_name = field.name().unwrap_or("").to_string();
-//         match field_name.as_str() {
-//             "name" => {
-//                 in_name = match field.text().await {
-//                     Ok(opt) => Some(opt),
-//                     Err(_) => None,
-//                 };
-//             }
-//             "version" => {
-//                 in_version = match field.text().await {
-//                     Ok(opt) => Some(opt),
-//                     Err(_) => None,
-//                 };
-//             }
-//             "file" => {
-//                 in_file_bytes = match field.bytes().await {
-//                     Ok(opt) => Some(opt.to_vec()),
-//                     Err(_) => None,
-//                 };
-//             }
-//             _ => {}
-//         }
-//     }
-
-//     // Basic validation
-//     if in_name.is_some() {
-//         if in_name.clone().unwrap().len() > 100 {
-//             return Err(rest::error::client_error(
-//                 StatusCode::BAD_REQUEST,
-//                 "name too long (max 100)".to_string(),
-//             ));
-//         }
-//     };
-
-//     if in_version.is_some() {
-//         if in_version.clone().unwrap().len() > 100 {
-//             return Err(rest::error::client_error(
-//                 StatusCode::BAD_REQUEST,
-//                 "version too long (max 100)".to_string(),
-//             ));
-//         }
-//     };
-
-//     let mut updated_firmware = UpdateFirmware {
-//         name: in_name,
-//         version: in_version,
-//         file_id: None,
-//         size: None,
-//         sha256: None,
-//     };
-
-//     let mut base_path = api_config.data_storage_location;
-//     base_path.push("firmware");
-//     let mut new_path: Option<PathBuf> = None;
-//     if in_file_bytes.is_some() {
-//         let file = in_file_bytes.unwrap();
-//         let in_size = file.len() as i64;
-//         let in_sha256 = {
-//             let mut hasher = Sha256::new();
-//             hasher.update(&file);
-//             format!("{:x}", hasher.finalize())
-//         };
-
-//         updated_firmware.file_id = Some(Uuid::new_v4().to_string());
-//         updated_firmware.size = Some(in_size);
-//         updated_firmware.sha256 = Some(in_sha256);
-
-//         let safe_name = format!("{}.bin", updated_firmware.file_id.clone().unwrap());
-//         fs::create_dir_all(&base_path)
-//             .await
-//             .map_err(rest::error::internal_error)?;
-//         let mut path = base_path.clone();
-//         path.push(&safe_name);
-//         fs::write(&path, &file)
-//             .await
-//             .map_err(rest::error::internal_error)?;
-//         new_path = Some(path);
-//     };
-
-//     let mut conn = match api_config.shared_pool.get().await {
-//         Ok(c) => c,
-//         Err(e) => {
-//             if new_path.is_some() {
-//                 let _ = fs::remove_file(&new_path.unwrap()).await;
-//             }
-//             return Err(internal_error(e));
-//         }
-//     };
-
-//     let changedset = updated_firmware.clone();
-//     let old_base = base_path.clone();
-//     let tx_result: Result<(Firmware, Option<PathBuf>), diesel::result::Error> = conn
-//         .transaction::<(Firmware, Option<PathBuf>), diesel::result::Error, _>(|mut conn| {
-//             Box::pin(async move {
-//                 let mut old_path: Option<PathBuf> = None;
-
-//                 if changedset.file_id.is_some() {
-//                     let old: Firmware = diesel::QueryDsl::for_update(
-//                         firmware
-//                             .select(Firmware::as_select())
-//                             .filter(id.eq(path_id)),
-//                     )
-//                     .first(&mut conn)
-//                     .await?;
-
-//                     let old_safe_name = format!("{}.bin", old.file_id);
-//                     let mut temp_path = old_base;
-//                     temp_path.push(&old_safe_name);
-//                     old_path = Some(temp_path);
-//                 }
-
-//                 let new_firmware: Firmware = diesel::update(firmware.find(path_id))
-//                     .set(&changedset)
-//                     .returning(Firmware::as_returning())
-//                     .get_result(&mut conn)
-//                     .await?;
-
-//                 Ok((new_firmware, old_path))
-//             })
-//         })
-//         .await;
-//     match tx_result {
-//         Ok((new_fw, old_path)) => {
-//             if let Some(p) = old_path {
-//                 let _ = fs::remove_file(&p).await; // best-effort
-//             }
-//             return Ok((StatusCode::OK, Json(new_fw)));
-//         }
-//         Err(diesel::result::Error::NotFound) => {
-//             if new_path.is_some() {
-//                 let _ = fs::remove_file(&new_path.unwrap()).await;
-//             }
-//             return Err(rest::error::client_error(
-//                 axum::http::StatusCode::NOT_FOUND,
-//                 format!("firmware {} not found", path_id),
-//             ));
-//         }
-//         Err(diesel::result::Error::DatabaseError(kind, info)) => {
-//             // Handle uniqueness violation nicely (if you have a unique index on name)
-//             if kind == DatabaseErrorKind::UniqueViolation {
-//                 if new_path.is_some() {
-//                     let _ = fs::remove_file(&new_path.unwrap()).await;
-//                 }
-//                 return Err(client_error(
-//                     StatusCode::CONFLICT,
-//                     "firmware with this name and version already exists".to_string(),
-//                 ));
-//             } else {
-//                 if new_path.is_some() {
-//                     let _ = fs::remove_file(&new_path.unwrap()).await;
-//                 }
-//                 let error = diesel::result::Error::DatabaseError(kind, info);
-//                 return Err(internal_error(error));
-//             }
-//         }
-//         Err(e) => {
-//             if new_path.is_some() {
-//                 let _ = fs::remove_file(&new_path.unwrap()).await;
-//             }
-//             return Err(internal_error(e));
-//         }
-//     };
-// }
+#[axum::debug_handler]
+pub async fn update_firmware(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+    mut multipart: Multipart,
+) -> Result<Json<Firmware>, rest::error::ApiError> {
+    use crate::db::schema::firmware::dsl::*;
+
+    let mut base_path = api_config.data_storage_location;
+    base_path.push("firmware");
+    fs::create_dir_all(&base_path)
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let mut form =
+        FirmwareForm::read(&mut multipart, &base_path, api_config.max_firmware_size).await?;
+
+    // Basic validation, optional fields sent empty are removed
+    let metadata = form.metadata()?;
+    if let Some(in_name) = &form.name {
+        validate_label("name", in_name)?;
+    }
+    if let Some(in_version) = &form.version {
+        validate_label("version", in_version)?;
+    }
+
+    let mut changes = UpdateFirmware {
+        name: form.name,
+        version_sort_key: form.version.as_deref().map(firmware_version::sort_key),
+        version: form.version,
+        description: metadata.description,
+        file_id: None,
+        size: None,
+        sha256: None,
+        release_notes: metadata.release_notes,
+        build_timestamp: metadata.build_timestamp,
+        git_commit: metadata.git_commit,
+        channel: metadata.channel,
+    };
+    let (upload, new_path) = match form.file {
+        Some((_, 0, _)) => {
+            return Err(rest::error::client_error(
+                StatusCode::BAD_REQUEST,
+                "firmware file cannot be empty".to_string(),
+            ));
+        }
+        Some((upload, in_size, in_sha256)) => {
+            let new_file_id = Uuid::new_v4().to_string();
+            let path = base_path.join(format!("{}.bin", new_file_id));
+            changes.file_id = Some(new_file_id);
+            changes.size = Some(in_size);
+            changes.sha256 = Some(in_sha256);
+            (Some(upload), Some(path))
+        }
+        None => (None, None),
+    };
+    if changes.name.is_none()
+        && changes.version.is_none()
+        && changes.description.is_none()
+        && changes.release_notes.is_none()
+        && changes.build_timestamp.is_none()
+        && changes.git_commit.is_none()
+        && changes.channel.is_none()
+        && upload.is_none()
+    {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "nothing to update".to_string(),
+        ));
+    }
+
+    let mut conn = match api_config.shared_pool.get().await {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(rest::error::internal_error(e));
+        }
+    };
+
+    let result: Result<(Firmware, Firmware), rest::error::TransactionError> = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            let changes = &changes;
+            let new_path = &new_path;
+            Box::pin(async move {
+                // Blocks devices from being pointed to the firmware until the file is replaced
+                let before: Firmware = diesel::QueryDsl::for_update(
+                    firmware.find(path_id).select(Firmware::as_select()),
+                )
+                .first(conn)
+                .await?;
+                if upload.is_some() {
+                    let referencing: i64 = diesel::QueryDsl::count(
+                        schema::device::table.filter(
+                            schema::device::firmware
+                                .eq(path_id)
+                                .or(schema::device::desired_firmware.eq(path_id)),
+                        ),
+                    )
+                    .get_result(conn)
+                    .await?;
+                    if referencing > 0 {
+                        return Err(rest::error::client_error(
+                            StatusCode::CONFLICT,
+                            format!(
+                                "firmware {} is referenced by {} device(s), its file cannot be replaced",
+                                path_id, referencing
+                            ),
+                        )
+                        .into());
+                    }
+                    // Later waves would install another binary than the earlier ones
+                    let campaigns: i64 = diesel::QueryDsl::count(
+                        schema::rollout_campaign::table.filter(
+                            schema::rollout_campaign::target_firmware
+                                .eq(path_id)
+                                .and(schema::rollout_campaign::status.eq_any([
+                                    CampaignStatus::Running,
+                                    CampaignStatus::Paused,
+                                ])),
+                        ),
+                    )
+                    .get_result(conn)
+                    .await?;
+                    if campaigns > 0 {
+                        return Err(rest::error::client_error(
+                            StatusCode::CONFLICT,
+                            format!(
+                                "firmware {} is the target of {} running or paused rollout campaign(s), its file cannot be replaced",
+                                path_id, campaigns
+                            ),
+                        )
+                        .into());
+                    }
+                }
+                let updated: Firmware = diesel::update(firmware.find(path_id))
+                    .set(changes)
+                    .returning(Firmware::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("update", "firmware", path_id)
+                        .before(&before)
+                        .after(&updated),
+                )
+                .await?;
+                // Last step, a failed rename rolls back the update
+                if let (Some(upload), Some(path)) = (upload, new_path) {
+                    upload.persist(path).await.map_err(|e| {
+                        rest::error::TransactionError::from(rest::error::internal_error(e))
+                    })?;
+                }
+                Ok((before, updated))
+            })
+        })
+        .await;
+
+    let remove_new_file = || async {
+        if let Some(path) = &new_path {
+            let _ = fs::remove_file(path).await;
+        }
+    };
+    match result {
+        Ok((before, updated)) => {
+            if new_path.is_some() {
+                let old_path = base_path.join(format!("{}.bin", before.file_id));
+                if let Err(e) = fs::remove_file(&old_path).await {
+                    warn!(
+                        "Failed to remove replaced firmware file {:?}: {}",
+                        old_path, e
+                    );
+                }
+            }
+            Ok(Json(updated))
+        }
+        Err(rest::error::TransactionError::Db(diesel::result::Error::NotFound)) => {
+            Err(rest::error::client_error(
+                StatusCode::NOT_FOUND,
+                format!("firmware {} not found", path_id),
+            ))
+        }
+        Err(rest::error::TransactionError::Db(diesel::result::Error::DatabaseError(
+            kind,
+            info,
+        ))) => {
+            if kind == DatabaseErrorKind::UniqueViolation {
+                Err(rest::error::client_error(
+                    StatusCode::CONFLICT,
+                    "firmware with this name and version already exists".to_string(),
+                ))
+            } else {
+                // The commit may have failed after the file was moved into place
+                remove_new_file().await;
+                let error = diesel::result::Error::DatabaseError(kind, info);
+                Err(rest::error::internal_error(error))
+            }
+        }
+        Err(rest::error::TransactionError::Db(err)) => {
+            remove_new_file().await;
+            Err(rest::error::internal_error(err))
+        }
+        Err(rest::error::TransactionError::Api(api)) => Err(api),
+    }
+}
 
 #[axum::debug_handler]
 pub async fn get_firmware(
@@ -388,6 +754,7 @@ pub async fn get_firmware(
 #[axum::debug_handler]
 pub async fn delete_firmware(
     State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
     Path(path_id): Path<i32>,
 ) -> Result<Json<Firmware>, rest::error::ApiError> {
     use crate::db::schema::firmware::dsl::*;
@@ -399,11 +766,22 @@ pub async fn delete_firmware(
         .await
         .map_err(rest::error::internal_error)?;
 
-    let deleted: Result<Firmware, diesel::result::Error> =
-        diesel::delete(firmware.filter(id.eq(path_id)))
-            .returning(Firmware::as_returning())
-            .get_result(&mut conn)
-            .await;
+    let deleted: Result<Firmware, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let row: Firmware = diesel::delete(firmware.filter(id.eq(path_id)))
+                    .returning(Firmware::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor.event("delete", "firmware", path_id).before(&row),
+                )
+                .await?;
+                Ok(row)
+            })
+        })
+        .await;
 
     match deleted {
         Ok(row) => {
@@ -428,11 +806,10 @@ pub async fn delete_firmware(
     }
 }
 
-#[axum::debug_handler]
-pub async fn get_firmware_file_metadata(
-    State(api_config): State<rest::RestApiConfig>,
-    Path(path_id): Path<i32>,
-) -> Result<impl IntoResponse, rest::error::ApiError> {
+async fn load_firmware(
+    api_config: &rest::RestApiConfig,
+    path_id: i32,
+) -> Result<Firmware, rest::error::ApiError> {
     use crate::db::schema::firmware::dsl::*;
 
     let mut conn = api_config
@@ -441,145 +818,190 @@ pub async fn get_firmware_file_metadata(
         .get_owned()
         .await
         .map_err(rest::error::internal_error)?;
-    let fw = match firmware
+    match firmware
         .select(Firmware::as_select())
         .filter(id.eq(path_id))
         .first(&mut conn)
         .await
     {
-        Ok(fw) => fw,
-        Err(diesel::result::Error::NotFound) => {
-            return Err(rest::error::client_error(
-                StatusCode::NOT_FOUND,
-                format!("firmware {} not found", path_id),
-            ));
-        }
-        Err(e) => {
-            return Err(rest::error::internal_error(e));
-        }
-    };
+        Ok(fw) => Ok(fw),
+        Err(diesel::result::Error::NotFound) => Err(rest::error::client_error(
+            StatusCode::NOT_FOUND,
+            format!("firmware {} not found", path_id),
+        )),
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+fn header_value(value: String) -> Result<HeaderValue, rest::error::ApiError> {
+    HeaderValue::from_str(&value).map_err(rest::error::internal_error)
+}
 
-    // Prepare headers
+/// Headers of every download response except 304 Not Modified
+fn download_headers(fw: &Firmware) -> Result<HeaderMap, rest::error::ApiError> {
     let mut headers = HeaderMap::new();
     headers.insert(
-        "Content-Type",
+        header::CONTENT_TYPE,
         HeaderValue::from_static("application/octet-stream"),
     );
     // Suggest a filename (customize as needed)
     let filename = format!("{}-{}-{}.bin", fw.name, fw.version, fw.id);
     headers.insert(
-        "Content-Disposition",
-        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)).map_err(|_| {
-            rest::error::internal_error(rest::error::client_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to set Content-Disposition header".to_string(),
-            ))
-        })?,
+        header::CONTENT_DISPOSITION,
+        header_value(format!("attachment; filename=\"{}\"", filename))?,
     );
-    headers.insert(
-        "ETag",
-        HeaderValue::from_str(&format!("\"{}\"", fw.sha256)).map_err(|_| {
-            rest::error::internal_error(rest::error::client_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to set ETag header".to_string(),
-            ))
-        })?,
-    );
-    //headers.insert("Accept-Ranges", HeaderValue::from_static("bytes"));
-    headers.insert(
-        "Content-Length",
-        HeaderValue::from_str(&fw.size.to_string()).map_err(|_| {
-            rest::error::internal_error(rest::error::client_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to set Content-Length header".to_string(),
-            ))
-        })?,
-    );
-
-    Ok((headers, Body::empty()))
+    headers.insert(header::ETAG, header_value(format!("\"{}\"", fw.sha256))?);
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    Ok(headers)
 }
 
-#[axum::debug_handler]
-pub async fn get_firmware_file(
-    State(api_config): State<rest::RestApiConfig>,
-    Path(path_id): Path<i32>,
-) -> Result<impl IntoResponse, rest::error::ApiError> {
-    use crate::db::schema::firmware::dsl::*;
+fn not_modified(etag: String) -> Result<Response, rest::error::ApiError> {
+    Ok((
+        StatusCode::NOT_MODIFIED,
+        [(header::ETAG, header_value(etag)?)],
+    )
+        .into_response())
+}
 
-    let mut conn = api_config
-        .shared_pool
-        .clone()
-        .get_owned()
+/// Bytes `first` to `last` (inclusive) of the file at `path`
+async fn open_section(
+    path: &std::path::Path,
+    first: u64,
+    last: u64,
+) -> Result<tokio::io::Take<fs::File>, rest::error::ApiError> {
+    let mut file = fs::File::open(path)
         .await
         .map_err(rest::error::internal_error)?;
-    let fw = match firmware
-        .select(Firmware::as_select())
-        .filter(id.eq(path_id))
-        .first(&mut conn)
+    file.seek(SeekFrom::Start(first))
         .await
-    {
-        Ok(fw) => fw,
-        Err(diesel::result::Error::NotFound) => {
-            return Err(rest::error::client_error(
-                StatusCode::NOT_FOUND,
-                format!("firmware {} not found", path_id),
-            ));
-        }
-        Err(e) => {
-            return Err(rest::error::internal_error(e));
+        .map_err(rest::error::internal_error)?;
+    Ok(file.take(last - first + 1))
+}
+
+/// Streams `reader`, counting what is sent as firmware bytes served
+fn counted_body<R: AsyncRead + Send + 'static>(served: prometheus::IntCounter, reader: R) -> Body {
+    Body::from_stream(ReaderStream::new(reader).map(move |chunk| {
+        if let Ok(bytes) = &chunk {
+            served.inc_by(bytes.len() as u64);
         }
-    };
+        chunk
+    }))
+}
+
+#[axum::debug_handler]
+pub async fn get_firmware_file_metadata(
+    State(api_config): State<rest::RestApiConfig>,
+    Path(path_id): Path<i32>,
+    request_headers: HeaderMap,
+) -> Result<Response, rest::error::ApiError> {
+    let fw = load_firmware(&api_config, path_id).await?;
+    let etag = format!("\"{}\"", fw.sha256);
+    if range::not_modified(&request_headers, &etag) {
+        return not_modified(etag);
+    }
+
+    let mut headers = download_headers(&fw)?;
+    headers.insert(header::CONTENT_LENGTH, header_value(fw.size.to_string())?);
+    Ok((headers, Body::empty()).into_response())
+}
 
+#[axum::debug_handler]
+pub async fn get_firmware_file(
+    State(api_config): State<rest::RestApiConfig>,
+    Path(path_id): Path<i32>,
+    request_headers: HeaderMap,
+) -> Result<Response, rest::error::ApiError> {
+    let fw = load_firmware(&api_config, path_id).await?;
+    let etag = format!("\"{}\"", fw.sha256);
+    if range::not_modified(&request_headers, &etag) {
+        return not_modified(etag);
+    }
+
+    let served = api_config
+        .metrics
+        .firmware_bytes_served
+        .with_label_values(&["rest"]);
     let mut path = api_config.data_storage_location;
     let safe_name = format!("{}.bin", fw.file_id);
     path.push("firmware");
     path.push(&safe_name);
 
-    // Open file
-    let file = fs::File::open(&path)
-        .await
-        .map_err(rest::error::internal_error)?;
-
-    // Stream the file to the client
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
-
-    // Prepare headers
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "Content-Type",
-        HeaderValue::from_static("application/octet-stream"),
-    );
-    // Suggest a filename (customize as needed)
-    let filename = format!("{}-{}-{}.bin", fw.name, fw.version, fw.id);
-    headers.insert(
-        "Content-Disposition",
-        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)).map_err(|_| {
-            rest::error::internal_error(rest::error::client_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to set Content-Disposition header".to_string(),
-            ))
-        })?,
-    );
-    headers.insert(
-        "ETag",
-        HeaderValue::from_str(&format!("\"{}\"", fw.sha256)).map_err(|_| {
-            rest::error::internal_error(rest::error::client_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to set ETag header".to_string(),
-            ))
-        })?,
-    );
-    //headers.insert("Accept-Ranges", HeaderValue::from_static("bytes"));
-    headers.insert(
-        "Content-Length",
-        HeaderValue::from_str(&fw.size.to_string()).map_err(|_| {
-            rest::error::internal_error(rest::error::client_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to set Content-Length header".to_string(),
-            ))
-        })?,
-    );
-
-    Ok((headers, body))
+    let size = fw.size as u64;
+    let mut headers = download_headers(&fw)?;
+    match range::requested_ranges(&request_headers, &etag, size) {
+        RangeRequest::Full => {
+            // Stream the file to the client
+            let file = fs::File::open(&path)
+                .await
+                .map_err(rest::error::internal_error)?;
+            headers.insert(header::CONTENT_LENGTH, header_value(size.to_string())?);
+            Ok((headers, counted_body(served, file)).into_response())
+        }
+        RangeRequest::Unsatisfiable => Ok((
+            [(
+                header::CONTENT_RANGE,
+                header_value(format!("bytes */{}", size))?,
+            )],
+            rest::error::client_error(
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                format!("range not satisfiable, firmware has {} bytes", size),
+            ),
+        )
+            .into_response()),
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let (first, last) = ranges[0];
+            let section = open_section(&path, first, last).await?;
+            headers.insert(
+                header::CONTENT_RANGE,
+                header_value(format!("bytes {}-{}/{}", first, last, size))?,
+            );
+            headers.insert(
+                header::CONTENT_LENGTH,
+                header_value((last - first + 1).to_string())?,
+            );
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                headers,
+                counted_body(served, section),
+            )
+                .into_response())
+        }
+        RangeRequest::Partial(ranges) => {
+            // multipart/byteranges, every part is preceded by its own headers
+            let boundary = Uuid::new_v4().simple().to_string();
+            let mut body: Pin<Box<dyn AsyncRead + Send>> = Box::pin(tokio::io::empty());
+            let mut length = 0;
+            for (i, (first, last)) in ranges.into_iter().enumerate() {
+                let part_headers = format!(
+                    "{}--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    if i == 0 { "" } else { "\r\n" },
+                    boundary,
+                    first,
+                    last,
+                    size
+                );
+                length += part_headers.len() as u64 + last - first + 1;
+                let section = open_section(&path, first, last).await?;
+                body = Box::pin(
+                    body.chain(Cursor::new(part_headers.into_bytes()))
+                        .chain(section),
+                );
+            }
+            let closing = format!("\r\n--{}--\r\n", boundary);
+            length += closing.len() as u64;
+            body = Box::pin(body.chain(Cursor::new(closing.into_bytes())));
+
+            headers.insert(
+                header::CONTENT_TYPE,
+                header_value(format!("multipart/byteranges; boundary={}", boundary))?,
+            );
+            headers.insert(header::CONTENT_LENGTH, header_value(length.to_string())?);
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                headers,
+                counted_body(served, body),
+            )
+                .into_response())
+        }
+    }
 }
@@ -0,0 +1,135 @@
+use crate::api::rest;
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use diesel_async::RunQueryDsl;
+use log::warn;
+use serde::Serialize;
+use std::fmt::Display;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// Upper bound of each check, the pool may otherwise wait for a connection much longer
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub checks: ReadinessChecks,
+}
+
+/// `ok` or what failed
+#[derive(Serialize, Debug)]
+pub struct ReadinessChecks {
+    pub database: String,
+    pub data_directory: String,
+    pub migrations: String,
+    pub cbor_api: String,
+}
+
+/// The process is up and serving requests
+#[axum::debug_handler]
+pub async fn healthz() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Whether the dependencies work, served without authentication like `healthz`
+#[axum::debug_handler]
+pub async fn readyz(State(api_config): State<rest::RestApiConfig>) -> impl IntoResponse {
+    let (database, data_directory, migrations) = tokio::join!(
+        with_timeout("database", check_database(&api_config)),
+        with_timeout("data_directory", check_data_directory(&api_config)),
+        with_timeout("migrations", check_migrations(&api_config)),
+    );
+    let cbor_api = if api_config.cbor_health.is_running() {
+        Ok(())
+    } else {
+        Err(failed("cbor_api", "UDP loop ended", "stopped"))
+    };
+
+    let ready =
+        database.is_ok() && data_directory.is_ok() && migrations.is_ok() && cbor_api.is_ok();
+    let outcome = |result: Result<(), String>| result.err().unwrap_or_else(|| "ok".to_string());
+    let readiness = Readiness {
+        status: if ready { "ready" } else { "not_ready" },
+        checks: ReadinessChecks {
+            database: outcome(database),
+            data_directory: outcome(data_directory),
+            migrations: outcome(migrations),
+            cbor_api: outcome(cbor_api),
+        },
+    };
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+/// Logs the cause and returns `summary` for the response, which only names what failed
+fn failed(check: &str, cause: impl Display, summary: &str) -> String {
+    warn!("Readiness check {} failed: {}", check, cause);
+    summary.to_string()
+}
+
+async fn with_timeout(
+    check: &str,
+    future: impl Future<Output = Result<(), String>>,
+) -> Result<(), String> {
+    tokio::time::timeout(CHECK_TIMEOUT, future)
+        .await
+        .unwrap_or_else(|_| Err(failed(check, "no result in time", "timeout")))
+}
+
+async fn check_database(api_config: &rest::RestApiConfig) -> Result<(), String> {
+    let mut conn = api_config
+        .shared_pool
+        .get()
+        .await
+        .map_err(|e| failed("database", e, "unavailable"))?;
+    diesel::sql_query("SELECT 1")
+        .execute(&mut conn)
+        .await
+        .map_err(|e| failed("database", e, "unavailable"))?;
+    Ok(())
+}
+
+/// Firmware uploads are written below the data directory
+async fn check_data_directory(api_config: &rest::RestApiConfig) -> Result<(), String> {
+    let path = api_config
+        .data_storage_location
+        .join(format!(".readyz-{}", Uuid::new_v4().simple()));
+    let written = async {
+        let mut file = tokio::fs::File::create(&path).await?;
+        file.write_all(b"ok").await?;
+        file.sync_all().await
+    }
+    .await;
+    let removed = tokio::fs::remove_file(&path).await;
+    written.and(removed).map_err(|e| {
+        failed(
+            "data_directory",
+            format!("{:?}: {}", path, e),
+            "not writable",
+        )
+    })
+}
+
+async fn check_migrations(api_config: &rest::RestApiConfig) -> Result<(), String> {
+    let mut conn = api_config
+        .shared_pool
+        .get()
+        .await
+        .map_err(|e| failed("migrations", e, "unavailable"))?;
+    let pending = crate::db::migrations::pending_migrations(&mut conn)
+        .await
+        .map_err(|e| failed("migrations", e, "unavailable"))?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let cause = format!("pending: {}", pending.join(", "));
+    Err(failed("migrations", cause, "pending"))
+}
@@ -0,0 +1,451 @@
+use crate::api::rest::api_key::Principal;
+use crate::db::models::ApiKeyRole;
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::body::Bytes;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Minimum time between reloads of the key set for tokens with an unknown key id
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_DOCUMENT_SIZE: usize = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum JwtError {
+    #[error("failed to read JWKS file {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("invalid JSON document: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+    #[error("failed to fetch {0}: {1}")]
+    Fetch(String, String),
+    #[error("no key for key id {0:?}")]
+    UnknownKey(Option<String>),
+    #[error("algorithm {0:?} not allowed")]
+    Algorithm(Algorithm),
+    #[error(transparent)]
+    Token(#[from] jsonwebtoken::errors::Error),
+    #[error("token has no subject")]
+    MissingSubject,
+    #[error("token of \"{0}\" grants no role")]
+    NoRole(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    /// Local key set, keys are discovered from the issuer if unset
+    pub jwks_file: Option<PathBuf>,
+    /// Expected `iss` claim
+    pub issuer: Option<String>,
+    /// Expected `aud` claim, not validated if unset
+    pub audience: Option<String>,
+    /// Claim holding the role names, nested claims are separated by `.`
+    pub role_claim: String,
+}
+
+/// Verifies bearer tokens with the keys of a local or discovered JWKS
+pub struct JwtValidator {
+    config: JwtConfig,
+    keys: RwLock<Arc<JwkSet>>,
+    last_refresh: tokio::sync::Mutex<Instant>,
+}
+
+#[derive(serde::Deserialize)]
+struct OpenIdConfiguration {
+    jwks_uri: String,
+}
+
+impl JwtValidator {
+    pub async fn new(config: JwtConfig) -> Result<Self, JwtError> {
+        let keys = load_keys(&config).await?;
+        Ok(JwtValidator {
+            config,
+            keys: RwLock::new(Arc::new(keys)),
+            last_refresh: tokio::sync::Mutex::new(Instant::now()),
+        })
+    }
+
+    /// The granted role is the highest one named by the role claim
+    pub async fn authenticate(&self, token: &str) -> Result<Principal, JwtError> {
+        let header = jsonwebtoken::decode_header(token)?;
+        // Only asymmetric algorithms, a public key must never be usable as HMAC secret
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(JwtError::Algorithm(header.alg));
+        }
+
+        let jwk = match self.find_key(header.kid.as_deref()) {
+            Some(jwk) => jwk,
+            None => {
+                self.refresh().await?;
+                self.find_key(header.kid.as_deref())
+                    .ok_or(JwtError::UnknownKey(header.kid.clone()))?
+            }
+        };
+        if let Some(key_algorithm) = jwk.common.key_algorithm
+            && key_algorithm.to_string() != format!("{:?}", header.alg)
+        {
+            return Err(JwtError::Algorithm(header.alg));
+        }
+        let key = DecodingKey::from_jwk(&jwk)?;
+
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let claims = jsonwebtoken::decode::<Map<String, Value>>(token, &key, &validation)?.claims;
+
+        let subject = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .ok_or(JwtError::MissingSubject)?;
+        let role = roles(&claims, &self.config.role_claim)
+            .into_iter()
+            .max()
+            .ok_or_else(|| JwtError::NoRole(subject.to_string()))?;
+        Ok(Principal {
+            name: format!("jwt:{}", subject),
+            role,
+        })
+    }
+
+    fn find_key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        match kid {
+            Some(kid) => keys.find(kid).cloned(),
+            // Tokens without key id are only accepted if there is no choice
+            None if keys.keys.len() == 1 => keys.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    async fn refresh(&self) -> Result<(), JwtError> {
+        let mut last_refresh = self.last_refresh.lock().await;
+        if last_refresh.elapsed() < JWKS_REFRESH_INTERVAL {
+            return Ok(());
+        }
+        *last_refresh = Instant::now();
+        let keys = load_keys(&self.config).await?;
+        *self.keys.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(keys);
+        Ok(())
+    }
+}
+
+async fn load_keys(config: &JwtConfig) -> Result<JwkSet, JwtError> {
+    let mut keys: JwkSet = match (&config.jwks_file, &config.issuer) {
+        (Some(path), _) => {
+            let content =
+                std::fs::read(path).map_err(|e| JwtError::Io(path.display().to_string(), e))?;
+            serde_json::from_slice(&content)?
+        }
+        (None, Some(issuer)) => {
+            let discovery_url = format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            );
+            let openid_configuration: OpenIdConfiguration = fetch_json(&discovery_url).await?;
+            fetch_json(&openid_configuration.jwks_uri).await?
+        }
+        (None, None) => JwkSet { keys: Vec::new() },
+    };
+    keys.keys
+        .retain(|jwk| !matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_)));
+    Ok(keys)
+}
+
+async fn fetch_json<T: DeserializeOwned>(url: &str) -> Result<T, JwtError> {
+    let fetch_error = |e: String| JwtError::Fetch(url.to_string(), e);
+    let uri: hyper::Uri = url.parse().map_err(|e| fetch_error(format!("{}", e)))?;
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_provider_and_webpki_roots(Arc::new(rustls::crypto::ring::default_provider()))
+        .map_err(|e| fetch_error(e.to_string()))?
+        .https_only()
+        .enable_http1()
+        .build();
+    let client: Client<_, Empty<Bytes>> = Client::builder(TokioExecutor::new()).build(https);
+
+    let response = tokio::time::timeout(FETCH_TIMEOUT, client.get(uri))
+        .await
+        .map_err(|_| fetch_error("timeout".to_string()))?
+        .map_err(|e| fetch_error(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(fetch_error(format!("status {}", response.status())));
+    }
+    let body = Limited::new(response.into_body(), MAX_DOCUMENT_SIZE)
+        .collect()
+        .await
+        .map_err(|e| fetch_error(e.to_string()))?
+        .to_bytes();
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Roles named by the claim `path`, which is a list or a space separated string
+fn roles(claims: &Map<String, Value>, path: &str) -> Vec<ApiKeyRole> {
+    let mut parts = path.split('.');
+    let Some(mut value) = parts.next().and_then(|first| claims.get(first)) else {
+        return Vec::new();
+    };
+    for part in parts {
+        match value.get(part) {
+            Some(v) => value = v,
+            None => return Vec::new(),
+        }
+    }
+    let names: Vec<&str> = match value {
+        Value::String(s) => s.split_whitespace().collect(),
+        Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    names
+        .into_iter()
+        .filter_map(|name| serde_json::from_value(Value::String(name.to_string())).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine as _;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const ISSUER: &str = "https://idp.example.com";
+    const AUDIENCE: &str = "firmups";
+
+    struct TestKey {
+        kid: &'static str,
+        alg: Algorithm,
+        key_pair: rcgen::KeyPair,
+    }
+
+    impl TestKey {
+        fn new(kid: &'static str, alg: Algorithm) -> Self {
+            let signature_algorithm = match alg {
+                Algorithm::ES256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+                Algorithm::ES384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+                _ => unreachable!("only EC keys can be generated"),
+            };
+            TestKey {
+                kid,
+                alg,
+                key_pair: rcgen::KeyPair::generate_for(signature_algorithm).unwrap(),
+            }
+        }
+
+        fn jwk(&self) -> Value {
+            // Uncompressed point 0x04 || x || y
+            let point = &self.key_pair.public_key_raw()[1..];
+            let (x, y) = point.split_at(point.len() / 2);
+            let crv = match self.alg {
+                Algorithm::ES256 => "P-256",
+                _ => "P-384",
+            };
+            json!({
+                "kty": "EC",
+                "kid": self.kid,
+                "alg": format!("{:?}", self.alg),
+                "crv": crv,
+                "x": URL_SAFE_NO_PAD.encode(x),
+                "y": URL_SAFE_NO_PAD.encode(y),
+            })
+        }
+
+        fn sign(&self, claims: &Value) -> String {
+            let mut header = Header::new(self.alg);
+            header.kid = Some(self.kid.to_string());
+            let key = EncodingKey::from_ec_pem(self.key_pair.serialize_pem().as_bytes()).unwrap();
+            jsonwebtoken::encode(&header, claims, &key).unwrap()
+        }
+    }
+
+    fn claims(roles: Value) -> Value {
+        json!({
+            "sub": "alice",
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "exp": chrono::Utc::now().timestamp() + 300,
+            "realm_access": { "roles": roles },
+        })
+    }
+
+    /// Validator with a local JWKS, so that nothing is fetched
+    async fn validator(jwks: Value) -> JwtValidator {
+        let path = std::env::temp_dir().join(format!("firmups-jwks-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, jwks.to_string()).unwrap();
+        let validator = JwtValidator::new(JwtConfig {
+            jwks_file: Some(path.clone()),
+            issuer: Some(ISSUER.to_string()),
+            audience: Some(AUDIENCE.to_string()),
+            role_claim: "realm_access.roles".to_string(),
+        })
+        .await
+        .unwrap();
+        let _ = std::fs::remove_file(path);
+        validator
+    }
+
+    async fn setup() -> (TestKey, TestKey, JwtValidator) {
+        let es256 = TestKey::new("es256", Algorithm::ES256);
+        let es384 = TestKey::new("es384", Algorithm::ES384);
+        let validator = validator(json!({ "keys": [es256.jwk(), es384.jwk()] })).await;
+        (es256, es384, validator)
+    }
+
+    #[tokio::test]
+    async fn accepts_valid_tokens() {
+        let (es256, es384, validator) = setup().await;
+        for key in [&es256, &es384] {
+            let principal = validator
+                .authenticate(&key.sign(&claims(json!(["OPERATOR"]))))
+                .await
+                .unwrap();
+            assert_eq!(principal.name, "jwt:alice");
+            assert_eq!(principal.role, ApiKeyRole::Operator);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_token() {
+        let (es256, _, validator) = setup().await;
+        let mut claims = claims(json!(["ADMIN"]));
+        claims["exp"] = json!(chrono::Utc::now().timestamp() - 3600);
+        assert!(matches!(
+            validator.authenticate(&es256.sign(&claims)).await,
+            Err(JwtError::Token(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_issuer_or_audience() {
+        let (es256, _, validator) = setup().await;
+        let mut wrong_issuer = claims(json!(["ADMIN"]));
+        wrong_issuer["iss"] = json!("https://other.example.com");
+        assert!(matches!(
+            validator.authenticate(&es256.sign(&wrong_issuer)).await,
+            Err(JwtError::Token(_))
+        ));
+
+        let mut wrong_audience = claims(json!(["ADMIN"]));
+        wrong_audience["aud"] = json!("other");
+        assert!(matches!(
+            validator.authenticate(&es256.sign(&wrong_audience)).await,
+            Err(JwtError::Token(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_key_used_with_other_algorithm() {
+        let (_, _, validator) = setup().await;
+        let es384 = TestKey {
+            kid: "es256",
+            ..TestKey::new("es384", Algorithm::ES384)
+        };
+        assert!(matches!(
+            validator
+                .authenticate(&es384.sign(&claims(json!(["ADMIN"]))))
+                .await,
+            Err(JwtError::Algorithm(Algorithm::ES384))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_hmac_tokens_and_symmetric_keys() {
+        let secret = b"shared secret of at least 32 bytes";
+        let validator = validator(json!({ "keys": [{
+            "kty": "oct",
+            "kid": "oct",
+            "alg": "HS256",
+            "k": URL_SAFE_NO_PAD.encode(secret),
+        }] }))
+        .await;
+        assert!(validator.keys.read().unwrap().keys.is_empty());
+
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("oct".to_string());
+        let token = jsonwebtoken::encode(
+            &header,
+            &claims(json!(["ADMIN"])),
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap();
+        assert!(matches!(
+            validator.authenticate(&token).await,
+            Err(JwtError::Algorithm(Algorithm::HS256))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_key_id() {
+        let (_, _, validator) = setup().await;
+        let unknown = TestKey::new("unknown", Algorithm::ES256);
+        assert!(matches!(
+            validator.authenticate(&unknown.sign(&claims(json!(["ADMIN"])))).await,
+            Err(JwtError::UnknownKey(Some(kid))) if kid == "unknown"
+        ));
+    }
+
+    #[tokio::test]
+    async fn maps_highest_known_role() {
+        let (es256, _, validator) = setup().await;
+        let role = async |roles: Value| {
+            validator
+                .authenticate(&es256.sign(&claims(roles)))
+                .await
+                .map(|p| p.role)
+        };
+        assert_eq!(
+            role(json!(["READ_ONLY", "ADMIN", "OPERATOR"]))
+                .await
+                .unwrap(),
+            ApiKeyRole::Admin
+        );
+        assert_eq!(
+            role(json!("READ_ONLY OPERATOR")).await.unwrap(),
+            ApiKeyRole::Operator
+        );
+        assert_eq!(
+            role(json!(["superuser", "READ_ONLY"])).await.unwrap(),
+            ApiKeyRole::ReadOnly
+        );
+        assert!(matches!(
+            role(json!(["superuser"])).await,
+            Err(JwtError::NoRole(subject)) if subject == "alice"
+        ));
+        assert!(matches!(role(json!([])).await, Err(JwtError::NoRole(_))));
+
+        let mut no_role_claim = claims(json!(["ADMIN"]));
+        no_role_claim
+            .as_object_mut()
+            .unwrap()
+            .remove("realm_access");
+        assert!(matches!(
+            validator.authenticate(&es256.sign(&no_role_claim)).await,
+            Err(JwtError::NoRole(_))
+        ));
+    }
+
+    #[test]
+    fn roles_follow_nested_claim() {
+        let claims = json!({ "a": { "b": ["ADMIN"] }, "flat": "OPERATOR" });
+        let claims = claims.as_object().unwrap();
+        assert_eq!(roles(claims, "a.b"), vec![ApiKeyRole::Admin]);
+        assert_eq!(roles(claims, "flat"), vec![ApiKeyRole::Operator]);
+        assert!(roles(claims, "a.c").is_empty());
+        assert!(roles(claims, "a").is_empty());
+        assert!(roles(claims, "missing").is_empty());
+    }
+}
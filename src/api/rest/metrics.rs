@@ -0,0 +1,54 @@
+use crate::api::rest;
+use axum::extract::{MatchedPath, State};
+use axum::http::{Method, header};
+use axum::response::IntoResponse;
+
+/// Served without authentication, the metrics only contain counts and timings
+#[axum::debug_handler]
+pub async fn get_metrics(
+    State(api_config): State<rest::RestApiConfig>,
+) -> Result<impl IntoResponse, rest::error::ApiError> {
+    let body = api_config
+        .metrics
+        .encode()
+        .map_err(rest::error::internal_error)?;
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body))
+}
+
+/// Label of `method`, this layer runs before authentication so custom methods must not add series
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        Method::CONNECT => "CONNECT",
+        Method::TRACE => "TRACE",
+        _ => "OTHER",
+    }
+}
+
+/// Records the latency of every request by its route pattern, so that IDs do not become labels
+pub async fn track_request(
+    State(api_config): State<rest::RestApiConfig>,
+    req: axum::http::Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let method = method_label(req.method());
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let timer = std::time::Instant::now();
+    let response = next.run(req).await;
+    api_config
+        .metrics
+        .http_request_duration
+        .with_label_values(&[method, &route, response.status().as_str()])
+        .observe(timer.elapsed().as_secs_f64());
+    response
+}
@@ -1,26 +1,55 @@
-use axum::http::StatusCode;
+use crate::db::models::ApiKeyRole;
+use axum::http::{Method, StatusCode};
 use axum::response::{Html, IntoResponse};
-use log::{info, warn};
+use log::{debug, info, warn};
 use std::path::PathBuf;
 use std::{net::SocketAddr, sync::Arc};
 use tokio::net::TcpListener;
 use tokio::signal;
 
+pub mod api_key;
+pub mod audit;
+pub mod certificate_authority;
 mod device;
+mod device_group;
 mod device_key;
+mod device_tag;
 mod device_type;
 mod device_type_firmware;
 mod error;
+mod events;
 mod firmware;
+mod health;
+pub mod jwt;
+mod metrics;
+mod pagination;
+mod range;
+mod rollout_campaign;
 mod serde_helpers;
+pub mod tls;
+mod webhook;
 
 #[derive(Clone)]
 pub struct RestApiConfig {
     pub listen_address: SocketAddr,
     pub shared_pool: Arc<crate::DbPool>,
+    pub key_encryption_key: Arc<crate::db::key_encryption::KeyEncryptionKey>,
     pub max_firmware_size: usize,
     pub data_storage_location: PathBuf,
+    /// Bootstrap key with ADMIN role, further keys are stored in the database
     pub api_key: String,
+    pub key_export_enabled: bool,
+    pub certificate_authority: Option<Arc<certificate_authority::CertificateAuthority>>,
+    /// Bearer tokens are rejected if unset
+    pub jwt_validator: Option<Arc<jwt::JwtValidator>>,
+    /// Plain HTTP is served if unset
+    pub tls: Option<tls::TlsConfig>,
+    pub events: crate::api::events::EventBus,
+    pub metrics: crate::api::metrics::Metrics,
+    /// Whether the CBOR API is still receiving, for the readiness check
+    pub cbor_health: crate::api::cbor::CborApiHealth,
+    /// Cancelled on CTRL+C, ends streaming responses so that the graceful shutdown completes
+    pub shutdown: tokio_util::sync::CancellationToken,
 }
 
 pub struct RestApi {
@@ -30,39 +59,125 @@ pub struct RestApi {
 
 async fn api_key_mw(
     axum::extract::State(state): axum::extract::State<RestApiConfig>,
-    req: axum::http::Request<axum::body::Body>,
+    mut req: axum::http::Request<axum::body::Body>,
     next: axum::middleware::Next,
 ) -> axum::response::Response {
     let unauthorized = || {
         (
             StatusCode::UNAUTHORIZED,
-            [("www-authenticate", r#"ApiKey realm="api""#)],
-            "missing or invalid x-api-key",
+            [(
+                "www-authenticate",
+                r#"ApiKey realm="api", Bearer realm="api""#,
+            )],
+            "missing or invalid x-api-key or bearer token",
         )
             .into_response()
     };
 
+    let path = req
+        .extensions()
+        .get::<axum::extract::MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    let peer_opt: Option<SocketAddr> = req
+        .extensions()
+        .get::<axum::extract::ConnectInfo<SocketAddr>>()
+        .map(|ci| ci.0);
+
     let key = req.headers().get("x-api-key").and_then(|v| v.to_str().ok());
-    match key {
-        Some(k) if state.api_key == k => next.run(req).await,
-        _ => {
-            let peer_opt: Option<SocketAddr> = req
-                .extensions()
-                .get::<axum::extract::ConnectInfo<SocketAddr>>()
-                .map(|ci| ci.0);
-            if let Some(peer) = peer_opt {
+    let bearer = req
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let principal = if let Some(token) = bearer {
+        let Some(validator) = &state.jwt_validator else {
+            warn!("bearer token received but JWT authentication is not configured");
+            return unauthorized();
+        };
+        match validator.authenticate(token.trim()).await {
+            Ok(p) => Some(p),
+            Err(jwt::JwtError::NoRole(subject)) => {
                 warn!(
-                    "unauthorized access to endpoint \"{}\" from \"{:?}\"",
-                    req.uri().path(),
-                    peer
+                    "bearer token of \"{}\" grants no role, denied {} \"{}\"",
+                    subject,
+                    req.method(),
+                    path
                 );
-            } else {
-                warn!("unauthorized access to endpoint \"{}\"", req.uri().path());
+                return (StatusCode::FORBIDDEN, "token grants no role").into_response();
+            }
+            Err(e) => {
+                debug!("invalid bearer token: {}", e);
+                None
+            }
+        }
+    } else if let Some(k) = key {
+        // An explicit key wins over the certificate the client presents on every connection
+        if state.api_key == k {
+            Some(api_key::Principal {
+                name: "bootstrap".to_string(),
+                role: ApiKeyRole::Admin,
+            })
+        } else {
+            let mut conn = match state.shared_pool.get().await {
+                Ok(c) => c,
+                Err(e) => return error::internal_error(e).into_response(),
+            };
+            match api_key::authenticate(&mut conn, k).await {
+                Ok(p) => p,
+                Err(e) => return error::internal_error(e).into_response(),
             }
+        }
+    } else {
+        // Client certificates are only present if they were verified against the client CA
+        req.extensions()
+            .get::<tls::ClientCertificate>()
+            .map(|client_certificate| api_key::Principal {
+                name: format!("cert:{}", client_certificate.subject),
+                role: ApiKeyRole::Operator,
+            })
+    };
 
-            unauthorized()
+    let Some(principal) = principal else {
+        if let Some(peer) = peer_opt {
+            warn!(
+                "unauthorized access to endpoint \"{}\" from \"{:?}\"",
+                path, peer
+            );
+        } else {
+            warn!("unauthorized access to endpoint \"{}\"", path);
         }
+        return unauthorized();
+    };
+
+    let required_role = api_key::required_role(req.method(), &path);
+    if principal.role < required_role {
+        warn!(
+            "\"{}\" with role {:?} denied {} \"{}\", requires {:?}",
+            principal.name,
+            principal.role,
+            req.method(),
+            path,
+            required_role
+        );
+        return (
+            StatusCode::FORBIDDEN,
+            format!("role {:?} required", required_role),
+        )
+            .into_response();
     }
+    if req.method() == Method::GET || req.method() == Method::HEAD {
+        debug!("access to endpoint \"{}\" by \"{}\"", path, principal.name);
+    } else {
+        info!(
+            "{} \"{}\" by \"{}\"",
+            req.method(),
+            req.uri().path(),
+            principal.name
+        );
+    }
+    req.extensions_mut().insert(principal);
+    next.run(req).await
 }
 
 impl RestApi {
@@ -94,6 +209,55 @@ impl RestApi {
             .route("/device/{id}", axum::routing::get(device::get_device))
             .route("/device/{id}", axum::routing::patch(device::update_device))
             .route("/device/{id}", axum::routing::delete(device::delete_device))
+            .route(
+                "/device/{id}/tag",
+                axum::routing::get(device_tag::list_device_tags),
+            )
+            .route(
+                "/device/{id}/tag",
+                axum::routing::post(device_tag::create_device_tag),
+            )
+            .route(
+                "/device/{id}/tag/{tag}",
+                axum::routing::delete(device_tag::delete_device_tag),
+            )
+            .route("/tag", axum::routing::get(device_tag::list_tags))
+            .route(
+                "/device_group",
+                axum::routing::get(device_group::list_device_groups),
+            )
+            .route(
+                "/device_group",
+                axum::routing::post(device_group::create_device_group),
+            )
+            .route(
+                "/device_group/{id}",
+                axum::routing::get(device_group::get_device_group),
+            )
+            .route(
+                "/device_group/{id}",
+                axum::routing::patch(device_group::update_device_group),
+            )
+            .route(
+                "/device_group/{id}",
+                axum::routing::delete(device_group::delete_device_group),
+            )
+            .route(
+                "/device_group/{id}/device",
+                axum::routing::get(device_group::list_device_group_devices),
+            )
+            .route(
+                "/device_group/{id}/device",
+                axum::routing::post(device_group::add_device_group_devices),
+            )
+            .route(
+                "/device_group/{id}/device/{id}",
+                axum::routing::delete(device_group::remove_device_group_device),
+            )
+            .route(
+                "/device_group/{id}/bulk_update",
+                axum::routing::post(device_group::bulk_update_device_group),
+            )
             .route(
                 "/device/{id}/key",
                 axum::routing::get(device_key::list_device_keys),
@@ -110,6 +274,38 @@ impl RestApi {
                 "/device/{id}/key/{id}",
                 axum::routing::delete(device_key::delete_device_key),
             )
+            .route(
+                "/device/{id}/key/{id}/promote",
+                axum::routing::post(device_key::promote_device_key),
+            )
+            .route(
+                "/device/{id}/key/{id}/expire",
+                axum::routing::post(device_key::expire_device_key),
+            )
+            .route(
+                "/device/{id}/key/{id}/export",
+                axum::routing::post(device_key::export_device_key),
+            )
+            .route(
+                "/device/{id}/key/{id}/certificate",
+                axum::routing::get(device_key::get_device_key_certificate),
+            )
+            .route(
+                "/device/{id}/key/{id}/revoke",
+                axum::routing::post(device_key::revoke_device_key),
+            )
+            .route(
+                "/ca/crl",
+                axum::routing::get(certificate_authority::get_certificate_revocation_list),
+            )
+            .route("/api_key", axum::routing::get(api_key::list_api_keys))
+            .route("/api_key", axum::routing::post(api_key::create_api_key))
+            .route(
+                "/api_key/{id}/revoke",
+                axum::routing::post(api_key::revoke_api_key),
+            )
+            .route("/audit", axum::routing::get(audit::list_audit_events))
+            .route("/events", axum::routing::get(events::stream_events))
             .route("/firmware", axum::routing::get(firmware::list_firmwares))
             .route(
                 "/firmware",
@@ -118,6 +314,12 @@ impl RestApi {
                 ),
             )
             .route("/firmware/{id}", axum::routing::get(firmware::get_firmware))
+            .route(
+                "/firmware/{id}",
+                axum::routing::patch(firmware::update_firmware).route_layer(
+                    axum::extract::DefaultBodyLimit::max(config.max_firmware_size),
+                ),
+            )
             .route(
                 "/firmware/{id}",
                 axum::routing::delete(firmware::delete_firmware),
@@ -146,32 +348,113 @@ impl RestApi {
                 "/device_type_firmware/{id}",
                 axum::routing::delete(device_type_firmware::delete_device_type_firmware),
             )
+            .route(
+                "/rollout_campaign",
+                axum::routing::get(rollout_campaign::list_rollout_campaigns),
+            )
+            .route(
+                "/rollout_campaign",
+                axum::routing::post(rollout_campaign::create_rollout_campaign),
+            )
+            .route(
+                "/rollout_campaign/{id}",
+                axum::routing::get(rollout_campaign::get_rollout_campaign),
+            )
+            .route(
+                "/rollout_campaign/{id}/device",
+                axum::routing::get(rollout_campaign::list_rollout_campaign_devices),
+            )
+            .route(
+                "/rollout_campaign/{id}/pause",
+                axum::routing::post(rollout_campaign::pause_rollout_campaign),
+            )
+            .route(
+                "/rollout_campaign/{id}/resume",
+                axum::routing::post(rollout_campaign::resume_rollout_campaign),
+            )
+            .route(
+                "/rollout_campaign/{id}/abort",
+                axum::routing::post(rollout_campaign::abort_rollout_campaign),
+            )
+            .route("/webhook", axum::routing::get(webhook::list_webhooks))
+            .route("/webhook", axum::routing::post(webhook::create_webhook))
+            .route("/webhook/{id}", axum::routing::get(webhook::get_webhook))
+            .route(
+                "/webhook/{id}",
+                axum::routing::patch(webhook::update_webhook),
+            )
+            .route(
+                "/webhook/{id}",
+                axum::routing::delete(webhook::delete_webhook),
+            )
+            .route(
+                "/webhook/{id}/delivery",
+                axum::routing::get(webhook::list_webhook_deliveries),
+            )
             .with_state(config.clone())
             .layer(axum::middleware::from_fn_with_state(
                 config.clone(),
                 api_key_mw,
-            )); // apply globally
+            )) // apply globally
+            // Probed and scraped without API key
+            .merge(
+                axum::Router::new()
+                    .route("/healthz", axum::routing::get(health::healthz))
+                    .route("/readyz", axum::routing::get(health::readyz))
+                    .route("/metrics", axum::routing::get(metrics::get_metrics))
+                    .with_state(config.clone()),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                config.clone(),
+                metrics::track_request,
+            ));
         RestApi { config, router }
     }
 
     pub async fn start_blocking(&mut self) {
+        firmware::remove_partial_uploads(&self.config.data_storage_location).await;
         let tcp = TcpListener::bind(self.config.listen_address)
             .await
             .expect("Failed to bind TCP listener");
+        if let Some(tls_config) = self.config.tls.clone() {
+            let server_config = tls_config.load().expect("Failed to load TLS configuration");
+            info!(
+                "HTTPS listening on {}:{}{}",
+                self.config.listen_address.ip(),
+                self.config.listen_address.port(),
+                if tls_config.client_ca_path.is_some() {
+                    " (client certificates accepted)"
+                } else {
+                    ""
+                }
+            );
+            tls::serve(
+                tcp,
+                self.router.clone(),
+                tls_config,
+                server_config,
+                self.config.shutdown.clone(),
+            )
+            .await;
+            return;
+        }
+        warn!("FIRMUPS_TLS_CERT_FILE not set, API keys are sent in cleartext");
         info!(
             "HTTP listening on {}:{}",
             self.config.listen_address.ip(),
             self.config.listen_address.port()
         );
+        let shutdown = self.config.shutdown.clone();
         axum::serve(
             tcp,
             self.router
                 .clone()
                 .into_make_service_with_connect_info::<SocketAddr>(),
         )
-        .with_graceful_shutdown(async {
+        .with_graceful_shutdown(async move {
             let _ = signal::ctrl_c().await;
             info!("CTRL+C received; shutting down");
+            shutdown.cancel();
         })
         .await
         .expect("Server error");
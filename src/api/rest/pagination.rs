@@ -0,0 +1,151 @@
+use crate::api::rest;
+use axum::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use diesel::dsl::{AsSelect, Asc, CountStar};
+use diesel::pg::Pg;
+use diesel::query_builder::QueryId;
+use diesel::query_dsl::methods::{LimitDsl, OffsetDsl, SelectDsl, ThenOrderDsl};
+use diesel::{ExpressionMethods, Selectable, SelectableHelper};
+use diesel_async::methods::LoadQuery;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+
+/// Number of rows matching the filters, the body of a list stays a plain array
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PageQuery {
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+    /// Field to sort by, prefixed with `-` for descending order
+    pub sort: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub offset: i64,
+    pub limit: i64,
+    /// One of the sort fields of the endpoint, the first one if not requested
+    pub sort_field: &'static str,
+    pub descending: bool,
+}
+
+impl PageQuery {
+    pub fn validate(self, sort_fields: &[&'static str]) -> Result<Page, rest::error::ApiError> {
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(rest::error::client_error(
+                StatusCode::BAD_REQUEST,
+                format!("limit must be between 1 and {}", MAX_LIMIT),
+            ));
+        }
+        let offset = self.offset.unwrap_or(0);
+        if offset < 0 {
+            return Err(rest::error::client_error(
+                StatusCode::BAD_REQUEST,
+                "offset must not be negative".to_string(),
+            ));
+        }
+        let (field, descending) = match self.sort.as_deref() {
+            Some(sort) => match sort.strip_prefix('-') {
+                Some(field) => (field, true),
+                None => (sort, false),
+            },
+            None => (sort_fields[0], false),
+        };
+        let Some(sort_field) = sort_fields.iter().find(|f| **f == field) else {
+            return Err(rest::error::client_error(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "cannot sort by '{}', expected one of {}",
+                    field,
+                    sort_fields.join(", ")
+                ),
+            ));
+        };
+        Ok(Page {
+            offset,
+            limit,
+            sort_field,
+            descending,
+        })
+    }
+}
+
+/// LIKE pattern matching values starting with `prefix`
+pub fn like_prefix(prefix: &str) -> String {
+    let mut pattern = prefix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    pattern.push('%');
+    pattern
+}
+
+/// Load `page` of the rows of `filtered()` and count all of them. `sort` orders the rows by the
+/// requested field, ties are broken by `id` so that pages do not overlap.
+pub fn load_page<'a, Q, T, Id>(
+    conn: &'a mut AsyncPgConnection,
+    page: &Page,
+    filtered: impl Fn() -> Q,
+    sort: impl FnOnce(Q) -> Q,
+    id: Id,
+) -> impl Future<Output = Result<Paged<T>, rest::error::ApiError>> + Send + 'a
+where
+    Q: SelectDsl<CountStar>
+        + SelectDsl<AsSelect<T, Pg>>
+        + ThenOrderDsl<Asc<Id>, Output = Q>
+        + OffsetDsl<Output = Q>
+        + LimitDsl<Output = Q>,
+    <Q as SelectDsl<CountStar>>::Output:
+        LoadQuery<'static, AsyncPgConnection, i64> + Send + 'static,
+    <Q as SelectDsl<AsSelect<T, Pg>>>::Output:
+        LoadQuery<'static, AsyncPgConnection, T> + Send + 'static,
+    T: Selectable<Pg> + Send + 'a,
+    T::SelectExpression: QueryId,
+    Id: ExpressionMethods,
+{
+    // Built outside of the future, which keeps it Send for the handlers
+    let count = filtered().select(diesel::dsl::count_star());
+    let rows = sort(filtered())
+        .then_order_by(id.asc())
+        .offset(page.offset)
+        .limit(page.limit)
+        .select(T::as_select());
+    async move {
+        let total: i64 = count
+            .get_result(conn)
+            .await
+            .map_err(rest::error::internal_error)?;
+        let items = rows.load(conn).await.map_err(rest::error::internal_error)?;
+        Ok(Paged { total, items })
+    }
+}
+
+/// One page of a list together with the number of all matching rows
+pub struct Paged<T> {
+    pub total: i64,
+    pub items: Vec<T>,
+}
+
+impl<T> Paged<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Paged<U> {
+        Paged {
+            total: self.total,
+            items: self.items.into_iter().map(f).collect(),
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Paged<T> {
+    fn into_response(self) -> Response {
+        (
+            [(TOTAL_COUNT_HEADER, self.total.to_string())],
+            Json(self.items),
+        )
+            .into_response()
+    }
+}
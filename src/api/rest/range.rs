@@ -0,0 +1,208 @@
+use axum::http::HeaderMap;
+use axum::http::header::{IF_NONE_MATCH, IF_RANGE, RANGE};
+
+/// Requests with more ranges are answered with the full file
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable range, the full file is sent
+    Full,
+    /// Sorted, non-overlapping inclusive byte ranges
+    Partial(Vec<(u64, u64)>),
+    /// None of the ranges overlaps the file
+    Unsatisfiable,
+}
+
+/// Whether `If-None-Match` matches `etag`, in which case 304 Not Modified is returned
+pub fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        // Weak comparison, `W/` is ignored
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Ranges to send of a file of `size` bytes with the entity tag `etag`
+pub fn requested_ranges(headers: &HeaderMap, etag: &str, size: u64) -> RangeRequest {
+    let Some(range) = headers.get(RANGE).and_then(|v| v.to_str().ok()) else {
+        return RangeRequest::Full;
+    };
+    if let Some(if_range) = headers.get(IF_RANGE) {
+        // Strong comparison, weak tags and dates never match
+        if if_range.to_str().ok().map(str::trim) != Some(etag) {
+            return RangeRequest::Full;
+        }
+    }
+    parse_range(range, size)
+}
+
+/// Byte ranges of the `Range` header, overlapping and touching ranges are merged
+fn parse_range(header: &str, size: u64) -> RangeRequest {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        // Unknown range unit
+        return RangeRequest::Full;
+    };
+
+    let mut ranges = Vec::new();
+    let mut specs_seen = 0;
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        specs_seen += 1;
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            // Suffix range: the last `n` bytes
+            let Ok(length) = last.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            if length == 0 || size == 0 {
+                continue;
+            }
+            (size.saturating_sub(length), size - 1)
+        } else {
+            let Ok(first) = first.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            let last = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(last) if last >= first => last,
+                    _ => return RangeRequest::Full,
+                }
+            };
+            if first >= size {
+                continue;
+            }
+            (first, last.min(size - 1))
+        };
+        ranges.push(range);
+        if ranges.len() > MAX_RANGES {
+            return RangeRequest::Full;
+        }
+    }
+    if specs_seen == 0 {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(previous) if first <= previous.1.saturating_add(1) => {
+                previous.1 = previous.1.max(last)
+            }
+            _ => merged.push((first, last)),
+        }
+    }
+    RangeRequest::Partial(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-99", 1000),
+            RangeRequest::Partial(vec![(0, 99)])
+        );
+        // The last byte is clamped to the file
+        assert_eq!(
+            parse_range("bytes=900-2000", 1000),
+            RangeRequest::Partial(vec![(900, 999)])
+        );
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            RangeRequest::Partial(vec![(900, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=-5000", 1000),
+            RangeRequest::Partial(vec![(0, 999)])
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn open_ended_ranges() {
+        assert_eq!(
+            parse_range("bytes=500-", 1000),
+            RangeRequest::Partial(vec![(500, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=999-", 1000),
+            RangeRequest::Partial(vec![(999, 999)])
+        );
+    }
+
+    #[test]
+    fn skips_ranges_starting_after_the_file() {
+        assert_eq!(
+            parse_range("bytes=1000-1100, 0-9", 1000),
+            RangeRequest::Partial(vec![(0, 9)])
+        );
+        assert_eq!(
+            parse_range("bytes=2000-, 1000-1000", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn merges_overlapping_and_touching_ranges() {
+        assert_eq!(
+            parse_range("bytes=10-19, 0-9, 30-39, 35-50", 1000),
+            RangeRequest::Partial(vec![(0, 19), (30, 50)])
+        );
+        // A gap of one byte is kept
+        assert_eq!(
+            parse_range("bytes=0-9, 11-19", 1000),
+            RangeRequest::Partial(vec![(0, 9), (11, 19)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-, -10", 1000),
+            RangeRequest::Partial(vec![(0, 999)])
+        );
+    }
+
+    #[test]
+    fn too_many_ranges_send_the_full_file() {
+        let specs = |count: usize| {
+            (0..count)
+                .map(|i| format!("{}-{}", i * 10, i * 10))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        assert!(matches!(
+            parse_range(&format!("bytes={}", specs(MAX_RANGES)), 1000),
+            RangeRequest::Partial(ranges) if ranges.len() == MAX_RANGES
+        ));
+        assert_eq!(
+            parse_range(&format!("bytes={}", specs(MAX_RANGES + 1)), 1000),
+            RangeRequest::Full
+        );
+    }
+
+    #[test]
+    fn malformed_headers_send_the_full_file() {
+        assert_eq!(parse_range("items=0-9", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=9-0", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=a-9", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=0-9, 20", 1000), RangeRequest::Full);
+    }
+}
@@ -0,0 +1,710 @@
+use crate::api::rest;
+use crate::api::rest::device::{DeviceFilter, filtered_devices};
+use crate::db::audit;
+use crate::db::models::{
+    CampaignDeviceStatus, CampaignStatus, NewRolloutCampaign, RolloutCampaign,
+    RolloutCampaignDevice,
+};
+use crate::db::rollout::{self, CampaignStats};
+use crate::db::schema;
+use crate::db::schema::rollout_campaign::dsl as campaign_dsl;
+use crate::db::schema::rollout_campaign_device::dsl as member_dsl;
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use diesel::ExpressionMethods;
+use diesel::PgExpressionMethods;
+use diesel::PgSortExpressionMethods;
+use diesel::SelectableHelper;
+use diesel::pg::Pg;
+use diesel::query_dsl::methods::{FilterDsl, FindDsl, OrderDsl, SelectDsl};
+use diesel::result::DatabaseErrorKind;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+const CAMPAIGN_SORT_FIELDS: &[&str] = &["id", "name", "status", "created_at"];
+const CAMPAIGN_DEVICE_SORT_FIELDS: &[&str] = &["id", "device", "status", "wave"];
+const MAX_WAVES: usize = 100;
+// Rows per insert, PostgreSQL accepts at most 65535 bind parameters per statement
+const INSERT_CHUNK_SIZE: usize = 10_000;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CampaignFilter {
+    pub status: Option<CampaignStatus>,
+    pub target_firmware: Option<i32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CampaignDeviceFilter {
+    pub status: Option<CampaignDeviceStatus>,
+}
+
+/// Cumulative size of a wave
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaveSize {
+    /// Share of all targeted devices, rounded up
+    Percent(u32),
+    Count(u32),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewRolloutCampaignPayload {
+    pub name: String,
+    pub target_firmware: i32,
+    #[serde(default)]
+    pub device_filter: DeviceFilter,
+    /// A single wave with all devices if unset
+    pub waves: Option<Vec<WaveSize>>,
+    pub wave_interval_seconds: Option<i32>,
+    pub device_timeout_seconds: Option<i32>,
+    pub max_failure_percent: Option<i32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ResumeRolloutCampaignPayload {
+    pub max_failure_percent: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RolloutCampaignPayload {
+    #[serde(flatten)]
+    pub campaign: RolloutCampaign,
+    pub devices: CampaignStats,
+}
+
+fn validate_max_failure_percent(value: i32) -> Result<(), rest::error::ApiError> {
+    if !(0..=100).contains(&value) {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "max_failure_percent must be between 0 and 100".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Cumulative device counts of the waves, the last one covers all `total` devices
+fn resolve_waves(waves: &[WaveSize], total: usize) -> Result<Vec<i32>, rest::error::ApiError> {
+    let mut counts: Vec<i32> = Vec::with_capacity(waves.len() + 1);
+    for wave in waves {
+        let count = match *wave {
+            WaveSize::Percent(percent) if (1..=100).contains(&percent) => {
+                (total * percent as usize).div_ceil(100)
+            }
+            WaveSize::Count(count) if count > 0 => (count as usize).min(total),
+            _ => {
+                return Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "waves need a percent between 1 and 100 or a count above 0".to_string(),
+                ));
+            }
+        };
+        let count = count as i32;
+        match counts.last() {
+            Some(previous) if count < *previous => {
+                return Err(rest::error::client_error(
+                    StatusCode::BAD_REQUEST,
+                    "waves are cumulative and must not shrink".to_string(),
+                ));
+            }
+            Some(previous) if count == *previous => {}
+            _ => counts.push(count),
+        }
+    }
+    if counts.last() != Some(&(total as i32)) {
+        counts.push(total as i32);
+    }
+    Ok(counts)
+}
+
+fn filtered_campaigns(
+    filter: &CampaignFilter,
+) -> schema::rollout_campaign::BoxedQuery<'static, Pg> {
+    let mut query = diesel::QueryDsl::into_boxed(campaign_dsl::rollout_campaign);
+    if let Some(status) = filter.status {
+        query = query.filter(campaign_dsl::status.eq(status));
+    }
+    if let Some(target_firmware) = filter.target_firmware {
+        query = query.filter(campaign_dsl::target_firmware.eq(target_firmware));
+    }
+    query
+}
+
+fn filtered_campaign_devices(
+    campaign_id: i32,
+    filter: &CampaignDeviceFilter,
+) -> schema::rollout_campaign_device::BoxedQuery<'static, Pg> {
+    let mut query = diesel::QueryDsl::into_boxed(
+        member_dsl::rollout_campaign_device.filter(member_dsl::campaign.eq(campaign_id)),
+    );
+    if let Some(status) = filter.status {
+        query = query.filter(member_dsl::status.eq(status));
+    }
+    query
+}
+
+async fn with_stats(
+    conn: &mut AsyncPgConnection,
+    campaign: RolloutCampaign,
+) -> Result<RolloutCampaignPayload, diesel::result::Error> {
+    let devices = rollout::stats(conn, campaign.id).await?;
+    Ok(RolloutCampaignPayload { campaign, devices })
+}
+
+fn campaign_not_found(campaign_id: i32) -> rest::error::ApiError {
+    rest::error::client_error(
+        StatusCode::NOT_FOUND,
+        format!("rollout campaign {} not found", campaign_id),
+    )
+}
+
+#[axum::debug_handler]
+pub async fn list_rollout_campaigns(
+    State(api_config): State<rest::RestApiConfig>,
+    Query(page): Query<rest::pagination::PageQuery>,
+    Query(filter): Query<CampaignFilter>,
+) -> Result<rest::pagination::Paged<RolloutCampaign>, rest::error::ApiError> {
+    use crate::db::schema::rollout_campaign::dsl::*;
+
+    let page = page.validate(CAMPAIGN_SORT_FIELDS)?;
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || filtered_campaigns(&filter),
+        |query| match (page.sort_field, page.descending) {
+            ("name", false) => query.order(name.asc()),
+            ("name", true) => query.order(name.desc()),
+            ("status", false) => query.order(status.asc()),
+            ("status", true) => query.order(status.desc()),
+            ("created_at", false) => query.order(created_at.asc()),
+            ("created_at", true) => query.order(created_at.desc()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await
+}
+
+#[axum::debug_handler]
+pub async fn create_rollout_campaign(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Json(payload): Json<NewRolloutCampaignPayload>,
+) -> Result<(StatusCode, Json<RolloutCampaignPayload>), rest::error::ApiError> {
+    use crate::db::schema::device::dsl as device_dsl;
+    use crate::db::schema::device_type_firmware::dsl as link_dsl;
+    use crate::db::schema::firmware::dsl as fw_dsl;
+
+    let name_trimmed = payload.name.trim().to_string();
+    if name_trimmed.is_empty() || name_trimmed.len() > 100 {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "name must be between 1 and 100 characters".to_string(),
+        ));
+    }
+    let waves = payload.waves.clone().unwrap_or_default();
+    if waves.len() > MAX_WAVES {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            format!("at most {} waves are supported", MAX_WAVES),
+        ));
+    }
+    let wave_interval_seconds = payload.wave_interval_seconds.unwrap_or(3600);
+    if wave_interval_seconds < 0 {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "wave_interval_seconds must not be negative".to_string(),
+        ));
+    }
+    let device_timeout_seconds = payload.device_timeout_seconds.unwrap_or(86400);
+    if device_timeout_seconds < 1 {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "device_timeout_seconds must be positive".to_string(),
+        ));
+    }
+    let max_failure_percent = payload.max_failure_percent.unwrap_or(10);
+    validate_max_failure_percent(max_failure_percent)?;
+    let device_filter =
+        serde_json::to_value(&payload.device_filter).map_err(rest::error::internal_error)?;
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tx_result: Result<RolloutCampaignPayload, rest::error::TransactionError> = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            Box::pin(async move {
+                let target_exists: i64 = diesel::QueryDsl::count(
+                    fw_dsl::firmware.filter(fw_dsl::id.eq(payload.target_firmware)),
+                )
+                .get_result(conn)
+                .await?;
+                if target_exists == 0 {
+                    return Err(rest::error::TransactionError::from(
+                        rest::error::client_error(
+                            StatusCode::BAD_REQUEST,
+                            "unknown target firmware".to_string(),
+                        ),
+                    ));
+                }
+
+                // Devices that can run the target firmware, do not yet and are in no other
+                // unfinished campaign, in random order to spread the waves
+                let linked_types = link_dsl::device_type_firmware
+                    .filter(link_dsl::firmware.eq(payload.target_firmware))
+                    .select(link_dsl::device_type);
+                let active_devices = member_dsl::rollout_campaign_device
+                    .filter(member_dsl::status.eq_any([
+                        CampaignDeviceStatus::Pending,
+                        CampaignDeviceStatus::Assigned,
+                    ]))
+                    .select(member_dsl::device);
+                let device_ids: Vec<i32> = filtered_devices(&payload.device_filter)
+                    .filter(device_dsl::type_.eq_any(linked_types))
+                    .filter(device_dsl::firmware.is_distinct_from(payload.target_firmware))
+                    .filter(diesel::dsl::not(device_dsl::id.eq_any(active_devices)))
+                    .order(diesel::dsl::sql::<diesel::sql_types::Double>("random()"))
+                    .select(device_dsl::id)
+                    .load(conn)
+                    .await?;
+                if device_ids.is_empty() {
+                    return Err(rest::error::TransactionError::from(
+                        rest::error::client_error(
+                            StatusCode::BAD_REQUEST,
+                            "no device matches the filter, can run the target firmware and \
+                             is not yet updated or part of another campaign"
+                                .to_string(),
+                        ),
+                    ));
+                }
+                let waves = resolve_waves(&waves, device_ids.len())?;
+
+                let new_campaign = NewRolloutCampaign {
+                    name: name_trimmed,
+                    target_firmware: payload.target_firmware,
+                    device_filter,
+                    waves,
+                    wave_interval_seconds,
+                    device_timeout_seconds,
+                    max_failure_percent,
+                };
+                let created: RolloutCampaign = diesel::insert_into(campaign_dsl::rollout_campaign)
+                    .values(&new_campaign)
+                    .returning(RolloutCampaign::as_returning())
+                    .get_result(conn)
+                    .await?;
+                // The runner assigns the devices in the order of their ids
+                for chunk in device_ids.chunks(INSERT_CHUNK_SIZE) {
+                    let rows: Vec<_> = chunk
+                        .iter()
+                        .map(|device_id| {
+                            (
+                                member_dsl::campaign.eq(created.id),
+                                member_dsl::device.eq(*device_id),
+                            )
+                        })
+                        .collect();
+                    diesel::insert_into(member_dsl::rollout_campaign_device)
+                        .values(&rows)
+                        .execute(conn)
+                        .await?;
+                }
+                audit::record(
+                    conn,
+                    actor
+                        .event("create", "rollout_campaign", created.id)
+                        .after(&created),
+                )
+                .await?;
+                Ok(with_stats(conn, created).await?)
+            })
+        })
+        .await;
+
+    match tx_result {
+        Ok(created) => {
+            info!(
+                "Created rollout campaign {} \"{}\" of firmware {} for {} devices",
+                created.campaign.id,
+                created.campaign.name,
+                created.campaign.target_firmware,
+                created.devices.pending
+            );
+            Ok((StatusCode::CREATED, Json(created)))
+        }
+        Err(rest::error::TransactionError::Db(diesel::result::Error::DatabaseError(
+            DatabaseErrorKind::UniqueViolation,
+            info,
+        ))) => match info.constraint_name() {
+            Some("rollout_campaign_device_active_idx") => Err(rest::error::client_error(
+                StatusCode::CONFLICT,
+                "devices were added to another campaign concurrently".to_string(),
+            )),
+            _ => Err(rest::error::client_error(
+                StatusCode::CONFLICT,
+                format!("rollout campaign '{}' already exists", payload.name.trim()),
+            )),
+        },
+        Err(rest::error::TransactionError::Db(e)) => Err(rest::error::internal_error(e)),
+        Err(rest::error::TransactionError::Api(api)) => Err(api),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn get_rollout_campaign(
+    State(api_config): State<rest::RestApiConfig>,
+    Path(path_id): Path<i32>,
+) -> Result<Json<RolloutCampaignPayload>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    let campaign: RolloutCampaign = match campaign_dsl::rollout_campaign
+        .find(path_id)
+        .select(RolloutCampaign::as_select())
+        .first(&mut conn)
+        .await
+    {
+        Ok(c) => c,
+        Err(diesel::result::Error::NotFound) => return Err(campaign_not_found(path_id)),
+        Err(e) => return Err(rest::error::internal_error(e)),
+    };
+    let payload = with_stats(&mut conn, campaign)
+        .await
+        .map_err(rest::error::internal_error)?;
+    Ok(Json(payload))
+}
+
+#[axum::debug_handler]
+pub async fn list_rollout_campaign_devices(
+    State(api_config): State<rest::RestApiConfig>,
+    Path(path_id): Path<i32>,
+    Query(page): Query<rest::pagination::PageQuery>,
+    Query(filter): Query<CampaignDeviceFilter>,
+) -> Result<rest::pagination::Paged<RolloutCampaignDevice>, rest::error::ApiError> {
+    use crate::db::schema::rollout_campaign_device::dsl::*;
+
+    let page = page.validate(CAMPAIGN_DEVICE_SORT_FIELDS)?;
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    let exists: i64 = diesel::QueryDsl::count(campaign_dsl::rollout_campaign.find(path_id))
+        .get_result(&mut conn)
+        .await
+        .map_err(rest::error::internal_error)?;
+    if exists == 0 {
+        return Err(campaign_not_found(path_id));
+    }
+    rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || filtered_campaign_devices(path_id, &filter),
+        |query| match (page.sort_field, page.descending) {
+            ("device", false) => query.order(device.asc()),
+            ("device", true) => query.order(device.desc()),
+            ("status", false) => query.order(status.asc()),
+            ("status", true) => query.order(status.desc()),
+            ("wave", false) => query.order(wave.asc().nulls_last()),
+            ("wave", true) => query.order(wave.desc().nulls_first()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await
+}
+
+/// Lock the campaign and check that it is in one of the `allowed` states
+async fn lock_campaign(
+    conn: &mut AsyncPgConnection,
+    campaign_id: i32,
+    action: &str,
+    allowed: &[CampaignStatus],
+) -> Result<RolloutCampaign, rest::error::TransactionError> {
+    let campaign: RolloutCampaign = diesel::QueryDsl::for_update(
+        campaign_dsl::rollout_campaign
+            .find(campaign_id)
+            .select(RolloutCampaign::as_select()),
+    )
+    .first(conn)
+    .await?;
+    if !allowed.contains(&campaign.status) {
+        return Err(rest::error::TransactionError::from(
+            rest::error::client_error(
+                StatusCode::CONFLICT,
+                format!(
+                    "cannot {} rollout campaign {} with status {:?}",
+                    action, campaign_id, campaign.status
+                ),
+            ),
+        ));
+    }
+    Ok(campaign)
+}
+
+fn transition_result(
+    campaign_id: i32,
+    tx_result: Result<RolloutCampaignPayload, rest::error::TransactionError>,
+) -> Result<Json<RolloutCampaignPayload>, rest::error::ApiError> {
+    match tx_result {
+        Ok(payload) => {
+            info!(
+                "Rollout campaign {} is {:?}",
+                campaign_id, payload.campaign.status
+            );
+            Ok(Json(payload))
+        }
+        Err(rest::error::TransactionError::Db(diesel::result::Error::NotFound)) => {
+            Err(campaign_not_found(campaign_id))
+        }
+        Err(rest::error::TransactionError::Db(e)) => Err(rest::error::internal_error(e)),
+        Err(rest::error::TransactionError::Api(api)) => Err(api),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn pause_rollout_campaign(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+) -> Result<Json<RolloutCampaignPayload>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tx_result = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            Box::pin(async move {
+                let before =
+                    lock_campaign(conn, path_id, "pause", &[CampaignStatus::Running]).await?;
+                let updated: RolloutCampaign =
+                    diesel::update(campaign_dsl::rollout_campaign.find(path_id))
+                        .set((
+                            campaign_dsl::status.eq(CampaignStatus::Paused),
+                            campaign_dsl::status_reason.eq(format!("paused by {}", actor.name)),
+                        ))
+                        .returning(RolloutCampaign::as_returning())
+                        .get_result(conn)
+                        .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("pause", "rollout_campaign", path_id)
+                        .before(&before)
+                        .after(&updated),
+                )
+                .await?;
+                Ok(with_stats(conn, updated).await?)
+            })
+        })
+        .await;
+    transition_result(path_id, tx_result)
+}
+
+#[axum::debug_handler]
+pub async fn resume_rollout_campaign(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+    payload: Option<Json<ResumeRolloutCampaignPayload>>,
+) -> Result<Json<RolloutCampaignPayload>, rest::error::ApiError> {
+    let payload = payload.map(|Json(p)| p).unwrap_or_default();
+    if let Some(max_failure_percent) = payload.max_failure_percent {
+        validate_max_failure_percent(max_failure_percent)?;
+    }
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tx_result = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            Box::pin(async move {
+                let before =
+                    lock_campaign(conn, path_id, "resume", &[CampaignStatus::Paused]).await?;
+                let max_failure_percent = payload
+                    .max_failure_percent
+                    .unwrap_or(before.max_failure_percent);
+                // Stats are cumulative, the runner would pause the campaign again right away
+                let stats = rollout::stats(conn, path_id).await?;
+                if stats.exceeds(max_failure_percent) {
+                    let failed = stats.failed + stats.rolled_back;
+                    return Err(rest::error::TransactionError::from(
+                        rest::error::client_error(
+                            StatusCode::CONFLICT,
+                            format!(
+                                "{} of {} finished devices ({:.1}%) failed or were rolled back, which exceeds max_failure_percent {}, resume with a higher max_failure_percent",
+                                failed,
+                                stats.finished(),
+                                failed as f64 * 100.0 / stats.finished() as f64,
+                                max_failure_percent
+                            ),
+                        ),
+                    ));
+                }
+                let updated: RolloutCampaign =
+                    diesel::update(campaign_dsl::rollout_campaign.find(path_id))
+                        .set((
+                            campaign_dsl::status.eq(CampaignStatus::Running),
+                            campaign_dsl::status_reason.eq(None::<String>),
+                            campaign_dsl::max_failure_percent.eq(max_failure_percent),
+                        ))
+                        .returning(RolloutCampaign::as_returning())
+                        .get_result(conn)
+                        .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("resume", "rollout_campaign", path_id)
+                        .before(&before)
+                        .after(&updated),
+                )
+                .await?;
+                Ok(with_stats(conn, updated).await?)
+            })
+        })
+        .await;
+    transition_result(path_id, tx_result)
+}
+
+#[axum::debug_handler]
+pub async fn abort_rollout_campaign(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+) -> Result<Json<RolloutCampaignPayload>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let tx_result = conn
+        .transaction::<_, rest::error::TransactionError, _>(|conn| {
+            Box::pin(async move {
+                let before = lock_campaign(
+                    conn,
+                    path_id,
+                    "abort",
+                    &[CampaignStatus::Running, CampaignStatus::Paused],
+                )
+                .await?;
+                let restored = rollout::cancel_devices(conn, &before).await?;
+                let updated: RolloutCampaign =
+                    diesel::update(campaign_dsl::rollout_campaign.find(path_id))
+                        .set((
+                            campaign_dsl::status.eq(CampaignStatus::Aborted),
+                            campaign_dsl::status_reason.eq(format!("aborted by {}", actor.name)),
+                            campaign_dsl::finished_at.eq(chrono::Utc::now().naive_utc()),
+                        ))
+                        .returning(RolloutCampaign::as_returning())
+                        .get_result(conn)
+                        .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("abort", "rollout_campaign", path_id)
+                        .before(&before)
+                        .after(&updated),
+                )
+                .await?;
+                info!(
+                    "Restored the desired firmware of {} devices of rollout campaign {}",
+                    restored, path_id
+                );
+                Ok(with_stats(conn, updated).await?)
+            })
+        })
+        .await;
+    transition_result(path_id, tx_result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waves(waves: &[WaveSize], total: usize) -> Option<Vec<i32>> {
+        resolve_waves(waves, total).ok()
+    }
+
+    #[test]
+    fn percent_rounds_up() {
+        assert_eq!(waves(&[WaveSize::Percent(15)], 10), Some(vec![2, 10]));
+        assert_eq!(waves(&[WaveSize::Percent(50)], 3), Some(vec![2, 3]));
+        assert_eq!(waves(&[WaveSize::Percent(1)], 1000), Some(vec![10, 1000]));
+        assert_eq!(waves(&[WaveSize::Percent(1)], 1), Some(vec![1]));
+    }
+
+    #[test]
+    fn count_is_clamped_to_total() {
+        assert_eq!(waves(&[WaveSize::Count(10)], 5), Some(vec![5]));
+        assert_eq!(
+            waves(&[WaveSize::Count(3), WaveSize::Count(10)], 5),
+            Some(vec![3, 5])
+        );
+    }
+
+    #[test]
+    fn shrinking_waves_are_rejected() {
+        assert_eq!(waves(&[WaveSize::Count(5), WaveSize::Count(3)], 10), None);
+        assert_eq!(
+            waves(&[WaveSize::Percent(50), WaveSize::Count(2)], 10),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        assert_eq!(waves(&[WaveSize::Percent(0)], 10), None);
+        assert_eq!(waves(&[WaveSize::Percent(101)], 10), None);
+        assert_eq!(waves(&[WaveSize::Count(0)], 10), None);
+    }
+
+    #[test]
+    fn duplicate_waves_are_merged() {
+        assert_eq!(
+            waves(&[WaveSize::Count(5), WaveSize::Percent(50)], 10),
+            Some(vec![5, 10])
+        );
+        // Both resolve to all devices once clamped
+        assert_eq!(
+            waves(&[WaveSize::Count(20), WaveSize::Percent(100)], 10),
+            Some(vec![10])
+        );
+    }
+
+    #[test]
+    fn final_wave_covers_all_devices() {
+        assert_eq!(waves(&[], 10), Some(vec![10]));
+        assert_eq!(
+            waves(&[WaveSize::Count(1), WaveSize::Percent(50)], 10),
+            Some(vec![1, 5, 10])
+        );
+        assert_eq!(waves(&[WaveSize::Percent(100)], 10), Some(vec![10]));
+        assert_eq!(waves(&[WaveSize::Percent(10)], 0), Some(vec![0]));
+    }
+}
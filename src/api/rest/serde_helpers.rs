@@ -9,12 +9,35 @@ where
     ser.serialize_str(&s)
 }
 
-pub fn from_base64<'de, D>(de: D) -> Result<Vec<u8>, D::Error>
+pub fn as_base64_opt<S>(bytes: &Option<Vec<u8>>, ser: S) -> Result<S::Ok, S::Error>
 where
+    S: Serializer,
+{
+    match bytes {
+        Some(bytes) => as_base64(bytes, ser),
+        None => ser.serialize_none(),
+    }
+}
+
+pub fn from_base64_opt<'de, D>(de: D) -> Result<Option<Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = Option::<String>::deserialize(de)?;
+    s.map(|s| {
+        STANDARD
+            .decode(s.as_bytes())
+            .map_err(serde::de::Error::custom)
+    })
+    .transpose()
+}
+
+/// Tells an explicit `null` (`Some(None)`) apart from a missing field (`None`), the field also
+/// needs `#[serde(default)]`
+pub fn double_option<'de, T, D>(de: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
     D: Deserializer<'de>,
 {
-    let s = String::deserialize(de)?;
-    STANDARD
-        .decode(s.as_bytes())
-        .map_err(serde::de::Error::custom)
+    Option::<T>::deserialize(de).map(Some)
 }
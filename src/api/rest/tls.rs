@@ -0,0 +1,208 @@
+use axum::extract::ConnectInfo;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use log::{debug, error, info, warn};
+use rustls::RootCertStore;
+use rustls::ServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tower::ServiceExt;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("failed to read {0}: {1}")]
+    Pem(String, rustls::pki_types::pem::Error),
+    #[error("no certificate found in {0}")]
+    NoCertificate(String),
+    #[error("no certificate revocation list found in {0}")]
+    NoCrl(String),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error("invalid client CA: {0}")]
+    ClientVerifier(#[from] rustls::server::VerifierBuilderError),
+}
+
+/// PEM files of the REST API certificate, private key and optional client CA
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// CA to verify client certificates against, client authentication is disabled if unset
+    pub client_ca_path: Option<PathBuf>,
+    /// CRLs of the client CA, certificates they list are rejected
+    pub client_crl_path: Option<PathBuf>,
+}
+
+/// Verified certificate the client authenticated with, passed to the handlers as extension
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    pub subject: String,
+}
+
+impl ClientCertificate {
+    fn from_der(cert: &CertificateDer<'_>) -> Self {
+        // The certificate was already parsed by the verifier
+        let subject = x509_parser::parse_x509_certificate(cert)
+            .map(|(_, cert)| cert.subject().to_string())
+            .unwrap_or_default();
+        ClientCertificate { subject }
+    }
+}
+
+impl TlsConfig {
+    pub fn load(&self) -> Result<Arc<ServerConfig>, TlsError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let certs = load_certs(&self.cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| TlsError::Pem(self.key_path.display().to_string(), e))?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca_path {
+            Some(client_ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(client_ca_path)? {
+                    roots.add(cert)?;
+                }
+                let mut verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .allow_unauthenticated();
+                if let Some(client_crl_path) = &self.client_crl_path {
+                    verifier = verifier.with_crls(load_crls(client_crl_path)?);
+                }
+                let verifier = verifier.build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs, key)?;
+        // HTTP/2 is not offered, early responses (e.g. 401 before the upload body was read)
+        // end with a stream reset that older clients report as error
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    /// Whether the client CA file contains a certificate of `ca_cert_path`
+    pub fn client_ca_contains(&self, ca_cert_path: &Path) -> Result<bool, TlsError> {
+        let Some(client_ca_path) = &self.client_ca_path else {
+            return Ok(false);
+        };
+        let client_cas = load_certs(client_ca_path)?;
+        Ok(load_certs(ca_cert_path)?
+            .iter()
+            .any(|cert| client_cas.contains(cert)))
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Pem(path.display().to_string(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.display().to_string()));
+    }
+    Ok(certs)
+}
+
+fn load_crls(path: &Path) -> Result<Vec<CertificateRevocationListDer<'static>>, TlsError> {
+    let crls = CertificateRevocationListDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Pem(path.display().to_string(), e))?;
+    if crls.is_empty() {
+        return Err(TlsError::NoCrl(path.display().to_string()));
+    }
+    Ok(crls)
+}
+
+/// Serve `router` over TLS until CTRL+C is received, which cancels `shutdown`.
+/// SIGHUP reloads the configuration, connections accepted before keep theirs.
+pub async fn serve(
+    listener: TcpListener,
+    router: axum::Router,
+    tls_config: TlsConfig,
+    server_config: Arc<ServerConfig>,
+    shutdown: CancellationToken,
+) {
+    let mut acceptor = TlsAcceptor::from(server_config);
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to register SIGHUP handler");
+    let graceful = GracefulShutdown::new();
+    let mut ctrl_c = std::pin::pin!(signal::ctrl_c());
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (tcp, peer) = match accepted {
+                    Ok(a) => a,
+                    Err(e) => {
+                        warn!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let router = router.clone();
+                let watcher = graceful.watcher();
+                tokio::spawn(async move {
+                    let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            debug!("TLS handshake with \"{:?}\" failed: {}", peer, e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("TLS handshake with \"{:?}\" timed out", peer);
+                            return;
+                        }
+                    };
+                    let client_certificate = stream
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .and_then(|certs| certs.first())
+                        .map(ClientCertificate::from_der);
+                    let service = hyper::service::service_fn(move |mut req: hyper::Request<Incoming>| {
+                        req.extensions_mut().insert(ConnectInfo::<SocketAddr>(peer));
+                        if let Some(client_certificate) = &client_certificate {
+                            req.extensions_mut().insert(client_certificate.clone());
+                        }
+                        router.clone().oneshot(req)
+                    });
+                    let builder = Builder::new(TokioExecutor::new());
+                    let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                    if let Err(e) = watcher.watch(conn).await {
+                        debug!("Connection with \"{:?}\" failed: {}", peer, e);
+                    }
+                });
+            }
+            _ = hangup.recv() => {
+                match tls_config.load() {
+                    Ok(server_config) => {
+                        acceptor = TlsAcceptor::from(server_config);
+                        info!("SIGHUP received; reloaded TLS configuration");
+                    }
+                    Err(e) => error!("Failed to reload TLS configuration, keeping the previous one: {}", e),
+                }
+            }
+            _ = &mut ctrl_c => {
+                info!("CTRL+C received; shutting down");
+                shutdown.cancel();
+                break;
+            }
+        }
+    }
+    graceful.shutdown().await;
+}
@@ -0,0 +1,448 @@
+use crate::api::rest;
+use crate::db::audit;
+use crate::db::models::{
+    NewWebhook, UpdateWebhook, Webhook, WebhookDelivery, WebhookDeliveryStatus, WebhookEventType,
+};
+use crate::db::schema;
+use crate::db::schema::webhook::dsl as webhook_dsl;
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use chrono::NaiveDateTime;
+use diesel::ExpressionMethods;
+use diesel::SelectableHelper;
+use diesel::pg::Pg;
+use diesel::query_dsl::methods::{FilterDsl, FindDsl, OrderDsl, SelectDsl};
+use diesel::result::DatabaseErrorKind;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+const WEBHOOK_SORT_FIELDS: &[&str] = &["id", "name"];
+const DELIVERY_SORT_FIELDS: &[&str] = &["id", "next_attempt_at"];
+const MIN_SECRET_LEN: usize = 16;
+const MAX_SECRET_LEN: usize = 128;
+const MAX_URL_LEN: usize = 2000;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewWebhookPayload {
+    pub name: String,
+    pub url: String,
+    /// Generated if not given
+    #[serde(default)]
+    pub secret: Option<String>,
+    pub event_types: Vec<WebhookEventType>,
+    #[serde(default)]
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateWebhookPayload {
+    pub name: Option<String>,
+    pub url: Option<String>,
+    pub secret: Option<String>,
+    pub event_types: Option<Vec<WebhookEventType>>,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookPayload {
+    pub id: i32,
+    pub name: String,
+    pub url: String,
+    pub event_types: Vec<WebhookEventType>,
+    pub enabled: bool,
+    pub created_at: NaiveDateTime,
+    /// Only present in the create response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl From<Webhook> for WebhookPayload {
+    fn from(src: Webhook) -> Self {
+        Self {
+            id: src.id,
+            name: src.name,
+            url: src.url,
+            event_types: src.event_types,
+            enabled: src.enabled,
+            created_at: src.created_at,
+            secret: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookDeliveryFilter {
+    pub status: Option<WebhookDeliveryStatus>,
+    pub event_type: Option<WebhookEventType>,
+}
+
+fn validate_name(name: &str) -> Result<(), rest::error::ApiError> {
+    if name.is_empty() || name.len() > 100 {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "name must be between 1 and 100 characters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Absolute http(s) URL
+fn validate_url(url: &str) -> Result<(), rest::error::ApiError> {
+    let invalid = || {
+        rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "url must be an absolute http or https URL".to_string(),
+        )
+    };
+    if url.len() > MAX_URL_LEN {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            format!("url too long (max {})", MAX_URL_LEN),
+        ));
+    }
+    let uri: axum::http::Uri = url.parse().map_err(|_| invalid())?;
+    match (uri.scheme_str(), uri.authority()) {
+        (Some("http" | "https"), Some(_)) => Ok(()),
+        _ => Err(invalid()),
+    }
+}
+
+fn validate_secret(secret: &str) -> Result<(), rest::error::ApiError> {
+    if !(MIN_SECRET_LEN..=MAX_SECRET_LEN).contains(&secret.len()) {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "secret must be between {} and {} characters",
+                MIN_SECRET_LEN, MAX_SECRET_LEN
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Sorted without duplicates
+fn normalize_event_types(
+    mut event_types: Vec<WebhookEventType>,
+) -> Result<Vec<WebhookEventType>, rest::error::ApiError> {
+    if event_types.is_empty() {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "event_types cannot be empty".to_string(),
+        ));
+    }
+    event_types.sort_by_key(|t| *t as u8);
+    event_types.dedup();
+    Ok(event_types)
+}
+
+fn webhook_not_found(webhook_id: i32) -> rest::error::ApiError {
+    rest::error::client_error(
+        StatusCode::NOT_FOUND,
+        format!("webhook {} not found", webhook_id),
+    )
+}
+
+fn filtered_deliveries(
+    webhook_id: i32,
+    filter: &WebhookDeliveryFilter,
+) -> schema::webhook_delivery::BoxedQuery<'static, Pg> {
+    use crate::db::schema::webhook_delivery::dsl::*;
+
+    let mut query = diesel::QueryDsl::into_boxed(webhook_delivery.filter(webhook.eq(webhook_id)));
+    if let Some(s) = filter.status {
+        query = query.filter(status.eq(s));
+    }
+    if let Some(t) = filter.event_type {
+        query = query.filter(event_type.eq(t));
+    }
+    query
+}
+
+#[axum::debug_handler]
+pub async fn create_webhook(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Json(payload): Json<NewWebhookPayload>,
+) -> Result<(StatusCode, Json<WebhookPayload>), rest::error::ApiError> {
+    let name_trimmed = payload.name.trim().to_string();
+    validate_name(&name_trimmed)?;
+    let url = payload.url.trim().to_string();
+    validate_url(&url)?;
+    if let Some(secret) = &payload.secret {
+        validate_secret(secret)?;
+    }
+    let secret = payload.secret.unwrap_or_else(rest::api_key::generate_key);
+    let new_row = NewWebhook {
+        name: name_trimmed.clone(),
+        url,
+        secret: secret.clone(),
+        event_types: normalize_event_types(payload.event_types)?,
+        enabled: payload.enabled.unwrap_or(true),
+    };
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let result: Result<Webhook, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let created: Webhook = diesel::insert_into(webhook_dsl::webhook)
+                    .values(&new_row)
+                    .returning(Webhook::as_returning())
+                    .get_result(conn)
+                    .await?;
+                // The payload without secret, it does not belong in the audit log
+                audit::record(
+                    conn,
+                    actor
+                        .event("create", "webhook", created.id)
+                        .after(&WebhookPayload::from(created.clone())),
+                )
+                .await?;
+                Ok(created)
+            })
+        })
+        .await;
+
+    match result {
+        Ok(created) => {
+            info!(
+                "Created webhook {} \"{}\" for {}",
+                created.id, created.name, created.url
+            );
+            let mut response = WebhookPayload::from(created);
+            response.secret = Some(secret);
+            Ok((StatusCode::CREATED, Json(response)))
+        }
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Err(rest::error::client_error(
+                StatusCode::CONFLICT,
+                format!("webhook '{}' already exists", name_trimmed),
+            ))
+        }
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn list_webhooks(
+    State(api_config): State<rest::RestApiConfig>,
+    Query(page): Query<rest::pagination::PageQuery>,
+) -> Result<rest::pagination::Paged<WebhookPayload>, rest::error::ApiError> {
+    use crate::db::schema::webhook::dsl::*;
+
+    let page = page.validate(WEBHOOK_SORT_FIELDS)?;
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    let result: rest::pagination::Paged<Webhook> = rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || diesel::QueryDsl::into_boxed(webhook),
+        |query| match (page.sort_field, page.descending) {
+            ("name", false) => query.order(name.asc()),
+            ("name", true) => query.order(name.desc()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await?;
+    Ok(result.map(WebhookPayload::from))
+}
+
+#[axum::debug_handler]
+pub async fn get_webhook(
+    State(api_config): State<rest::RestApiConfig>,
+    Path(path_id): Path<i32>,
+) -> Result<Json<WebhookPayload>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    match webhook_dsl::webhook
+        .find(path_id)
+        .select(Webhook::as_select())
+        .first(&mut conn)
+        .await
+    {
+        Ok(webhook) => Ok(Json(webhook.into())),
+        Err(diesel::result::Error::NotFound) => Err(webhook_not_found(path_id)),
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn update_webhook(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+    Json(payload): Json<UpdateWebhookPayload>,
+) -> Result<Json<WebhookPayload>, rest::error::ApiError> {
+    let name = payload.name.map(|n| n.trim().to_string());
+    if let Some(name) = &name {
+        validate_name(name)?;
+    }
+    let url = payload.url.map(|u| u.trim().to_string());
+    if let Some(url) = &url {
+        validate_url(url)?;
+    }
+    if let Some(secret) = &payload.secret {
+        validate_secret(secret)?;
+    }
+    let changes = UpdateWebhook {
+        name,
+        url,
+        secret: payload.secret,
+        event_types: payload.event_types.map(normalize_event_types).transpose()?,
+        enabled: payload.enabled,
+    };
+    if changes.name.is_none()
+        && changes.url.is_none()
+        && changes.secret.is_none()
+        && changes.event_types.is_none()
+        && changes.enabled.is_none()
+    {
+        return Err(rest::error::client_error(
+            StatusCode::BAD_REQUEST,
+            "nothing to update".to_string(),
+        ));
+    }
+
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let result: Result<Webhook, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                let before: Webhook = diesel::QueryDsl::for_update(
+                    webhook_dsl::webhook
+                        .find(path_id)
+                        .select(Webhook::as_select()),
+                )
+                .first(conn)
+                .await?;
+                let updated: Webhook = diesel::update(webhook_dsl::webhook.find(path_id))
+                    .set(&changes)
+                    .returning(Webhook::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("update", "webhook", path_id)
+                        .before(&WebhookPayload::from(before))
+                        .after(&WebhookPayload::from(updated.clone())),
+                )
+                .await?;
+                Ok(updated)
+            })
+        })
+        .await;
+
+    match result {
+        Ok(updated) => Ok(Json(updated.into())),
+        Err(diesel::result::Error::NotFound) => Err(webhook_not_found(path_id)),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Err(rest::error::client_error(
+                StatusCode::CONFLICT,
+                "webhook with this name already exists".to_string(),
+            ))
+        }
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn delete_webhook(
+    State(api_config): State<rest::RestApiConfig>,
+    actor: rest::audit::Actor,
+    Path(path_id): Path<i32>,
+) -> Result<Json<WebhookPayload>, rest::error::ApiError> {
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+
+    let deleted: Result<Webhook, diesel::result::Error> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            Box::pin(async move {
+                // Deliveries are removed by the cascade
+                let row: Webhook = diesel::delete(webhook_dsl::webhook.find(path_id))
+                    .returning(Webhook::as_returning())
+                    .get_result(conn)
+                    .await?;
+                audit::record(
+                    conn,
+                    actor
+                        .event("delete", "webhook", path_id)
+                        .before(&WebhookPayload::from(row.clone())),
+                )
+                .await?;
+                Ok(row)
+            })
+        })
+        .await;
+
+    match deleted {
+        Ok(row) => Ok(Json(row.into())),
+        Err(diesel::result::Error::NotFound) => Err(webhook_not_found(path_id)),
+        Err(e) => Err(rest::error::internal_error(e)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn list_webhook_deliveries(
+    State(api_config): State<rest::RestApiConfig>,
+    Path(path_id): Path<i32>,
+    Query(page): Query<rest::pagination::PageQuery>,
+    Query(filter): Query<WebhookDeliveryFilter>,
+) -> Result<rest::pagination::Paged<WebhookDelivery>, rest::error::ApiError> {
+    use crate::db::schema::webhook_delivery::dsl::*;
+
+    let page = page.validate(DELIVERY_SORT_FIELDS)?;
+    let mut conn = api_config
+        .shared_pool
+        .clone()
+        .get_owned()
+        .await
+        .map_err(rest::error::internal_error)?;
+    let exists: bool = diesel::select(diesel::dsl::exists(webhook_dsl::webhook.find(path_id)))
+        .get_result(&mut conn)
+        .await
+        .map_err(rest::error::internal_error)?;
+    if !exists {
+        return Err(webhook_not_found(path_id));
+    }
+
+    rest::pagination::load_page(
+        &mut conn,
+        &page,
+        || filtered_deliveries(path_id, &filter),
+        |query| match (page.sort_field, page.descending) {
+            ("next_attempt_at", false) => query.order(next_attempt_at.asc()),
+            ("next_attempt_at", true) => query.order(next_attempt_at.desc()),
+            (_, false) => query.order(id.asc()),
+            (_, true) => query.order(id.desc()),
+        },
+        id,
+    )
+    .await
+}
@@ -0,0 +1,49 @@
+use crate::db::models::NewAuditEvent;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+
+impl NewAuditEvent {
+    pub fn new(
+        actor: &str,
+        source_ip: Option<String>,
+        action: &str,
+        entity_type: &str,
+        entity_id: i32,
+    ) -> Self {
+        NewAuditEvent {
+            actor: actor.to_string(),
+            source_ip,
+            action: action.to_string(),
+            entity_type: entity_type.to_string(),
+            entity_id,
+            before: None,
+            after: None,
+        }
+    }
+
+    /// State of the entity before the mutation, must not contain key material
+    pub fn before<T: Serialize>(mut self, state: &T) -> Self {
+        self.before = serde_json::to_value(state).ok();
+        self
+    }
+
+    /// State of the entity after the mutation, must not contain key material
+    pub fn after<T: Serialize>(mut self, state: &T) -> Self {
+        self.after = serde_json::to_value(state).ok();
+        self
+    }
+}
+
+/// Insert `event` in the transaction of the mutation it describes, the table is append-only
+pub async fn record(
+    conn: &mut AsyncPgConnection,
+    event: NewAuditEvent,
+) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::audit_event::dsl as audit_dsl;
+
+    diesel::insert_into(audit_dsl::audit_event)
+        .values(&event)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
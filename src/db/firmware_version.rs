@@ -0,0 +1,135 @@
+use crate::db::schema::firmware::dsl as fw_dsl;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel_async::RunQueryDsl;
+use semver::Version;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FirmwareVersionError {
+    #[error("failed to get database connection: {0}")]
+    Pool(String),
+    #[error(transparent)]
+    Db(#[from] diesel::result::Error),
+}
+
+/// Key whose byte-wise order is the semver precedence of `version`, `None` for other versions
+pub fn sort_key(version: &str) -> Option<String> {
+    let version = Version::parse(version.strip_prefix('v').unwrap_or(version)).ok()?;
+    let mut key = String::new();
+    for number in [version.major, version.minor, version.patch] {
+        push_number(&mut key, number);
+    }
+    if version.pre.is_empty() {
+        // Sorts after every pre-release
+        key.push('~');
+        return Some(key);
+    }
+    key.push('-');
+    for (i, identifier) in version.pre.as_str().split('.').enumerate() {
+        if i > 0 {
+            // Sorts before every character allowed in identifiers
+            key.push('!');
+        }
+        // Numeric identifiers have lower precedence than alphanumeric ones
+        match identifier.parse::<u64>() {
+            Ok(number) => {
+                key.push('0');
+                push_number(&mut key, number);
+            }
+            Err(_) => {
+                key.push('1');
+                key.push_str(identifier);
+            }
+        }
+    }
+    Some(key)
+}
+
+/// Prefixed with the number of digits, so that longer numbers sort after shorter ones
+fn push_number(key: &mut String, number: u64) {
+    let digits = number.to_string();
+    key.push_str(&format!("{:02}", digits.len()));
+    key.push_str(&digits);
+}
+
+/// Set the sort key of firmware stored before versions were parsed.
+/// Returns the number of updated firmware.
+pub async fn backfill_sort_keys(
+    shared_pool: &crate::DbPool,
+) -> Result<usize, FirmwareVersionError> {
+    let mut conn = shared_pool
+        .get()
+        .await
+        .map_err(|e| FirmwareVersionError::Pool(e.to_string()))?;
+
+    let rows: Vec<(i32, String)> = fw_dsl::firmware
+        .filter(fw_dsl::version_sort_key.is_null())
+        .select((fw_dsl::id, fw_dsl::version))
+        .load(&mut conn)
+        .await?;
+    let mut count = 0;
+    for (id, version) in rows {
+        let Some(key) = sort_key(&version) else {
+            continue;
+        };
+        diesel::update(fw_dsl::firmware.find(id))
+            .set(fw_dsl::version_sort_key.eq(key))
+            .execute(&mut conn)
+            .await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_by_semver_precedence() {
+        // Example of the semver specification, section 11
+        let versions = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+        ];
+        let mut keys: Vec<(String, &str)> = versions
+            .iter()
+            .rev()
+            .map(|version| (sort_key(version).unwrap(), *version))
+            .collect();
+        keys.sort();
+        let sorted: Vec<&str> = keys.into_iter().map(|(_, version)| version).collect();
+        assert_eq!(sorted, versions);
+    }
+
+    #[test]
+    fn compares_numbers_by_value() {
+        assert!(sort_key("9.0.0") < sort_key("10.0.0"));
+        assert!(sort_key("1.9.0") < sort_key("1.10.0"));
+        assert!(sort_key("1.0.9") < sort_key("1.0.10"));
+        assert!(sort_key("1.0.0") < sort_key("1.0.1-alpha"));
+    }
+
+    #[test]
+    fn ignores_v_prefix_and_build_metadata() {
+        assert_eq!(sort_key("v1.2.3"), sort_key("1.2.3"));
+        assert_eq!(sort_key("v1.2.3-rc.1"), sort_key("1.2.3-rc.1"));
+        assert_eq!(sort_key("1.2.3+build.5"), sort_key("1.2.3"));
+    }
+
+    #[test]
+    fn no_key_for_other_versions() {
+        assert_eq!(sort_key(""), None);
+        assert_eq!(sort_key("1.2"), None);
+        assert_eq!(sort_key("V1.2.3"), None);
+        assert_eq!(sort_key("2024-01-05"), None);
+        assert_eq!(sort_key("release"), None);
+    }
+}
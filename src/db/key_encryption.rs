@@ -0,0 +1,389 @@
+use crate::db::models::LightweightKeyDetails;
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use zeroize::Zeroize;
+
+pub const KEK_LEN: usize = 32;
+const KEK_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+const FINGERPRINT_LEN: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum KeyEncryptionError {
+    #[error("invalid key encryption key: {0}")]
+    InvalidKek(String),
+    #[error("key of device key {0} is wrapped with an unknown key encryption key")]
+    UnknownKek(i32),
+    #[error("failed to wrap key of device key {0}")]
+    Encryption(i32),
+    #[error("failed to unwrap key of device key {0}")]
+    Decryption(i32),
+    #[error(transparent)]
+    Db(#[from] diesel::result::Error),
+    #[error("failed to get DB connection: {0}")]
+    Pool(String),
+}
+
+/// AES-256-GCM key wrapping the lightweight device keys at rest
+pub struct KeyEncryptionKey {
+    key: Vec<u8>,
+    id: Vec<u8>,
+}
+
+impl Drop for KeyEncryptionKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl KeyEncryptionKey {
+    pub fn new(key: Vec<u8>) -> Result<Self, KeyEncryptionError> {
+        if key.len() != KEK_LEN {
+            return Err(KeyEncryptionError::InvalidKek(format!(
+                "length {} should be {}",
+                key.len(),
+                KEK_LEN
+            )));
+        }
+        let id = Sha256::digest(&key)[..KEK_ID_LEN].to_vec();
+        Ok(KeyEncryptionKey { key, id })
+    }
+
+    /// Load a base64 encoded KEK from the file named by `<var>_FILE` or from `<var>` itself
+    pub fn from_env(var: &str) -> Result<Option<Self>, KeyEncryptionError> {
+        let encoded = match std::env::var(format!("{}_FILE", var)) {
+            Ok(path) => std::fs::read_to_string(&path).map_err(|e| {
+                KeyEncryptionError::InvalidKek(format!("failed to read {}: {}", path, e))
+            })?,
+            Err(_) => match std::env::var(var) {
+                Ok(value) => value,
+                Err(_) => return Ok(None),
+            },
+        };
+        // Left empty in the .env templates
+        if encoded.trim().is_empty() {
+            return Ok(None);
+        }
+        let key = STANDARD
+            .decode(encoded.trim().as_bytes())
+            .map_err(|e| KeyEncryptionError::InvalidKek(e.to_string()))?;
+        Self::new(key).map(Some)
+    }
+
+    /// First bytes of the SHA-256 of the key, stored with every key it wraps
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// Returns `nonce || ciphertext || tag`
+    pub fn wrap(&self, device_key_id: i32, key: &[u8]) -> Result<Vec<u8>, KeyEncryptionError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::fill(&mut nonce).map_err(|_| KeyEncryptionError::Encryption(device_key_id))?;
+        // Binds the wrapped key to its row, so it cannot be swapped with another one
+        let aad = device_key_id.to_be_bytes();
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: key,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| KeyEncryptionError::Encryption(device_key_id))?;
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&ciphertext);
+        Ok(wrapped)
+    }
+
+    pub fn unwrap(&self, details: &LightweightKeyDetails) -> Result<Vec<u8>, KeyEncryptionError> {
+        if details.kek_id.as_deref() != Some(self.id()) {
+            return Err(KeyEncryptionError::UnknownKek(details.device_key));
+        }
+        if details.key.len() < NONCE_LEN {
+            return Err(KeyEncryptionError::Decryption(details.device_key));
+        }
+        let (nonce, ciphertext) = details.key.split_at(NONCE_LEN);
+        let aad = details.device_key.to_be_bytes();
+        self.cipher()
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| KeyEncryptionError::Decryption(details.device_key))
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.key))
+    }
+}
+
+/// Hex encoded start of the SHA-256 of a plaintext key, identifies it without revealing it
+pub fn fingerprint(key: &[u8]) -> String {
+    Sha256::digest(key)[..FINGERPRINT_LEN]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Key of `details` wrapped with `new_kek` and its fingerprint, `None` if it already is wrapped
+/// and fingerprinted
+fn rewrap(
+    details: &LightweightKeyDetails,
+    old_kek: Option<&KeyEncryptionKey>,
+    new_kek: &KeyEncryptionKey,
+) -> Result<Option<(Vec<u8>, String)>, KeyEncryptionError> {
+    let mut key = match details.kek_id.as_deref() {
+        Some(id) if id == new_kek.id() && details.fingerprint.is_some() => return Ok(None),
+        Some(id) if id == new_kek.id() => new_kek.unwrap(details)?,
+        None => details.key.clone(),
+        Some(_) => match old_kek {
+            Some(old_kek) => old_kek.unwrap(details)?,
+            None => return Err(KeyEncryptionError::UnknownKek(details.device_key)),
+        },
+    };
+    let wrapped = new_kek.wrap(details.device_key, &key);
+    let fingerprint = fingerprint(&key);
+    key.zeroize();
+    Ok(Some((wrapped?, fingerprint)))
+}
+
+/// Wrap all lightweight keys with `new_kek`.
+///
+/// Plaintext keys and keys wrapped with `old_kek` are re-wrapped, keys already wrapped with
+/// `new_kek` are left untouched unless they lack a fingerprint. Any other key aborts the whole
+/// operation. Returns the number of updated keys.
+pub async fn rewrap_keys(
+    shared_pool: &crate::DbPool,
+    old_kek: Option<&KeyEncryptionKey>,
+    new_kek: &KeyEncryptionKey,
+) -> Result<usize, KeyEncryptionError> {
+    use crate::db::schema::lightweight_key_details::dsl as lw_dsl;
+
+    let mut conn = shared_pool
+        .get()
+        .await
+        .map_err(|e| KeyEncryptionError::Pool(e.to_string()))?;
+
+    conn.transaction::<_, KeyEncryptionError, _>(|mut conn| {
+        Box::pin(async move {
+            let rows: Vec<LightweightKeyDetails> = lw_dsl::lightweight_key_details
+                .select(LightweightKeyDetails::as_select())
+                .for_update()
+                .load(&mut conn)
+                .await?;
+
+            let mut count = 0;
+            for details in rows {
+                let Some((wrapped, fingerprint)) = rewrap(&details, old_kek, new_kek)? else {
+                    continue;
+                };
+                diesel::update(lw_dsl::lightweight_key_details.find(details.id))
+                    .set((
+                        lw_dsl::key.eq(wrapped),
+                        lw_dsl::kek_id.eq(Some(new_kek.id().to_vec())),
+                        lw_dsl::fingerprint.eq(Some(fingerprint)),
+                    ))
+                    .execute(&mut conn)
+                    .await?;
+                count += 1;
+            }
+            Ok(count)
+        })
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::CryptoAlgorithm;
+
+    const KEY: &[u8] = b"0123456789abcdef";
+
+    fn kek(byte: u8) -> KeyEncryptionKey {
+        KeyEncryptionKey::new(vec![byte; KEK_LEN]).unwrap()
+    }
+
+    fn details(device_key: i32, key: Vec<u8>, kek_id: Option<&[u8]>) -> LightweightKeyDetails {
+        LightweightKeyDetails {
+            id: 1,
+            device_key,
+            algorithm: CryptoAlgorithm::AesGcm128,
+            key,
+            kek_id: kek_id.map(|id| id.to_vec()),
+            fingerprint: None,
+        }
+    }
+
+    fn wrapped(kek: &KeyEncryptionKey, device_key: i32) -> LightweightKeyDetails {
+        LightweightKeyDetails {
+            fingerprint: Some(fingerprint(KEY)),
+            ..details(
+                device_key,
+                kek.wrap(device_key, KEY).unwrap(),
+                Some(kek.id()),
+            )
+        }
+    }
+
+    #[test]
+    fn kek_id_is_sha256_prefix() {
+        let key = vec![7u8; KEK_LEN];
+        let derived = KeyEncryptionKey::new(key.clone()).unwrap();
+        assert_eq!(derived.id(), &Sha256::digest(&key)[..KEK_ID_LEN]);
+        assert_eq!(derived.id(), kek(7).id());
+        assert_ne!(derived.id(), kek(8).id());
+    }
+
+    #[test]
+    fn rejects_kek_of_wrong_length() {
+        assert!(KeyEncryptionKey::new(vec![0; KEK_LEN - 1]).is_err());
+        assert!(KeyEncryptionKey::new(vec![0; KEK_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn wrap_unwrap_round_trip() {
+        let kek = kek(1);
+        let details = wrapped(&kek, 42);
+        assert_eq!(details.key.len(), NONCE_LEN + KEY.len() + 16);
+        assert_ne!(&details.key[NONCE_LEN..NONCE_LEN + KEY.len()], KEY);
+        assert_eq!(kek.unwrap(&details).unwrap(), KEY);
+    }
+
+    #[test]
+    fn wrap_uses_fresh_nonce() {
+        let kek = kek(1);
+        assert_ne!(kek.wrap(42, KEY).unwrap(), kek.wrap(42, KEY).unwrap());
+    }
+
+    #[test]
+    fn unwrap_fails_under_other_kek() {
+        let details = wrapped(&kek(1), 42);
+        let other = kek(2);
+        assert!(matches!(
+            other.unwrap(&details),
+            Err(KeyEncryptionError::UnknownKek(42))
+        ));
+
+        // Even if the stored id claims the other KEK, authentication fails
+        let forged = LightweightKeyDetails {
+            kek_id: Some(other.id().to_vec()),
+            ..details
+        };
+        assert!(matches!(
+            other.unwrap(&forged),
+            Err(KeyEncryptionError::Decryption(42))
+        ));
+    }
+
+    #[test]
+    fn unwrap_fails_for_other_device_key() {
+        let kek = kek(1);
+        let moved = LightweightKeyDetails {
+            device_key: 43,
+            ..wrapped(&kek, 42)
+        };
+        assert!(matches!(
+            kek.unwrap(&moved),
+            Err(KeyEncryptionError::Decryption(43))
+        ));
+    }
+
+    #[test]
+    fn unwrap_fails_for_truncated_key() {
+        let kek = kek(1);
+        let mut details = wrapped(&kek, 42);
+        details.key.truncate(NONCE_LEN - 1);
+        assert!(matches!(
+            kek.unwrap(&details),
+            Err(KeyEncryptionError::Decryption(42))
+        ));
+    }
+
+    #[test]
+    fn rewrap_leaves_keys_wrapped_with_new_kek() {
+        let new_kek = kek(2);
+        let details = wrapped(&new_kek, 42);
+        assert!(rewrap(&details, Some(&kek(1)), &new_kek).unwrap().is_none());
+        assert!(rewrap(&details, None, &new_kek).unwrap().is_none());
+    }
+
+    #[test]
+    fn rewrap_fingerprints_keys_wrapped_with_new_kek() {
+        let new_kek = kek(2);
+        let unfingerprinted = LightweightKeyDetails {
+            fingerprint: None,
+            ..wrapped(&new_kek, 42)
+        };
+        let (key, fingerprint) = rewrap(&unfingerprinted, None, &new_kek).unwrap().unwrap();
+        assert_eq!(fingerprint, super::fingerprint(KEY));
+        assert_eq!(
+            new_kek
+                .unwrap(&details(42, key, Some(new_kek.id())))
+                .unwrap(),
+            KEY
+        );
+    }
+
+    #[test]
+    fn rewrap_wraps_plaintext_keys() {
+        let new_kek = kek(2);
+        let plain = details(42, KEY.to_vec(), None);
+        let (key, fingerprint) = rewrap(&plain, None, &new_kek).unwrap().unwrap();
+        assert_eq!(fingerprint, super::fingerprint(KEY));
+        assert_eq!(
+            new_kek
+                .unwrap(&details(42, key, Some(new_kek.id())))
+                .unwrap(),
+            KEY
+        );
+    }
+
+    #[test]
+    fn rewrap_moves_keys_from_old_kek() {
+        let old_kek = kek(1);
+        let new_kek = kek(2);
+        let (key, fingerprint) = rewrap(&wrapped(&old_kek, 42), Some(&old_kek), &new_kek)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fingerprint, super::fingerprint(KEY));
+        assert_eq!(
+            new_kek
+                .unwrap(&details(42, key, Some(new_kek.id())))
+                .unwrap(),
+            KEY
+        );
+    }
+
+    #[test]
+    fn fingerprint_is_hex_sha256_prefix() {
+        // SHA-256 test vector of FIPS 180-2
+        assert_eq!(fingerprint(b"abc"), "ba7816bf8f01cfea");
+    }
+
+    #[test]
+    fn rewrap_rejects_keys_of_unknown_kek() {
+        let details = wrapped(&kek(3), 42);
+        assert!(matches!(
+            rewrap(&details, None, &kek(2)),
+            Err(KeyEncryptionError::UnknownKek(42))
+        ));
+        assert!(matches!(
+            rewrap(&details, Some(&kek(1)), &kek(2)),
+            Err(KeyEncryptionError::UnknownKek(42))
+        ));
+    }
+}
@@ -0,0 +1,64 @@
+use crate::db::models::KeyStatus;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+/// Whether `key_id` is currently the NEXT key of the device
+pub async fn is_next_key(
+    conn: &mut AsyncPgConnection,
+    device_id: i32,
+    key_id: i32,
+) -> Result<bool, diesel::result::Error> {
+    use crate::db::schema::device_key::dsl as key_dsl;
+
+    let next_filter = key_dsl::device_key
+        .filter(key_dsl::id.eq(key_id))
+        .filter(key_dsl::device.eq(device_id))
+        .filter(key_dsl::status.eq(KeyStatus::Next));
+    diesel::select(diesel::dsl::exists(next_filter))
+        .get_result(conn)
+        .await
+}
+
+/// Promote the NEXT key `key_id` of a device to ACTIVE and expire the previously ACTIVE key.
+///
+/// Has to be called inside a transaction. Returns `false` if `key_id` is not a NEXT key of
+/// the device (e.g. because it was promoted concurrently).
+pub async fn promote_key(
+    conn: &mut AsyncPgConnection,
+    device_id: i32,
+    key_id: i32,
+) -> Result<bool, diesel::result::Error> {
+    use crate::db::schema::device_key::dsl as key_dsl;
+    use crate::db::schema::device_replay_window::dsl as window_dsl;
+
+    // Same lock as key creation, so a new NEXT key cannot appear in between
+    diesel::dsl::sql_query("SELECT pg_advisory_xact_lock($1)")
+        .bind::<diesel::sql_types::BigInt, _>(device_id as i64)
+        .execute(conn)
+        .await?;
+
+    if !is_next_key(conn, device_id, key_id).await? {
+        return Ok(false);
+    }
+
+    diesel::update(
+        key_dsl::device_key
+            .filter(key_dsl::device.eq(device_id))
+            .filter(key_dsl::status.eq(KeyStatus::Active)),
+    )
+    .set(key_dsl::status.eq(KeyStatus::Expired))
+    .execute(conn)
+    .await?;
+    diesel::update(key_dsl::device_key.find(key_id))
+        .set(key_dsl::status.eq(KeyStatus::Active))
+        .execute(conn)
+        .await?;
+
+    // Sequence numbers are only unique per key, messages under the expired key can no
+    // longer be decrypted so its replay window can be dropped
+    diesel::delete(window_dsl::device_replay_window.find(device_id))
+        .execute(conn)
+        .await?;
+    Ok(true)
+}
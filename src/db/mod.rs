@@ -1,2 +1,9 @@
+pub mod audit;
+pub mod firmware_version;
+pub mod key_encryption;
+pub mod key_rotation;
+pub mod migrations;
 pub mod models;
+pub mod rollout;
 pub mod schema;
+pub mod webhook;
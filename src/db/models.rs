@@ -3,6 +3,52 @@ use diesel::prelude::*;
 
 use diesel_derive_enum::DbEnum;
 
+/// Ordered by privilege, each role includes the permissions of the previous ones
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, DbEnum, serde::Serialize, serde::Deserialize,
+)]
+#[ExistingTypePath = "crate::db::schema::sql_types::ApiKeyRole"]
+#[DbValueStyle = "SCREAMING_SNAKE_CASE"]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ApiKeyRole {
+    ReadOnly,
+    Operator,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, serde::Serialize, serde::Deserialize)]
+#[ExistingTypePath = "crate::db::schema::sql_types::CampaignDeviceStatus"]
+#[DbValueStyle = "SCREAMING_SNAKE_CASE"]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CampaignDeviceStatus {
+    /// Not yet assigned the target firmware
+    Pending,
+    /// Target firmware assigned, waiting for the device to report it
+    Assigned,
+    Succeeded,
+    /// No report of the target firmware within the device timeout
+    Failed,
+    /// Reported another firmware after downloading the target firmware
+    RolledBack,
+    /// Removed by aborting the campaign or because the device no longer fits
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, serde::Serialize, serde::Deserialize)]
+#[ExistingTypePath = "crate::db::schema::sql_types::CampaignStatus"]
+#[DbValueStyle = "SCREAMING_SNAKE_CASE"]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CampaignStatus {
+    #[serde(alias = "running")]
+    Running,
+    #[serde(alias = "paused")]
+    Paused,
+    #[serde(alias = "aborted")]
+    Aborted,
+    #[serde(alias = "completed")]
+    Completed,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, serde::Serialize, serde::Deserialize)]
 #[ExistingTypePath = "crate::db::schema::sql_types::CryptoAlgorithm"]
 pub enum CryptoAlgorithm {
@@ -17,15 +63,28 @@ pub enum CryptoAlgorithm {
     AsconAead128,
 }
 
+impl CryptoAlgorithm {
+    /// Key length in bytes required by the algorithm
+    pub fn key_len(&self) -> usize {
+        match self {
+            CryptoAlgorithm::AesGcm128 => 16,
+            CryptoAlgorithm::AsconAead128 => 16,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, serde::Serialize, serde::Deserialize)]
 #[ExistingTypePath = "crate::db::schema::sql_types::DeviceStatus"]
 #[DbValueStyle = "snake_case"]
 pub enum DeviceStatus {
     #[db_rename = "ACTIVE"]
+    #[serde(alias = "ACTIVE")]
     Active = 0,
     #[db_rename = "INACTIVE"]
+    #[serde(alias = "INACTIVE")]
     Inactive = 1,
     #[db_rename = "MAINTENANCE"]
+    #[serde(alias = "MAINTENANCE")]
     Maintenance = 2,
 }
 
@@ -62,6 +121,52 @@ pub enum ParameterType {
     Binary,
 }
 
+/// Ordered by maturity
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, DbEnum, serde::Serialize, serde::Deserialize,
+)]
+#[ExistingTypePath = "crate::db::schema::sql_types::ReleaseChannel"]
+#[DbValueStyle = "SCREAMING_SNAKE_CASE"]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ReleaseChannel {
+    #[serde(alias = "dev")]
+    Dev,
+    #[serde(alias = "beta")]
+    Beta,
+    #[serde(alias = "stable")]
+    Stable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, serde::Serialize, serde::Deserialize)]
+#[ExistingTypePath = "crate::db::schema::sql_types::WebhookDeliveryStatus"]
+#[DbValueStyle = "SCREAMING_SNAKE_CASE"]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum WebhookDeliveryStatus {
+    #[serde(alias = "pending")]
+    Pending,
+    #[serde(alias = "delivered")]
+    Delivered,
+    /// Gave up after the maximum number of attempts
+    #[serde(alias = "failed")]
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, serde::Serialize, serde::Deserialize)]
+#[ExistingTypePath = "crate::db::schema::sql_types::WebhookEventType"]
+#[DbValueStyle = "SCREAMING_SNAKE_CASE"]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum WebhookEventType {
+    /// A device reported a firmware other than the one it reported before
+    #[serde(alias = "device_firmware_reported")]
+    DeviceFirmwareReported,
+    #[serde(alias = "device_key_created")]
+    DeviceKeyCreated,
+    #[serde(alias = "device_key_deleted")]
+    DeviceKeyDeleted,
+    #[serde(alias = "firmware_uploaded")]
+    FirmwareUploaded,
+}
+
 // -----------------------------
 // Models
 // -----------------------------
@@ -107,6 +212,41 @@ pub struct UpdateDevice {
     pub status: Option<DeviceStatus>,
 }
 
+// device_group
+#[derive(Debug, Clone, Identifiable, Queryable, Selectable, serde::Serialize)]
+#[diesel(table_name = crate::db::schema::device_group)]
+pub struct DeviceGroup {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Insertable, serde::Serialize, serde::Deserialize)]
+#[diesel(table_name = crate::db::schema::device_group)]
+pub struct NewDeviceGroup {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, AsChangeset, serde::Serialize)]
+#[diesel(table_name = crate::db::schema::device_group)]
+pub struct UpdateDeviceGroup {
+    pub name: Option<String>,
+    /// `Some(None)` clears the description
+    pub description: Option<Option<String>>,
+}
+
+// device_group_device
+#[derive(Debug, Clone, Identifiable, Queryable, Selectable, Associations, serde::Serialize)]
+#[diesel(table_name = crate::db::schema::device_group_device)]
+#[diesel(belongs_to(DeviceGroup, foreign_key = device_group))]
+#[diesel(belongs_to(Device, foreign_key = device))]
+pub struct DeviceGroupDevice {
+    pub id: i32,
+    pub device_group: i32,
+    pub device: i32,
+}
+
 // device_key
 #[derive(Debug, Clone, Identifiable, Queryable, Selectable, Associations, AsChangeset)]
 #[diesel(table_name = crate::db::schema::device_key)]
@@ -116,6 +256,8 @@ pub struct DeviceKey {
     pub device: i32,
     pub key_type: KeyType,
     pub status: KeyStatus,
+    pub delivered_at: Option<NaiveDateTime>, // NEXT key fetched by the device
+    pub acknowledged_at: Option<NaiveDateTime>, // NEXT key installed by the device
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -148,6 +290,33 @@ pub struct NewDeviceParameter {
     pub value: Option<Vec<u8>>,
 }
 
+// device_replay_window
+#[derive(Debug, Clone, Identifiable, Queryable, Selectable, Insertable, AsChangeset)]
+#[diesel(table_name = crate::db::schema::device_replay_window)]
+#[diesel(primary_key(device))]
+pub struct DeviceReplayWindow {
+    pub device: i32, // FK -> device.id
+    pub highest_sequence_number: i64,
+    pub window_bitmap: i64,
+}
+
+// device_tag
+#[derive(Debug, Clone, Identifiable, Queryable, Selectable, Associations, serde::Serialize)]
+#[diesel(table_name = crate::db::schema::device_tag)]
+#[diesel(belongs_to(Device, foreign_key = device))]
+pub struct DeviceTag {
+    pub id: i32,
+    pub device: i32,
+    pub tag: String,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::db::schema::device_tag)]
+pub struct NewDeviceTag {
+    pub device: i32,
+    pub tag: String,
+}
+
 // device_type
 #[derive(Debug, Clone, Identifiable, Queryable, Selectable, AsChangeset, serde::Serialize)]
 #[diesel(table_name = crate::db::schema::device_type)]
@@ -235,6 +404,14 @@ pub struct Firmware {
     pub file_id: String,
     pub size: i64,
     pub sha256: String,
+    pub description: Option<String>,
+    pub release_notes: Option<String>,
+    pub build_timestamp: Option<NaiveDateTime>,
+    pub git_commit: Option<String>,
+    pub channel: ReleaseChannel,
+    /// See `crate::db::firmware_version`
+    #[serde(skip)]
+    pub version_sort_key: Option<String>,
 }
 
 #[derive(Debug, Clone, Insertable, serde::Serialize, serde::Deserialize)]
@@ -245,6 +422,33 @@ pub struct NewFirmware {
     pub file_id: String,
     pub size: i64,
     pub sha256: String,
+    pub description: Option<String>,
+    pub release_notes: Option<String>,
+    pub build_timestamp: Option<NaiveDateTime>,
+    pub git_commit: Option<String>,
+    pub channel: ReleaseChannel,
+    /// See `crate::db::firmware_version`
+    #[serde(skip)]
+    pub version_sort_key: Option<String>,
+}
+
+#[derive(Debug, Clone, AsChangeset, serde::Serialize, serde::Deserialize)]
+#[diesel(table_name = crate::db::schema::firmware)]
+pub struct UpdateFirmware {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// `Some(None)` clears the description, likewise for the other optional fields
+    pub description: Option<Option<String>>,
+    pub file_id: Option<String>,
+    pub size: Option<i64>,
+    pub sha256: Option<String>,
+    pub release_notes: Option<Option<String>>,
+    pub build_timestamp: Option<Option<NaiveDateTime>>,
+    pub git_commit: Option<Option<String>>,
+    pub channel: Option<ReleaseChannel>,
+    /// Set together with `version`
+    #[serde(skip)]
+    pub version_sort_key: Option<Option<String>>,
 }
 
 // lightweight_key_details
@@ -265,7 +469,9 @@ pub struct LightweightKeyDetails {
     pub id: i32,
     pub device_key: i32, // FK -> device_key.id
     pub algorithm: CryptoAlgorithm,
-    pub key: Vec<u8>,
+    pub key: Vec<u8>, // wrapped, see db::key_encryption
+    pub kek_id: Option<Vec<u8>>,
+    pub fingerprint: Option<String>,
 }
 
 #[derive(Debug, Clone, Insertable, serde::Serialize, serde::Deserialize)]
@@ -274,7 +480,9 @@ pub struct LightweightKeyDetails {
 pub struct NewLightweightKeyDetails {
     pub device_key: i32,
     pub algorithm: CryptoAlgorithm,
-    pub key: Vec<u8>,
+    pub key: Vec<u8>, // wrapped, see db::key_encryption
+    pub kek_id: Option<Vec<u8>>,
+    pub fingerprint: Option<String>,
 }
 
 // tls_key_details
@@ -296,6 +504,9 @@ pub struct TlsKeyDetails {
     pub device_key: i32, // FK -> device_key.id
     pub valid_from: NaiveDateTime,
     pub valid_to: NaiveDateTime,
+    pub serial: String,      // hex encoded
+    pub certificate: String, // PEM
+    pub revoked_at: Option<NaiveDateTime>,
 }
 
 #[derive(Debug, Clone, Insertable, serde::Serialize, serde::Deserialize)]
@@ -305,4 +516,164 @@ pub struct NewTlsKeyDetails {
     pub device_key: i32,
     pub valid_from: NaiveDateTime,
     pub valid_to: NaiveDateTime,
+    pub serial: String,
+    pub certificate: String,
+}
+
+// api_key
+#[derive(Debug, Clone, Identifiable, Queryable, Selectable)]
+#[diesel(table_name = crate::db::schema::api_key)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    pub key_hash: Vec<u8>, // SHA-256 of the key
+    pub role: ApiKeyRole,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::db::schema::api_key)]
+pub struct NewApiKey {
+    pub name: String,
+    pub key_hash: Vec<u8>,
+    pub role: ApiKeyRole,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+// audit_event
+#[derive(Debug, Clone, Identifiable, Queryable, Selectable, serde::Serialize)]
+#[diesel(table_name = crate::db::schema::audit_event)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub created_at: NaiveDateTime,
+    pub actor: String,
+    pub source_ip: Option<String>,
+    pub action: String,
+    pub entity_type: String,
+    pub entity_id: i32,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::db::schema::audit_event)]
+pub struct NewAuditEvent {
+    pub actor: String,
+    pub source_ip: Option<String>,
+    pub action: String,
+    pub entity_type: String,
+    pub entity_id: i32,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+// rollout_campaign
+#[derive(Debug, Clone, Identifiable, Queryable, Selectable, serde::Serialize)]
+#[diesel(table_name = crate::db::schema::rollout_campaign)]
+pub struct RolloutCampaign {
+    pub id: i32,
+    pub name: String,
+    pub target_firmware: i32,
+    pub device_filter: serde_json::Value,
+    pub waves: Vec<i32>,
+    pub wave_interval_seconds: i32,
+    pub device_timeout_seconds: i32,
+    pub max_failure_percent: i32,
+    pub status: CampaignStatus,
+    pub status_reason: Option<String>,
+    pub current_wave: i32,
+    pub created_at: NaiveDateTime,
+    pub wave_started_at: Option<NaiveDateTime>,
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::db::schema::rollout_campaign)]
+pub struct NewRolloutCampaign {
+    pub name: String,
+    pub target_firmware: i32,
+    pub device_filter: serde_json::Value,
+    pub waves: Vec<i32>,
+    pub wave_interval_seconds: i32,
+    pub device_timeout_seconds: i32,
+    pub max_failure_percent: i32,
+}
+
+// rollout_campaign_device
+#[derive(Debug, Clone, Identifiable, Queryable, Selectable, Associations, serde::Serialize)]
+#[diesel(table_name = crate::db::schema::rollout_campaign_device)]
+#[diesel(belongs_to(RolloutCampaign, foreign_key = campaign))]
+pub struct RolloutCampaignDevice {
+    pub id: i32,
+    pub campaign: i32,
+    pub device: i32,
+    pub status: CampaignDeviceStatus,
+    pub wave: Option<i32>,
+    pub previous_desired_firmware: Option<i32>,
+    pub assigned_at: Option<NaiveDateTime>,
+    pub downloaded_at: Option<NaiveDateTime>,
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+// webhook
+#[derive(Debug, Clone, Identifiable, Queryable, Selectable)]
+#[diesel(table_name = crate::db::schema::webhook)]
+pub struct Webhook {
+    pub id: i32,
+    pub name: String,
+    pub url: String,
+    pub secret: String, // HMAC key of the request signatures
+    pub event_types: Vec<WebhookEventType>,
+    pub enabled: bool,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::db::schema::webhook)]
+pub struct NewWebhook {
+    pub name: String,
+    pub url: String,
+    pub secret: String,
+    pub event_types: Vec<WebhookEventType>,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, AsChangeset)]
+#[diesel(table_name = crate::db::schema::webhook)]
+pub struct UpdateWebhook {
+    pub name: Option<String>,
+    pub url: Option<String>,
+    pub secret: Option<String>,
+    pub event_types: Option<Vec<WebhookEventType>>,
+    pub enabled: Option<bool>,
+}
+
+// webhook_delivery
+#[derive(Debug, Clone, Identifiable, Queryable, Selectable, Associations, serde::Serialize)]
+#[diesel(table_name = crate::db::schema::webhook_delivery)]
+#[diesel(belongs_to(Webhook, foreign_key = webhook))]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook: i32,
+    pub event_type: WebhookEventType,
+    pub payload: serde_json::Value,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_attempt_at: Option<NaiveDateTime>,
+    pub last_response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::db::schema::webhook_delivery)]
+pub struct NewWebhookDelivery {
+    pub webhook: i32,
+    pub event_type: WebhookEventType,
+    pub payload: serde_json::Value,
 }
@@ -0,0 +1,502 @@
+use crate::db::audit;
+use crate::db::models::{
+    CampaignDeviceStatus, CampaignStatus, Device, NewAuditEvent, RolloutCampaign,
+    RolloutCampaignDevice,
+};
+use crate::db::schema::device::dsl as device_dsl;
+use crate::db::schema::device_type_firmware::dsl as link_dsl;
+use crate::db::schema::rollout_campaign::dsl as campaign_dsl;
+use crate::db::schema::rollout_campaign_device::dsl as member_dsl;
+use chrono::NaiveDateTime;
+use diesel::ExpressionMethods;
+use diesel::OptionalExtension;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use log::{error, info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Actor of the audit events recorded by the campaign runner
+pub const RUNNER_ACTOR: &str = "rollout";
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of campaign devices per status
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct CampaignStats {
+    pub pending: i64,
+    pub assigned: i64,
+    pub succeeded: i64,
+    pub failed: i64,
+    pub rolled_back: i64,
+    pub cancelled: i64,
+}
+
+impl CampaignStats {
+    pub fn finished(&self) -> i64 {
+        self.succeeded + self.failed + self.rolled_back
+    }
+
+    /// Failed and rolled back devices exceed `max_failure_percent` of the finished ones
+    pub fn exceeds(&self, max_failure_percent: i32) -> bool {
+        let finished = self.finished();
+        finished > 0
+            && (self.failed + self.rolled_back) * 100 > max_failure_percent as i64 * finished
+    }
+}
+
+pub async fn stats(
+    conn: &mut AsyncPgConnection,
+    campaign_id: i32,
+) -> Result<CampaignStats, diesel::result::Error> {
+    let counts: Vec<(CampaignDeviceStatus, i64)> = member_dsl::rollout_campaign_device
+        .filter(member_dsl::campaign.eq(campaign_id))
+        .group_by(member_dsl::status)
+        .select((member_dsl::status, diesel::dsl::count_star()))
+        .load(conn)
+        .await?;
+    let mut stats = CampaignStats::default();
+    for (status, count) in counts {
+        match status {
+            CampaignDeviceStatus::Pending => stats.pending = count,
+            CampaignDeviceStatus::Assigned => stats.assigned = count,
+            CampaignDeviceStatus::Succeeded => stats.succeeded = count,
+            CampaignDeviceStatus::Failed => stats.failed = count,
+            CampaignDeviceStatus::RolledBack => stats.rolled_back = count,
+            CampaignDeviceStatus::Cancelled => stats.cancelled = count,
+        }
+    }
+    Ok(stats)
+}
+
+/// Advance the running campaigns every `TICK_INTERVAL` until `cancel` is triggered
+pub async fn run(shared_pool: Arc<crate::DbPool>, cancel: CancellationToken) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = cancel.cancelled() => break,
+        }
+        let mut conn = match shared_pool.get().await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to get DB connection for rollout campaigns: {}", e);
+                continue;
+            }
+        };
+        let running: Vec<i32> = match campaign_dsl::rollout_campaign
+            .filter(campaign_dsl::status.eq(CampaignStatus::Running))
+            .select(campaign_dsl::id)
+            .load(&mut conn)
+            .await
+        {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("Failed to load running rollout campaigns: {}", e);
+                continue;
+            }
+        };
+        for campaign_id in running {
+            let result = conn
+                .transaction::<_, diesel::result::Error, _>(|conn| {
+                    Box::pin(async move { step(conn, campaign_id).await })
+                })
+                .await;
+            if let Err(e) = result {
+                error!("Failed to advance rollout campaign {}: {}", campaign_id, e);
+            }
+        }
+    }
+}
+
+/// Fail timed out devices, then pause or complete the campaign or start its next wave.
+/// Has to be called inside a transaction.
+async fn step(conn: &mut AsyncPgConnection, campaign_id: i32) -> Result<(), diesel::result::Error> {
+    // Skipped if another instance or a REST call is working on the campaign
+    let Some(campaign): Option<RolloutCampaign> = campaign_dsl::rollout_campaign
+        .find(campaign_id)
+        .filter(campaign_dsl::status.eq(CampaignStatus::Running))
+        .select(RolloutCampaign::as_select())
+        .for_update()
+        .skip_locked()
+        .first(conn)
+        .await
+        .optional()?
+    else {
+        return Ok(());
+    };
+    let now = chrono::Utc::now().naive_utc();
+
+    let deadline = now - chrono::Duration::seconds(campaign.device_timeout_seconds as i64);
+    diesel::update(
+        member_dsl::rollout_campaign_device
+            .filter(member_dsl::campaign.eq(campaign_id))
+            .filter(member_dsl::status.eq(CampaignDeviceStatus::Assigned))
+            .filter(member_dsl::assigned_at.lt(deadline)),
+    )
+    .set((
+        member_dsl::status.eq(CampaignDeviceStatus::Failed),
+        member_dsl::finished_at.eq(now),
+    ))
+    .execute(conn)
+    .await?;
+
+    let stats = stats(conn, campaign_id).await?;
+    if stats.exceeds(campaign.max_failure_percent) {
+        let reason = format!(
+            "{} failed and {} rolled back of {} finished devices exceed {}%",
+            stats.failed,
+            stats.rolled_back,
+            stats.succeeded + stats.failed + stats.rolled_back,
+            campaign.max_failure_percent
+        );
+        warn!("Pausing rollout campaign {}: {}", campaign_id, reason);
+        set_status(
+            conn,
+            &campaign,
+            CampaignStatus::Paused,
+            Some(reason),
+            "pause",
+        )
+        .await?;
+        return Ok(());
+    }
+    if stats.assigned > 0 {
+        return Ok(());
+    }
+    if stats.pending == 0 {
+        info!("Rollout campaign {} completed", campaign_id);
+        set_status(conn, &campaign, CampaignStatus::Completed, None, "complete").await?;
+        return Ok(());
+    }
+    if let Some(started) = campaign.wave_started_at
+        && now < started + chrono::Duration::seconds(campaign.wave_interval_seconds as i64)
+    {
+        return Ok(());
+    }
+    start_wave(conn, &campaign, now).await
+}
+
+async fn set_status(
+    conn: &mut AsyncPgConnection,
+    campaign: &RolloutCampaign,
+    status: CampaignStatus,
+    reason: Option<String>,
+    action: &str,
+) -> Result<(), diesel::result::Error> {
+    let finished_at = match status {
+        CampaignStatus::Completed | CampaignStatus::Aborted => Some(chrono::Utc::now().naive_utc()),
+        CampaignStatus::Running | CampaignStatus::Paused => None,
+    };
+    let updated: RolloutCampaign = diesel::update(campaign_dsl::rollout_campaign.find(campaign.id))
+        .set((
+            campaign_dsl::status.eq(status),
+            campaign_dsl::status_reason.eq(reason),
+            campaign_dsl::finished_at.eq(finished_at),
+        ))
+        .returning(RolloutCampaign::as_returning())
+        .get_result(conn)
+        .await?;
+    audit::record(
+        conn,
+        NewAuditEvent::new(RUNNER_ACTOR, None, action, "rollout_campaign", campaign.id)
+            .before(campaign)
+            .after(&updated),
+    )
+    .await
+}
+
+/// Assign the target firmware to the next devices up to the cumulative count of the wave
+async fn start_wave(
+    conn: &mut AsyncPgConnection,
+    campaign: &RolloutCampaign,
+    now: NaiveDateTime,
+) -> Result<(), diesel::result::Error> {
+    let wave = campaign.current_wave;
+    // The last wave covers all devices, see `waves` of the campaign
+    let cumulative = campaign
+        .waves
+        .get(wave as usize)
+        .copied()
+        .unwrap_or(i32::MAX) as i64;
+    let started: i64 = member_dsl::rollout_campaign_device
+        .filter(member_dsl::campaign.eq(campaign.id))
+        .filter(member_dsl::wave.is_not_null())
+        .count()
+        .get_result(conn)
+        .await?;
+    let members: Vec<RolloutCampaignDevice> = member_dsl::rollout_campaign_device
+        .filter(member_dsl::campaign.eq(campaign.id))
+        .filter(member_dsl::status.eq(CampaignDeviceStatus::Pending))
+        .order(member_dsl::id.asc())
+        .limit((cumulative - started).max(0))
+        .select(RolloutCampaignDevice::as_select())
+        .load(conn)
+        .await?;
+    let linked_types: Vec<i32> = link_dsl::device_type_firmware
+        .filter(link_dsl::firmware.eq(campaign.target_firmware))
+        .select(link_dsl::device_type)
+        .load(conn)
+        .await?;
+
+    let mut assigned = 0;
+    for member in &members {
+        let device: Device = device_dsl::device
+            .find(member.device)
+            .select(Device::as_select())
+            .for_update()
+            .first(conn)
+            .await?;
+        let member_row = member_dsl::rollout_campaign_device.find(member.id);
+        if !linked_types.contains(&device.type_) {
+            // The device type changed or lost the firmware link since the campaign was created
+            diesel::update(member_row)
+                .set((
+                    member_dsl::status.eq(CampaignDeviceStatus::Cancelled),
+                    member_dsl::finished_at.eq(now),
+                ))
+                .execute(conn)
+                .await?;
+            continue;
+        }
+        diesel::update(device_dsl::device.find(device.id))
+            .set(device_dsl::desired_firmware.eq(campaign.target_firmware))
+            .execute(conn)
+            .await?;
+        let (status, finished_at) = if device.firmware == Some(campaign.target_firmware) {
+            (CampaignDeviceStatus::Succeeded, Some(now))
+        } else {
+            (CampaignDeviceStatus::Assigned, None)
+        };
+        diesel::update(member_row)
+            .set((
+                member_dsl::status.eq(status),
+                member_dsl::wave.eq(wave),
+                member_dsl::previous_desired_firmware.eq(device.desired_firmware),
+                member_dsl::assigned_at.eq(now),
+                member_dsl::finished_at.eq(finished_at),
+            ))
+            .execute(conn)
+            .await?;
+        assigned += 1;
+    }
+
+    let updated: RolloutCampaign = diesel::update(campaign_dsl::rollout_campaign.find(campaign.id))
+        .set((
+            campaign_dsl::current_wave.eq(wave + 1),
+            campaign_dsl::wave_started_at.eq(now),
+        ))
+        .returning(RolloutCampaign::as_returning())
+        .get_result(conn)
+        .await?;
+    audit::record(
+        conn,
+        NewAuditEvent::new(
+            RUNNER_ACTOR,
+            None,
+            "start_wave",
+            "rollout_campaign",
+            campaign.id,
+        )
+        .before(campaign)
+        .after(&updated),
+    )
+    .await?;
+    info!(
+        "Rollout campaign {} started wave {} with {} devices",
+        campaign.id,
+        wave + 1,
+        assigned
+    );
+    Ok(())
+}
+
+/// Cancel the unfinished devices of an aborted campaign and restore the desired firmware of
+/// those that did not update yet. Has to be called inside a transaction.
+/// Returns the number of restored devices.
+pub async fn cancel_devices(
+    conn: &mut AsyncPgConnection,
+    campaign: &RolloutCampaign,
+) -> Result<usize, diesel::result::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let assigned: Vec<RolloutCampaignDevice> = member_dsl::rollout_campaign_device
+        .filter(member_dsl::campaign.eq(campaign.id))
+        .filter(member_dsl::status.eq(CampaignDeviceStatus::Assigned))
+        .select(RolloutCampaignDevice::as_select())
+        .load(conn)
+        .await?;
+    let mut restored = 0;
+    for member in assigned {
+        let Some(previous) = member.previous_desired_firmware else {
+            continue;
+        };
+        // Only if it was not changed by someone else in the meantime
+        restored += diesel::update(
+            device_dsl::device
+                .find(member.device)
+                .filter(device_dsl::desired_firmware.eq(campaign.target_firmware)),
+        )
+        .set(device_dsl::desired_firmware.eq(previous))
+        .execute(conn)
+        .await?;
+    }
+    diesel::update(
+        member_dsl::rollout_campaign_device
+            .filter(member_dsl::campaign.eq(campaign.id))
+            .filter(member_dsl::status.eq_any([
+                CampaignDeviceStatus::Pending,
+                CampaignDeviceStatus::Assigned,
+            ])),
+    )
+    .set((
+        member_dsl::status.eq(CampaignDeviceStatus::Cancelled),
+        member_dsl::finished_at.eq(now),
+    ))
+    .execute(conn)
+    .await?;
+    Ok(restored)
+}
+
+/// Track the firmware a device reported as installed.
+///
+/// Assigned devices succeed when they report the target firmware, other reports leave them
+/// assigned as the update may not be installed yet. Succeeded devices of an unfinished campaign
+/// that report another firmware while the target is still desired rolled back.
+/// Has to be called in the transaction of the device update.
+pub async fn record_report(
+    conn: &mut AsyncPgConnection,
+    device_id: i32,
+    firmware_id: i32,
+) -> Result<(), diesel::result::Error> {
+    let members: Vec<(RolloutCampaignDevice, i32)> = member_dsl::rollout_campaign_device
+        .inner_join(campaign_dsl::rollout_campaign)
+        .filter(member_dsl::device.eq(device_id))
+        .filter(member_dsl::status.eq_any([
+            CampaignDeviceStatus::Assigned,
+            CampaignDeviceStatus::Succeeded,
+        ]))
+        .filter(campaign_dsl::status.eq_any([CampaignStatus::Running, CampaignStatus::Paused]))
+        .select((
+            RolloutCampaignDevice::as_select(),
+            campaign_dsl::target_firmware,
+        ))
+        .load(conn)
+        .await?;
+    if members.is_empty() {
+        return Ok(());
+    }
+    let desired_firmware: i32 = device_dsl::device
+        .find(device_id)
+        .select(device_dsl::desired_firmware)
+        .first(conn)
+        .await?;
+
+    for (member, target_firmware) in members {
+        let status = match member.status {
+            CampaignDeviceStatus::Assigned if firmware_id == target_firmware => {
+                CampaignDeviceStatus::Succeeded
+            }
+            CampaignDeviceStatus::Succeeded
+                if firmware_id != target_firmware && desired_firmware == target_firmware =>
+            {
+                warn!(
+                    "Device {} reported firmware {} after installing {} of rollout campaign {}",
+                    device_id, firmware_id, target_firmware, member.campaign
+                );
+                CampaignDeviceStatus::RolledBack
+            }
+            _ => continue,
+        };
+        diesel::update(member_dsl::rollout_campaign_device.find(member.id))
+            .set((
+                member_dsl::status.eq(status),
+                member_dsl::finished_at.eq(chrono::Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Track that a device finished downloading a firmware, the download fails if this does so that
+/// the device asks for the last block again
+pub async fn record_download(
+    conn: &mut AsyncPgConnection,
+    device_id: i32,
+    firmware_id: i32,
+) -> Result<(), diesel::result::Error> {
+    diesel::update(
+        member_dsl::rollout_campaign_device
+            .filter(member_dsl::device.eq(device_id))
+            .filter(member_dsl::status.eq(CampaignDeviceStatus::Assigned))
+            .filter(
+                member_dsl::campaign.eq_any(
+                    campaign_dsl::rollout_campaign
+                        .filter(campaign_dsl::target_firmware.eq(firmware_id))
+                        .select(campaign_dsl::id),
+                ),
+            ),
+    )
+    .set(member_dsl::downloaded_at.eq(chrono::Utc::now().naive_utc()))
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finished(succeeded: i64, failed: i64, rolled_back: i64) -> CampaignStats {
+        CampaignStats {
+            succeeded,
+            failed,
+            rolled_back,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn nothing_finished_never_exceeds() {
+        let stats = CampaignStats {
+            pending: 10,
+            assigned: 5,
+            cancelled: 3,
+            ..Default::default()
+        };
+        assert!(!stats.exceeds(0));
+    }
+
+    #[test]
+    fn zero_percent_tolerates_no_failure() {
+        assert!(!finished(10, 0, 0).exceeds(0));
+        assert!(finished(99, 1, 0).exceeds(0));
+        assert!(finished(99, 0, 1).exceeds(0));
+    }
+
+    #[test]
+    fn hundred_percent_is_never_exceeded() {
+        assert!(!finished(0, 10, 0).exceeds(100));
+        assert!(!finished(0, 5, 5).exceeds(100));
+    }
+
+    #[test]
+    fn exact_boundary_does_not_exceed() {
+        assert!(!finished(9, 1, 0).exceeds(10));
+        assert!(finished(9, 1, 0).exceeds(9));
+        // Rolled back devices count as failures
+        assert!(!finished(8, 1, 1).exceeds(20));
+        assert!(finished(8, 1, 1).exceeds(19));
+        // Unfinished devices are not part of the share
+        let stats = CampaignStats {
+            pending: 100,
+            assigned: 100,
+            cancelled: 100,
+            ..finished(1, 1, 0)
+        };
+        assert!(!stats.exceeds(50));
+        assert!(stats.exceeds(49));
+    }
+}
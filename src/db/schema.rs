@@ -1,6 +1,18 @@
 // @generated automatically by Diesel CLI.
 
 pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "api_key_role"))]
+    pub struct ApiKeyRole;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "campaign_device_status"))]
+    pub struct CampaignDeviceStatus;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "campaign_status"))]
+    pub struct CampaignStatus;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "crypto_algorithm"))]
     pub struct CryptoAlgorithm;
@@ -20,6 +32,52 @@ pub mod sql_types {
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "parameter_type"))]
     pub struct ParameterType;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "release_channel"))]
+    pub struct ReleaseChannel;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "webhook_delivery_status"))]
+    pub struct WebhookDeliveryStatus;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "webhook_event_type"))]
+    pub struct WebhookEventType;
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::ApiKeyRole;
+
+    api_key (id) {
+        id -> Int4,
+        #[max_length = 100]
+        name -> Varchar,
+        key_hash -> Bytea,
+        role -> ApiKeyRole,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    audit_event (id) {
+        id -> Int8,
+        created_at -> Timestamp,
+        actor -> Text,
+        #[max_length = 45]
+        source_ip -> Nullable<Varchar>,
+        #[max_length = 50]
+        action -> Varchar,
+        #[max_length = 50]
+        entity_type -> Varchar,
+        entity_id -> Int4,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+    }
 }
 
 diesel::table! {
@@ -38,6 +96,24 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    device_group (id) {
+        id -> Int4,
+        #[max_length = 100]
+        name -> Varchar,
+        #[max_length = 1000]
+        description -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    device_group_device (id) {
+        id -> Int4,
+        device_group -> Int4,
+        device -> Int4,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::KeyType;
@@ -48,6 +124,8 @@ diesel::table! {
         device -> Int4,
         key_type -> KeyType,
         status -> KeyStatus,
+        delivered_at -> Nullable<Timestamp>,
+        acknowledged_at -> Nullable<Timestamp>,
     }
 }
 
@@ -66,6 +144,23 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    device_replay_window (device) {
+        device -> Int4,
+        highest_sequence_number -> Int8,
+        window_bitmap -> Int8,
+    }
+}
+
+diesel::table! {
+    device_tag (id) {
+        id -> Int4,
+        device -> Int4,
+        #[max_length = 100]
+        tag -> Varchar,
+    }
+}
+
 diesel::table! {
     device_type (id) {
         id -> Int4,
@@ -98,6 +193,9 @@ diesel::table! {
 }
 
 diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::ReleaseChannel;
+
     firmware (id) {
         id -> Int4,
         #[max_length = 100]
@@ -109,6 +207,14 @@ diesel::table! {
         size -> Int8,
         #[max_length = 64]
         sha256 -> Varchar,
+        #[max_length = 1000]
+        description -> Nullable<Varchar>,
+        release_notes -> Nullable<Text>,
+        build_timestamp -> Nullable<Timestamp>,
+        #[max_length = 64]
+        git_commit -> Nullable<Varchar>,
+        channel -> ReleaseChannel,
+        version_sort_key -> Nullable<Text>,
     }
 }
 
@@ -121,6 +227,49 @@ diesel::table! {
         device_key -> Int4,
         algorithm -> CryptoAlgorithm,
         key -> Bytea,
+        kek_id -> Nullable<Bytea>,
+        #[max_length = 16]
+        fingerprint -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::CampaignStatus;
+
+    rollout_campaign (id) {
+        id -> Int4,
+        #[max_length = 100]
+        name -> Varchar,
+        target_firmware -> Int4,
+        device_filter -> Jsonb,
+        waves -> Array<Int4>,
+        wave_interval_seconds -> Int4,
+        device_timeout_seconds -> Int4,
+        max_failure_percent -> Int4,
+        status -> CampaignStatus,
+        status_reason -> Nullable<Text>,
+        current_wave -> Int4,
+        created_at -> Timestamp,
+        wave_started_at -> Nullable<Timestamp>,
+        finished_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::CampaignDeviceStatus;
+
+    rollout_campaign_device (id) {
+        id -> Int4,
+        campaign -> Int4,
+        device -> Int4,
+        status -> CampaignDeviceStatus,
+        wave -> Nullable<Int4>,
+        previous_desired_firmware -> Nullable<Int4>,
+        assigned_at -> Nullable<Timestamp>,
+        downloaded_at -> Nullable<Timestamp>,
+        finished_at -> Nullable<Timestamp>,
     }
 }
 
@@ -130,26 +279,86 @@ diesel::table! {
         device_key -> Int4,
         valid_from -> Timestamp,
         valid_to -> Timestamp,
+        serial -> Text,
+        certificate -> Text,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::WebhookEventType;
+
+    webhook (id) {
+        id -> Int4,
+        #[max_length = 100]
+        name -> Varchar,
+        #[max_length = 2000]
+        url -> Varchar,
+        #[max_length = 128]
+        secret -> Varchar,
+        event_types -> Array<WebhookEventType>,
+        enabled -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::WebhookEventType;
+    use super::sql_types::WebhookDeliveryStatus;
+
+    webhook_delivery (id) {
+        id -> Int8,
+        webhook -> Int4,
+        event_type -> WebhookEventType,
+        payload -> Jsonb,
+        status -> WebhookDeliveryStatus,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+        last_attempt_at -> Nullable<Timestamp>,
+        last_response_status -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
     }
 }
 
 diesel::joinable!(device -> device_type (type_));
+diesel::joinable!(device_group_device -> device (device));
+diesel::joinable!(device_group_device -> device_group (device_group));
 diesel::joinable!(device_key -> device (device));
 diesel::joinable!(device_parameter -> device (device));
+diesel::joinable!(device_replay_window -> device (device));
+diesel::joinable!(device_tag -> device (device));
 diesel::joinable!(device_type_firmware -> device_type (device_type));
 diesel::joinable!(device_type_firmware -> firmware (firmware));
 diesel::joinable!(device_type_parameter -> device_type (device_type));
 diesel::joinable!(lightweight_key_details -> device_key (device_key));
+diesel::joinable!(rollout_campaign -> firmware (target_firmware));
+diesel::joinable!(rollout_campaign_device -> device (device));
+diesel::joinable!(rollout_campaign_device -> rollout_campaign (campaign));
 diesel::joinable!(tls_key_details -> device_key (device_key));
+diesel::joinable!(webhook_delivery -> webhook (webhook));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_key,
+    audit_event,
     device,
+    device_group,
+    device_group_device,
     device_key,
     device_parameter,
+    device_replay_window,
+    device_tag,
     device_type,
     device_type_firmware,
     device_type_parameter,
     firmware,
     lightweight_key_details,
+    rollout_campaign,
+    rollout_campaign_device,
     tls_key_details,
+    webhook,
+    webhook_delivery,
 );
@@ -0,0 +1,496 @@
+use crate::db::models::{
+    NewWebhookDelivery, Webhook, WebhookDelivery, WebhookDeliveryStatus, WebhookEventType,
+};
+use crate::db::schema::webhook::dsl as webhook_dsl;
+use crate::db::schema::webhook_delivery::dsl as delivery_dsl;
+use diesel::ExpressionMethods;
+use diesel::PgArrayExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use log::{error, info, warn};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(5);
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longer than a send including the recording of its result
+const CLAIM_LEASE: Duration = Duration::from_secs(60);
+const BATCH_SIZE: i64 = 50;
+pub const MAX_ATTEMPTS: i32 = 10;
+const BACKOFF_BASE_SECS: i64 = 30;
+const BACKOFF_MAX_SECS: i64 = 3600;
+const USER_AGENT: &str = concat!("firmups/", env!("CARGO_PKG_VERSION"));
+
+type HttpClient = Client<hyper_rustls::HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+/// Queue `data` for the enabled webhooks subscribed to `event`, returns the number of deliveries.
+/// Has to be called in the transaction of the change `data` describes.
+pub async fn enqueue<T: serde::Serialize>(
+    conn: &mut AsyncPgConnection,
+    event: WebhookEventType,
+    data: &T,
+) -> Result<usize, diesel::result::Error> {
+    let subscribers: Vec<i32> = webhook_dsl::webhook
+        .filter(webhook_dsl::enabled.eq(true))
+        .filter(webhook_dsl::event_types.contains(vec![event]))
+        .select(webhook_dsl::id)
+        .load(conn)
+        .await?;
+    if subscribers.is_empty() {
+        return Ok(0);
+    }
+    let payload = serde_json::to_value(data)
+        .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+    let rows: Vec<NewWebhookDelivery> = subscribers
+        .into_iter()
+        .map(|webhook_id| NewWebhookDelivery {
+            webhook: webhook_id,
+            event_type: event,
+            payload: payload.clone(),
+        })
+        .collect();
+    diesel::insert_into(delivery_dsl::webhook_delivery)
+        .values(&rows)
+        .execute(conn)
+        .await
+}
+
+/// Hex HMAC-SHA256 of "{timestamp}.{body}"
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Delay before the attempt following `attempts` failed ones
+fn backoff(attempts: i32) -> chrono::Duration {
+    let exponent = (attempts - 1).clamp(0, 16) as u32;
+    chrono::Duration::seconds((BACKOFF_BASE_SECS << exponent).min(BACKOFF_MAX_SECS))
+}
+
+fn http_client() -> Result<HttpClient, rustls::Error> {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_provider_and_webpki_roots(Arc::new(rustls::crypto::ring::default_provider()))?
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok(Client::builder(TokioExecutor::new()).build(connector))
+}
+
+/// Send the due deliveries every `DISPATCH_INTERVAL` until `cancel` is triggered
+pub async fn run(shared_pool: Arc<crate::DbPool>, cancel: CancellationToken) {
+    let client = match http_client() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to set up the webhook HTTP client: {}", e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(DISPATCH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = cancel.cancelled() => break,
+        }
+        let claimed = {
+            let mut conn = match shared_pool.get().await {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("Failed to get DB connection for webhooks: {}", e);
+                    continue;
+                }
+            };
+            match conn
+                .transaction::<_, diesel::result::Error, _>(|conn| Box::pin(claim(conn)))
+                .await
+            {
+                Ok(claimed) => claimed,
+                Err(e) => {
+                    error!("Failed to claim webhook deliveries: {}", e);
+                    continue;
+                }
+            }
+        };
+        let mut sends = tokio::task::JoinSet::new();
+        for (delivery, webhook) in claimed {
+            let shared_pool = shared_pool.clone();
+            let client = client.clone();
+            sends.spawn(async move {
+                let outcome = send(&client, &delivery, &webhook, SEND_TIMEOUT).await;
+                let mut conn = match shared_pool.get().await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("Failed to get DB connection for webhooks: {}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = record_attempt(&mut conn, &delivery, outcome).await {
+                    error!(
+                        "Failed to record attempt of webhook delivery {}: {}",
+                        delivery.id, e
+                    );
+                }
+            });
+        }
+        while sends.join_next().await.is_some() {}
+    }
+}
+
+/// Move the next attempt of the due deliveries behind `CLAIM_LEASE`, so that another instance
+/// does not send them meanwhile. Has to be called inside a transaction.
+async fn claim(
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<(WebhookDelivery, Webhook)>, diesel::result::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let due: Vec<WebhookDelivery> = delivery_dsl::webhook_delivery
+        .filter(delivery_dsl::status.eq(WebhookDeliveryStatus::Pending))
+        .filter(delivery_dsl::next_attempt_at.le(now))
+        .filter(
+            delivery_dsl::webhook.eq_any(
+                webhook_dsl::webhook
+                    .filter(webhook_dsl::enabled.eq(true))
+                    .select(webhook_dsl::id),
+            ),
+        )
+        .order(delivery_dsl::next_attempt_at.asc())
+        .limit(BATCH_SIZE)
+        .for_update()
+        .skip_locked()
+        .select(WebhookDelivery::as_select())
+        .load(conn)
+        .await?;
+    if due.is_empty() {
+        return Ok(Vec::new());
+    }
+    let ids: Vec<i64> = due.iter().map(|d| d.id).collect();
+    diesel::update(delivery_dsl::webhook_delivery.filter(delivery_dsl::id.eq_any(&ids)))
+        .set(delivery_dsl::next_attempt_at.eq(now + CLAIM_LEASE))
+        .execute(conn)
+        .await?;
+    let webhook_ids: Vec<i32> = due.iter().map(|d| d.webhook).collect();
+    let webhooks: Vec<Webhook> = webhook_dsl::webhook
+        .filter(webhook_dsl::id.eq_any(webhook_ids))
+        .select(Webhook::as_select())
+        .load(conn)
+        .await?;
+    Ok(due
+        .into_iter()
+        .filter_map(|delivery| {
+            let webhook = webhooks.iter().find(|w| w.id == delivery.webhook)?.clone();
+            Some((delivery, webhook))
+        })
+        .collect())
+}
+
+/// Result of a single attempt
+struct Outcome {
+    response_status: Option<i32>,
+    error: Option<String>,
+}
+
+async fn send(
+    client: &HttpClient,
+    delivery: &WebhookDelivery,
+    webhook: &Webhook,
+    timeout: Duration,
+) -> Outcome {
+    let failed = |error: String| Outcome {
+        response_status: None,
+        error: Some(error),
+    };
+    let body = serde_json::json!({
+        "id": delivery.id,
+        "event": delivery.event_type,
+        "created_at": delivery.created_at,
+        "data": delivery.payload,
+    })
+    .to_string();
+    let timestamp = chrono::Utc::now().timestamp();
+    let event = serde_json::to_value(delivery.event_type)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default();
+    let request = match hyper::Request::post(&webhook.url)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(hyper::header::USER_AGENT, USER_AGENT)
+        .header("x-firmups-event", event)
+        .header("x-firmups-delivery", delivery.id)
+        .header("x-firmups-timestamp", timestamp)
+        .header(
+            "x-firmups-signature",
+            format!(
+                "sha256={}",
+                signature(&webhook.secret, timestamp, body.as_bytes())
+            ),
+        )
+        .body(Full::new(Bytes::from(body)))
+    {
+        Ok(r) => r,
+        Err(e) => return failed(e.to_string()),
+    };
+    match tokio::time::timeout(timeout, client.request(request)).await {
+        Err(_) => failed("timeout".to_string()),
+        Ok(Err(e)) => failed(e.to_string()),
+        Ok(Ok(response)) => {
+            let status = response.status();
+            Outcome {
+                response_status: Some(status.as_u16() as i32),
+                error: (!status.is_success()).then(|| format!("status {}", status)),
+            }
+        }
+    }
+}
+
+async fn record_attempt(
+    conn: &mut AsyncPgConnection,
+    delivery: &WebhookDelivery,
+    outcome: Outcome,
+) -> Result<(), diesel::result::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let attempts = delivery.attempts + 1;
+    let (status, next_attempt_at, delivered_at) = match &outcome.error {
+        None => (WebhookDeliveryStatus::Delivered, now, Some(now)),
+        Some(_) if attempts >= MAX_ATTEMPTS => (WebhookDeliveryStatus::Failed, now, None),
+        Some(_) => (
+            WebhookDeliveryStatus::Pending,
+            now + backoff(attempts),
+            None,
+        ),
+    };
+    match (&outcome.error, status) {
+        (None, _) => info!(
+            "Delivered webhook delivery {} to webhook {}",
+            delivery.id, delivery.webhook
+        ),
+        (Some(e), WebhookDeliveryStatus::Failed) => warn!(
+            "Giving up webhook delivery {} to webhook {} after {} attempts: {}",
+            delivery.id, delivery.webhook, attempts, e
+        ),
+        (Some(e), _) => warn!(
+            "Attempt {} of webhook delivery {} to webhook {} failed: {}",
+            attempts, delivery.id, delivery.webhook, e
+        ),
+    }
+    diesel::update(delivery_dsl::webhook_delivery.find(delivery.id))
+        .set((
+            delivery_dsl::status.eq(status),
+            delivery_dsl::attempts.eq(attempts),
+            delivery_dsl::next_attempt_at.eq(next_attempt_at),
+            delivery_dsl::last_attempt_at.eq(now),
+            delivery_dsl::last_response_status.eq(outcome.response_status),
+            delivery_dsl::last_error.eq(outcome.error),
+            delivery_dsl::delivered_at.eq(delivered_at),
+        ))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn signature_matches_known_vector() {
+        assert_eq!(
+            signature("whsec_test", 1700000000, br#"{"id":1}"#),
+            "2f441ba4b3b2d50d28a9ab9d9fd8880376ecd1eb5d0435401553f5d8d0a5dcf8"
+        );
+        assert_ne!(
+            signature("whsec_other", 1700000000, br#"{"id":1}"#),
+            signature("whsec_test", 1700000000, br#"{"id":1}"#)
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_cap() {
+        assert_eq!(backoff(0).num_seconds(), BACKOFF_BASE_SECS);
+        assert_eq!(backoff(1).num_seconds(), BACKOFF_BASE_SECS);
+        assert_eq!(backoff(2).num_seconds(), 2 * BACKOFF_BASE_SECS);
+        assert_eq!(backoff(3).num_seconds(), 4 * BACKOFF_BASE_SECS);
+        assert_eq!(backoff(7).num_seconds(), 64 * BACKOFF_BASE_SECS);
+        assert_eq!(backoff(8).num_seconds(), BACKOFF_MAX_SECS);
+        assert_eq!(backoff(MAX_ATTEMPTS).num_seconds(), BACKOFF_MAX_SECS);
+        assert_eq!(backoff(i32::MAX).num_seconds(), BACKOFF_MAX_SECS);
+    }
+
+    struct ReceivedRequest {
+        request_line: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    /// Local stand-in for a webhook receiver that answers one request with `status`
+    async fn receiver(status: u16) -> (String, tokio::task::JoinHandle<ReceivedRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let header_end = loop {
+                let mut chunk = [0u8; 1024];
+                let read = stream.read(&mut chunk).await.unwrap();
+                assert!(read > 0, "connection closed before the headers ended");
+                buf.extend_from_slice(&chunk[..read]);
+                if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break pos;
+                }
+            };
+            let head = String::from_utf8(buf[..header_end].to_vec()).unwrap();
+            let mut lines = head.split("\r\n");
+            let request_line = lines.next().unwrap().to_string();
+            let headers: HashMap<String, String> = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+                .collect();
+            let length: usize = headers["content-length"].parse().unwrap();
+            let mut body = buf[header_end + 4..].to_vec();
+            while body.len() < length {
+                let mut chunk = [0u8; 1024];
+                let read = stream.read(&mut chunk).await.unwrap();
+                body.extend_from_slice(&chunk[..read]);
+            }
+            let response = format!("HTTP/1.1 {} Test\r\ncontent-length: 0\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            ReceivedRequest {
+                request_line,
+                headers,
+                body,
+            }
+        });
+        (url, handle)
+    }
+
+    fn webhook(url: String) -> Webhook {
+        Webhook {
+            id: 3,
+            name: "test".to_string(),
+            url,
+            secret: "whsec_test".to_string(),
+            event_types: vec![WebhookEventType::FirmwareUploaded],
+            enabled: true,
+            created_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    fn delivery() -> WebhookDelivery {
+        let now = chrono::Utc::now().naive_utc();
+        WebhookDelivery {
+            id: 17,
+            webhook: 3,
+            event_type: WebhookEventType::FirmwareUploaded,
+            payload: serde_json::json!({ "firmware": 5 }),
+            status: WebhookDeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            last_attempt_at: None,
+            last_response_status: None,
+            last_error: None,
+            created_at: now,
+            delivered_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn send_signs_request() {
+        let (url, received) = receiver(204).await;
+        let outcome = send(
+            &http_client().unwrap(),
+            &delivery(),
+            &webhook(url),
+            SEND_TIMEOUT,
+        )
+        .await;
+        assert_eq!(outcome.response_status, Some(204));
+        assert_eq!(outcome.error, None);
+
+        let request = received.await.unwrap();
+        assert_eq!(request.request_line, "POST /hook HTTP/1.1");
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(request.headers["user-agent"], USER_AGENT);
+        assert_eq!(request.headers["x-firmups-event"], "FIRMWARE_UPLOADED");
+        assert_eq!(request.headers["x-firmups-delivery"], "17");
+        let timestamp: i64 = request.headers["x-firmups-timestamp"].parse().unwrap();
+        assert!((chrono::Utc::now().timestamp() - timestamp).abs() < 60);
+        assert_eq!(
+            request.headers["x-firmups-signature"],
+            format!(
+                "sha256={}",
+                signature("whsec_test", timestamp, &request.body)
+            )
+        );
+
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["id"], 17);
+        assert_eq!(body["event"], "FIRMWARE_UPLOADED");
+        assert_eq!(body["data"], serde_json::json!({ "firmware": 5 }));
+    }
+
+    #[tokio::test]
+    async fn send_fails_on_error_status() {
+        let (url, received) = receiver(500).await;
+        let outcome = send(
+            &http_client().unwrap(),
+            &delivery(),
+            &webhook(url),
+            SEND_TIMEOUT,
+        )
+        .await;
+        received.await.unwrap();
+        assert_eq!(outcome.response_status, Some(500));
+        assert!(outcome.error.unwrap().contains("500"));
+    }
+
+    #[tokio::test]
+    async fn send_fails_on_timeout() {
+        // Accepts the connection but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let outcome = send(
+            &http_client().unwrap(),
+            &delivery(),
+            &webhook(url),
+            Duration::from_millis(200),
+        )
+        .await;
+        assert_eq!(outcome.response_status, None);
+        assert_eq!(outcome.error.as_deref(), Some("timeout"));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn send_fails_without_receiver() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        drop(listener);
+        let outcome = send(
+            &http_client().unwrap(),
+            &delivery(),
+            &webhook(url),
+            SEND_TIMEOUT,
+        )
+        .await;
+        assert_eq!(outcome.response_status, None);
+        assert!(outcome.error.is_some());
+    }
+}
@@ -1,11 +1,16 @@
+use db::key_encryption::KeyEncryptionKey;
 use diesel_async::{
     AsyncPgConnection,
     pooled_connection::{AsyncDieselConnectionManager, bb8},
 };
 use dotenvy::dotenv;
-use log::{error, info};
+use log::{error, info, warn};
 use std::fs;
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -76,12 +81,142 @@ async fn main() {
 
     info!("Logging initialized.");
 
+    // Key encryption key for lightweight keys at rest
+    let key_encryption_key = match KeyEncryptionKey::from_env("FIRMUPS_KEY_ENCRYPTION_KEY") {
+        Ok(Some(kek)) => Arc::new(kek),
+        Ok(None) => {
+            error!(
+                "FIRMUPS_KEY_ENCRYPTION_KEY or FIRMUPS_KEY_ENCRYPTION_KEY_FILE must be set to a base64 encoded {} byte key, e.g. from `openssl rand -base64 32`",
+                db::key_encryption::KEK_LEN
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("Failed to load key encryption key: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if std::env::args().nth(1).as_deref() == Some("rewrap-keys") {
+        // Re-wrap all keys after a KEK rotation, the previous KEK is only needed here
+        let old_key_encryption_key =
+            match KeyEncryptionKey::from_env("FIRMUPS_OLD_KEY_ENCRYPTION_KEY") {
+                Ok(kek) => kek,
+                Err(e) => {
+                    error!("Failed to load old key encryption key: {}", e);
+                    std::process::exit(1);
+                }
+            };
+        match db::key_encryption::rewrap_keys(
+            &shared_pool,
+            old_key_encryption_key.as_ref(),
+            &key_encryption_key,
+        )
+        .await
+        {
+            Ok(count) => info!("Re-wrapped {} keys", count),
+            Err(e) => {
+                error!("Failed to re-wrap keys: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // The startup steps need the columns of every migration, with pending migrations the server
+    // still starts so that /readyz can report them
+    let pending_migrations = match shared_pool.get().await {
+        Ok(mut conn) => db::migrations::pending_migrations(&mut conn).await,
+        Err(e) => Err(e.into()),
+    };
+    match pending_migrations {
+        Ok(pending) if !pending.is_empty() => {
+            warn!(
+                "Skipping the startup steps, migrations {} are pending",
+                pending.join(", ")
+            );
+        }
+        Ok(_) => {
+            // Wrap keys stored before encryption at rest, fingerprint those stored before the
+            // fingerprint column
+            match db::key_encryption::rewrap_keys(&shared_pool, None, &key_encryption_key).await {
+                Ok(0) => {}
+                Ok(count) => info!("Wrapped or fingerprinted {} stored keys", count),
+                Err(e) => {
+                    error!("Failed to wrap stored keys: {}", e);
+                    return;
+                }
+            }
+
+            // Order firmware stored before versions were parsed
+            match db::firmware_version::backfill_sort_keys(&shared_pool).await {
+                Ok(0) => {}
+                Ok(count) => info!("Parsed the version of {} firmware", count),
+                Err(e) => {
+                    error!("Failed to parse stored firmware versions: {}", e);
+                    return;
+                }
+            }
+        }
+        Err(e) => {
+            error!("Failed to check for pending migrations: {}", e);
+            return;
+        }
+    }
+
+    // Rollout campaigns
+    let rollout_cancel = tokio_util::sync::CancellationToken::new();
+    let rollout_runner = tokio::spawn(db::rollout::run(
+        shared_pool.clone(),
+        rollout_cancel.clone(),
+    ));
+
+    // Outbound webhooks
+    let webhook_cancel = tokio_util::sync::CancellationToken::new();
+    let webhook_dispatcher = tokio::spawn(db::webhook::run(
+        shared_pool.clone(),
+        webhook_cancel.clone(),
+    ));
+
+    // Live events published by the CBOR API and streamed by the REST API
+    let events = api::events::EventBus::new();
+
+    // Prometheus metrics recorded by both APIs
+    let metrics = match api::metrics::Metrics::new(shared_pool.clone()) {
+        Ok(m) => m,
+        Err(e) => {
+            error!("Failed to register metrics: {}", e);
+            return;
+        }
+    };
+
     // CBOR API
     let cbor_addr: SocketAddr = "0.0.0.0:53585".parse().unwrap();
+    let coap_addr: SocketAddr = "0.0.0.0:5683".parse().unwrap();
+    let cbor_max_concurrent_requests: usize =
+        match std::env::var("FIRMUPS_CBOR_MAX_CONCURRENT_REQUESTS") {
+            Ok(value) => match value.parse::<usize>() {
+                Ok(max) if (1..=api::cbor::MAX_CONCURRENT_REQUESTS).contains(&max) => max,
+                _ => {
+                    error!(
+                        "FIRMUPS_CBOR_MAX_CONCURRENT_REQUESTS must be between 1 and {}, got '{}'",
+                        api::cbor::MAX_CONCURRENT_REQUESTS,
+                        value
+                    );
+                    return;
+                }
+            },
+            Err(_) => 256,
+        };
     let cbor_api_config = api::cbor::CborApiConfig {
         listen_address: cbor_addr,
+        coap_listen_address: coap_addr,
         shared_pool: shared_pool.clone(),
+        key_encryption_key: key_encryption_key.clone(),
         data_storage_location: data_path.clone(),
+        max_concurrent_requests: cbor_max_concurrent_requests,
+        events: events.clone(),
+        metrics: metrics.clone(),
     };
     let mut cbor_api = api::cbor::CborApi::new(cbor_api_config);
     cbor_api.start().await;
@@ -115,34 +250,143 @@ async fn main() {
         }
         Err(_) => {
             info!("FIRMUPS_API_KEY not set generating random key...");
-            const CHARSET: &[u8; 62] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                              abcdefghijklmnopqrstuvwxyz\
-                              0123456789";
-            const LENGTH: usize = 32;
-            let mut buf = [0u8; LENGTH];
-            getrandom::fill(&mut buf).expect("Failed to get random bytes");
-
-            let key: String = buf
-                .iter()
-                .map(|&b| {
-                    let idx = (b as usize) % CHARSET.len();
-                    CHARSET[idx] as char
-                })
-                .collect();
-
+            let key = api::rest::api_key::generate_key();
             info!("Generated api_key {}", key);
             key
         }
     };
 
+    let key_export_enabled: bool = std::env::var("FIRMUPS_KEY_EXPORT_ENABLED")
+        .ok()
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+
+    let certificate_authority = match (
+        std::env::var("FIRMUPS_CA_CERT_FILE"),
+        std::env::var("FIRMUPS_CA_KEY_FILE"),
+    ) {
+        (Ok(cert_path), Ok(key_path)) => {
+            match api::rest::certificate_authority::CertificateAuthority::load(
+                &cert_path, &key_path,
+            ) {
+                Ok(ca) => Some(Arc::new(ca)),
+                Err(e) => {
+                    error!("Failed to load certificate authority: {}", e);
+                    return;
+                }
+            }
+        }
+        _ => {
+            info!(
+                "FIRMUPS_CA_CERT_FILE or FIRMUPS_CA_KEY_FILE not set, TLS keys cannot be created"
+            );
+            None
+        }
+    };
+
+    let tls = match (
+        std::env::var("FIRMUPS_TLS_CERT_FILE"),
+        std::env::var("FIRMUPS_TLS_KEY_FILE"),
+    ) {
+        (Ok(cert_path), Ok(key_path)) => Some(api::rest::tls::TlsConfig {
+            cert_path: PathBuf::from(cert_path),
+            key_path: PathBuf::from(key_path),
+            client_ca_path: std::env::var("FIRMUPS_TLS_CLIENT_CA_FILE")
+                .ok()
+                .map(PathBuf::from),
+            client_crl_path: std::env::var("FIRMUPS_TLS_CLIENT_CRL_FILE")
+                .ok()
+                .map(PathBuf::from),
+        }),
+        (Err(_), Err(_)) => {
+            if std::env::var("FIRMUPS_TLS_CLIENT_CA_FILE").is_ok() {
+                error!(
+                    "FIRMUPS_TLS_CLIENT_CA_FILE requires FIRMUPS_TLS_CERT_FILE and FIRMUPS_TLS_KEY_FILE"
+                );
+                return;
+            }
+            None
+        }
+        _ => {
+            error!("FIRMUPS_TLS_CERT_FILE and FIRMUPS_TLS_KEY_FILE have to be set together");
+            return;
+        }
+    };
+
+    if let Some(tls) = &tls {
+        if tls.client_crl_path.is_some() && tls.client_ca_path.is_none() {
+            error!("FIRMUPS_TLS_CLIENT_CRL_FILE requires FIRMUPS_TLS_CLIENT_CA_FILE");
+            return;
+        }
+        // Every device certificate would otherwise authenticate as OPERATOR
+        if let Ok(ca_cert_path) = std::env::var("FIRMUPS_CA_CERT_FILE") {
+            match tls.client_ca_contains(Path::new(&ca_cert_path)) {
+                Ok(false) => {}
+                Ok(true) => {
+                    error!(
+                        "FIRMUPS_TLS_CLIENT_CA_FILE must not contain the device CA of FIRMUPS_CA_CERT_FILE"
+                    );
+                    return;
+                }
+                Err(e) => {
+                    error!("Failed to compare the client CA with the device CA: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    let jwt_jwks_file = std::env::var("FIRMUPS_JWT_JWKS_FILE")
+        .ok()
+        .map(PathBuf::from);
+    let jwt_issuer = std::env::var("FIRMUPS_JWT_ISSUER").ok();
+    let jwt_validator = if jwt_jwks_file.is_some() || jwt_issuer.is_some() {
+        let jwt_config = api::rest::jwt::JwtConfig {
+            jwks_file: jwt_jwks_file,
+            issuer: jwt_issuer,
+            audience: std::env::var("FIRMUPS_JWT_AUDIENCE").ok(),
+            role_claim: std::env::var("FIRMUPS_JWT_ROLE_CLAIM")
+                .unwrap_or_else(|_| "roles".to_string()),
+        };
+        match api::rest::jwt::JwtValidator::new(jwt_config).await {
+            Ok(validator) => {
+                info!("Bearer token authentication enabled");
+                Some(Arc::new(validator))
+            }
+            Err(e) => {
+                error!("Failed to load JWT signing keys: {}", e);
+                return;
+            }
+        }
+    } else {
+        if std::env::var("FIRMUPS_JWT_AUDIENCE").is_ok() {
+            error!("FIRMUPS_JWT_AUDIENCE requires FIRMUPS_JWT_JWKS_FILE or FIRMUPS_JWT_ISSUER");
+            return;
+        }
+        None
+    };
+
     let rest_api_config = api::rest::RestApiConfig {
         listen_address: rest_addr,
         shared_pool: shared_pool.clone(),
+        key_encryption_key,
         data_storage_location: data_path.clone(),
         max_firmware_size,
         api_key,
+        key_export_enabled,
+        certificate_authority,
+        jwt_validator,
+        tls,
+        events,
+        metrics,
+        cbor_health: cbor_api.health(),
+        shutdown: tokio_util::sync::CancellationToken::new(),
     };
     let mut rest_api = api::rest::RestApi::new(rest_api_config);
     rest_api.start_blocking().await;
     cbor_api.shutdown().await;
+    rollout_cancel.cancel();
+    let _ = rollout_runner.await;
+    webhook_cancel.cancel();
+    let _ = webhook_dispatcher.await;
 }